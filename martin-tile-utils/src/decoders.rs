@@ -1,7 +1,9 @@
 use std::io::{Read as _, Write as _};
 
-use flate2::read::GzDecoder;
-use flate2::write::GzEncoder;
+use flate2::read::{GzDecoder, ZlibDecoder};
+use flate2::write::{GzEncoder, ZlibEncoder};
+
+use crate::Encoding;
 
 pub fn decode_gzip(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
     let mut decoder = GzDecoder::new(data);
@@ -11,7 +13,28 @@ pub fn decode_gzip(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
 }
 
 pub fn encode_gzip(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
-    let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encode_gzip_level(data, flate2::Compression::default())
+}
+
+fn encode_gzip_level(data: &[u8], level: flate2::Compression) -> Result<Vec<u8>, std::io::Error> {
+    let mut encoder = GzEncoder::new(Vec::new(), level);
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+pub fn decode_zlib(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+    let mut decoder = ZlibDecoder::new(data);
+    let mut decompressed = Vec::new();
+    decoder.read_to_end(&mut decompressed)?;
+    Ok(decompressed)
+}
+
+pub fn encode_zlib(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+    encode_zlib_level(data, flate2::Compression::default())
+}
+
+fn encode_zlib_level(data: &[u8], level: flate2::Compression) -> Result<Vec<u8>, std::io::Error> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), level);
     encoder.write_all(data)?;
     encoder.finish()
 }
@@ -24,7 +47,81 @@ pub fn decode_brotli(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
 }
 
 pub fn encode_brotli(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
-    let mut encoder = brotli::CompressorWriter::new(Vec::new(), 4096, 11, 22);
+    encode_brotli_level(data, 11)
+}
+
+fn encode_brotli_level(data: &[u8], level: u32) -> Result<Vec<u8>, std::io::Error> {
+    let mut encoder = brotli::CompressorWriter::new(Vec::new(), 4096, level, 22);
     encoder.write_all(data)?;
     Ok(encoder.into_inner())
 }
+
+pub fn decode_zstd(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+    zstd::decode_all(data)
+}
+
+pub fn encode_zstd(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+    zstd::encode_all(data, zstd::DEFAULT_COMPRESSION_LEVEL)
+}
+
+/// Decode data compressed with the given encoding.
+/// Uncompressed data and formats with internal compression like PNG are returned as is.
+pub fn decode(data: &[u8], encoding: Encoding) -> Result<Vec<u8>, std::io::Error> {
+    match encoding {
+        Encoding::Uncompressed | Encoding::Internal => Ok(data.to_vec()),
+        Encoding::Gzip => decode_gzip(data),
+        Encoding::Zlib => decode_zlib(data),
+        Encoding::Brotli => decode_brotli(data),
+        Encoding::Zstd => decode_zstd(data),
+    }
+}
+
+/// Compress data with the given encoding. The `level` is clamped to the maximum level supported
+/// by the encoding (9 for gzip and zlib, 11 for brotli, 22 for zstd), and uses the same default as
+/// the encoding-specific functions if not set.
+/// Uncompressed data and formats with internal compression like PNG are returned as is.
+pub fn encode(
+    data: &[u8],
+    encoding: Encoding,
+    level: Option<u32>,
+) -> Result<Vec<u8>, std::io::Error> {
+    let flate_level = || {
+        level.map_or_else(flate2::Compression::default, |v| {
+            flate2::Compression::new(v.min(9))
+        })
+    };
+    match encoding {
+        Encoding::Uncompressed | Encoding::Internal => Ok(data.to_vec()),
+        Encoding::Gzip => encode_gzip_level(data, flate_level()),
+        Encoding::Zlib => encode_zlib_level(data, flate_level()),
+        Encoding::Brotli => encode_brotli_level(data, level.map_or(11, |v| v.min(11))),
+        Encoding::Zstd => {
+            let level = level.map_or(zstd::DEFAULT_COMPRESSION_LEVEL, |v| {
+                i32::try_from(v.min(22)).unwrap_or_default()
+            });
+            zstd::encode_all(data, level)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let data = b"some tile data, some tile data, some tile data".repeat(10);
+        for encoding in [
+            Encoding::Uncompressed,
+            Encoding::Gzip,
+            Encoding::Zlib,
+            Encoding::Brotli,
+            Encoding::Zstd,
+        ] {
+            for level in [None, Some(1), Some(100)] {
+                let encoded = encode(&data, encoding, level).unwrap();
+                assert_eq!(decode(&encoded, encoding).unwrap(), data, "{encoding:?}");
+            }
+        }
+    }
+}
@@ -179,6 +179,7 @@ impl TileInfo {
             // Compressed prefixes assume MVT content
             v if v.starts_with(b"\x1f\x8b") => Self::new(Mvt, Gzip),
             v if v.starts_with(b"\x78\x9c") => Self::new(Mvt, Zlib),
+            v if v.starts_with(b"\x28\xb5\x2f\xfd") => Self::new(Mvt, Zstd),
             v if v.starts_with(b"\x89\x50\x4E\x47\x0D\x0A\x1A\x0A") => Self::new(Png, Internal),
             v if v.starts_with(b"\x47\x49\x46\x38\x39\x61") => Self::new(Gif, Internal),
             v if v.starts_with(b"\xFF\xD8\xFF") => Self::new(Jpeg, Internal),
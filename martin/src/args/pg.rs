@@ -62,14 +62,23 @@ impl PgArgs {
             .into_iter()
             .map(|s| PgConfig {
                 connection_string: Some(s),
+                read_replicas: OptOneMany::NoVals,
+                equivalent_hosts: OptOneMany::NoVals,
+                load_balancing: None,
                 ssl_certificates: certs.clone(),
                 default_srid,
                 auto_bounds: self.auto_bounds,
                 max_feature_count: self.max_feature_count,
+                lookup_limit: None,
                 pool_size: self.pool_size,
+                statement_timeout_ms: None,
+                request_context: None,
+                startup_retry: None,
+                rediscover_interval: None,
                 auto_publish: OptBoolObj::NoValue,
                 tables: None,
                 functions: None,
+                tile_tables: None,
             })
             .collect();
 
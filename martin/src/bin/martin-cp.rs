@@ -12,6 +12,8 @@ use futures::stream::{self, StreamExt};
 use futures::TryStreamExt;
 use log::{debug, error, info, log_enabled};
 use martin::args::{Args, ExtraArgs, MetaArgs, OsEnv, SrvArgs};
+#[cfg(feature = "postgres")]
+use martin::pg::{query_tile_tables::tilejson_to_metadata, PgTileTableWriter, TileTableConflict};
 use martin::srv::{merge_tilejson, DynTileSource};
 use martin::{
     append_rect, read_config, Config, MartinError, MartinResult, ServerState, Source, TileData,
@@ -24,7 +26,7 @@ use mbtiles::{
     init_mbtiles_schema, is_empty_database, CopyDuplicateMode, MbtError, MbtType, MbtTypeCli,
     Mbtiles,
 };
-use tilejson::Bounds;
+use tilejson::{Bounds, TileJSON};
 use tokio::sync::mpsc::channel;
 use tokio::time::Instant;
 use tokio::try_join;
@@ -58,8 +60,25 @@ pub struct CopyArgs {
     #[arg(short, long)]
     pub source: String,
     /// Path to the mbtiles file to copy to.
-    #[arg(short, long)]
-    pub output_file: PathBuf,
+    #[cfg_attr(
+        feature = "postgres",
+        arg(short, long, required_unless_present("output_table"))
+    )]
+    #[cfg_attr(not(feature = "postgres"), arg(short, long, required = true))]
+    pub output_file: Option<PathBuf>,
+    /// Postgres table to copy to instead of an mbtiles file, as `table` or `schema.table`.
+    /// The table is created if it does not exist, and can be served with the `tile_tables` configuration.
+    #[cfg(feature = "postgres")]
+    #[arg(long, conflicts_with("output_file"), requires("output_connection"))]
+    pub output_table: Option<String>,
+    /// Connection string of the Postgres database with the output table
+    #[cfg(feature = "postgres")]
+    #[arg(long, requires("output_table"))]
+    pub output_connection: Option<String>,
+    /// Name of the metadata table in the same schema as the output table. Metadata is not saved if not set.
+    #[cfg(feature = "postgres")]
+    #[arg(long, requires("output_table"))]
+    pub output_metadata_table: Option<String>,
     /// Output format of the new destination file. Ignored if the file exists. Defaults to 'normalized'.
     #[arg(
         long = "mbtiles-type",
@@ -230,6 +249,14 @@ enum MartinCpError {
     Actix(#[from] actix_web::Error),
     #[error(transparent)]
     Mbt(#[from] MbtError),
+    #[cfg(feature = "postgres")]
+    #[error(transparent)]
+    Pg(#[from] martin::pg::PgError),
+    #[cfg(feature = "postgres")]
+    #[error(
+        "Destination table {0} is not empty. Use --on-duplicate to copy into an existing table"
+    )]
+    DestinationTableExists(String),
 }
 
 impl Display for Progress {
@@ -273,11 +300,11 @@ fn iterate_tiles(tiles: Vec<TileRect>) -> impl Iterator<Item = TileCoord> {
 }
 
 async fn run_tile_copy(args: CopyArgs, state: ServerState) -> MartinCpResult<()> {
-    let output_file = &args.output_file;
     let concurrency = args.concurrency.unwrap_or(1);
 
+    let tiles = state.tiles.load();
     let src = DynTileSource::new(
-        &state.tiles,
+        &tiles,
         args.source.as_str(),
         None,
         args.url_query.as_deref().unwrap_or_default(),
@@ -290,24 +317,12 @@ async fn run_tile_copy(args: CopyArgs, state: ServerState) -> MartinCpResult<()>
 
     let (tx, mut rx) = channel::<TileXyz>(500);
     let tiles = compute_tile_ranges(&args);
-    let mbt = Mbtiles::new(output_file)?;
-    let mut conn = mbt.open_or_new().await?;
-    let on_duplicate = if let Some(on_duplicate) = args.on_duplicate {
-        on_duplicate
-    } else if !is_empty_database(&mut conn).await? {
-        return Err(MbtError::DestinationFileExists(output_file.clone()).into());
-    } else {
-        CopyDuplicateMode::Override
-    };
-    let mbt_type = init_schema(&mbt, &mut conn, src.sources.as_slice(), src.info, &args).await?;
+    let mut writer = TileWriter::new(&args, src.sources.as_slice(), src.info).await?;
 
     let progress = Progress::new(&tiles);
     info!(
-        "Copying {} {} tiles from {} to {}",
-        progress.total,
-        src.info,
-        args.source,
-        args.output_file.display()
+        "Copying {} {} tiles from {} to {writer}",
+        progress.total, src.info, args.source,
     );
 
     try_join!(
@@ -339,8 +354,7 @@ async fn run_tile_copy(args: CopyArgs, state: ServerState) -> MartinCpResult<()>
                 } else {
                     batch.push((tile.xyz.z, tile.xyz.x, tile.xyz.y, tile.data));
                     if batch.len() >= BATCH_SIZE || last_saved.elapsed() > SAVE_EVERY {
-                        mbt.insert_tiles(&mut conn, mbt_type, on_duplicate, &batch)
-                            .await?;
+                        writer.insert_tiles(&batch).await?;
                         batch.clear();
                         last_saved = Instant::now();
                     }
@@ -354,8 +368,7 @@ async fn run_tile_copy(args: CopyArgs, state: ServerState) -> MartinCpResult<()>
                 }
             }
             if !batch.is_empty() {
-                mbt.insert_tiles(&mut conn, mbt_type, on_duplicate, &batch)
-                    .await?;
+                writer.insert_tiles(&batch).await?;
             }
             Ok(())
         }
@@ -363,30 +376,143 @@ async fn run_tile_copy(args: CopyArgs, state: ServerState) -> MartinCpResult<()>
 
     info!("{progress}");
 
-    mbt.update_metadata(&mut conn, GrowOnly).await?;
+    writer
+        .finish(&args, progress.non_empty.load(Ordering::Relaxed) > 0)
+        .await
+}
 
-    for (key, value) in args.set_meta {
-        info!("Setting metadata key={key} value={value}");
-        mbt.set_metadata_value(&mut conn, &key, value).await?;
+fn parse_encoding(encoding: &str) -> MartinCpResult<AcceptEncoding> {
+    let req = TestRequest::default()
+        .insert_header((ACCEPT_ENCODING, encoding))
+        .finish();
+    Ok(AcceptEncoding::parse(&req)?)
+}
+
+/// Destination of the copied tiles
+#[allow(clippy::large_enum_variant)]
+enum TileWriter {
+    Mbtiles {
+        mbt: Mbtiles,
+        conn: SqliteConnection,
+        mbt_type: MbtType,
+        on_duplicate: CopyDuplicateMode,
+    },
+    #[cfg(feature = "postgres")]
+    PgTable {
+        writer: PgTileTableWriter,
+        on_duplicate: TileTableConflict,
+    },
+}
+
+impl Display for TileWriter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Mbtiles { mbt, .. } => write!(f, "{}", mbt.filepath()),
+            #[cfg(feature = "postgres")]
+            Self::PgTable { writer, .. } => write!(f, "table {}", writer.get_id()),
+        }
     }
+}
 
-    if !args.skip_agg_tiles_hash {
-        if progress.non_empty.load(Ordering::Relaxed) == 0 {
-            info!("No tiles were copied, skipping agg_tiles_hash computation");
+impl TileWriter {
+    async fn new(
+        args: &CopyArgs,
+        sources: &[&dyn Source],
+        tile_info: TileInfo,
+    ) -> MartinCpResult<Self> {
+        #[cfg(feature = "postgres")]
+        if let (Some(table), Some(connection)) = (&args.output_table, &args.output_connection) {
+            let writer = PgTileTableWriter::new(
+                connection.clone(),
+                table,
+                args.output_metadata_table.clone(),
+            )
+            .await?;
+            writer.create_tables().await?;
+            let is_empty = writer.is_empty().await?;
+            let on_duplicate = match args.on_duplicate {
+                Some(CopyDuplicateMode::Override) => TileTableConflict::Override,
+                Some(CopyDuplicateMode::Ignore) => TileTableConflict::Ignore,
+                Some(CopyDuplicateMode::Abort) => TileTableConflict::Abort,
+                None if is_empty => TileTableConflict::Override,
+                None => return Err(MartinCpError::DestinationTableExists(writer.get_id())),
+            };
+            if is_empty {
+                let tj = build_tilejson(sources, tile_info, args);
+                writer.set_metadata(&tilejson_to_metadata(&tj)).await?;
+            }
+            return Ok(Self::PgTable {
+                writer,
+                on_duplicate,
+            });
+        }
+
+        let Some(output_file) = &args.output_file else {
+            unreachable!("clap requires either an output file or an output table")
+        };
+        let mbt = Mbtiles::new(output_file)?;
+        let mut conn = mbt.open_or_new().await?;
+        let on_duplicate = if let Some(on_duplicate) = args.on_duplicate {
+            on_duplicate
+        } else if !is_empty_database(&mut conn).await? {
+            return Err(MbtError::DestinationFileExists(output_file.clone()).into());
         } else {
-            info!("Computing agg_tiles_hash value...");
-            mbt.update_agg_tiles_hash(&mut conn).await?;
+            CopyDuplicateMode::Override
+        };
+        let mbt_type = init_schema(&mbt, &mut conn, sources, tile_info, args).await?;
+        Ok(Self::Mbtiles {
+            mbt,
+            conn,
+            mbt_type,
+            on_duplicate,
+        })
+    }
+
+    async fn insert_tiles(&mut self, batch: &[(u8, u32, u32, Vec<u8>)]) -> MartinResult<()> {
+        match self {
+            Self::Mbtiles {
+                mbt,
+                conn,
+                mbt_type,
+                on_duplicate,
+            } => {
+                mbt.insert_tiles(conn, *mbt_type, *on_duplicate, batch)
+                    .await?;
+            }
+            #[cfg(feature = "postgres")]
+            Self::PgTable {
+                writer,
+                on_duplicate,
+            } => writer.insert_tiles(batch, *on_duplicate).await?,
         }
+        Ok(())
     }
 
-    Ok(())
-}
+    /// Update the metadata after all tiles have been copied
+    async fn finish(&mut self, args: &CopyArgs, has_tiles: bool) -> MartinCpResult<()> {
+        match self {
+            Self::Mbtiles { mbt, conn, .. } => {
+                mbt.update_metadata(conn, GrowOnly).await?;
 
-fn parse_encoding(encoding: &str) -> MartinCpResult<AcceptEncoding> {
-    let req = TestRequest::default()
-        .insert_header((ACCEPT_ENCODING, encoding))
-        .finish();
-    Ok(AcceptEncoding::parse(&req)?)
+                for (key, value) in &args.set_meta {
+                    info!("Setting metadata key={key} value={value}");
+                    mbt.set_metadata_value(conn, key, value).await?;
+                }
+
+                if !args.skip_agg_tiles_hash {
+                    if has_tiles {
+                        info!("Computing agg_tiles_hash value...");
+                        mbt.update_agg_tiles_hash(conn).await?;
+                    } else {
+                        info!("No tiles were copied, skipping agg_tiles_hash computation");
+                    }
+                }
+            }
+            #[cfg(feature = "postgres")]
+            Self::PgTable { writer, .. } => writer.set_metadata(&args.set_meta).await?,
+        }
+        Ok(())
+    }
 }
 
 async fn init_schema(
@@ -403,22 +529,7 @@ async fn init_schema(
             MbtTypeCli::Normalized => MbtType::Normalized { hash_view: true },
         };
         init_mbtiles_schema(&mut *conn, mbt_type).await?;
-        let mut tj = merge_tilejson(sources, String::new());
-        tj.other.insert(
-            "format".to_string(),
-            serde_json::Value::String(tile_info.format.metadata_format_value().to_string()),
-        );
-        tj.other.insert(
-            "generator".to_string(),
-            serde_json::Value::String(format!("martin-cp v{VERSION}")),
-        );
-        let zooms = get_zooms(args);
-        if let Some(min_zoom) = zooms.iter().min() {
-            tj.minzoom = Some(*min_zoom);
-        }
-        if let Some(max_zoom) = zooms.iter().max() {
-            tj.maxzoom = Some(*max_zoom);
-        }
+        let tj = build_tilejson(sources, tile_info, args);
         mbt.insert_metadata(&mut *conn, &tj).await?;
         mbt_type
     } else {
@@ -426,6 +537,26 @@ async fn init_schema(
     })
 }
 
+fn build_tilejson(sources: &[&dyn Source], tile_info: TileInfo, args: &CopyArgs) -> TileJSON {
+    let mut tj = merge_tilejson(sources, String::new());
+    tj.other.insert(
+        "format".to_string(),
+        serde_json::Value::String(tile_info.format.metadata_format_value().to_string()),
+    );
+    tj.other.insert(
+        "generator".to_string(),
+        serde_json::Value::String(format!("martin-cp v{VERSION}")),
+    );
+    let zooms = get_zooms(args);
+    if let Some(min_zoom) = zooms.iter().min() {
+        tj.minzoom = Some(*min_zoom);
+    }
+    if let Some(max_zoom) = zooms.iter().max() {
+        tj.maxzoom = Some(*max_zoom);
+    }
+    tj
+}
+
 #[actix_web::main]
 async fn main() {
     let env = env_logger::Env::default().default_filter_or("martin_cp=info");
@@ -23,7 +23,8 @@ async fn start(args: Args) -> MartinResult<()> {
 
     args.merge_into_config(&mut config, &env)?;
     config.finalize()?;
-    let sources = config.resolve().await?;
+    let mut sources = config.resolve().await?;
+    sources.spawn_rediscovery();
 
     if let Some(file_name) = save_config {
         config.save_to_file(file_name)?;
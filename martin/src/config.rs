@@ -15,23 +15,39 @@ use subst::VariableMap;
 use crate::file_config::FileConfigEnum;
 #[cfg(feature = "fonts")]
 use crate::fonts::FontSources;
-use crate::source::{TileInfoSources, TileSources};
+use crate::source::{SharedTileSources, TileInfoSources, TileSources};
 #[cfg(feature = "sprites")]
 use crate::sprites::{SpriteConfig, SpriteSources};
 use crate::srv::SrvConfig;
 use crate::utils::{init_aws_lc_tls, parse_base_path, CacheValue, MainCache, OptMainCache};
-use crate::MartinError::{ConfigLoadError, ConfigParseError, ConfigWriteError, NoSources};
+use crate::MartinError::{
+    ConfigLoadError, ConfigParseError, ConfigWriteError, EmptyAdminToken, NoSources,
+};
 use crate::{IdResolver, MartinResult, OptOneMany};
 
 pub type UnrecognizedValues = HashMap<String, serde_yaml::Value>;
 
 pub struct ServerState {
     pub cache: OptMainCache,
-    pub tiles: TileSources,
+    pub tiles: SharedTileSources,
     #[cfg(feature = "sprites")]
     pub sprites: SpriteSources,
     #[cfg(feature = "fonts")]
     pub fonts: FontSources,
+    /// Postgres sources to rediscover periodically, see [`ServerState::spawn_rediscovery`]
+    #[cfg(feature = "postgres")]
+    pub pg_rediscovery: Vec<crate::pg::PgRediscovery>,
+}
+
+impl ServerState {
+    /// Start the periodic rediscovery of the Postgres sources, if configured.
+    /// Only the server does this, other tools keep using the sources as they were resolved.
+    pub fn spawn_rediscovery(&mut self) {
+        #[cfg(feature = "postgres")]
+        for task in std::mem::take(&mut self.pg_rediscovery) {
+            actix_rt::spawn(task.run(self.tiles.clone(), self.cache.clone()));
+        }
+    }
 }
 
 #[serde_with::skip_serializing_none]
@@ -74,6 +90,11 @@ impl Config {
         if let Some(path) = &self.srv.base_path {
             self.srv.base_path = Some(parse_base_path(path)?);
         }
+        if let Some(token) = &self.srv.admin_token {
+            if token.trim().is_empty() {
+                return Err(EmptyAdminToken);
+            }
+        }
 
         #[cfg(feature = "postgres")]
         for pg in self.postgres.iter_mut() {
@@ -118,7 +139,16 @@ impl Config {
 
     pub async fn resolve(&mut self) -> MartinResult<ServerState> {
         init_aws_lc_tls()?;
-        let reserved_keywords: &[&str] = &["_", "catalog", "config", "font", "health"];
+        let reserved_keywords: &[&str] = &[
+            "_",
+            "admin",
+            "catalog",
+            "collections",
+            "config",
+            "conformance",
+            "font",
+            "health",
+        ];
         let resolver = IdResolver::new(reserved_keywords);
         let cache_size = self.cache_size_mb.unwrap_or(512) * 1024 * 1024;
         let cache = if cache_size > 0 {
@@ -127,7 +157,7 @@ impl Config {
                 MainCache::builder()
                     .weigher(|_key, value: &CacheValue| -> u32 {
                         match value {
-                            CacheValue::Tile(v) => v.len().try_into().unwrap_or(u32::MAX),
+                            CacheValue::Tile(v) => v.data.len().try_into().unwrap_or(u32::MAX),
                             #[cfg(feature = "pmtiles")]
                             CacheValue::PmtDirectory(v) => {
                                 v.get_approx_byte_size().try_into().unwrap_or(u32::MAX)
@@ -135,6 +165,8 @@ impl Config {
                         }
                     })
                     .max_capacity(cache_size)
+                    // Tiles of the rediscovered sources are removed from the cache
+                    .support_invalidation_closures()
                     .build(),
             )
         } else {
@@ -142,13 +174,34 @@ impl Config {
             None
         };
 
+        // Rediscovery needs the configuration as it was before the sources were resolved
+        #[cfg(feature = "postgres")]
+        let rediscover: Vec<_> = self
+            .postgres
+            .iter()
+            .map(|cfg| cfg.rediscover_interval.map(|_| cfg.clone()))
+            .collect();
+
+        let sources = self.resolve_tile_sources(&resolver, cache.clone()).await?;
+
+        #[cfg(feature = "postgres")]
+        let pg_rediscovery = rediscover
+            .into_iter()
+            .zip(self.postgres.iter())
+            .filter_map(|(cfg, resolved)| {
+                crate::pg::PgRediscovery::new(cfg?, resolved, resolver.clone())
+            })
+            .collect();
+
         Ok(ServerState {
-            tiles: self.resolve_tile_sources(&resolver, cache.clone()).await?,
+            tiles: SharedTileSources::new(TileSources::new(sources)),
             #[cfg(feature = "sprites")]
             sprites: SpriteSources::resolve(&mut self.sprites)?,
             #[cfg(feature = "fonts")]
             fonts: FontSources::resolve(&mut self.fonts)?,
             cache,
+            #[cfg(feature = "postgres")]
+            pg_rediscovery,
         })
     }
 
@@ -156,7 +209,7 @@ impl Config {
         &mut self,
         #[allow(unused_variables)] idr: &IdResolver,
         #[allow(unused_variables)] cache: OptMainCache,
-    ) -> MartinResult<TileSources> {
+    ) -> MartinResult<Vec<TileInfoSources>> {
         #[allow(unused_mut)]
         let mut sources: Vec<Pin<Box<dyn Future<Output = MartinResult<TileInfoSources>>>>> =
             Vec::new();
@@ -180,7 +233,7 @@ impl Config {
             sources.push(Box::pin(val));
         }
 
-        Ok(TileSources::new(try_join_all(sources).await?))
+        try_join_all(sources).await
     }
 
     pub fn save_to_file(&self, file_name: PathBuf) -> MartinResult<()> {
@@ -5,7 +5,10 @@ mod config;
 pub use config::{read_config, Config, ServerState};
 
 mod source;
-pub use source::{CatalogSourceEntry, Source, Tile, TileData, TileSources, UrlQuery};
+pub use source::{
+    CatalogSourceEntry, ItemStream, ItemsQuery, RequestAttributes, RequestContext,
+    SharedTileSources, Source, Tile, TileData, TileSources, TileWithEtag, UrlQuery,
+};
 
 mod utils;
 pub use utils::{
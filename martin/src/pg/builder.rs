@@ -1,5 +1,6 @@
 use std::cmp::Ordering;
 use std::collections::HashSet;
+use std::sync::Arc;
 
 use futures::future::join_all;
 use itertools::Itertools as _;
@@ -9,10 +10,13 @@ use crate::args::BoundsCalcType;
 use crate::pg::config::{PgConfig, PgInfo};
 use crate::pg::config_function::{FuncInfoSources, FunctionInfo};
 use crate::pg::config_table::{TableInfo, TableInfoSources};
+use crate::pg::config_tile_table::TileTableInfoSources;
 use crate::pg::pg_source::{PgSource, PgSqlInfo};
 use crate::pg::pool::PgPool;
 use crate::pg::query_functions::query_available_function;
 use crate::pg::query_tables::{query_available_tables, table_to_query};
+use crate::pg::query_tile_tables::tile_table_to_query;
+use crate::pg::request_context::PgRequestContext;
 use crate::pg::utils::{find_info, find_kv_ignore_case, normalize_key, InfoMap};
 use crate::pg::PgError::InvalidTableExtent;
 use crate::pg::{PgCfgPublish, PgCfgPublishFuncs, PgResult};
@@ -31,11 +35,50 @@ pub struct PgBuilder {
     default_srid: Option<i32>,
     auto_bounds: BoundsCalcType,
     max_feature_count: Option<usize>,
+    lookup_limit: Option<usize>,
+    request_context: Option<Arc<PgRequestContext>>,
+    statement_timeout_ms: Option<u64>,
     auto_functions: Option<PgBuilderFuncs>,
     auto_tables: Option<PgBuilderTables>,
     id_resolver: IdResolver,
     tables: TableInfoSources,
     functions: FuncInfoSources,
+    tile_tables: TileTableInfoSources,
+    /// Sources that are already published. They are still discovered, but only created again if changed.
+    existing: PgSourceInfos,
+}
+
+/// The info of all sources discovered in one Postgres database, by source ID
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PgSourceInfos {
+    pub tables: TableInfoSources,
+    pub functions: FuncInfoSources,
+    pub tile_tables: TileTableInfoSources,
+}
+
+impl PgSourceInfos {
+    #[must_use]
+    pub fn from_config(config: &PgConfig) -> Self {
+        Self {
+            tables: config.tables.clone().unwrap_or_default(),
+            functions: config.functions.clone().unwrap_or_default(),
+            tile_tables: config.tile_tables.clone().unwrap_or_default(),
+        }
+    }
+
+    #[must_use]
+    pub fn contains(&self, id: &str) -> bool {
+        self.tables.contains_key(id)
+            || self.functions.contains_key(id)
+            || self.tile_tables.contains_key(id)
+    }
+
+    pub fn ids(&self) -> impl Iterator<Item = &String> {
+        self.tables
+            .keys()
+            .chain(self.functions.keys())
+            .chain(self.tile_tables.keys())
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -82,20 +125,36 @@ impl PgBuilder {
         let pool = PgPool::new(config).await?;
 
         let (auto_tables, auto_functions) = calc_auto(config);
+        if let Some(ctx) = &config.request_context {
+            if ctx.uses_jwt() && ctx.jwt_secret.is_none() && ctx.is_verified_by_proxy() {
+                warn!("Request context trusts the JWT claims without verifying them, because jwt_verified_by_proxy is set. Make sure a proxy in front of Martin verifies every token.");
+            }
+        }
 
         Ok(Self {
             pool,
             default_srid: config.default_srid,
             auto_bounds: config.auto_bounds.unwrap_or_default(),
             max_feature_count: config.max_feature_count,
+            lookup_limit: config.lookup_limit,
+            request_context: config.request_context.clone().map(Arc::new),
+            statement_timeout_ms: config.statement_timeout_ms,
             id_resolver,
             tables: config.tables.clone().unwrap_or_default(),
             functions: config.functions.clone().unwrap_or_default(),
+            tile_tables: config.tile_tables.clone().unwrap_or_default(),
             auto_functions,
             auto_tables,
+            existing: PgSourceInfos::default(),
         })
     }
 
+    /// Set the info of the sources that are already published,
+    /// so that only the new and the changed sources are created
+    pub fn set_existing(&mut self, existing: PgSourceInfos) {
+        self.existing = existing;
+    }
+
     pub fn auto_bounds(&self) -> BoundsCalcType {
         self.auto_bounds
     }
@@ -112,6 +171,7 @@ impl PgBuilder {
         // Match configured sources with the discovered ones and add them to the pending list.
         let mut used = HashSet::<(&str, &str, &str)>::new();
         let mut pending = Vec::new();
+        let mut existing = Vec::new();
         for (id, cfg_inf) in &self.tables {
             // TODO: move this validation to serde somehow?
             if let Some(extent) = cfg_inf.extent {
@@ -143,6 +203,10 @@ impl PgBuilder {
                 continue;
             };
             warn_on_rename(id, &id2, "Table");
+            if let Some(inf) = self.unchanged_table(&id2, &merged_inf) {
+                existing.push((id2, inf));
+                continue;
+            }
             info!("Configured {dup}source {id2} from {}", summary(&merged_inf));
             pending.push(table_to_query(
                 id2,
@@ -186,6 +250,10 @@ impl PgBuilder {
                         };
                         db_inf.srid = srid;
                         update_auto_fields(&id2, &mut db_inf, auto_tables);
+                        if let Some(inf) = self.unchanged_table(&id2, &db_inf) {
+                            existing.push((id2, inf));
+                            continue;
+                        }
                         info!("Discovered source {id2} from {}", summary(&db_inf));
                         pending.push(table_to_query(
                             id2,
@@ -200,7 +268,7 @@ impl PgBuilder {
         }
 
         let mut res = TileInfoSources::default();
-        let mut info_map = TableInfoSources::new();
+        let mut info_map: TableInfoSources = existing.into_iter().collect();
         let pending = join_all(pending).await;
         for src in pending {
             match src {
@@ -243,6 +311,12 @@ impl PgBuilder {
             let dup = !used.insert((&cfg_inf.schema, func_name));
             let dup = if dup { "duplicate " } else { "" };
             let id2 = self.resolve_id(id, &merged_inf);
+            if self.existing.functions.get(&id2) == Some(&merged_inf) {
+                info_map.insert(id2, merged_inf);
+                continue;
+            }
+            let mut pg_sql = pg_sql.clone();
+            pg_sql.tile_info = merged_inf.tile_info(&id2)?;
             self.add_func_src(&mut res, id2.clone(), &merged_inf, pg_sql.clone());
             warn_on_rename(id, &id2, "Function");
             let signature = &pg_sql.signature;
@@ -277,6 +351,10 @@ impl PgBuilder {
                         .replace("{schema}", &schema)
                         .replace("{function}", &func);
                     let id2 = self.resolve_id(&source_id, &db_inf);
+                    if self.existing.functions.get(&id2) == Some(&db_inf) {
+                        info_map.insert(id2, db_inf);
+                        continue;
+                    }
                     self.add_func_src(&mut res, id2.clone(), &db_inf, pg_sql.clone());
                     info!("Discovered source {id2} from function {}", pg_sql.signature);
                     debug!("{id2} query: {}", pg_sql.sql_query);
@@ -287,6 +365,36 @@ impl PgBuilder {
         Ok((res, info_map))
     }
 
+    pub async fn instantiate_tile_tables(
+        &self,
+    ) -> PgResult<(TileInfoSources, TileTableInfoSources)> {
+        let mut res = TileInfoSources::default();
+        let mut info_map = TileTableInfoSources::new();
+        for (id, cfg_inf) in &self.tile_tables {
+            let id2 = self.resolve_id(id, cfg_inf);
+            // Tile tables are only configured, not discovered, so they cannot change
+            if let Some(inf) = self.existing.tile_tables.get(&id2) {
+                info_map.insert(id2, inf.clone());
+                continue;
+            }
+            let (pg_sql, inf) = tile_table_to_query(&self.pool, cfg_inf.clone()).await?;
+            self.add_func_src(&mut res, id2.clone(), &inf, pg_sql);
+            warn_on_rename(id, &id2, "Tile table");
+            info!(
+                "Configured source {id2} from the tile table {}",
+                inf.format_id()
+            );
+            info_map.insert(id2, inf);
+        }
+        Ok((res, info_map))
+    }
+
+    /// The info of a published table source, unless the discovered table differs from it
+    fn unchanged_table(&self, id: &str, discovered: &TableInfo) -> Option<TableInfo> {
+        let published = self.existing.tables.get(id)?;
+        is_same_table(published, discovered).then(|| published.clone())
+    }
+
     fn resolve_id<T: PgInfo>(&self, id: &str, src_inf: &T) -> String {
         let signature = format!("{}.{}", self.pool.get_id(), src_inf.format_id());
         self.id_resolver.resolve(id, signature)
@@ -297,9 +405,29 @@ impl PgBuilder {
         sources: &mut TileInfoSources,
         id: String,
         pg_info: &impl PgInfo,
-        sql_info: PgSqlInfo,
+        mut sql_info: PgSqlInfo,
     ) {
-        let tilejson = pg_info.to_tilejson(id.clone());
+        sql_info.statement_timeout_ms =
+            pg_info.statement_timeout_ms().or(self.statement_timeout_ms);
+        if let Some(limit) = self.lookup_limit {
+            sql_info.lookup_limit = limit;
+        }
+        sql_info.request_context.clone_from(&self.request_context);
+        let mut tilejson = pg_info.to_tilejson(id.clone());
+        // Document the URL query parameters accepted by the function
+        let url_params: Vec<_> = sql_info
+            .url_params
+            .iter()
+            .filter(|p| !p.is_json())
+            .collect();
+        if !url_params.is_empty() {
+            match serde_json::to_value(url_params) {
+                Ok(v) => {
+                    tilejson.other.insert("url_params".to_string(), v);
+                }
+                Err(e) => warn!("Unable to add URL parameters to the tilejson of {id}: {e}"),
+            }
+        }
         let source = PgSource::new(id, sql_info, tilejson, self.pool.clone());
         sources.push(Box::new(source));
     }
@@ -448,6 +576,21 @@ fn warn_on_rename(old_id: &String, new_id: &String, typ: &str) {
     }
 }
 
+/// Compare the discovered table with the one of a published source.
+/// Bounds and temporal extent are calculated when the source is created, so they only count if configured.
+fn is_same_table(published: &TableInfo, discovered: &TableInfo) -> bool {
+    let mut discovered = discovered.clone();
+    if discovered.bounds.is_none() {
+        discovered.bounds = published.bounds;
+    }
+    if discovered.temporal_extent.is_none() {
+        discovered
+            .temporal_extent
+            .clone_from(&published.temporal_extent);
+    }
+    *published == discovered
+}
+
 fn summary(info: &TableInfo) -> String {
     let relkind = match info.is_view {
         Some(true) => "view",
@@ -634,4 +777,33 @@ mod tests {
             auto_funcs: ~
             "###);
     }
+
+    #[test]
+    fn test_is_same_table() {
+        let discovered = TableInfo {
+            schema: "public".to_string(),
+            table: "points".to_string(),
+            geometry_column: "geom".to_string(),
+            srid: 4326,
+            ..TableInfo::default()
+        };
+        let published = TableInfo {
+            bounds: Some(tilejson::Bounds::new(-10.0, -20.0, 10.0, 20.0)),
+            temporal_extent: Some([Some("2024-01-01T00:00:00Z".to_string()), None]),
+            ..discovered.clone()
+        };
+        assert!(is_same_table(&published, &discovered));
+
+        let changed = TableInfo {
+            id_column: Some("gid".to_string()),
+            ..discovered.clone()
+        };
+        assert!(!is_same_table(&published, &changed));
+
+        let rebounded = TableInfo {
+            bounds: Some(tilejson::Bounds::MAX),
+            ..discovered
+        };
+        assert!(!is_same_table(&published, &rebounded));
+    }
 }
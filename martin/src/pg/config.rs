@@ -1,25 +1,36 @@
+use std::collections::HashSet;
+use std::future::Future;
 use std::ops::Add;
 use std::time::Duration;
 
-use futures::future::try_join;
-use log::warn;
+use enum_display::EnumDisplay;
+use futures::future::try_join3;
+use itertools::Itertools as _;
+use log::{debug, info, warn};
 use serde::{Deserialize, Serialize};
 use tilejson::TileJSON;
+use tokio::time::sleep;
 
 use crate::args::{BoundsCalcType, DEFAULT_BOUNDS_TIMEOUT};
 use crate::config::{copy_unrecognized_config, UnrecognizedValues};
-use crate::pg::builder::PgBuilder;
+use crate::pg::builder::{PgBuilder, PgSourceInfos};
 use crate::pg::config_function::FuncInfoSources;
 use crate::pg::config_table::TableInfoSources;
+use crate::pg::config_tile_table::TileTableInfoSources;
+use crate::pg::request_context::PgRequestContext;
 use crate::pg::utils::on_slow;
 use crate::pg::PgResult;
-use crate::source::TileInfoSources;
-use crate::utils::{IdResolver, OptBoolObj, OptOneMany};
+use crate::source::{SharedTileSources, TileInfoSources};
+use crate::utils::{invalidate_source_tiles, IdResolver, OptBoolObj, OptMainCache, OptOneMany};
 use crate::MartinResult;
 
+const INITIAL_BACKOFF_MS: u64 = 500;
+const MAX_BACKOFF_MS: u64 = 30_000;
+
 pub trait PgInfo {
     fn format_id(&self) -> String;
     fn to_tilejson(&self, source_id: String) -> TileJSON;
+    fn statement_timeout_ms(&self) -> Option<u64>;
 }
 
 #[serde_with::skip_serializing_none]
@@ -40,16 +51,90 @@ pub struct PgSslCerts {
 #[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
 pub struct PgConfig {
     pub connection_string: Option<String>,
+    /// Connection strings of read replicas used to serve tiles.
+    /// Source discovery always uses the primary `connection_string`.
+    #[serde(default, skip_serializing_if = "OptOneMany::is_none")]
+    pub read_replicas: OptOneMany<String>,
+    /// Connection strings of hosts with the same data as the primary `connection_string`.
+    /// Tiles are served by all of them, and source discovery uses the next one if the primary is down.
+    #[serde(default, skip_serializing_if = "OptOneMany::is_none")]
+    pub equivalent_hosts: OptOneMany<String>,
+    /// How to pick a read replica or equivalent host for each tile request [default: round-robin]
+    pub load_balancing: Option<PgLoadBalancing>,
     #[serde(flatten)]
     pub ssl_certificates: PgSslCerts,
     pub default_srid: Option<i32>,
     pub auto_bounds: Option<BoundsCalcType>,
     pub max_feature_count: Option<usize>,
+    /// Maximum number of features returned by the `/{source_id}/features` lookup endpoint [default: 100]
+    pub lookup_limit: Option<usize>,
     pub pool_size: Option<usize>,
+    /// Abort tile queries taking longer than this many milliseconds, same as the `statement_timeout` setting.
+    /// Source discovery and bounds calculation are not limited. Can be overridden for individual tables and functions.
+    pub statement_timeout_ms: Option<u64>,
+    /// Pass request attributes like JWT claims or headers to Postgres as settings, e.g. for row-level security
+    pub request_context: Option<PgRequestContext>,
+    /// Retry connecting to the database and discovering sources on startup
+    pub startup_retry: Option<PgStartupRetry>,
+    /// Re-run source discovery every this many seconds, publishing new tables and functions,
+    /// replacing the changed ones and retiring the dropped ones without a restart
+    pub rediscover_interval: Option<u64>,
     #[serde(default, skip_serializing_if = "OptBoolObj::is_none")]
     pub auto_publish: OptBoolObj<PgCfgPublish>,
     pub tables: Option<TableInfoSources>,
     pub functions: Option<FuncInfoSources>,
+    /// Tables with pre-rendered tiles
+    pub tile_tables: Option<TileTableInfoSources>,
+}
+
+#[derive(PartialEq, Eq, Default, Debug, Clone, Copy, Serialize, Deserialize, EnumDisplay)]
+#[serde(rename_all = "kebab-case")]
+#[enum_display(case = "Kebab")]
+pub enum PgLoadBalancing {
+    /// Use each available host in turn
+    #[default]
+    RoundRobin,
+    /// Use the available host with the fewest tile requests in progress
+    LeastOutstanding,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PgStartupRetry {
+    /// Maximum number of attempts to connect to the database [default: 1, i.e. no retries]
+    pub attempts: Option<u32>,
+    /// Delay before the first retry in milliseconds, doubled after each attempt [default: 500]
+    pub initial_backoff_ms: Option<u64>,
+    /// Maximum delay between retries in milliseconds [default: 30000]
+    pub max_backoff_ms: Option<u64>,
+}
+
+impl PgStartupRetry {
+    /// Run `f` until it succeeds, fails with a non-transient error, or runs out of attempts
+    pub async fn run<T, F, Fut>(&self, mut f: F) -> PgResult<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = PgResult<T>>,
+    {
+        let attempts = self.attempts.unwrap_or(1).max(1);
+        let max_backoff = Duration::from_millis(self.max_backoff_ms.unwrap_or(MAX_BACKOFF_MS));
+        let mut backoff =
+            Duration::from_millis(self.initial_backoff_ms.unwrap_or(INITIAL_BACKOFF_MS))
+                .min(max_backoff);
+
+        let mut attempt = 1;
+        loop {
+            match f().await {
+                Err(e) if attempt < attempts && e.is_transient() => {
+                    warn!("Unable to discover PostgreSQL sources (attempt {attempt} of {attempts}), retrying in {}ms: {e}", backoff.as_millis());
+                    sleep(backoff).await;
+                    backoff = backoff.saturating_mul(2).min(max_backoff);
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
+    }
 }
 
 #[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
@@ -104,9 +189,22 @@ impl PgConfig {
         if let Some(ref fs) = self.functions {
             for (k, v) in fs {
                 copy_unrecognized_config(&mut res, &format!("functions.{k}."), &v.unrecognized);
+                v.tile_info(k)?;
             }
         }
-        if self.tables.is_none() && self.functions.is_none() && self.auto_publish.is_none() {
+        if let Some(ref tts) = self.tile_tables {
+            for (k, v) in tts {
+                copy_unrecognized_config(&mut res, &format!("tile_tables.{k}."), &v.unrecognized);
+            }
+        }
+        if let Some(ref ctx) = self.request_context {
+            ctx.validate()?;
+        }
+        if self.tables.is_none()
+            && self.functions.is_none()
+            && self.tile_tables.is_none()
+            && self.auto_publish.is_none()
+        {
             self.auto_publish = OptBoolObj::Bool(true);
         }
 
@@ -114,6 +212,45 @@ impl PgConfig {
     }
 
     pub async fn resolve(&mut self, id_resolver: IdResolver) -> MartinResult<TileInfoSources> {
+        let retry = self.startup_retry.clone().unwrap_or_default();
+        let (tables, tbl_info, func_info, tile_tbl_info) =
+            retry.run(|| self.instantiate(id_resolver.clone())).await?;
+
+        self.tables = Some(tbl_info);
+        self.functions = Some(func_info);
+        if self.tile_tables.is_some() {
+            self.tile_tables = Some(tile_tbl_info);
+        }
+        Ok(tables)
+    }
+
+    /// Create the new and the changed sources, and collect the info of all the discovered ones
+    async fn discover(pg: &PgBuilder) -> PgResult<(TileInfoSources, PgSourceInfos)> {
+        let ((mut tables, tbl_info), (funcs, func_info), (tile_tables, tile_tbl_info)) = try_join3(
+            pg.instantiate_tables(),
+            pg.instantiate_functions(),
+            pg.instantiate_tile_tables(),
+        )
+        .await?;
+        tables.extend(funcs);
+        tables.extend(tile_tables);
+        let infos = PgSourceInfos {
+            tables: tbl_info,
+            functions: func_info,
+            tile_tables: tile_tbl_info,
+        };
+        Ok((tables, infos))
+    }
+
+    async fn instantiate(
+        &self,
+        id_resolver: IdResolver,
+    ) -> PgResult<(
+        TileInfoSources,
+        TableInfoSources,
+        FuncInfoSources,
+        TileTableInfoSources,
+    )> {
         let pg = PgBuilder::new(self, id_resolver).await?;
         let inst_tables = on_slow(
             pg.instantiate_tables(),
@@ -127,13 +264,111 @@ impl PgConfig {
                 }
             },
         );
-        let ((mut tables, tbl_info), (funcs, func_info)) =
-            try_join(inst_tables, pg.instantiate_functions()).await?;
+        let ((mut tables, tbl_info), (funcs, func_info), (tile_tables, tile_tbl_info)) = try_join3(
+            inst_tables,
+            pg.instantiate_functions(),
+            pg.instantiate_tile_tables(),
+        )
+        .await?;
 
-        self.tables = Some(tbl_info);
-        self.functions = Some(func_info);
         tables.extend(funcs);
-        Ok(tables)
+        tables.extend(tile_tables);
+        Ok((tables, tbl_info, func_info, tile_tbl_info))
+    }
+}
+
+/// Periodic re-run of the source discovery of one Postgres connection,
+/// adding new sources, replacing the changed ones, and retiring the dropped ones
+#[derive(Debug)]
+pub struct PgRediscovery {
+    /// The configuration before it was resolved
+    config: PgConfig,
+    /// The sources published from the resolved configuration
+    published: PgSourceInfos,
+    id_resolver: IdResolver,
+}
+
+impl PgRediscovery {
+    /// Prepare the rediscovery, if it is enabled in `config`.
+    /// `config` must be the configuration before it was resolved, and `resolved` the same one after.
+    #[must_use]
+    pub fn new(config: PgConfig, resolved: &PgConfig, id_resolver: IdResolver) -> Option<Self> {
+        config.rediscover_interval?;
+        Some(Self {
+            config,
+            published: PgSourceInfos::from_config(resolved),
+            id_resolver,
+        })
+    }
+
+    /// Run the discovery every `rediscover_interval` seconds, updating the `sources`,
+    /// and removing the cached tiles of the changed and the removed ones.
+    /// The discovery reuses one connection pool, and only creates the new and the changed sources.
+    pub async fn run(mut self, sources: SharedTileSources, cache: OptMainCache) {
+        let Some(interval) = self.config.rediscover_interval else {
+            return;
+        };
+        let interval = Duration::from_secs(interval.max(1));
+        info!(
+            "Rediscovering PostgreSQL sources every {}s",
+            interval.as_secs()
+        );
+        let mut builder = None;
+        loop {
+            sleep(interval).await;
+
+            let pg = match &mut builder {
+                Some(pg) => pg,
+                None => match PgBuilder::new(&self.config, self.id_resolver.clone()).await {
+                    Ok(pg) => builder.insert(pg),
+                    Err(e) => {
+                        warn!("Unable to rediscover PostgreSQL sources, keeping the current ones: {e}");
+                        continue;
+                    }
+                },
+            };
+            pg.set_existing(self.published.clone());
+            let (created, found) = match PgConfig::discover(pg).await {
+                Ok(v) => v,
+                Err(e) => {
+                    warn!("Unable to rediscover PostgreSQL sources, keeping the current ones: {e}");
+                    continue;
+                }
+            };
+
+            let removed: Vec<_> = self
+                .published
+                .ids()
+                .filter(|id| !found.contains(id))
+                .cloned()
+                .collect();
+            if created.is_empty() && removed.is_empty() {
+                debug!("Rediscovered PostgreSQL sources have not changed");
+                continue;
+            }
+            let (changed, added): (Vec<_>, Vec<_>) = created
+                .iter()
+                .map(|s| s.get_id())
+                .partition(|id| self.published.contains(id));
+            info!(
+                "Rediscovered PostgreSQL sources: added [{}], changed [{}], removed [{}]",
+                added.iter().sorted().join(", "),
+                changed.iter().sorted().join(", "),
+                removed.iter().sorted().join(", ")
+            );
+            let stale: HashSet<_> = changed
+                .into_iter()
+                .map(ToString::to_string)
+                .chain(removed.iter().cloned())
+                .collect();
+            // Changed sources have the same ID, so they replace the published ones
+            sources.update(removed.iter().map(String::as_str), created);
+            // A new source with the same ID must not serve the tiles cached for the old one
+            if let Some(cache) = &cache {
+                invalidate_source_tiles(cache, stale);
+            }
+            self.published = found;
+        }
     }
 }
 
@@ -141,14 +376,22 @@ impl PgConfig {
 mod tests {
     use std::collections::BTreeMap;
 
+    use deadpool_postgres::PoolError;
     use indoc::indoc;
+    use martin_tile_utils::{Encoding, Format, TileInfo};
     use tilejson::Bounds;
 
     use super::*;
     use crate::config::tests::assert_config;
     use crate::config::Config;
     use crate::pg::config_function::FunctionInfo;
-    use crate::pg::config_table::TableInfo;
+    use crate::pg::config_table::{
+        AggregateFunction, ClusterAggregate, ClusterInfo, ClusterMethod, RasterFormat, RasterInfo,
+        RasterResampling, TableInfo,
+    };
+    use crate::pg::config_tile_table::TileTableInfo;
+    use crate::pg::request_context::RequestAttribute;
+    use crate::pg::PgError;
     use crate::test_utils::some;
     use crate::utils::OptOneMany::{Many, One};
 
@@ -196,6 +439,384 @@ mod tests {
         );
     }
 
+    #[test]
+    fn parse_pg_replicas() {
+        assert_config(
+            indoc! {"
+            postgres:
+              connection_string: 'postgresql://postgres@primary/db'
+              read_replicas:
+                - 'postgresql://postgres@replica1/db'
+                - 'postgresql://postgres@replica2/db'
+              equivalent_hosts: 'postgresql://postgres@standby/db'
+              load_balancing: least-outstanding
+        "},
+            &Config {
+                postgres: One(PgConfig {
+                    connection_string: some("postgresql://postgres@primary/db"),
+                    read_replicas: Many(vec![
+                        "postgresql://postgres@replica1/db".to_string(),
+                        "postgresql://postgres@replica2/db".to_string(),
+                    ]),
+                    equivalent_hosts: One("postgresql://postgres@standby/db".to_string()),
+                    load_balancing: Some(PgLoadBalancing::LeastOutstanding),
+                    auto_publish: OptBoolObj::Bool(true),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        );
+    }
+
+    #[test]
+    fn parse_pg_startup_retry() {
+        assert_config(
+            indoc! {"
+            postgres:
+              connection_string: 'postgresql://postgres@localhost/db'
+              startup_retry:
+                attempts: 10
+                initial_backoff_ms: 250
+                max_backoff_ms: 5000
+        "},
+            &Config {
+                postgres: One(PgConfig {
+                    connection_string: some("postgresql://postgres@localhost/db"),
+                    startup_retry: Some(PgStartupRetry {
+                        attempts: Some(10),
+                        initial_backoff_ms: Some(250),
+                        max_backoff_ms: Some(5000),
+                    }),
+                    auto_publish: OptBoolObj::Bool(true),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        );
+    }
+
+    #[test]
+    fn parse_pg_rediscover_interval() {
+        assert_config(
+            indoc! {"
+            postgres:
+              connection_string: 'postgresql://postgres@localhost/db'
+              rediscover_interval: 300
+        "},
+            &Config {
+                postgres: One(PgConfig {
+                    connection_string: some("postgresql://postgres@localhost/db"),
+                    rediscover_interval: Some(300),
+                    auto_publish: OptBoolObj::Bool(true),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        );
+    }
+
+    #[test]
+    fn parse_pg_request_context() {
+        assert_config(
+            indoc! {"
+            postgres:
+              connection_string: 'postgresql://postgres@localhost/db'
+              request_context:
+                jwt_secret: secret
+                settings:
+                  request.jwt.claims: jwt
+                  request.user_id: jwt:sub
+                  request.tenant: header:X-Tenant
+                  request.region: query:region
+        "},
+            &Config {
+                postgres: One(PgConfig {
+                    connection_string: some("postgresql://postgres@localhost/db"),
+                    request_context: Some(PgRequestContext {
+                        jwt_secret: some("secret"),
+                        jwt_verified_by_proxy: None,
+                        settings: BTreeMap::from([
+                            (
+                                "request.jwt.claims".to_string(),
+                                RequestAttribute::JwtClaims,
+                            ),
+                            (
+                                "request.user_id".to_string(),
+                                RequestAttribute::JwtClaim("sub".to_string()),
+                            ),
+                            (
+                                "request.tenant".to_string(),
+                                RequestAttribute::Header("x-tenant".to_string()),
+                            ),
+                            (
+                                "request.region".to_string(),
+                                RequestAttribute::Query("region".to_string()),
+                            ),
+                        ]),
+                    }),
+                    auto_publish: OptBoolObj::Bool(true),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        );
+    }
+
+    #[test]
+    fn parse_pg_statement_timeout() {
+        assert_config(
+            indoc! {"
+            postgres:
+              connection_string: 'postgresql://postgres@localhost/db'
+              statement_timeout_ms: 5000
+              tables:
+                table_source:
+                  schema: public
+                  table: table_source
+                  srid: 4326
+                  geometry_column: geom
+                  statement_timeout_ms: 20000
+              functions:
+                function_zxy_query:
+                  schema: public
+                  function: function_zxy_query
+                  statement_timeout_ms: 1000
+        "},
+            &Config {
+                postgres: One(PgConfig {
+                    connection_string: some("postgresql://postgres@localhost/db"),
+                    statement_timeout_ms: Some(5000),
+                    tables: Some(BTreeMap::from([(
+                        "table_source".to_string(),
+                        TableInfo {
+                            schema: "public".to_string(),
+                            table: "table_source".to_string(),
+                            srid: 4326,
+                            geometry_column: "geom".to_string(),
+                            statement_timeout_ms: Some(20000),
+                            ..Default::default()
+                        },
+                    )])),
+                    functions: Some(BTreeMap::from([(
+                        "function_zxy_query".to_string(),
+                        FunctionInfo {
+                            schema: "public".to_string(),
+                            function: "function_zxy_query".to_string(),
+                            statement_timeout_ms: Some(1000),
+                            ..Default::default()
+                        },
+                    )])),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        );
+    }
+
+    #[test]
+    fn parse_pg_raster_table() {
+        assert_config(
+            indoc! {"
+            postgres:
+              connection_string: 'postgresql://postgres@localhost/db'
+              tables:
+                elevation:
+                  schema: public
+                  table: dem
+                  srid: 4326
+                  geometry_column: rast
+                  geometry_type: RASTER
+                  raster:
+                    format: webp
+                    tile_size: 512
+                    bands: [1]
+                    colormap: pseudocolor
+                    nodata: -9999
+                    range: [0, 3000]
+                    resampling: bilinear
+        "},
+            &Config {
+                postgres: One(PgConfig {
+                    connection_string: some("postgresql://postgres@localhost/db"),
+                    tables: Some(BTreeMap::from([(
+                        "elevation".to_string(),
+                        TableInfo {
+                            schema: "public".to_string(),
+                            table: "dem".to_string(),
+                            srid: 4326,
+                            geometry_column: "rast".to_string(),
+                            geometry_type: some("RASTER"),
+                            raster: Some(RasterInfo {
+                                format: Some(RasterFormat::Webp),
+                                tile_size: Some(512),
+                                bands: Some(vec![1]),
+                                colormap: some("pseudocolor"),
+                                nodata: Some(-9999.0),
+                                range: Some([0.0, 3000.0]),
+                                resampling: Some(RasterResampling::Bilinear),
+                            }),
+                            ..Default::default()
+                        },
+                    )])),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        );
+    }
+
+    #[test]
+    fn parse_pg_table_cluster() {
+        assert_config(
+            indoc! {"
+            postgres:
+              connection_string: 'postgresql://postgres@localhost/db'
+              tables:
+                places:
+                  schema: public
+                  table: places
+                  srid: 4326
+                  geometry_column: geom
+                  geometry_type: POINT
+                  cluster:
+                    max_zoom: 10
+                    method: dbscan
+                    radius: 128
+                    min_points: 3
+                    aggregates:
+                      total_population:
+                        function: sum
+                        column: population
+        "},
+            &Config {
+                postgres: One(PgConfig {
+                    connection_string: some("postgresql://postgres@localhost/db"),
+                    tables: Some(BTreeMap::from([(
+                        "places".to_string(),
+                        TableInfo {
+                            schema: "public".to_string(),
+                            table: "places".to_string(),
+                            srid: 4326,
+                            geometry_column: "geom".to_string(),
+                            geometry_type: some("POINT"),
+                            cluster: Some(ClusterInfo {
+                                max_zoom: 10,
+                                method: Some(ClusterMethod::Dbscan),
+                                radius: Some(128),
+                                min_points: Some(3),
+                                aggregates: Some(BTreeMap::from([(
+                                    "total_population".to_string(),
+                                    ClusterAggregate {
+                                        function: AggregateFunction::Sum,
+                                        column: "population".to_string(),
+                                    },
+                                )])),
+                            }),
+                            ..Default::default()
+                        },
+                    )])),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        );
+    }
+
+    #[test]
+    fn parse_pg_tile_tables() {
+        assert_config(
+            indoc! {"
+            postgres:
+              connection_string: 'postgresql://postgres@localhost/db'
+              tile_tables:
+                cached:
+                  schema: cache
+                  table: tiles
+                  metadata_table: tiles_metadata
+                  tms: true
+                custom:
+                  schema: public
+                  table: custom_tiles
+                  zoom_column: z
+                  x_column: x
+                  y_column: y
+                  data_column: tile
+                  maxzoom: 12
+        "},
+            &Config {
+                postgres: One(PgConfig {
+                    connection_string: some("postgresql://postgres@localhost/db"),
+                    tile_tables: Some(BTreeMap::from([
+                        (
+                            "cached".to_string(),
+                            TileTableInfo {
+                                metadata_table: some("tiles_metadata"),
+                                tms: Some(true),
+                                ..TileTableInfo::new("cache".to_string(), "tiles".to_string())
+                            },
+                        ),
+                        (
+                            "custom".to_string(),
+                            TileTableInfo {
+                                zoom_column: some("z"),
+                                x_column: some("x"),
+                                y_column: some("y"),
+                                data_column: some("tile"),
+                                maxzoom: Some(12),
+                                ..TileTableInfo::new(
+                                    "public".to_string(),
+                                    "custom_tiles".to_string(),
+                                )
+                            },
+                        ),
+                    ])),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        );
+    }
+
+    #[test]
+    fn parse_pg_function_format() {
+        let cfg: Config = serde_yaml::from_str(indoc! {"
+            postgres:
+              connection_string: 'postgresql://postgres@localhost/db'
+              functions:
+                raster:
+                  schema: public
+                  function: raster_zxy
+                  format: png
+                mvt_gz:
+                  schema: public
+                  function: mvt_gz_zxy
+                  encoding: gzip
+                detect:
+                  schema: public
+                  function: detect_zxy
+        "})
+        .unwrap();
+        let One(pg) = &cfg.postgres else {
+            panic!("expected a single postgres config")
+        };
+        let funcs = pg.functions.as_ref().unwrap();
+        assert_eq!(
+            funcs["raster"].tile_info("raster").unwrap(),
+            Some(TileInfo::new(Format::Png, Encoding::Internal))
+        );
+        assert_eq!(
+            funcs["mvt_gz"].tile_info("mvt_gz").unwrap(),
+            Some(TileInfo::new(Format::Mvt, Encoding::Gzip))
+        );
+        assert_eq!(funcs["detect"].tile_info("detect").unwrap(), None);
+
+        let bad = FunctionInfo {
+            format: some("tiff"),
+            ..Default::default()
+        };
+        assert!(bad.tile_info("bad").is_err());
+    }
+
     #[test]
     fn parse_pg_config() {
         assert_config(
@@ -205,6 +826,7 @@ mod tests {
               default_srid: 4326
               pool_size: 20
               max_feature_count: 100
+              lookup_limit: 50
 
               tables:
                 table_source:
@@ -220,6 +842,9 @@ mod tests {
                   buffer: 10
                   clip_geom: false
                   geometry_type: GEOMETRY
+                  temporal_column: updated_at
+                  temporal_end_column: deleted_at
+                  temporal_extent: ['2024-01-01T00:00:00Z', ~]
                   properties:
                     gid: int4
 
@@ -237,6 +862,7 @@ mod tests {
                     default_srid: Some(4326),
                     pool_size: Some(20),
                     max_feature_count: Some(100),
+                    lookup_limit: Some(50),
                     tables: Some(BTreeMap::from([(
                         "table_source".to_string(),
                         TableInfo {
@@ -251,6 +877,9 @@ mod tests {
                             buffer: Some(10),
                             clip_geom: Some(false),
                             geometry_type: some("GEOMETRY"),
+                            temporal_column: some("updated_at"),
+                            temporal_end_column: some("deleted_at"),
+                            temporal_extent: Some([some("2024-01-01T00:00:00Z"), None]),
                             properties: Some(BTreeMap::from([(
                                 "gid".to_string(),
                                 "int4".to_string(),
@@ -274,4 +903,38 @@ mod tests {
             },
         );
     }
+
+    #[actix_rt::test]
+    async fn startup_retry_stops_on_non_transient_error() {
+        let retry = PgStartupRetry {
+            attempts: Some(5),
+            initial_backoff_ms: Some(1),
+            max_backoff_ms: Some(1),
+        };
+
+        let mut calls = 0;
+        let res: PgResult<()> = retry
+            .run(|| {
+                calls += 1;
+                async { Err(PgError::InvalidTableExtent("src".into(), "tbl".into())) }
+            })
+            .await;
+        assert!(res.is_err());
+        assert_eq!(calls, 1);
+
+        let mut calls = 0;
+        let res: PgResult<()> = retry
+            .run(|| {
+                calls += 1;
+                async {
+                    Err(PgError::PostgresPoolConnError(
+                        PoolError::Closed,
+                        "db".into(),
+                    ))
+                }
+            })
+            .await;
+        assert!(res.is_err());
+        assert_eq!(calls, 5);
+    }
 }
@@ -1,9 +1,12 @@
+use martin_tile_utils::{Encoding, Format, TileInfo};
 use serde::{Deserialize, Serialize};
 use tilejson::{Bounds, TileJSON};
 
 use crate::config::UnrecognizedValues;
 use crate::pg::config::PgInfo;
 use crate::pg::utils::{patch_json, InfoMap};
+use crate::pg::PgError::{UnknownTileEncoding, UnknownTileFormat};
+use crate::pg::PgResult;
 
 pub type FuncInfoSources = InfoMap<FunctionInfo>;
 
@@ -28,6 +31,16 @@ pub struct FunctionInfo {
     /// Values may be integers or floating point numbers.
     pub bounds: Option<Bounds>,
 
+    /// Format of the tiles returned by the function, e.g. `png` or `mvt`.
+    /// If neither format nor encoding is set, they are detected from the first non-empty tile.
+    pub format: Option<String>,
+
+    /// Encoding of the tiles returned by the function, e.g. `gzip` for pre-compressed MVT tiles
+    pub encoding: Option<String>,
+
+    /// Abort tile queries taking longer than this many milliseconds, overriding the `statement_timeout_ms` of the connection
+    pub statement_timeout_ms: Option<u64>,
+
     /// TileJSON provided by the SQL function comment. Not serialized.
     #[serde(skip)]
     pub tilejson: Option<serde_json::Value>,
@@ -82,9 +95,38 @@ impl PgInfo for FunctionInfo {
         tilejson.bounds = self.bounds;
         patch_json(tilejson, self.tilejson.as_ref())
     }
+
+    fn statement_timeout_ms(&self) -> Option<u64> {
+        self.statement_timeout_ms
+    }
 }
 
 impl FunctionInfo {
+    /// Get the configured tile format and encoding, or `None` if they should be detected
+    pub fn tile_info(&self, id: &str) -> PgResult<Option<TileInfo>> {
+        let format = self
+            .format
+            .as_deref()
+            .map(|v| {
+                Format::parse(v).ok_or_else(|| UnknownTileFormat(id.to_string(), v.to_string()))
+            })
+            .transpose()?;
+        let encoding = self
+            .encoding
+            .as_deref()
+            .map(|v| {
+                Encoding::parse(v).ok_or_else(|| UnknownTileEncoding(id.to_string(), v.to_string()))
+            })
+            .transpose()?;
+        Ok(match (format, encoding) {
+            (None, None) => None,
+            (format, None) => format.map(TileInfo::from),
+            (format, Some(encoding)) => {
+                Some(TileInfo::new(format.unwrap_or(Format::Mvt), encoding))
+            }
+        })
+    }
+
     /// For a given function info discovered from the database, append the configuration info provided by the user
     #[must_use]
     pub fn append_cfg_info(&self, cfg_inf: &FunctionInfo) -> FunctionInfo {
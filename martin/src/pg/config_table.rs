@@ -10,10 +10,13 @@ use crate::pg::utils::{normalize_key, patch_json, InfoMap};
 
 pub type TableInfoSources = InfoMap<TableInfo>;
 
+/// Geometry type of the raster columns
+pub const RASTER_TYPE: &str = "RASTER";
+
 #[serde_with::skip_serializing_none]
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Default)]
 pub struct TableInfo {
-    /// ID of the layer as specified in a tile (ST_AsMVT param)
+    /// ID of the layer as specified in a tile (`ST_AsMVT` param)
     pub layer_id: Option<String>,
 
     /// Table schema
@@ -32,10 +35,22 @@ pub struct TableInfo {
     #[serde(skip)]
     pub geometry_index: Option<bool>,
 
-    /// Flag indicating if table is actually a view (PostgreSQL relkind = 'v')
+    /// Flag indicating if table is actually a view (PostgreSQL `relkind = 'v'`)
     #[serde(skip)]
     pub is_view: Option<bool>,
 
+    /// Flag indicating if the geometry column has the `geography` type
+    #[serde(skip)]
+    pub is_geography: Option<bool>,
+
+    /// Column with the same geometries precomputed in Web Mercator (SRID 3857).
+    /// If set, the tiles are generated from it instead of transforming the geometry column for each tile.
+    pub webmercator_column: Option<String>,
+
+    /// Convert curved geometries to lines with `ST_CurveToLine`.
+    /// Defaults to true, unless the geometry type cannot contain curves.
+    pub curve_to_line: Option<bool>,
+
     /// Feature id column name
     pub id_column: Option<String>,
 
@@ -63,9 +78,37 @@ pub struct TableInfo {
     /// Geometry type
     pub geometry_type: Option<String>,
 
+    /// Abort tile queries taking longer than this many milliseconds, overriding the `statement_timeout_ms` of the connection
+    pub statement_timeout_ms: Option<u64>,
+
     /// List of columns, that should be encoded as tile properties
     pub properties: Option<BTreeMap<String, String>>,
 
+    /// Timestamp column matched against the `datetime` parameter of the tile requests
+    /// and the OGC API Features `items` endpoint. If `temporal_end_column` is set, it is the start of the feature's time interval.
+    pub temporal_column: Option<String>,
+
+    /// Timestamp column with the end of the feature's time interval, which is open-ended if the value is NULL
+    pub temporal_end_column: Option<String>,
+
+    /// The time interval covered by the features, as `[start, end]` timestamps, each possibly open-ended (null).
+    /// Computed from the `temporal_column` if not set, the same way as the bounds.
+    pub temporal_extent: Option<[Option<String>; 2]>,
+
+    /// Rendering options if the geometry column is a `PostGIS` raster
+    pub raster: Option<RasterInfo>,
+
+    /// Aggregate points into clusters at low zoom levels
+    pub cluster: Option<ClusterInfo>,
+
+    /// Number of raster bands, if the raster column has constraints
+    #[serde(skip)]
+    pub num_bands: Option<i32>,
+
+    /// Pixel type of each raster band, e.g. `8BUI` or `32BF`, if the raster column has constraints
+    #[serde(skip)]
+    pub pixel_types: Option<Vec<String>>,
+
     /// Mapping of properties to the actual table columns
     #[serde(skip)]
     pub prop_mapping: HashMap<String, String>,
@@ -73,7 +116,7 @@ pub struct TableInfo {
     #[serde(flatten, skip_serializing)]
     pub unrecognized: UnrecognizedValues,
 
-    /// TileJSON provider by the SQL comment. Shouldn't be serialized
+    /// `TileJSON` provider by the SQL comment. Shouldn't be serialized
     #[serde(skip)]
     pub tilejson: Option<serde_json::Value>,
 }
@@ -92,9 +135,24 @@ impl PgInfo for TableInfo {
         tilejson.minzoom = self.minzoom;
         tilejson.maxzoom = self.maxzoom;
         tilejson.bounds = self.bounds;
+        if let Some(extent) = &self.temporal_extent {
+            tilejson
+                .other
+                .insert("temporal_extent".to_string(), serde_json::json!(extent));
+        }
+        if self.is_raster() {
+            return patch_json(tilejson, self.tilejson.as_ref());
+        }
+        let mut fields = self.properties.clone().unwrap_or_default();
+        if let Some(cluster) = &self.cluster {
+            fields.insert("point_count".to_string(), "int8".to_string());
+            for name in cluster.aggregates.iter().flat_map(BTreeMap::keys) {
+                fields.insert(name.clone(), "numeric".to_string());
+            }
+        }
         let layer = VectorLayer {
             id: source_id,
-            fields: self.properties.clone().unwrap_or_default(),
+            fields,
             description: None,
             maxzoom: None,
             minzoom: None,
@@ -103,9 +161,46 @@ impl PgInfo for TableInfo {
         tilejson.vector_layers = Some(vec![layer]);
         patch_json(tilejson, self.tilejson.as_ref())
     }
+
+    fn statement_timeout_ms(&self) -> Option<u64> {
+        self.statement_timeout_ms
+    }
 }
 
 impl TableInfo {
+    /// True if the geometry column is a raster column
+    #[must_use]
+    pub fn is_raster(&self) -> bool {
+        self.geometry_type.as_deref() == Some(RASTER_TYPE)
+    }
+
+    /// True if the geometry column is a `geography` column
+    #[must_use]
+    pub fn is_geography(&self) -> bool {
+        self.is_geography == Some(true)
+    }
+
+    /// True if `ST_CurveToLine` should be applied to the geometries
+    #[must_use]
+    pub fn use_curve_to_line(&self) -> bool {
+        self.curve_to_line.unwrap_or_else(|| {
+            // Measured geometry types have an M suffix, e.g. POINTM
+            !matches!(
+                self.geometry_type
+                    .as_deref()
+                    .map(|v| v.trim_end_matches('M')),
+                Some(
+                    "POINT"
+                        | "LINESTRING"
+                        | "POLYGON"
+                        | "MULTIPOINT"
+                        | "MULTILINESTRING"
+                        | "MULTIPOLYGON"
+                )
+            )
+        })
+    }
+
     /// For a given table info discovered from the database, append the configuration info provided by the user
     #[must_use]
     pub fn append_cfg_info(
@@ -123,6 +218,9 @@ impl TableInfo {
             // These values are not serialized, so copy auto-detected values from the database
             geometry_index: self.geometry_index,
             is_view: self.is_view,
+            is_geography: self.is_geography,
+            num_bands: self.num_bands,
+            pixel_types: self.pixel_types.clone(),
             tilejson: self.tilejson.clone(),
             // Srid requires some logic
             srid: self.calc_srid(new_id, cfg_inf.srid, default_srid)?,
@@ -155,6 +253,29 @@ impl TableInfo {
             }
         }
 
+        if let Some(column) = &cfg_inf.temporal_column {
+            let prop = normalize_key(props, column.as_str(), "temporal_column", new_id)?;
+            inf.prop_mapping.insert(column.clone(), prop);
+        }
+
+        if let Some(column) = &cfg_inf.temporal_end_column {
+            if cfg_inf.temporal_column.is_none() {
+                warn!("Source {new_id} has a temporal_end_column without a temporal_column, ignoring it");
+                inf.temporal_end_column = None;
+            } else {
+                let prop = normalize_key(props, column.as_str(), "temporal_end_column", new_id)?;
+                inf.prop_mapping.insert(column.clone(), prop);
+            }
+        }
+
+        if let Some(aggregates) = cfg_inf.cluster.as_ref().and_then(|c| c.aggregates.as_ref()) {
+            for agg in aggregates.values() {
+                let column = agg.column.as_str();
+                let prop = normalize_key(props, column, "cluster aggregate column", new_id)?;
+                inf.prop_mapping.insert(agg.column.clone(), prop);
+            }
+        }
+
         Some(inf)
     }
 
@@ -187,3 +308,123 @@ impl TableInfo {
         }
     }
 }
+
+/// How raster tiles are rendered
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Default)]
+pub struct RasterInfo {
+    /// Image format of the tiles [default: png]
+    pub format: Option<RasterFormat>,
+
+    /// Width and height of the tiles in pixels [default: 256]
+    pub tile_size: Option<u32>,
+
+    /// Bands to render, starting from 1. Up to 4 bands are used as gray, gray+alpha, RGB, or RGBA
+    /// [default: band 1 if `colormap` is set, otherwise all bands up to 4]
+    pub bands: Option<Vec<u32>>,
+
+    /// Color map applied to the first band, in the `ST_ColorMap` format, e.g. `pseudocolor` or `0 0 0 255`
+    pub colormap: Option<String>,
+
+    /// Pixel value to treat as nodata, overriding the value stored in the raster
+    pub nodata: Option<f64>,
+
+    /// Values mapped to 1-255 for the bands that are not 8-bit unsigned, as `[min, max]`.
+    /// [default: the minimum and maximum band values, computed from the table]
+    pub range: Option<[f64; 2]>,
+
+    /// Resampling algorithm used to reproject the raster to the tile grid [default: nearest-neighbor]
+    pub resampling: Option<RasterResampling>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RasterFormat {
+    #[default]
+    Png,
+    Webp,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RasterResampling {
+    #[default]
+    NearestNeighbor,
+    Bilinear,
+    Cubic,
+    CubicSpline,
+    Lanczos,
+}
+
+impl RasterResampling {
+    /// Algorithm name as expected by the `PostGIS` raster functions
+    #[must_use]
+    pub fn to_sql(self) -> &'static str {
+        match self {
+            Self::NearestNeighbor => "NearestNeighbor",
+            Self::Bilinear => "Bilinear",
+            Self::Cubic => "Cubic",
+            Self::CubicSpline => "CubicSpline",
+            Self::Lanczos => "Lanczos",
+        }
+    }
+}
+
+/// Point clustering settings
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Default)]
+pub struct ClusterInfo {
+    /// Cluster points at this and all lower zoom levels. Raw points are returned at higher zooms.
+    pub max_zoom: u8,
+
+    /// How points are grouped into clusters [default: grid]
+    pub method: Option<ClusterMethod>,
+
+    /// Size of the grid cells, or the maximum distance between clustered points for `dbscan`,
+    /// in tile coordinate space [default: 256]
+    pub radius: Option<u32>,
+
+    /// Minimum number of nearby points to form a cluster with `dbscan`,
+    /// other points are returned as single-point clusters [default: 1]
+    pub min_points: Option<u32>,
+
+    /// Additional properties of each cluster, computed from the clustered points
+    pub aggregates: Option<BTreeMap<String, ClusterAggregate>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClusterMethod {
+    /// Group points falling into the same cell of a grid aligned with the tile
+    #[default]
+    Grid,
+    /// Group nearby points using `ST_ClusterDBSCAN`
+    Dbscan,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ClusterAggregate {
+    /// Aggregate function
+    pub function: AggregateFunction,
+    /// Table column to aggregate
+    pub column: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AggregateFunction {
+    Sum,
+    Min,
+    Max,
+}
+
+impl AggregateFunction {
+    #[must_use]
+    pub fn to_sql(self) -> &'static str {
+        match self {
+            Self::Sum => "sum",
+            Self::Min => "min",
+            Self::Max => "max",
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use serde::{Deserialize, Serialize};
+use tilejson::{Bounds, TileJSON};
+
+use crate::config::UnrecognizedValues;
+use crate::pg::config::PgInfo;
+use crate::pg::utils::InfoMap;
+
+pub type TileTableInfoSources = InfoMap<TileTableInfo>;
+
+/// A table with pre-rendered tiles, similar to the `tiles` table of an `MBTiles` file
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Default)]
+pub struct TileTableInfo {
+    /// Table schema
+    pub schema: String,
+
+    /// Table name
+    pub table: String,
+
+    /// Zoom level column name [default: `zoom_level`]
+    pub zoom_column: Option<String>,
+
+    /// Tile X column name [default: `tile_column`]
+    pub x_column: Option<String>,
+
+    /// Tile Y column name [default: `tile_row`]
+    pub y_column: Option<String>,
+
+    /// Tile data column name [default: `tile_data`]
+    pub data_column: Option<String>,
+
+    /// Tile Y values are stored in the TMS scheme like in `MBTiles` files, i.e. row 0 is at the bottom [default: false]
+    pub tms: Option<bool>,
+
+    /// A table in the same schema with `name` and `value` text columns, storing the tileset metadata like in `MBTiles` files
+    pub metadata_table: Option<String>,
+
+    /// An integer specifying the minimum zoom level
+    pub minzoom: Option<u8>,
+
+    /// An integer specifying the maximum zoom level. MUST be >= minzoom
+    pub maxzoom: Option<u8>,
+
+    /// The maximum extent of available map tiles. Bounds MUST define an area
+    /// covered by all zoom levels. The bounds are represented in WGS:84
+    /// latitude and longitude values, in the order left, bottom, right, top.
+    /// Values may be integers or floating point numbers.
+    pub bounds: Option<Bounds>,
+
+    /// Abort tile queries taking longer than this many milliseconds, overriding the `statement_timeout_ms` of the connection
+    pub statement_timeout_ms: Option<u64>,
+
+    /// `TileJSON` loaded from the metadata table. Not serialized.
+    #[serde(skip)]
+    pub tilejson: Option<TileJSON>,
+
+    #[serde(flatten, skip_serializing)]
+    pub unrecognized: UnrecognizedValues,
+}
+
+impl TileTableInfo {
+    #[must_use]
+    pub fn new(schema: String, table: String) -> Self {
+        Self {
+            schema,
+            table,
+            ..Default::default()
+        }
+    }
+
+    #[must_use]
+    pub fn zoom_column(&self) -> &str {
+        self.zoom_column.as_deref().unwrap_or("zoom_level")
+    }
+
+    #[must_use]
+    pub fn x_column(&self) -> &str {
+        self.x_column.as_deref().unwrap_or("tile_column")
+    }
+
+    #[must_use]
+    pub fn y_column(&self) -> &str {
+        self.y_column.as_deref().unwrap_or("tile_row")
+    }
+
+    #[must_use]
+    pub fn data_column(&self) -> &str {
+        self.data_column.as_deref().unwrap_or("tile_data")
+    }
+}
+
+impl PgInfo for TileTableInfo {
+    fn format_id(&self) -> String {
+        format!("{}.{}", self.schema, self.table)
+    }
+
+    fn to_tilejson(&self, source_id: String) -> TileJSON {
+        let mut tilejson = self
+            .tilejson
+            .clone()
+            .unwrap_or_else(|| tilejson::tilejson! { tiles: vec![] });
+        // tile source is required, but not yet known
+        tilejson.tiles = vec![];
+        if tilejson.name.is_none() {
+            tilejson.name = Some(source_id);
+        }
+        if tilejson.description.is_none() {
+            tilejson.description = Some(self.format_id());
+        }
+        if self.minzoom.is_some() {
+            tilejson.minzoom = self.minzoom;
+        }
+        if self.maxzoom.is_some() {
+            tilejson.maxzoom = self.maxzoom;
+        }
+        if self.bounds.is_some() {
+            tilejson.bounds = self.bounds;
+        }
+        tilejson
+    }
+
+    fn statement_timeout_ms(&self) -> Option<u64> {
+        self.statement_timeout_ms
+    }
+}
@@ -52,9 +52,21 @@ pub enum PgError {
     #[error("PostGIS version {0} is too old, minimum required is {1}")]
     PostgisTooOld(Version, Version),
 
+    #[error("Unknown tile format '{1}' in function source {0}. Use one of: gif, jpeg, json, mvt, png, webp")]
+    UnknownTileFormat(String, String),
+
+    #[error("Unknown tile encoding '{1}' in function source {0}. Use one of: none, gzip, zlib, brotli, zstd")]
+    UnknownTileEncoding(String, String),
+
     #[error("Invalid extent setting in source {0} for table {1}: extent=0")]
     InvalidTableExtent(String, String),
 
+    #[error("Invalid raster setting in source {0} for table {1}: {2}")]
+    InvalidRasterConfig(String, String, String),
+
+    #[error("Invalid cluster setting in source {0} for table {1}: {2}")]
+    InvalidClusterConfig(String, String, String),
+
     #[error("Error preparing a query for the tile '{1}' ({2}): {3} {0}")]
     PrepareQueryError(#[source] TokioPgError, String, String, String),
 
@@ -63,4 +75,39 @@ pub enum PgError {
 
     #[error(r#"Unable to get tile {2:#} with {:?} params from {1}: {0}"#, query_to_json(.3.as_ref()))]
     GetTileWithQueryError(#[source] TokioPgError, String, TileCoord, Option<UrlQuery>),
+
+    #[error("Invalid URL parameter '{1}' for source {0}: {2}")]
+    InvalidUrlParam(String, String, String),
+
+    #[error("Timed out getting tile {1:#} from {0}")]
+    GetTileTimeout(String, TileCoord),
+
+    #[error("Unable to look up features in {1}: {0}")]
+    GetFeaturesError(#[source] TokioPgError, String),
+
+    #[error("Unable to get items from {1}: {0}")]
+    GetItemsError(#[source] TokioPgError, String),
+
+    #[error("Invalid items query for source {0}: {1}")]
+    InvalidItemsQuery(String, String),
+
+    #[error("{0}")]
+    InvalidRequestContext(String),
+}
+
+impl PgError {
+    /// Connection-level errors that may go away on their own, e.g. while the database is still starting up.
+    /// Errors reported by the database for a query, e.g. bad SQL or missing permissions, are not retried.
+    #[must_use]
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::PostgresPoolConnError(..) => true,
+            Self::PostgresError(e, _) => e.as_db_error().map_or(true, |db| {
+                let code = db.code().code();
+                // admin_shutdown, cannot_connect_now, and the connection_exception class
+                code == "57P01" || code == "57P03" || code.starts_with("08")
+            }),
+            _ => false,
+        }
+    }
 }
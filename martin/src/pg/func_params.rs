@@ -0,0 +1,340 @@
+use std::fmt::Write as _;
+use std::sync::OnceLock;
+
+use deadpool_postgres::tokio_postgres::types::Type;
+use regex::Regex;
+use serde::Serialize;
+
+/// How URL query values are validated before being passed to a function argument
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PgParamType {
+    Boolean,
+    SmallInt,
+    Integer,
+    BigInt,
+    Float,
+    Text,
+    Date,
+    Timestamp,
+    /// Receives all URL query parameters as a single JSON object
+    Json,
+    /// An OGC `datetime` instant or interval, passed as a `tstzrange`
+    Datetime,
+}
+
+impl PgParamType {
+    fn parse(sql_type: &str) -> Option<Self> {
+        Some(match sql_type {
+            "boolean" => Self::Boolean,
+            "smallint" => Self::SmallInt,
+            "integer" => Self::Integer,
+            "bigint" => Self::BigInt,
+            "real" | "double precision" | "numeric" => Self::Float,
+            "text" | "character varying" | "character" => Self::Text,
+            "date" => Self::Date,
+            "timestamp without time zone" | "timestamp with time zone" => Self::Timestamp,
+            "json" | "jsonb" => Self::Json,
+            _ => None?,
+        })
+    }
+
+    fn validate(self, value: &str) -> Result<(), String> {
+        let is_valid = match self {
+            Self::Boolean => matches!(
+                value.to_ascii_lowercase().as_str(),
+                "true" | "false" | "t" | "f" | "1" | "0"
+            ),
+            Self::SmallInt => value.parse::<i16>().is_ok(),
+            Self::Integer => value.parse::<i32>().is_ok(),
+            Self::BigInt => value.parse::<i64>().is_ok(),
+            Self::Float => value.parse::<f64>().is_ok_and(f64::is_finite),
+            Self::Text | Self::Json => true,
+            Self::Date => is_valid_date(value),
+            Self::Timestamp => is_valid_timestamp(value),
+            Self::Datetime => datetime_to_range(value).is_some(),
+        };
+        if is_valid {
+            Ok(())
+        } else {
+            Err(format!("'{value}' is not a valid {self:?} value"))
+        }
+    }
+}
+
+/// A function argument beyond z/x/y, set from the URL query parameter with the same name
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PgFuncParam {
+    pub name: String,
+    /// SQL type of the argument, e.g. `date` or `text[]`
+    #[serde(rename = "type")]
+    pub sql_type: String,
+    /// SQL expression used when the parameter is not given. Parameters without a default are required.
+    pub default: Option<String>,
+    #[serde(skip)]
+    pub param_type: PgParamType,
+    #[serde(skip)]
+    pub is_array: bool,
+}
+
+impl PgFuncParam {
+    /// Returns `None` if the argument type is not supported
+    #[must_use]
+    pub fn new(name: String, sql_type: String, default: Option<String>) -> Option<Self> {
+        let (base_type, is_array) = match sql_type.strip_suffix("[]") {
+            Some(base_type) => (base_type, true),
+            None => (sql_type.as_str(), false),
+        };
+        let param_type = PgParamType::parse(base_type)?;
+        if param_type == PgParamType::Json {
+            if is_array {
+                return None;
+            }
+        } else if name == "_" {
+            // unnamed arguments cannot be matched with URL parameters
+            return None;
+        }
+        Some(Self {
+            name,
+            sql_type,
+            default,
+            param_type,
+            is_array,
+        })
+    }
+
+    /// The `datetime` parameter of the table sources with a temporal column.
+    /// It is optional, so the SQL query must handle `NULL` values.
+    #[must_use]
+    pub fn datetime() -> Self {
+        Self {
+            name: "datetime".to_string(),
+            sql_type: "tstzrange".to_string(),
+            default: Some("NULL".to_string()),
+            param_type: PgParamType::Datetime,
+            is_array: false,
+        }
+    }
+
+    #[must_use]
+    pub fn is_json(&self) -> bool {
+        self.param_type == PgParamType::Json
+    }
+
+    /// The type of the value bound to the prepared query
+    #[must_use]
+    pub fn pg_type(&self) -> Type {
+        if self.is_json() {
+            Type::JSON
+        } else {
+            Type::TEXT
+        }
+    }
+
+    /// SQL expression passing the query parameter `$index` to this argument
+    #[must_use]
+    pub fn to_sql_arg(&self, index: usize) -> String {
+        let mut arg = format!("${index}::{}", self.sql_type);
+        if let (Some(default), false) = (&self.default, self.is_json()) {
+            arg = format!("COALESCE({arg}, {default})");
+        }
+        arg
+    }
+
+    /// Validate the URL query value and convert it to its SQL text representation.
+    /// Missing values are returned as `None`, which makes the function use the declared default.
+    pub fn parse_value(&self, value: Option<&str>) -> Result<Option<String>, String> {
+        let Some(value) = value else {
+            return if self.default.is_some() || self.is_json() {
+                Ok(None)
+            } else {
+                Err("the parameter is required".to_string())
+            };
+        };
+        if self.param_type == PgParamType::Datetime {
+            self.param_type.validate(value)?;
+            return Ok(datetime_to_range(value));
+        }
+        if !self.is_array {
+            self.param_type.validate(value)?;
+            return Ok(Some(value.to_string()));
+        }
+
+        let mut result = String::from("{");
+        if !value.is_empty() {
+            for (idx, item) in value.split(',').enumerate() {
+                self.param_type.validate(item)?;
+                if idx > 0 {
+                    result.push(',');
+                }
+                let item = item.replace('\\', "\\\\").replace('"', "\\\"");
+                write!(result, "\"{item}\"").unwrap();
+            }
+        }
+        result.push('}');
+        Ok(Some(result))
+    }
+}
+
+/// Convert an OGC `datetime` instant like `2024-01-01T00:00:00Z`, or an interval like
+/// `2024-01-01/2024-02-01`, `../2024-02-01` or `2024-01-01/..`, to a `tstzrange` literal
+fn datetime_to_range(value: &str) -> Option<String> {
+    let (start, end) = value.split_once('/').unwrap_or((value, value));
+    let bound = |v: &str| match v {
+        "" | ".." => Some(String::new()),
+        v if is_valid_timestamp(v) => Some(format!("\"{v}\"")),
+        _ => None,
+    };
+    let (start, end) = (bound(start)?, bound(end)?);
+    if start.is_empty() && end.is_empty() {
+        return None;
+    }
+    Some(format!("[{start},{end}]"))
+}
+
+fn is_valid_date(value: &str) -> bool {
+    let bytes = value.as_bytes();
+    if bytes.len() != 10
+        || bytes[4] != b'-'
+        || bytes[7] != b'-'
+        || !bytes
+            .iter()
+            .enumerate()
+            .all(|(idx, c)| idx == 4 || idx == 7 || c.is_ascii_digit())
+    {
+        return false;
+    }
+    let (Ok(year), Ok(month), Ok(day)) = (
+        value[0..4].parse::<u32>(),
+        value[5..7].parse::<u32>(),
+        value[8..10].parse::<u32>(),
+    ) else {
+        return false;
+    };
+    let is_leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days = match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if is_leap => 29,
+        2 => 28,
+        _ => return false,
+    };
+    (1..=days).contains(&day)
+}
+
+fn is_valid_timestamp(value: &str) -> bool {
+    static RE_TIMESTAMP: OnceLock<Regex> = OnceLock::new();
+    RE_TIMESTAMP
+        .get_or_init(|| {
+            Regex::new(
+                r"^(\d{4}-\d{2}-\d{2})([T ]\d{2}:\d{2}(:\d{2}(\.\d{1,6})?)?)?(Z|[+-]\d{2}(:?\d{2})?)?$",
+            )
+            .unwrap()
+        })
+        .captures(value)
+        .is_some_and(|c| is_valid_date(c.get(1).unwrap().as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn param(sql_type: &str, default: Option<&str>) -> PgFuncParam {
+        PgFuncParam::new(
+            "p".to_string(),
+            sql_type.to_string(),
+            default.map(ToString::to_string),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn unsupported_params() {
+        let new = |name: &str, typ: &str| PgFuncParam::new(name.to_string(), typ.to_string(), None);
+        assert!(new("p", "geometry").is_none());
+        assert!(new("_", "integer").is_none());
+        assert!(new("p", "json[]").is_none());
+        assert!(new("_", "json").is_some());
+    }
+
+    #[test]
+    fn sql_args() {
+        assert_eq!(param("date", None).to_sql_arg(4), "$4::date");
+        assert_eq!(
+            param("text[]", Some("'{a}'::text[]")).to_sql_arg(5),
+            "COALESCE($5::text[], '{a}'::text[])"
+        );
+        assert_eq!(param("jsonb", Some("'{}'")).to_sql_arg(4), "$4::jsonb");
+    }
+
+    #[test]
+    fn parse_values() {
+        let p = param("integer", None);
+        assert_eq!(p.parse_value(Some("42")), Ok(Some("42".to_string())));
+        assert!(p.parse_value(Some("4.2")).is_err());
+        assert!(p.parse_value(Some("3000000000")).is_err());
+        assert!(p.parse_value(None).is_err());
+
+        let p = param("integer", Some("10"));
+        assert_eq!(p.parse_value(None), Ok(None));
+
+        let p = param("boolean", None);
+        assert!(p.parse_value(Some("true")).is_ok());
+        assert!(p.parse_value(Some("yes please")).is_err());
+
+        let p = param("double precision", None);
+        assert!(p.parse_value(Some("-1.5e3")).is_ok());
+        assert!(p.parse_value(Some("NaN")).is_err());
+
+        let p = param("date", None);
+        assert!(p.parse_value(Some("2024-02-29")).is_ok());
+        assert!(p.parse_value(Some("2023-02-29")).is_err());
+        assert!(p.parse_value(Some("2024-2-1")).is_err());
+        assert!(p.parse_value(Some("'; DROP TABLE x")).is_err());
+
+        let p = param("timestamp with time zone", None);
+        assert!(p.parse_value(Some("2024-01-31T10:20:30Z")).is_ok());
+        assert!(p.parse_value(Some("2024-01-31 10:20:30.123+02:00")).is_ok());
+        assert!(p.parse_value(Some("2024-01-32T10:20")).is_err());
+
+        let p = param("text[]", None);
+        assert_eq!(
+            p.parse_value(Some(r#"a,b"c,d\e"#)),
+            Ok(Some(r#"{"a","b\"c","d\\e"}"#.to_string()))
+        );
+        assert_eq!(p.parse_value(Some("")), Ok(Some("{}".to_string())));
+
+        let p = param("integer[]", None);
+        assert_eq!(
+            p.parse_value(Some("1,2")),
+            Ok(Some(r#"{"1","2"}"#.to_string()))
+        );
+        assert!(p.parse_value(Some("1,x")).is_err());
+
+        let p = PgFuncParam::datetime();
+        assert_eq!(p.parse_value(None), Ok(None));
+        assert_eq!(
+            p.parse_value(Some("2024-01-31T10:20:30Z")),
+            Ok(Some(
+                r#"["2024-01-31T10:20:30Z","2024-01-31T10:20:30Z"]"#.to_string()
+            ))
+        );
+        assert_eq!(
+            p.parse_value(Some("2024-01-01/2024-02-01")),
+            Ok(Some(r#"["2024-01-01","2024-02-01"]"#.to_string()))
+        );
+        assert_eq!(
+            p.parse_value(Some("../2024-02-01")),
+            Ok(Some(r#"[,"2024-02-01"]"#.to_string()))
+        );
+        assert_eq!(
+            p.parse_value(Some("2024-01-01/")),
+            Ok(Some(r#"["2024-01-01",]"#.to_string()))
+        );
+        assert!(p.parse_value(Some("")).is_err());
+        assert!(p.parse_value(Some("2024-01-01/tomorrow")).is_err());
+        assert!(p
+            .parse_value(Some("2024-01-01/2024-02-01/2024-03-01"))
+            .is_err());
+    }
+}
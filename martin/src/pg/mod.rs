@@ -2,17 +2,29 @@ pub mod builder;
 mod config;
 mod config_function;
 mod config_table;
+mod config_tile_table;
 mod errors;
+mod func_params;
 pub mod pg_source;
 mod pool;
 pub mod query_functions;
+pub mod query_items;
 pub mod query_tables;
+pub mod query_tile_tables;
+mod request_context;
 mod tls;
 mod utils;
 
-pub use config::{PgCfgPublish, PgCfgPublishFuncs, PgCfgPublishTables, PgConfig, PgSslCerts};
+pub use config::{
+    PgCfgPublish, PgCfgPublishFuncs, PgCfgPublishTables, PgConfig, PgLoadBalancing, PgRediscovery,
+    PgSslCerts, PgStartupRetry,
+};
 pub use config_function::FunctionInfo;
 pub use config_table::TableInfo;
+pub use config_tile_table::TileTableInfo;
 pub use errors::{PgError, PgResult};
-pub use pool::{PgPool, POOL_SIZE_DEFAULT};
+pub use func_params::PgFuncParam;
+pub use pool::{PgConnection, PgPool, POOL_SIZE_DEFAULT};
 pub use query_functions::query_available_function;
+pub use query_tile_tables::{PgTileTableWriter, TileTableConflict};
+pub use request_context::{PgRequestContext, RequestAttribute};
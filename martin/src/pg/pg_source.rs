@@ -1,25 +1,43 @@
+use std::iter::zip;
+
 use async_trait::async_trait;
+use deadpool_postgres::tokio_postgres::error::SqlState;
 use deadpool_postgres::tokio_postgres::types::{ToSql, Type};
-use log::debug;
+use deadpool_postgres::tokio_postgres::Row;
+use deadpool_postgres::GenericClient;
+use futures::{stream, StreamExt as _};
+use log::{debug, info};
 use martin_tile_utils::Encoding::Uncompressed;
 use martin_tile_utils::Format::Mvt;
 use martin_tile_utils::{TileCoord, TileInfo};
+use serde_json::json;
 use tilejson::TileJSON;
 
 use crate::pg::pool::PgPool;
+use crate::pg::query_items::PgItemsInfo;
+use crate::pg::request_context::PgRequestContext;
 use crate::pg::utils::query_to_json;
-use crate::pg::PgError::{GetTileError, GetTileWithQueryError, PrepareQueryError};
-use crate::source::{Source, TileData, UrlQuery};
+use crate::pg::PgError::{
+    GetFeaturesError, GetItemsError, GetTileError, GetTileTimeout, GetTileWithQueryError,
+    InvalidItemsQuery, InvalidUrlParam, PostgresError, PrepareQueryError,
+};
+use crate::pg::PgFuncParam;
+use crate::source::{
+    ItemStream, ItemsQuery, RequestAttributes, RequestContext, Source, TileData, TileWithEtag,
+    UrlQuery,
+};
 use crate::MartinResult;
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
+use std::time::Instant;
 use tokio::sync::RwLock;
 
 // Adjust the following imports to the correct paths:
 use crate::pg::query_tables::fetch_postgis_metadata; // Fixed import
-use crate::srv::server::{Catalog, AddSourceInput, SourceMetadata}; // Ensure these paths are correct
+use crate::srv::server::{AddSourceInput, Catalog, SourceMetadata}; // Ensure these paths are correct
 use actix_web::Error; // Or define your own Error type if needed
 
-
+/// Default maximum number of features returned by a single feature lookup
+pub const DEFAULT_LOOKUP_LIMIT: usize = 100;
 
 #[derive(Clone, Debug)]
 pub struct PgSource {
@@ -27,17 +45,180 @@ pub struct PgSource {
     info: PgSqlInfo,
     pool: PgPool,
     tilejson: TileJSON,
+    /// Either configured, or detected from the first non-empty tile
+    tile_info: Arc<OnceLock<TileInfo>>,
 }
 
 impl PgSource {
     #[must_use]
     pub fn new(id: String, info: PgSqlInfo, tilejson: TileJSON, pool: PgPool) -> Self {
+        let tile_info = Arc::new(info.tile_info.map_or_else(OnceLock::new, OnceLock::from));
         Self {
             id,
             info,
             pool,
             tilejson,
+            tile_info,
+        }
+    }
+
+    fn detect_tile_info(&self, data: &[u8]) {
+        if self.tile_info.get().is_some() || data.is_empty() {
+            return;
+        }
+        let info = TileInfo::detect(data).unwrap_or(TileInfo::new(Mvt, Uncompressed));
+        if self.tile_info.set(info).is_ok() {
+            info!("Detected {info} tiles in source {}", self.id);
+        }
+    }
+
+    /// Validate the URL query parameters used as function arguments
+    fn url_args(&self, url_query: Option<&UrlQuery>) -> MartinResult<Vec<Option<String>>> {
+        let args = self
+            .info
+            .url_params
+            .iter()
+            .map(|p| {
+                let value = url_query.and_then(|q| q.get(&p.name)).map(String::as_str);
+                p.parse_value(value)
+                    .map_err(|e| InvalidUrlParam(self.id.to_string(), p.name.to_string(), e))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(args)
+    }
+
+    async fn query_features(
+        &self,
+        conn: &impl GenericClient,
+        sql: &str,
+        bbox: [f64; 4],
+        limit: i64,
+        url_args: &[Option<String>],
+    ) -> MartinResult<serde_json::Value> {
+        // The URL parameters of the table sources, i.e. the datetime, follow the limit
+        let mut param_types = vec![
+            Type::FLOAT8,
+            Type::FLOAT8,
+            Type::FLOAT8,
+            Type::FLOAT8,
+            Type::INT8,
+        ];
+        param_types.extend(self.info.url_params.iter().map(PgFuncParam::pg_type));
+        let prep_query = conn
+            .prepare_typed_cached(sql, &param_types)
+            .await
+            .map_err(|e| {
+                PrepareQueryError(
+                    e,
+                    self.id.to_string(),
+                    self.info.signature.to_string(),
+                    sql.to_string(),
+                )
+            })?;
+
+        let [left, bottom, right, top] = bbox;
+        debug!("SQL: {sql} [{left},{bottom},{right},{top}, {limit}, {url_args:?}]");
+        let mut params: Vec<&(dyn ToSql + Sync)> = vec![&left, &bottom, &right, &top, &limit];
+        params.extend(url_args.iter().map(|v| v as &(dyn ToSql + Sync)));
+        let row = conn
+            .query_one(&prep_query, &params)
+            .await
+            .map_err(|e| GetFeaturesError(e, self.id.to_string()))?;
+        Ok(row.get(0))
+    }
+
+    /// Run a tile query, or a query wrapping it, with the tile coordinates and URL parameters
+    async fn query_tile_row(
+        &self,
+        conn: &impl GenericClient,
+        sql: &str,
+        xyz: TileCoord,
+        url_query: Option<&UrlQuery>,
+        url_args: &[Option<String>],
+    ) -> MartinResult<Option<Row>> {
+        let mut param_types = vec![Type::INT2, Type::INT8, Type::INT8];
+        param_types.extend(self.info.url_params.iter().map(PgFuncParam::pg_type));
+
+        let prep_query = conn
+            .prepare_typed_cached(sql, &param_types)
+            .await
+            .map_err(|e| {
+                PrepareQueryError(
+                    e,
+                    self.id.to_string(),
+                    self.info.signature.to_string(),
+                    sql.to_string(),
+                )
+            })?;
+
+        let z = i16::from(xyz.z);
+        let x = i64::from(xyz.x);
+        let y = i64::from(xyz.y);
+        let mut params: Vec<&(dyn ToSql + Sync)> = vec![&z, &x, &y];
+        let tile = if self.support_url_query() {
+            let json = query_to_json(url_query);
+            debug!("SQL: {sql} [{xyz}, {json:?}]");
+            for (param, arg) in zip(&self.info.url_params, url_args) {
+                params.push(if param.is_json() { &json } else { arg });
+            }
+            conn.query_opt(&prep_query, &params).await
+        } else {
+            debug!("SQL: {sql} [{xyz}]");
+            conn.query_opt(&prep_query, &params).await
+        };
+
+        Ok(tile.map_err(|e| {
+            if e.code() == Some(&SqlState::QUERY_CANCELED) {
+                GetTileTimeout(self.id.to_string(), xyz)
+            } else if self.support_url_query() {
+                GetTileWithQueryError(e, self.id.to_string(), xyz, url_query.cloned())
+            } else {
+                GetTileError(e, self.id.to_string(), xyz)
+            }
+        })?)
+    }
+
+    /// Apply the per-source statement timeout and the request context to the current transaction
+    async fn set_tile_settings(
+        &self,
+        conn: &impl GenericClient,
+        context: Option<&RequestContext>,
+    ) -> MartinResult<()> {
+        if let Some(timeout) = self.info.statement_timeout_ms {
+            conn.batch_execute(&format!("SET LOCAL statement_timeout = {timeout}"))
+                .await
+                .map_err(|e| PostgresError(e, "setting statement timeout"))?;
         }
+        if let Some(context) = context {
+            set_request_context(conn, context).await?;
+        }
+        Ok(())
+    }
+
+    async fn query_tile(
+        &self,
+        conn: &impl GenericClient,
+        xyz: TileCoord,
+        url_query: Option<&UrlQuery>,
+        url_args: &[Option<String>],
+    ) -> MartinResult<TileWithEtag> {
+        let row = self
+            .query_tile_row(conn, &self.info.sql_query, xyz, url_query, url_args)
+            .await?;
+
+        let Some(row) = row else {
+            return Ok(TileWithEtag::default());
+        };
+        let data = row.get::<_, Option<TileData>>(0).unwrap_or_default();
+        // functions may return the tile hash as the second column
+        let etag = if row.len() > 1 {
+            row.get::<_, Option<String>>(1)
+        } else {
+            None
+        };
+        self.detect_tile_info(&data);
+
+        Ok(TileWithEtag { data, etag })
     }
 }
 
@@ -52,7 +233,10 @@ impl Source for PgSource {
     }
 
     fn get_tile_info(&self) -> TileInfo {
-        TileInfo::new(Mvt, Uncompressed)
+        self.tile_info
+            .get()
+            .copied()
+            .unwrap_or(TileInfo::new(Mvt, Uncompressed))
     }
 
     fn clone_source(&self) -> Box<dyn Source> {
@@ -68,65 +252,277 @@ impl Source for PgSource {
         xyz: TileCoord,
         url_query: Option<&UrlQuery>,
     ) -> MartinResult<TileData> {
-        let conn = self.pool.get().await?;
-        let param_types: &[Type] = if self.support_url_query() {
-            &[Type::INT2, Type::INT8, Type::INT8, Type::JSON]
-        } else {
-            &[Type::INT2, Type::INT8, Type::INT8]
+        Ok(self.get_tile_with_etag(xyz, url_query, None).await?.data)
+    }
+
+    fn get_request_context(
+        &self,
+        attrs: &RequestAttributes,
+    ) -> MartinResult<Option<RequestContext>> {
+        let Some(request_context) = &self.info.request_context else {
+            return Ok(None);
         };
+        Ok(Some(request_context.to_context(attrs)?))
+    }
 
-        let sql = &self.info.sql_query;
-        let prep_query = conn
-            .prepare_typed_cached(sql, param_types)
-            .await
-            .map_err(|e| {
-                PrepareQueryError(
-                    e,
-                    self.id.to_string(),
-                    self.info.signature.to_string(),
-                    self.info.sql_query.to_string(),
-                )
-            })?;
+    fn get_request_headers(&self) -> Vec<String> {
+        self.info
+            .request_context
+            .as_ref()
+            .map(|v| v.header_names().into_iter().map(String::from).collect())
+            .unwrap_or_default()
+    }
 
-        let tile = if self.support_url_query() {
-            let json = query_to_json(url_query);
-            debug!("SQL: {sql} [{xyz}, {json:?}]");
-            let params: &[&(dyn ToSql + Sync)] = &[
-                &i16::from(xyz.z),
-                &i64::from(xyz.x),
-                &i64::from(xyz.y),
-                &json,
-            ];
-            conn.query_opt(&prep_query, params).await
+    fn get_request_query_params(&self) -> Vec<String> {
+        self.info
+            .request_context
+            .as_ref()
+            .map(|v| v.query_names().into_iter().map(String::from).collect())
+            .unwrap_or_default()
+    }
+
+    async fn get_features(
+        &self,
+        bbox: [f64; 4],
+        limit: Option<usize>,
+        url_query: Option<&UrlQuery>,
+        context: Option<&RequestContext>,
+    ) -> MartinResult<Option<serde_json::Value>> {
+        let Some(sql) = &self.info.lookup_query else {
+            return Ok(None);
+        };
+        let limit = limit.map_or(self.info.lookup_limit, |v| v.min(self.info.lookup_limit));
+        let limit = i64::try_from(limit).unwrap_or(i64::MAX);
+        let url_args = self.url_args(url_query)?;
+
+        let mut conn = self.pool.get_tile_conn().await?;
+        let features = if let Some(context) = context.filter(|v| !v.is_empty()) {
+            let tx = conn
+                .transaction()
+                .await
+                .map_err(|e| PostgresError(e, "starting a feature lookup transaction"))?;
+            set_request_context(&tx, context).await?;
+            let features = self
+                .query_features(&tx, sql, bbox, limit, &url_args)
+                .await?;
+            tx.commit()
+                .await
+                .map_err(|e| PostgresError(e, "committing a feature lookup transaction"))?;
+            features
         } else {
-            debug!("SQL: {sql} [{xyz}]");
-            conn.query_opt(
-                &prep_query,
-                &[&i16::from(xyz.z), &i64::from(xyz.x), &i64::from(xyz.y)],
-            )
+            self.query_features(&*conn, sql, bbox, limit, &url_args)
+                .await?
+        };
+        Ok(Some(features))
+    }
+
+    fn get_items_srids(&self) -> Option<Vec<i32>> {
+        self.info.items.as_ref().map(PgItemsInfo::srids)
+    }
+
+    fn has_stable_items_order(&self) -> bool {
+        self.info
+            .items
+            .as_ref()
+            .is_some_and(PgItemsInfo::is_ordered)
+    }
+
+    async fn get_items(
+        &self,
+        query: &ItemsQuery,
+        context: Option<&RequestContext>,
+    ) -> MartinResult<Option<ItemStream>> {
+        let Some(items) = &self.info.items else {
+            return Ok(None);
+        };
+        let (sql, params) = items.to_query(&self.id, query)?;
+        debug!("SQL: {sql} [{query:?}]");
+
+        let mut conn = self.pool.get_tile_conn().await?;
+        // Stop the query if the client disconnects before all features are sent.
+        // After an error, the connection may be left in a transaction, so it is not reused either.
+        conn.set_cancel_on_drop(true);
+        let context = context.filter(|v| !v.is_empty());
+        if let Some(context) = context {
+            // The rows are streamed after this function returns, so the transaction is managed manually
+            conn.batch_execute("BEGIN")
+                .await
+                .map_err(|e| PostgresError(e, "starting an items transaction"))?;
+            set_request_context(&*conn, context).await?;
+        }
+        let in_transaction = context.is_some();
+
+        let id = self.id.clone();
+        let rows = conn.query_raw(sql.as_str(), params).await.map_err(|e| {
+            if e.code().is_some_and(|c| c.code().starts_with("22")) {
+                // Data exceptions are caused by invalid values, e.g. an unparsable datetime
+                InvalidItemsQuery(id.clone(), e.to_string())
+            } else {
+                GetItemsError(e, id.clone())
+            }
+        })?;
+
+        let stream = stream::unfold((conn, Box::pin(rows)), move |(mut conn, mut rows)| {
+            let id = id.clone();
+            async move {
+                let item = match rows.next().await {
+                    Some(Ok(row)) => Ok(row.get::<_, String>(0)),
+                    Some(Err(e)) => Err(GetItemsError(e, id).into()),
+                    None => {
+                        // The query has completed, so the connection can be reused
+                        if !in_transaction || conn.batch_execute("COMMIT").await.is_ok() {
+                            conn.set_cancel_on_drop(false);
+                        }
+                        return None;
+                    }
+                };
+                Some((item, (conn, rows)))
+            }
+        });
+        Ok(Some(stream.boxed()))
+    }
+
+    fn get_sql_info(&self) -> Option<serde_json::Value> {
+        let params: Vec<_> = ["z", "x", "y"]
+            .into_iter()
+            .map(ToString::to_string)
+            .chain(self.info.url_params.iter().map(|p| p.name.clone()))
+            .collect();
+        Some(json!({
+            "id": self.id,
+            "signature": self.info.signature,
+            "sql": self.info.sql_query,
+            "params": params,
+            "statement_timeout_ms": self.info.statement_timeout_ms,
+            "warnings": self.info.warnings(),
+        }))
+    }
+
+    async fn explain_tile(
+        &self,
+        xyz: TileCoord,
+        url_query: Option<&UrlQuery>,
+        context: Option<&RequestContext>,
+    ) -> MartinResult<Option<serde_json::Value>> {
+        let url_args = self.url_args(url_query)?;
+        let sql = format!(
+            "EXPLAIN (ANALYZE, BUFFERS, FORMAT JSON) {}",
+            self.info.sql_query
+        );
+        let mut conn = self.pool.get_tile_conn().await?;
+        conn.set_cancel_on_drop(true);
+        // EXPLAIN ANALYZE runs the query, so it uses the same settings as the tile requests
+        let tx = conn
+            .transaction()
             .await
+            .map_err(|e| PostgresError(e, "starting an explain transaction"))?;
+        self.set_tile_settings(&tx, context.filter(|v| !v.is_empty()))
+            .await?;
+        let start = Instant::now();
+        let row = match self
+            .query_tile_row(&tx, &sql, xyz, url_query, &url_args)
+            .await
+        {
+            Ok(row) => row,
+            Err(e) => {
+                drop(tx);
+                conn.set_cancel_on_drop(false);
+                return Err(e);
+            }
         };
+        let elapsed = start.elapsed();
+        // Any changes made by the query are discarded
+        tx.rollback()
+            .await
+            .map_err(|e| PostgresError(e, "rolling back an explain transaction"))?;
+        conn.set_cancel_on_drop(false);
 
-        let tile = tile
-            .map(|row| row.and_then(|r| r.get::<_, Option<TileData>>(0)))
-            .map_err(|e| {
-                if self.support_url_query() {
-                    GetTileWithQueryError(e, self.id.to_string(), xyz, url_query.cloned())
-                } else {
-                    GetTileError(e, self.id.to_string(), xyz)
-                }
-            })?
+        let plan = row
+            .map(|row| row.get::<_, serde_json::Value>(0))
             .unwrap_or_default();
+        let timing = |key: &str| plan.get(0).and_then(|v| v.get(key)).cloned();
+        Ok(Some(json!({
+            "id": self.id,
+            "tile": xyz.to_string(),
+            "planning_time_ms": timing("Planning Time"),
+            "execution_time_ms": timing("Execution Time"),
+            "total_time_ms": elapsed.as_secs_f64() * 1000.0,
+            "warnings": self.info.warnings(),
+            "plan": plan,
+        })))
+    }
 
+    async fn get_tile_with_etag(
+        &self,
+        xyz: TileCoord,
+        url_query: Option<&UrlQuery>,
+        context: Option<&RequestContext>,
+    ) -> MartinResult<TileWithEtag> {
+        let url_args = self.url_args(url_query)?;
+        let mut conn = self.pool.get_tile_conn().await?;
+        conn.set_cancel_on_drop(true);
+        let context = context.filter(|v| !v.is_empty());
+        let tile = if self.info.statement_timeout_ms.is_some() || context.is_some() {
+            // SET LOCAL only lasts until the end of the transaction
+            let tx = conn
+                .transaction()
+                .await
+                .map_err(|e| PostgresError(e, "starting a tile transaction"))?;
+            self.set_tile_settings(&tx, context).await?;
+            let tile = self.query_tile(&tx, xyz, url_query, &url_args).await?;
+            tx.commit()
+                .await
+                .map_err(|e| PostgresError(e, "committing a tile transaction"))?;
+            tile
+        } else {
+            self.query_tile(&*conn, xyz, url_query, &url_args).await?
+        };
+        // On any error above, the connection is discarded on drop instead of going back to the pool,
+        // e.g. it may still be inside an aborted transaction or running a cancelled query
+        conn.set_cancel_on_drop(false);
         Ok(tile)
     }
 }
 
+/// Apply the request context to the current transaction, same as `SET LOCAL` for each setting
+async fn set_request_context(
+    conn: &impl GenericClient,
+    context: &RequestContext,
+) -> MartinResult<()> {
+    let (names, values): (Vec<&str>, Vec<&str>) = context
+        .iter()
+        .map(|(k, v)| (k.as_str(), v.as_str()))
+        .unzip();
+    conn.execute(
+        "SELECT set_config(name, value, true) FROM unnest($1::text[], $2::text[]) AS t(name, value)",
+        &[&names, &values],
+    )
+    .await
+    .map_err(|e| PostgresError(e, "setting the request context"))?;
+    Ok(())
+}
+
 #[derive(Clone, Debug)]
 pub struct PgSqlInfo {
     pub sql_query: String,
     pub use_url_query: bool,
     pub signature: String,
+    /// Statement timeout in milliseconds, set with `SET LOCAL` inside the tile query transaction
+    pub statement_timeout_ms: Option<u64>,
+    /// Format and encoding of the returned tiles, detected from the first non-empty tile if not set
+    pub tile_info: Option<TileInfo>,
+    /// Function arguments after z,x,y, set from the URL query parameters
+    pub url_params: Vec<PgFuncParam>,
+    /// Query returning the features within a bounding box, only available for table sources
+    pub lookup_query: Option<String>,
+    /// Maximum number of features returned by the lookup query
+    pub lookup_limit: usize,
+    /// Information to query the features for the OGC API Features endpoints, only available for table sources
+    pub items: Option<PgItemsInfo>,
+    /// Maps the request attributes to Postgres settings
+    pub request_context: Option<Arc<PgRequestContext>>,
+    /// Whether the geometry column of a table source has a spatial index
+    pub geometry_index: Option<bool>,
 }
 
 impl PgSqlInfo {
@@ -136,13 +532,39 @@ impl PgSqlInfo {
             sql_query: query,
             use_url_query: has_query_params,
             signature,
+            statement_timeout_ms: None,
+            tile_info: None,
+            url_params: Vec::new(),
+            lookup_query: None,
+            lookup_limit: DEFAULT_LOOKUP_LIMIT,
+            items: None,
+            request_context: None,
+            geometry_index: None,
         }
     }
+
+    /// Possible performance issues of the source
+    #[must_use]
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        if self.geometry_index == Some(false) {
+            warnings.push(
+                "The geometry column has no spatial index, so each tile query scans the whole table"
+                    .to_string(),
+            );
+        }
+        warnings
+    }
+
+    #[must_use]
+    pub fn with_url_params(self, url_params: Vec<PgFuncParam>) -> Self {
+        Self { url_params, ..self }
+    }
 }
 
 pub async fn add_source_to_catalog(
-    catalog: &Arc<RwLock<Catalog>>, 
-    input: &AddSourceInput
+    catalog: &Arc<RwLock<Catalog>>,
+    input: &AddSourceInput,
 ) -> Result<(), Error> {
     let mut catalog = catalog.write().await;
 
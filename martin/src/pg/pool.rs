@@ -1,9 +1,17 @@
-use deadpool_postgres::{Manager, ManagerConfig, Object, Pool, RecyclingMethod};
-use log::{info, warn};
+use std::fmt::{Debug, Display, Formatter};
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use deadpool_postgres::tokio_postgres::NoTls;
+use deadpool_postgres::{Manager, ManagerConfig, Object, Pool, PoolError, RecyclingMethod};
+use log::{debug, error, info, warn};
 use postgres::config::SslMode;
 use semver::Version;
+use tokio_postgres_rustls::MakeRustlsConnect;
 
-use crate::pg::config::PgConfig;
+use crate::pg::config::{PgConfig, PgLoadBalancing};
 use crate::pg::tls::{make_connector, parse_conn_str, SslModeOverride};
 use crate::pg::PgError::{
     BadPostgisVersion, PostgisTooOld, PostgresError, PostgresPoolBuildError, PostgresPoolConnError,
@@ -18,54 +26,327 @@ const MINIMUM_POSTGIS_VER: Version = Version::new(3, 0, 0);
 // After this version we can use margin parameter in ST_TileEnvelope
 const RECOMMENDED_POSTGIS_VER: Version = Version::new(3, 1, 0);
 
+// An unhealthy host is retried after this delay, doubling with each consecutive failure
+const HOST_RETRY_INITIAL: Duration = Duration::from_secs(1);
+const HOST_RETRY_MAX: Duration = Duration::from_secs(60);
+
 #[derive(Clone, Debug)]
 pub struct PgPool {
     id: String,
-    pool: Pool,
+    // The primary host followed by the equivalent hosts, in the configured order.
+    // Discovery uses the first available one, so all sources are discovered on the same host.
+    primaries: Vec<Arc<PgHost>>,
+    // Read replicas used to serve tiles. If there are none, tiles are balanced across the primaries.
+    replicas: Vec<Arc<PgHost>>,
+    balancing: PgLoadBalancing,
+    next_host: Arc<AtomicUsize>,
     // When true, we can use margin parameter in ST_TileEnvelope
     margin: bool,
 }
 
-impl PgPool {
-    pub async fn new(config: &PgConfig) -> PgResult<Self> {
-        let (id, mgr) = Self::parse_config(config)?;
+#[derive(Debug)]
+struct PgHost {
+    id: String,
+    pool: Pool,
+    tls: PgTls,
+    outstanding: AtomicUsize,
+    health: Mutex<HostHealth>,
+    // Set once the PostGIS version of this host has been checked
+    verified: AtomicBool,
+    // Set if this host cannot be used, e.g. its PostGIS version is too old
+    rejected: AtomicBool,
+}
 
-        let pool = Pool::builder(mgr)
-            .max_size(config.pool_size.unwrap_or(POOL_SIZE_DEFAULT))
-            .build()
-            .map_err(|e| PostgresPoolBuildError(e, id.clone()))?;
+/// TLS connector used to send cancel requests to the same host as the original connection
+#[derive(Clone)]
+enum PgTls {
+    NoTls,
+    Rustls(MakeRustlsConnect),
+}
 
-        let version: String = get_conn(&pool, id.as_str())
-            .await?
-            .query_one(
-                r"
-SELECT
-    (regexp_matches(
-           PostGIS_Lib_Version(),
-           '^(\d+\.\d+\.\d+)',
-           'g'
-    ))[1] as version;
-                ",
-                &[],
-            )
-            .await
-            .map(|row| row.get("version"))
-            .map_err(|e| PostgresError(e, "querying postgis version"))?;
+impl Debug for PgTls {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoTls => write!(f, "NoTls"),
+            Self::Rustls(_) => write!(f, "Rustls"),
+        }
+    }
+}
+
+/// Tracks consecutive connection failures of a host, and when it may be retried
+#[derive(Debug, Default)]
+struct HostHealth {
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
+impl HostHealth {
+    fn is_available(&self, now: Instant) -> bool {
+        self.retry_at.map_or(true, |v| v <= now)
+    }
+
+    /// Register a failure, and return the delay before the host can be retried
+    fn mark_failed(&mut self, now: Instant) -> Duration {
+        self.failures = self.failures.saturating_add(1);
+        let delay = HOST_RETRY_INITIAL
+            .saturating_mul(1 << (self.failures - 1).min(16))
+            .min(HOST_RETRY_MAX);
+        self.retry_at = Some(now + delay);
+        delay
+    }
+
+    /// Register a success, and return true if the host was previously unhealthy
+    fn mark_healthy(&mut self) -> bool {
+        let was_unhealthy = self.failures > 0;
+        self.failures = 0;
+        self.retry_at = None;
+        was_unhealthy
+    }
+}
+
+impl PgHost {
+    fn new(id: String, mgr: Manager, tls: PgTls, config: &PgConfig) -> PgResult<Self> {
+        Ok(Self {
+            pool: build_pool(mgr, config, &id)?,
+            tls,
+            id,
+            outstanding: AtomicUsize::new(0),
+            health: Mutex::new(HostHealth::default()),
+            verified: AtomicBool::new(false),
+            rejected: AtomicBool::new(false),
+        })
+    }
+
+    fn is_available(&self, now: Instant) -> bool {
+        !self.rejected.load(Ordering::Relaxed)
+            && self.health.lock().expect("host health").is_available(now)
+    }
 
-        let version: Version = version.parse().map_err(|e| BadPostgisVersion(e, version))?;
-        if version < MINIMUM_POSTGIS_VER {
-            return Err(PostgisTooOld(version, MINIMUM_POSTGIS_VER));
+    fn is_verified(&self) -> bool {
+        self.verified.load(Ordering::Relaxed)
+    }
+
+    fn mark_failed(&self, err: &dyn Display) {
+        let delay = self
+            .health
+            .lock()
+            .expect("host health")
+            .mark_failed(Instant::now());
+        warn!(
+            "PostgreSQL host {} is unavailable, will retry in {}s: {err}",
+            self.id,
+            delay.as_secs()
+        );
+    }
+
+    fn mark_healthy(&self) {
+        if self.health.lock().expect("host health").mark_healthy() {
+            info!("PostgreSQL host {} is available again", self.id);
+        }
+    }
+
+    /// Check the `PostGIS` version of this host on startup, and return true if it supports the tile margin
+    async fn verify_on_startup(&self) -> PgResult<bool> {
+        let conn = get_conn(&self.pool, &self.id).await?;
+        let margin = check_postgis_version(&get_postgis_version(&conn).await?, &self.id)?;
+        self.verified.store(true, Ordering::Relaxed);
+        Ok(margin)
+    }
+
+    /// Get a connection, checking the `PostGIS` version first if this host was unreachable on startup.
+    /// Returns `None` if the host cannot be used because it does not support the features of the other hosts.
+    async fn connect(&self, margin: bool) -> PgResult<Option<Object>> {
+        let conn = match get_conn(&self.pool, &self.id).await {
+            Ok(conn) => conn,
+            Err(e) => {
+                self.mark_failed(&e);
+                return Err(e);
+            }
+        };
+        if !self.is_verified() {
+            let supported = match get_postgis_version(&conn).await {
+                Ok(version) => check_postgis_version(&version, &self.id).map(|v| v || !margin),
+                Err(e) if e.is_transient() => {
+                    self.mark_failed(&e);
+                    return Err(e);
+                }
+                Err(e) => Err(e),
+            };
+            match supported {
+                Ok(true) => self.verified.store(true, Ordering::Relaxed),
+                Ok(false) => {
+                    return Ok(self.reject(
+                        &"tile margin is supported by the other hosts, but not by this one",
+                    ));
+                }
+                Err(e) => return Ok(self.reject(&e)),
+            }
         }
-        if version < RECOMMENDED_POSTGIS_VER {
-            warn!("PostGIS {version} is before the recommended {RECOMMENDED_POSTGIS_VER}. Margin parameter in ST_TileEnvelope is not supported, so tiles may be cut off at the edges.");
+        self.mark_healthy();
+        Ok(Some(conn))
+    }
+
+    fn reject(&self, reason: &dyn Display) -> Option<Object> {
+        error!("PostgreSQL host {} will not be used: {reason}", self.id);
+        self.rejected.store(true, Ordering::Relaxed);
+        None
+    }
+}
+
+/// A pooled connection that keeps track of the number of outstanding requests per host
+pub struct PgConnection {
+    conn: Option<Object>,
+    cancel_on_drop: bool,
+    guard: OutstandingGuard,
+}
+
+impl PgConnection {
+    fn new(conn: Object, host: Arc<PgHost>) -> Self {
+        Self {
+            conn: Some(conn),
+            cancel_on_drop: false,
+            guard: OutstandingGuard::new(host),
         }
+    }
+
+    /// If set, dropping this connection cancels the running query, e.g. when the HTTP client has disconnected
+    /// and the request future was dropped. The connection is removed from the pool to prevent its reuse.
+    pub fn set_cancel_on_drop(&mut self, value: bool) {
+        self.cancel_on_drop = value;
+    }
+}
+
+impl Deref for PgConnection {
+    type Target = Object;
+
+    fn deref(&self) -> &Self::Target {
+        self.conn
+            .as_ref()
+            .expect("connection is only taken on drop")
+    }
+}
 
-        let margin = version >= RECOMMENDED_POSTGIS_VER;
-        Ok(Self { id, pool, margin })
+impl DerefMut for PgConnection {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.conn
+            .as_mut()
+            .expect("connection is only taken on drop")
     }
+}
 
-    fn parse_config(config: &PgConfig) -> PgResult<(String, Manager)> {
+impl Drop for PgConnection {
+    fn drop(&mut self) {
+        if !self.cancel_on_drop {
+            return;
+        }
+        let host = &self.guard.0;
+        if self.conn.as_ref().is_some_and(|c| c.is_closed()) {
+            // The query failed because the connection to the host was lost
+            host.mark_failed(&"connection closed while querying a tile");
+            return;
+        }
+        let (Some(conn), Ok(handle)) = (self.conn.take(), tokio::runtime::Handle::try_current())
+        else {
+            return;
+        };
+        // Keep the client alive until the query is cancelled, and never return it to the pool
+        let client = Object::take(conn);
+        let tls = host.tls.clone();
+        handle.spawn(async move {
+            debug!("Cancelling abandoned PostgreSQL query");
+            let token = client.cancel_token();
+            let res = match tls {
+                PgTls::NoTls => token.cancel_query(NoTls).await,
+                PgTls::Rustls(tls) => token.cancel_query(tls).await,
+            };
+            if let Err(e) = res {
+                warn!("Unable to cancel PostgreSQL query: {e}");
+            }
+        });
+    }
+}
+
+struct OutstandingGuard(Arc<PgHost>);
+
+impl OutstandingGuard {
+    fn new(host: Arc<PgHost>) -> Self {
+        host.outstanding.fetch_add(1, Ordering::Relaxed);
+        Self(host)
+    }
+}
+
+impl Drop for OutstandingGuard {
+    fn drop(&mut self) {
+        self.0.outstanding.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl PgPool {
+    pub async fn new(config: &PgConfig) -> PgResult<Self> {
         let conn_str = config.connection_string.as_ref().unwrap().as_str();
+        let (id, mgr, tls) = Self::parse_config(conn_str, config)?;
+        let mut primaries = vec![Arc::new(PgHost::new(id.clone(), mgr, tls, config)?)];
+        for (idx, conn_str) in config.equivalent_hosts.iter().enumerate() {
+            let (_, mgr, tls) = Self::parse_config(conn_str, config)?;
+            let host_id = format!("{id} host #{}", idx + 2);
+            primaries.push(Arc::new(PgHost::new(host_id, mgr, tls, config)?));
+        }
+        let mut replicas = Vec::new();
+        for (idx, conn_str) in config.read_replicas.iter().enumerate() {
+            let (_, mgr, tls) = Self::parse_config(conn_str, config)?;
+            let replica_id = format!("{id} replica #{}", idx + 1);
+            replicas.push(Arc::new(PgHost::new(replica_id, mgr, tls, config)?));
+        }
+
+        // An unreachable host does not prevent startup, and is checked once it becomes available.
+        // At least one of the primaries must be reachable to discover the sources.
+        let mut margin = true;
+        let mut primary_err = None;
+        for host in &primaries {
+            match host.verify_on_startup().await {
+                Ok(v) => margin &= v,
+                Err(e @ PostgresPoolConnError(..)) => {
+                    host.mark_failed(&e);
+                    primary_err.get_or_insert(e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        if let Some(e) = primary_err.filter(|_| primaries.iter().all(|h| !h.is_verified())) {
+            return Err(e);
+        }
+        for host in &replicas {
+            match host.verify_on_startup().await {
+                Ok(v) => margin &= v,
+                Err(PostgresPoolConnError(e, _)) => host.mark_failed(&e),
+                Err(e) => return Err(e),
+            }
+        }
+
+        let balancing = config.load_balancing.unwrap_or_default();
+        if !replicas.is_empty() {
+            info!(
+                "Serving tiles from {} read replica(s) of {id} using {balancing} load balancing",
+                replicas.len(),
+            );
+        } else if primaries.len() > 1 {
+            info!(
+                "Serving tiles from {} equivalent hosts of {id} using {balancing} load balancing",
+                primaries.len(),
+            );
+        }
+
+        Ok(Self {
+            id,
+            primaries,
+            replicas,
+            balancing,
+            next_host: Arc::new(AtomicUsize::new(0)),
+            margin,
+        })
+    }
+
+    fn parse_config(conn_str: &str, config: &PgConfig) -> PgResult<(String, Manager, PgTls)> {
         let (pg_cfg, ssl_mode) = parse_conn_str(conn_str)?;
 
         let id = pg_cfg.get_dbname().map_or_else(
@@ -77,10 +358,10 @@ SELECT
             recycling_method: RecyclingMethod::Fast,
         };
 
-        let mgr = if pg_cfg.get_ssl_mode() == SslMode::Disable {
+        let (mgr, tls) = if pg_cfg.get_ssl_mode() == SslMode::Disable {
             info!("Connecting without SSL support: {pg_cfg:?}");
-            let connector = deadpool_postgres::tokio_postgres::NoTls {};
-            Manager::from_config(pg_cfg, connector, mgr_config)
+            let mgr = Manager::from_config(pg_cfg, NoTls, mgr_config);
+            (mgr, PgTls::NoTls)
         } else {
             match ssl_mode {
                 SslModeOverride::Unmodified(_) => {
@@ -94,14 +375,77 @@ SELECT
                 }
             };
             let connector = make_connector(&config.ssl_certificates, ssl_mode)?;
-            Manager::from_config(pg_cfg, connector, mgr_config)
+            let mgr = Manager::from_config(pg_cfg, connector.clone(), mgr_config);
+            (mgr, PgTls::Rustls(connector))
         };
 
-        Ok((id, mgr))
+        Ok((id, mgr, tls))
     }
 
+    /// Get a connection to the primary database, or to the first available equivalent host if it is down.
+    /// Used for source discovery to ensure consistent results.
     pub async fn get(&self) -> PgResult<Object> {
-        get_conn(&self.pool, self.id.as_str()).await
+        Ok(self.connect_any(Vec::new()).await?.1)
+    }
+
+    /// Get a connection for serving a tile, balancing the load between the read replicas,
+    /// or between the equivalent hosts if there are no replicas.
+    /// Unavailable hosts are skipped until their backoff expires.
+    /// If no replica is available, the primary database is used instead.
+    pub async fn get_tile_conn(&self) -> PgResult<PgConnection> {
+        let balanced = if self.replicas.is_empty() {
+            &self.primaries
+        } else {
+            &self.replicas
+        };
+        let hosts = self.balanced_order(balanced, Instant::now());
+        let (host, conn) = self.connect_any(hosts).await?;
+        Ok(PgConnection::new(conn, host))
+    }
+
+    /// Try the given hosts in order, followed by the available primaries.
+    /// If none of them can be used, all primaries are tried regardless of their backoff.
+    async fn connect_any(&self, hosts: Vec<Arc<PgHost>>) -> PgResult<(Arc<PgHost>, Object)> {
+        let now = Instant::now();
+        let available = self.primaries.iter().filter(|h| h.is_available(now));
+        let fallback = self
+            .primaries
+            .iter()
+            .filter(|h| !h.rejected.load(Ordering::Relaxed));
+
+        let mut last_err = None;
+        let mut tried: Vec<&Arc<PgHost>> = Vec::new();
+        for host in hosts.iter().chain(available).chain(fallback) {
+            if tried.iter().any(|v| Arc::ptr_eq(v, host)) {
+                continue;
+            }
+            tried.push(host);
+            match host.connect(self.margin).await {
+                Ok(Some(conn)) => return Ok((host.clone(), conn)),
+                Ok(None) => {}
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| PostgresPoolConnError(PoolError::Closed, self.id.clone())))
+    }
+
+    /// List available hosts in the order they should be tried
+    fn balanced_order(&self, hosts: &[Arc<PgHost>], now: Instant) -> Vec<Arc<PgHost>> {
+        if hosts.is_empty() {
+            return Vec::new();
+        }
+        let start = self.next_host.fetch_add(1, Ordering::Relaxed) % hosts.len();
+        let mut result: Vec<_> = hosts[start..]
+            .iter()
+            .chain(&hosts[..start])
+            .filter(|h| h.is_available(now))
+            .cloned()
+            .collect();
+        if self.balancing == PgLoadBalancing::LeastOutstanding {
+            // stable sort keeps the round-robin order for hosts with the same load
+            result.sort_by_key(|h| h.outstanding.load(Ordering::Relaxed));
+        }
+        result
     }
 
     #[must_use]
@@ -115,8 +459,74 @@ SELECT
     }
 }
 
+fn build_pool(mgr: Manager, config: &PgConfig, id: &str) -> PgResult<Pool> {
+    Pool::builder(mgr)
+        .max_size(config.pool_size.unwrap_or(POOL_SIZE_DEFAULT))
+        .build()
+        .map_err(|e| PostgresPoolBuildError(e, id.to_string()))
+}
+
+async fn get_postgis_version(conn: &Object) -> PgResult<Version> {
+    let version: String = conn
+        .query_one(
+            r"
+SELECT
+    (regexp_matches(
+           PostGIS_Lib_Version(),
+           '^(\d+\.\d+\.\d+)',
+           'g'
+    ))[1] as version;
+                ",
+            &[],
+        )
+        .await
+        .map(|row| row.get("version"))
+        .map_err(|e| PostgresError(e, "querying postgis version"))?;
+
+    version.parse().map_err(|e| BadPostgisVersion(e, version))
+}
+
+// Ensure PostGIS version is supported, and return true if it supports the tile margin parameter
+fn check_postgis_version(version: &Version, id: &str) -> PgResult<bool> {
+    if *version < MINIMUM_POSTGIS_VER {
+        return Err(PostgisTooOld(version.clone(), MINIMUM_POSTGIS_VER));
+    }
+    if *version < RECOMMENDED_POSTGIS_VER {
+        warn!("PostGIS {version} on {id} is before the recommended {RECOMMENDED_POSTGIS_VER}. Margin parameter in ST_TileEnvelope is not supported, so tiles may be cut off at the edges.");
+        return Ok(false);
+    }
+    Ok(true)
+}
+
 async fn get_conn(pool: &Pool, id: &str) -> PgResult<Object> {
     pool.get()
         .await
         .map_err(|e| PostgresPoolConnError(e, id.to_string()))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn host_health_backoff() {
+        let now = Instant::now();
+        let mut health = HostHealth::default();
+        assert!(health.is_available(now));
+
+        assert_eq!(health.mark_failed(now), Duration::from_secs(1));
+        assert!(!health.is_available(now));
+        assert!(health.is_available(now + Duration::from_secs(1)));
+
+        assert_eq!(health.mark_failed(now), Duration::from_secs(2));
+        assert_eq!(health.mark_failed(now), Duration::from_secs(4));
+        for _ in 0..20 {
+            health.mark_failed(now);
+        }
+        assert_eq!(health.mark_failed(now), HOST_RETRY_MAX);
+
+        assert!(health.mark_healthy());
+        assert!(health.is_available(now));
+        assert!(!health.mark_healthy());
+    }
+}
@@ -1,12 +1,14 @@
 use std::fmt::Write as _;
 use std::iter::zip;
 
+use itertools::Itertools as _;
 use log::{debug, warn};
 use postgres_protocol::escape::escape_identifier;
 use serde_json::Value;
 
 use crate::pg::builder::SqlFuncInfoMapMap;
 use crate::pg::config_function::FunctionInfo;
+use crate::pg::func_params::PgFuncParam;
 use crate::pg::pg_source::PgSqlInfo;
 use crate::pg::pool::PgPool;
 use crate::pg::PgError::PostgresError;
@@ -33,6 +35,7 @@ pub async fn query_available_function(pool: &PgPool) -> PgResult<SqlFuncInfoMapM
             let output_record_names = jsonb_to_vec(row.get("output_record_names"));
             let input_types = jsonb_to_vec(row.get("input_types")).expect("Can't get input types");
             let input_names = jsonb_to_vec(row.get("input_names")).expect("Can't get input names");
+            let input_defaults = jsonb_to_opt_vec(row.get("input_defaults"));
             let tilejson = if let Some(text) = row.get("description") {
                 match serde_json::from_str::<Value>(text) {
                     Ok(v) => Some(v),
@@ -46,7 +49,7 @@ pub async fn query_available_function(pool: &PgPool) -> PgResult<SqlFuncInfoMapM
                 None
             };
 
-            assert!(input_types.len() >= 3 && input_types.len() <= 4);
+            assert!(input_types.len() >= 3);
             assert_eq!(input_types.len(), input_names.len());
             match (&output_record_names, &output_record_types) {
                 (Some(n), Some(t)) if n.len() == 1 && n.len() == t.len() => {
@@ -60,6 +63,17 @@ pub async fn query_available_function(pool: &PgPool) -> PgResult<SqlFuncInfoMapM
             }
             assert!(output_type == "bytea" || output_type == "record");
 
+            // Arguments after z,x,y are set from the URL query parameters
+            let mut url_params = Vec::new();
+            for (idx, (name, typ)) in zip(&input_names, &input_types).enumerate().skip(3) {
+                let default = input_defaults.get(idx).cloned().flatten();
+                let Some(param) = PgFuncParam::new(name.clone(), typ.clone(), default) else {
+                    warn!("Skipping function {schema}.{function} because its argument {name} of type {typ} cannot be set from a URL query parameter");
+                    return;
+                };
+                url_params.push(param);
+            }
+
             // Query preparation: the schema and function can't be part of a prepared query, so they
             // need to be escaped by hand.
             // However, schema and function comes from database introspection, so they should be safe.
@@ -68,7 +82,7 @@ pub async fn query_available_function(pool: &PgPool) -> PgResult<SqlFuncInfoMapM
             query.push('.');
             query.push_str(&escape_identifier(&function));
             query.push('(');
-            for (idx, (_name, typ)) in zip(input_names.iter(), input_types.iter()).enumerate() {
+            for (idx, typ) in input_types.iter().take(3).enumerate() {
                 if idx > 0 {
                     query.push_str(", ");
                 }
@@ -76,15 +90,19 @@ pub async fn query_available_function(pool: &PgPool) -> PgResult<SqlFuncInfoMapM
                 // where the name must be passed through escape_identifier
                 write!(query, "${index}::{typ}", index = idx + 1).unwrap();
             }
+            for (idx, param) in url_params.iter().enumerate() {
+                write!(query, ", {}", param.to_sql_arg(idx + 4)).unwrap();
+            }
             query.push(')');
 
             // TODO: Rewrite as a if-let chain:  if Some(names) = output_record_names && output_type == "record" { ... }
             let ret_inf = if let (Some(names), "record") = (output_record_names, output_type.as_str()) {
-                 // SELECT mvt FROM "public"."function_zxy_row2"(
+                 // SELECT mvt, key FROM "public"."function_zxy_row_key"(
                  //    "z" => $1::integer, "x" => $2::integer, "y" => $3::integer
                  // );
+                 // The optional second column is used as the tile's ETag
                  query.insert_str(0, " FROM ");
-                 query.insert_str(0, &escape_identifier(names[0].as_str()));
+                 query.insert_str(0, &names.iter().map(|v| escape_identifier(v)).join(", "));
                  query.insert_str(0, "SELECT ");
                  format!("[{}]", names.join(", "))
              } else {
@@ -101,12 +119,13 @@ pub async fn query_available_function(pool: &PgPool) -> PgResult<SqlFuncInfoMapM
                     (
                         PgSqlInfo::new(
                             query,
-                            input_types.len() == 4,
+                            !url_params.is_empty(),
                             format!(
                                 "{schema}.{function}({}) -> {ret_inf}",
                                 input_types.join(", ")
                             ),
-                        ),
+                        )
+                        .with_url_params(url_params),
                         FunctionInfo::new(schema, function, tilejson)
                     ),
                 )
@@ -118,6 +137,15 @@ pub async fn query_available_function(pool: &PgPool) -> PgResult<SqlFuncInfoMapM
     Ok(res)
 }
 
+fn jsonb_to_opt_vec(jsonb: Option<Value>) -> Vec<Option<String>> {
+    jsonb
+        .and_then(|json| json.as_array().cloned())
+        .unwrap_or_default()
+        .iter()
+        .map(|v| v.as_str().map(ToString::to_string))
+        .collect()
+}
+
 fn jsonb_to_vec(jsonb: Option<Value>) -> Option<Vec<String>> {
     jsonb.map(|json| {
         json.as_array()
@@ -0,0 +1,348 @@
+use std::collections::BTreeMap;
+
+use deadpool_postgres::tokio_postgres::types::ToSql;
+use postgres_protocol::escape::escape_identifier;
+
+use crate::pg::config_table::TableInfo;
+use crate::pg::query_tables::GeometrySql;
+use crate::pg::PgError::InvalidItemsQuery;
+use crate::pg::PgResult;
+use crate::source::ItemsQuery;
+
+pub type ItemsParams = Vec<Box<dyn ToSql + Sync + Send>>;
+
+/// Parts of a table source used to generate OGC API Features `items` queries
+#[derive(Clone, Debug, PartialEq)]
+pub struct PgItemsInfo {
+    /// Escaped `schema.table`
+    table: String,
+    geometry: GeometrySql,
+    srid: i32,
+    /// Escaped and aliased id and property columns, each prefixed with a comma
+    columns: String,
+    /// Name of the `id_column` in the output
+    id_column: Option<String>,
+    /// Escaped column the features are sorted by, so that the pages do not overlap
+    order_column: Option<String>,
+    /// Escaped timestamp column used by the `datetime` filter
+    temporal_column: Option<String>,
+    /// Escaped timestamp column with the end of the feature's time interval
+    temporal_end_column: Option<String>,
+    /// Property names mapped to the escaped table columns, used by the property filters
+    filters: BTreeMap<String, String>,
+}
+
+impl PgItemsInfo {
+    /// The `columns` are the same id and property columns as used by the tile query
+    #[must_use]
+    pub fn new(info: &TableInfo, columns: String) -> Self {
+        let column =
+            |name: &String| escape_identifier(info.prop_mapping.get(name).unwrap_or(name).as_str());
+        let filters = info
+            .properties
+            .iter()
+            .flat_map(BTreeMap::keys)
+            .chain(&info.id_column)
+            .map(|name| (name.clone(), column(name)))
+            .collect();
+        let order_column = match &info.id_column {
+            Some(id_column) => Some(column(id_column)),
+            // Views have no physical row location to sort by
+            None if info.is_view == Some(true) => None,
+            None => Some("ctid".to_string()),
+        };
+        Self {
+            table: format!(
+                "{}.{}",
+                escape_identifier(&info.schema),
+                escape_identifier(&info.table)
+            ),
+            geometry: GeometrySql::new(info),
+            srid: info.srid,
+            columns,
+            id_column: info.id_column.clone(),
+            order_column,
+            temporal_column: info.temporal_column.as_ref().map(column),
+            temporal_end_column: info.temporal_end_column.as_ref().map(column),
+            filters,
+        }
+    }
+
+    /// SRIDs the features can be returned in
+    #[must_use]
+    pub fn srids(&self) -> Vec<i32> {
+        let mut srids = vec![4326, 3857];
+        if !srids.contains(&self.srid) {
+            srids.push(self.srid);
+        }
+        srids
+    }
+
+    /// Check if the features are always returned in the same order, i.e. if paging is stable
+    #[must_use]
+    pub fn is_ordered(&self) -> bool {
+        self.order_column.is_some()
+    }
+
+    /// Generate a query returning each matching feature as a `GeoJSON` text, and its parameters.
+    /// The features are sorted by the `id_column`, or by the row location if a table has none.
+    pub fn to_query(&self, id: &str, query: &ItemsQuery) -> PgResult<(String, ItemsParams)> {
+        let mut params: ItemsParams = Vec::new();
+        let mut conditions = Vec::new();
+
+        if let Some(bbox) = query.bbox {
+            let [left, bottom, right, top] = bbox;
+            params.extend([left, bottom, right, top].map(|v| Box::new(v) as _));
+            conditions.push(self.geometry.intersects_exactly(
+                "ST_MakeEnvelope($1::float8, $2::float8, $3::float8, $4::float8, 4326)",
+                4326,
+            ));
+        }
+
+        if let Some((start, end)) = &query.datetime {
+            let Some(column) = &self.temporal_column else {
+                return Err(InvalidItemsQuery(
+                    id.to_string(),
+                    "datetime filter requires a temporal_column".to_string(),
+                ));
+            };
+            // Features match if their time interval intersects the requested one
+            if let Some(start) = start {
+                params.push(Box::new(start.clone()));
+                let idx = params.len();
+                conditions.push(match &self.temporal_end_column {
+                    Some(end_column) => format!(
+                        "({end_column} IS NULL OR {end_column} >= ${idx}::text::timestamptz)"
+                    ),
+                    None => format!("{column} >= ${idx}::text::timestamptz"),
+                });
+            }
+            if let Some(end) = end {
+                params.push(Box::new(end.clone()));
+                conditions.push(format!("{column} <= ${}::text::timestamptz", params.len()));
+            }
+        }
+
+        for (name, value) in &query.properties {
+            let Some(column) = self.filters.get(name) else {
+                return Err(InvalidItemsQuery(
+                    id.to_string(),
+                    format!("unknown property {name}"),
+                ));
+            };
+            params.push(Box::new(value.clone()));
+            conditions.push(format!("{column}::text = ${}::text", params.len()));
+        }
+
+        let srid = query.srid;
+        if !self.srids().contains(&srid) {
+            return Err(InvalidItemsQuery(
+                id.to_string(),
+                format!("unsupported SRID {srid}"),
+            ));
+        }
+
+        let feature_id = self.id_column.as_ref().map_or(String::new(), |v| {
+            format!(", 'id', feature.{}", escape_identifier(v))
+        });
+        let conditions = if conditions.is_empty() {
+            "true".to_string()
+        } else {
+            conditions.join(" AND ")
+        };
+        let order = self
+            .order_column
+            .as_ref()
+            .map_or(String::new(), |v| format!("ORDER BY {v}"));
+        let table = &self.table;
+        let geometry_column = self.geometry.geometry();
+        let feature_geometry = self.geometry.transform("feature.geom", srid);
+        let columns = &self.columns;
+        let limit = query.limit;
+        let offset = query.offset;
+        let sql = format!(
+            r"
+SELECT jsonb_build_object(
+  'type', 'Feature'{feature_id},
+  'geometry', ST_AsGeoJSON({feature_geometry})::jsonb,
+  'properties', to_jsonb(feature) - 'geom'
+)::text
+FROM (
+  SELECT
+    {geometry_column} AS geom
+    {columns}
+  FROM
+    {table}
+  WHERE
+    {conditions}
+  {order}
+  LIMIT {limit} OFFSET {offset}
+) AS feature
+"
+        )
+        .trim()
+        .to_string();
+
+        Ok((sql, params))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::some;
+
+    fn items_info() -> PgItemsInfo {
+        let info = TableInfo {
+            schema: "public".to_string(),
+            table: "incidents".to_string(),
+            geometry_column: "geom".to_string(),
+            srid: 4326,
+            id_column: Some("gid".to_string()),
+            temporal_column: Some("reported".to_string()),
+            properties: Some(BTreeMap::from([("kind".to_string(), "text".to_string())])),
+            prop_mapping: [("kind".to_string(), "Kind".to_string())].into(),
+            ..Default::default()
+        };
+        PgItemsInfo::new(&info, r#", "gid", "Kind" AS "kind""#.to_string())
+    }
+
+    #[test]
+    fn items_query() {
+        let query = ItemsQuery {
+            bbox: Some([-10.0, -5.0, 10.0, 5.0]),
+            limit: 10,
+            offset: 20,
+            datetime: Some((Some("2024-01-01T00:00:00Z".to_string()), None)),
+            properties: vec![("kind".to_string(), "fire".to_string())],
+            srid: 3857,
+        };
+        let (sql, params) = items_info().to_query("incidents", &query).unwrap();
+        assert_eq!(params.len(), 6);
+        assert_eq!(
+            sql,
+            r#"SELECT jsonb_build_object(
+  'type', 'Feature', 'id', feature."gid",
+  'geometry', ST_AsGeoJSON(ST_Transform(ST_CurveToLine(feature.geom), 3857))::jsonb,
+  'properties', to_jsonb(feature) - 'geom'
+)::text
+FROM (
+  SELECT
+    "geom" AS geom
+    , "gid", "Kind" AS "kind"
+  FROM
+    "public"."incidents"
+  WHERE
+    "geom" && ST_Transform(ST_MakeEnvelope($1::float8, $2::float8, $3::float8, $4::float8, 4326), 4326) AND ST_Intersects(ST_Transform(ST_CurveToLine("geom"), 4326), ST_Envelope(ST_MakeEnvelope($1::float8, $2::float8, $3::float8, $4::float8, 4326))) AND "reported" >= $5::text::timestamptz AND "Kind"::text = $6::text
+  ORDER BY "gid"
+  LIMIT 10 OFFSET 20
+) AS feature"#
+        );
+    }
+
+    #[test]
+    fn items_query_interval() {
+        let info = PgItemsInfo {
+            temporal_end_column: Some(r#""resolved""#.to_string()),
+            ..items_info()
+        };
+        let query = ItemsQuery {
+            datetime: Some((
+                Some("2024-01-01".to_string()),
+                Some("2024-02-01".to_string()),
+            )),
+            srid: 4326,
+            ..Default::default()
+        };
+        let (sql, params) = info.to_query("incidents", &query).unwrap();
+        assert_eq!(params.len(), 2);
+        assert!(sql.contains(r#"("resolved" IS NULL OR "resolved" >= $1::text::timestamptz) AND "reported" <= $2::text::timestamptz"#));
+    }
+
+    #[test]
+    fn items_query_geography() {
+        let info = TableInfo {
+            schema: "public".to_string(),
+            table: "airports".to_string(),
+            geometry_column: "location".to_string(),
+            srid: 4326,
+            is_geography: Some(true),
+            geometry_type: some("POINT"),
+            ..Default::default()
+        };
+        let query = ItemsQuery {
+            bbox: Some([-10.0, -5.0, 10.0, 5.0]),
+            limit: 10,
+            srid: 3857,
+            ..Default::default()
+        };
+        let (sql, _) = PgItemsInfo::new(&info, String::new())
+            .to_query("airports", &query)
+            .unwrap();
+        assert!(sql.contains("ST_AsGeoJSON(ST_Transform(feature.geom, 3857))"));
+        assert!(sql.contains(r#""location"::geometry AS geom"#));
+        assert!(sql.contains("ORDER BY ctid"));
+        assert!(sql.contains(r#""location" && ST_Segmentize(ST_Transform(ST_MakeEnvelope($1::float8, $2::float8, $3::float8, $4::float8, 4326), 4326), 1)::geography AND ST_Intersects(ST_Transform("location"::geometry, 4326), ST_Envelope(ST_MakeEnvelope($1::float8, $2::float8, $3::float8, $4::float8, 4326)))"#));
+    }
+
+    #[test]
+    fn items_query_view_order() {
+        let info = TableInfo {
+            schema: "public".to_string(),
+            table: "recent_incidents".to_string(),
+            geometry_column: "geom".to_string(),
+            srid: 4326,
+            is_view: Some(true),
+            ..Default::default()
+        };
+        let info = PgItemsInfo::new(&info, String::new());
+        assert!(!info.is_ordered());
+        let query = ItemsQuery {
+            srid: 4326,
+            ..Default::default()
+        };
+        let (sql, _) = info.to_query("recent_incidents", &query).unwrap();
+        assert!(!sql.contains("ORDER BY"));
+
+        let info = PgItemsInfo {
+            id_column: some("gid"),
+            order_column: some(r#""gid""#),
+            ..info
+        };
+        assert!(info.is_ordered());
+    }
+
+    #[test]
+    fn items_query_errors() {
+        let info = items_info();
+        let query = |query: ItemsQuery| info.to_query("incidents", &query).unwrap_err();
+        let srid = 4326;
+
+        let err = query(ItemsQuery {
+            properties: vec![("missing".to_string(), "value".to_string())],
+            srid,
+            ..Default::default()
+        });
+        assert!(matches!(err, InvalidItemsQuery(..)));
+
+        let err = query(ItemsQuery {
+            srid: 2193,
+            ..Default::default()
+        });
+        assert!(matches!(err, InvalidItemsQuery(..)));
+
+        let info = PgItemsInfo {
+            temporal_column: None,
+            ..info.clone()
+        };
+        let query = ItemsQuery {
+            datetime: Some((None, Some("2024-01-01".to_string()))),
+            srid,
+            ..Default::default()
+        };
+        assert!(matches!(
+            info.to_query("incidents", &query),
+            Err(InvalidItemsQuery(..))
+        ));
+    }
+}
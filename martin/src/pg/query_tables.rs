@@ -1,7 +1,10 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
 
 use futures::pin_mut;
+use itertools::Itertools as _;
 use log::{debug, warn};
+use martin_tile_utils::Format;
 use postgis::ewkb;
 use postgres_protocol::escape::{escape_identifier, escape_literal};
 use serde_json::Value;
@@ -11,16 +14,21 @@ use tokio::time::timeout;
 use crate::args::{BoundsCalcType, DEFAULT_BOUNDS_TIMEOUT};
 use crate::pg::builder::SqlTableInfoMapMapMap;
 use crate::pg::config::PgInfo;
-use crate::pg::config_table::TableInfo;
+use crate::pg::config_table::{ClusterMethod, RasterFormat, RasterInfo, TableInfo};
 use crate::pg::pg_source::PgSqlInfo;
 use crate::pg::pool::PgPool;
+use crate::pg::query_items::PgItemsInfo;
 use crate::pg::utils::{json_to_hashmap, polygon_to_bbox};
-use crate::pg::PgError::PostgresError;
-use crate::pg::PgResult;
+use crate::pg::PgError::{InvalidClusterConfig, InvalidRasterConfig, PostgresError};
+use crate::pg::{PgFuncParam, PgResult};
 
 static DEFAULT_EXTENT: u32 = 4096;
 static DEFAULT_BUFFER: u32 = 64;
 static DEFAULT_CLIP_GEOM: bool = true;
+static DEFAULT_CLUSTER_RADIUS: u32 = 256;
+static EARTH_CIRCUMFERENCE: f64 = 40_075_016.685_578_49;
+static DEFAULT_RASTER_TILE_SIZE: u32 = 256;
+static MAX_RASTER_TILE_SIZE: u32 = 4096;
 
 use crate::srv::server::{Catalog, AddSourceInput, SourceMetadata}; // Ensure these paths are correct
 use actix_web::Error; // Or define your own Error type if needed
@@ -28,18 +36,37 @@ use actix_web::Error; // Or define your own Error type if needed
 
 
 
-/// Examine a database to get a list of all tables that have geometry columns.
+/// Examine a database to get a list of all tables that have geometry or raster columns.
 pub async fn query_available_tables(pool: &PgPool) -> PgResult<SqlTableInfoMapMapMap> {
     let conn = pool.get().await?;
-    let rows = conn
+    let mut rows = conn
         .query(include_str!("scripts/query_available_tables.sql"), &[])
         .await
         .map_err(|e| PostgresError(e, "querying available tables"))?;
 
+    // The raster_columns view only exists if the postgis_raster extension is installed
+    let has_rasters: bool = conn
+        .query_one("SELECT to_regclass('raster_columns') IS NOT NULL", &[])
+        .await
+        .map_err(|e| PostgresError(e, "checking for raster support"))?
+        .get(0);
+    if has_rasters {
+        rows.extend(
+            conn.query(include_str!("scripts/query_available_rasters.sql"), &[])
+                .await
+                .map_err(|e| PostgresError(e, "querying available rasters"))?,
+        );
+    }
+
     let mut res = SqlTableInfoMapMapMap::new();
     for row in &rows {
         let schema: String = row.get("schema");
         let table: String = row.get("name");
+        let Some(srid) = row.get("srid") else {
+            let column: String = row.get("geom");
+            warn!("Skipping raster column {schema}.{table}.{column} because its SRID is unknown. Add the raster constraints with AddRasterConstraints to publish it");
+            continue;
+        };
         let tilejson = if let Some(text) = row.get("description") {
             match serde_json::from_str::<Value>(text) {
                 Ok(v) => Some(v),
@@ -59,9 +86,12 @@ pub async fn query_available_tables(pool: &PgPool) -> PgResult<SqlTableInfoMapMa
             geometry_column: row.get("geom"),
             geometry_index: row.get("geom_idx"),
             is_view: row.get("is_view"),
-            srid: row.get("srid"), // casting i32 to u32?
+            is_geography: row.try_get("is_geography").ok(),
+            srid, // casting i32 to u32?
             geometry_type: row.get("type"),
             properties: Some(json_to_hashmap(&row.get("properties"))),
+            num_bands: row.try_get("num_bands").ok().flatten(),
+            pixel_types: row.try_get("pixel_types").ok().flatten(),
             tilejson,
             ..Default::default()
         };
@@ -106,6 +136,7 @@ fn escape_with_alias(mapping: &HashMap<String, String>, field: &str) -> String {
 
 /// Generate a query to fetch tiles from a table.
 /// The function is async because it may need to query the database for the table bounds (could be very slow).
+#[allow(clippy::too_many_lines)]
 pub async fn table_to_query(
     id: String,
     mut info: TableInfo,
@@ -117,13 +148,20 @@ pub async fn table_to_query(
     let table = escape_identifier(&info.table);
     let geometry_column = escape_identifier(&info.geometry_column);
     let srid = info.srid;
+    // Raster bounds are computed from the extents of the individual rasters
+    let geometry = GeometrySql::new(&info);
+    let bounds_column = if info.is_raster() {
+        format!("ST_Envelope({geometry_column})")
+    } else {
+        geometry.geometry()
+    };
 
     if info.bounds.is_none() {
         match bounds_type {
             BoundsCalcType::Skip => {}
             BoundsCalcType::Calc => {
                 debug!("Computing {} table bounds for {id}", info.format_id());
-                info.bounds = calc_bounds(&pool, &schema, &table, &geometry_column, srid).await?;
+                info.bounds = calc_bounds(&pool, &schema, &table, &bounds_column, srid).await?;
             }
             BoundsCalcType::Quick => {
                 debug!(
@@ -131,7 +169,7 @@ pub async fn table_to_query(
                     info.format_id(),
                     DEFAULT_BOUNDS_TIMEOUT.as_secs()
                 );
-                let bounds = calc_bounds(&pool, &schema, &table, &geometry_column, srid);
+                let bounds = calc_bounds(&pool, &schema, &table, &bounds_column, srid);
                 pin_mut!(bounds);
                 if let Ok(bounds) = timeout(DEFAULT_BOUNDS_TIMEOUT, &mut bounds).await {
                     info.bounds = bounds?;
@@ -152,6 +190,48 @@ pub async fn table_to_query(
         }
     }
 
+    let temporal_columns = info.temporal_column.as_ref().map(|start| {
+        let column = |name: &String| escape_identifier(info.prop_mapping.get(name).unwrap_or(name));
+        (column(start), info.temporal_end_column.as_ref().map(column))
+    });
+    if let (Some((start, end)), None) = (&temporal_columns, &info.temporal_extent) {
+        let extent = calc_temporal_extent(&pool, &schema, &table, start, end.as_deref());
+        match bounds_type {
+            BoundsCalcType::Skip => {}
+            BoundsCalcType::Calc => {
+                debug!("Computing {} temporal extent for {id}", info.format_id());
+                info.temporal_extent = extent.await?;
+            }
+            BoundsCalcType::Quick => {
+                debug!(
+                    "Computing {} temporal extent with {}s timeout for {id}",
+                    info.format_id(),
+                    DEFAULT_BOUNDS_TIMEOUT.as_secs()
+                );
+                pin_mut!(extent);
+                if let Ok(extent) = timeout(DEFAULT_BOUNDS_TIMEOUT, &mut extent).await {
+                    info.temporal_extent = extent?;
+                } else {
+                    warn!(
+                        "Timeout computing {} temporal extent for {id}, aborting query. Use --auto-bounds=calc to wait until complete, or check the temporal column for missing indices.",
+                        info.format_id(),
+                    );
+                }
+            }
+        }
+    }
+
+    if info.is_raster() {
+        let ranges =
+            calc_raster_ranges(&pool, &id, &info, &schema, &table, &geometry_column).await?;
+        let (query, format) =
+            raster_to_query(&id, &info, &schema, &table, &geometry_column, &ranges)?;
+        let mut sql_info = PgSqlInfo::new(query, false, info.format_id());
+        sql_info.tile_info = Some(format.into());
+        sql_info.geometry_index = info.geometry_index.filter(|_| info.is_view != Some(true));
+        return Ok((id, sql_info, info));
+    }
+
     let properties = if let Some(props) = &info.properties {
         props
             .keys()
@@ -187,17 +267,22 @@ pub async fn table_to_query(
         "ST_TileEnvelope($1::integer, $2::integer, $3::integer)".to_string()
     };
 
+    // The optional datetime URL parameter is passed as a tstzrange
+    let temporal_filter = temporal_condition(temporal_columns.as_ref(), 4);
+
     let limit_clause = max_feature_count.map_or(String::new(), |v| format!("LIMIT {v}"));
     let layer_id = escape_literal(info.layer_id.as_ref().unwrap_or(&id));
+    let mvt_geometry = geometry.to_webmercator();
+    let bbox_filter = geometry.intersects(&bbox_search, 3857);
     let clip_geom = info.clip_geom.unwrap_or(DEFAULT_CLIP_GEOM);
-    let query = format!(
+    let mut query = format!(
         r#"
 SELECT
   ST_AsMVT(tile, {layer_id}, {extent}, 'geom'{id_name})
 FROM (
   SELECT
     ST_AsMVTGeom(
-        ST_Transform(ST_CurveToLine({geometry_column}), 3857),
+        {mvt_geometry},
         ST_TileEnvelope($1::integer, $2::integer, $3::integer),
         {extent}, {buffer}, {clip_geom}
     ) AS geom
@@ -205,7 +290,7 @@ FROM (
   FROM
     {schema}.{table}
   WHERE
-    {geometry_column} && ST_Transform({bbox_search}, {srid})
+    {bbox_filter}{temporal_filter}
   {limit_clause}
 ) AS tile;
 "#
@@ -213,7 +298,460 @@ FROM (
     .trim()
     .to_string();
 
-    Ok((id, PgSqlInfo::new(query, false, info.format_id()), info))
+    if let Some(cluster) = &info.cluster {
+        let source = format!("{schema}.{table} WHERE {bbox_filter}{temporal_filter}");
+        let mvt_geom_args = format!(
+            "ST_TileEnvelope($1::integer, $2::integer, $3::integer), {extent}, {buffer}, {clip_geom}"
+        );
+        let cluster_query = cluster_to_query(
+            &id,
+            &info,
+            &mvt_geometry,
+            &source,
+            extent,
+            &mvt_geom_args,
+            max_feature_count,
+        )?;
+        let raw_query = query.trim_end_matches(';');
+        query = format!(
+            "SELECT CASE WHEN $1::integer <= {} THEN ({cluster_query}) ELSE ({raw_query}) END;",
+            cluster.max_zoom
+        );
+    }
+
+    let mut sql_info = PgSqlInfo::new(query, temporal_columns.is_some(), info.format_id());
+    if temporal_columns.is_some() {
+        sql_info = sql_info.with_url_params(vec![PgFuncParam::datetime()]);
+    }
+    sql_info.tile_info = Some(Format::Mvt.into());
+    // Views cannot have indices, but generally use the indices of their tables
+    sql_info.geometry_index = info.geometry_index.filter(|_| info.is_view != Some(true));
+    sql_info.items = Some(PgItemsInfo::new(&info, format!("{id_field}{properties}")));
+    sql_info.lookup_query = Some(lookup_to_query(
+        &info,
+        &schema,
+        &table,
+        &geometry,
+        &id_field,
+        &properties,
+        &temporal_condition(temporal_columns.as_ref(), 6),
+    ));
+    Ok((id, sql_info, info))
+}
+
+/// Condition matching the features within the optional `datetime` range passed as the `tstzrange`
+/// parameter `$index`, prefixed with `AND`. Empty if the table has no temporal column.
+fn temporal_condition(temporal_columns: Option<&(String, Option<String>)>, index: usize) -> String {
+    match temporal_columns {
+        Some((start, Some(end))) => format!(
+            " AND (${index}::tstzrange IS NULL OR tstzrange({start}::timestamptz, {end}::timestamptz, '[]') && ${index}::tstzrange)"
+        ),
+        Some((start, None)) => format!(
+            " AND (${index}::tstzrange IS NULL OR ${index}::tstzrange @> {start}::timestamptz)"
+        ),
+        None => String::new(),
+    }
+}
+
+/// SQL expressions reading the geometry column of a table source
+#[derive(Clone, Debug, PartialEq)]
+pub struct GeometrySql {
+    /// Escaped geometry column
+    column: String,
+    /// Escaped column with the same geometries in Web Mercator
+    webmercator_column: Option<String>,
+    srid: i32,
+    is_geography: bool,
+    curve_to_line: bool,
+}
+
+impl GeometrySql {
+    #[must_use]
+    pub fn new(info: &TableInfo) -> Self {
+        Self {
+            column: escape_identifier(&info.geometry_column),
+            webmercator_column: info.webmercator_column.as_deref().map(escape_identifier),
+            srid: info.srid,
+            is_geography: info.is_geography(),
+            curve_to_line: info.use_curve_to_line(),
+        }
+    }
+
+    /// The geometry column, cast to `geometry` if needed
+    #[must_use]
+    pub fn geometry(&self) -> String {
+        if self.is_geography {
+            format!("{}::geometry", self.column)
+        } else {
+            self.column.clone()
+        }
+    }
+
+    /// Transform a geometry expression to the given SRID, converting the curves to lines if needed
+    #[must_use]
+    pub fn transform(&self, geometry: &str, srid: i32) -> String {
+        if self.curve_to_line {
+            format!("ST_Transform(ST_CurveToLine({geometry}), {srid})")
+        } else {
+            format!("ST_Transform({geometry}, {srid})")
+        }
+    }
+
+    /// The geometries in Web Mercator, as encoded in the tiles
+    #[must_use]
+    pub fn to_webmercator(&self) -> String {
+        match &self.webmercator_column {
+            Some(column) if self.curve_to_line => format!("ST_CurveToLine({column})"),
+            Some(column) => column.clone(),
+            None => self.transform(&self.geometry(), 3857),
+        }
+    }
+
+    /// Condition matching the geometries with a bounding box intersecting the envelope,
+    /// written so that the spatial index of the column can be used
+    #[must_use]
+    pub fn intersects(&self, envelope: &str, envelope_srid: i32) -> String {
+        let srid = self.srid;
+        match &self.webmercator_column {
+            Some(column) if envelope_srid == 3857 => format!("{column} && {envelope}"),
+            // Densify the envelope so that its edges follow the parallels, not the great circles
+            _ if self.is_geography => format!(
+                "{} && ST_Segmentize(ST_Transform({envelope}, {srid}), 1)::geography",
+                self.column
+            ),
+            _ => format!("{} && ST_Transform({envelope}, {srid})", self.column),
+        }
+    }
+
+    /// Condition matching the geometries intersecting the envelope.
+    /// The bounding boxes are compared first to use the spatial index, then the geometries themselves.
+    #[must_use]
+    pub fn intersects_exactly(&self, envelope: &str, envelope_srid: i32) -> String {
+        let geometry = if envelope_srid == 3857 {
+            self.to_webmercator()
+        } else {
+            self.transform(&self.geometry(), envelope_srid)
+        };
+        // ST_Envelope turns the zero-size envelope of a point lookup into a point
+        format!(
+            "{} AND ST_Intersects({geometry}, ST_Envelope({envelope}))",
+            self.intersects(envelope, envelope_srid),
+        )
+    }
+}
+
+/// Generate a query returning a `GeoJSON` `FeatureCollection` with the features
+/// within a Web Mercator bounding box given as `$1..$4`, limited to `$5` features.
+/// Tables with a temporal column are also filtered by the `datetime` range given as `$6`.
+fn lookup_to_query(
+    info: &TableInfo,
+    schema: &str,
+    table: &str,
+    geometry: &GeometrySql,
+    id_field: &str,
+    properties: &str,
+    temporal_filter: &str,
+) -> String {
+    let feature_geometry = geometry.transform("feature.geom", 4326);
+    let geometry_column = geometry.geometry();
+    let filter = geometry.intersects_exactly("ST_MakeEnvelope($1, $2, $3, $4, 3857)", 3857);
+    let feature_id = info.id_column.as_ref().map_or(String::new(), |v| {
+        format!(", 'id', feature.{}", escape_identifier(v))
+    });
+    format!(
+        r#"
+SELECT jsonb_build_object(
+  'type', 'FeatureCollection',
+  'features', COALESCE(jsonb_agg(jsonb_build_object(
+    'type', 'Feature'{feature_id},
+    'geometry', ST_AsGeoJSON({feature_geometry})::jsonb,
+    'properties', to_jsonb(feature) - 'geom'
+  )), '[]'::jsonb)
+)
+FROM (
+  SELECT
+    {geometry_column} AS geom
+    {id_field}{properties}
+  FROM
+    {schema}.{table}
+  WHERE
+    {filter}{temporal_filter}
+  LIMIT $5
+) AS feature;
+"#
+    )
+    .trim()
+    .to_string()
+}
+
+/// Generate a query aggregating the points of a table into clusters, each with a `point_count` property.
+/// The cluster geometry is the centroid of its points.
+/// With `max_feature_count`, only the largest clusters are kept.
+fn cluster_to_query(
+    id: &str,
+    info: &TableInfo,
+    mvt_geometry: &str,
+    source: &str,
+    extent: u32,
+    mvt_geom_args: &str,
+    max_feature_count: Option<usize>,
+) -> PgResult<String> {
+    let Some(cfg) = &info.cluster else {
+        return Ok(String::new());
+    };
+    if !matches!(
+        info.geometry_type.as_deref(),
+        None | Some("POINT" | "MULTIPOINT" | "GEOMETRY")
+    ) {
+        warn!(
+            "Source {id} clusters {} geometries of {}, using their centroids",
+            info.geometry_type.as_deref().unwrap_or_default(),
+            info.format_id()
+        );
+    }
+    let radius = cfg.radius.unwrap_or(DEFAULT_CLUSTER_RADIUS);
+    if radius == 0 {
+        let msg = "radius must be greater than 0".to_string();
+        return Err(InvalidClusterConfig(id.to_string(), info.format_id(), msg));
+    }
+    // Size of a tile coordinate space unit in Web Mercator meters at the requested zoom
+    let distance = format!("({EARTH_CIRCUMFERENCE} / 2 ^ $1::integer * {radius} / {extent})");
+
+    let mut columns = String::new();
+    let mut aggregates = String::new();
+    for (name, agg) in cfg.aggregates.iter().flatten() {
+        let column = info.prop_mapping.get(&agg.column).unwrap_or(&agg.column);
+        let column = escape_identifier(column);
+        write!(columns, ", {column}").unwrap();
+        let function = agg.function.to_sql();
+        write!(
+            aggregates,
+            ", {function}({column}) AS {}",
+            escape_identifier(name)
+        )
+        .unwrap();
+    }
+
+    let cluster_id = match cfg.method.unwrap_or_default() {
+        ClusterMethod::Grid => format!("ST_SnapToGrid(geom, {distance})"),
+        ClusterMethod::Dbscan => {
+            let min_points = cfg.min_points.unwrap_or(1).max(1);
+            // Points that are not part of any cluster become single-point clusters
+            format!(
+                "COALESCE(ST_ClusterDBSCAN(geom, eps => {distance}, minpoints => {min_points}) OVER (), -row_number() OVER ())"
+            )
+        }
+    };
+
+    let layer_id = escape_literal(info.layer_id.as_deref().unwrap_or(id));
+    let limit_clause = max_feature_count.map_or(String::new(), |v| {
+        format!("\n  ORDER BY point_count DESC\n  LIMIT {v}")
+    });
+
+    Ok(format!(
+        r#"
+SELECT
+  ST_AsMVT(tile, {layer_id}, {extent}, 'geom')
+FROM (
+  SELECT
+    ST_AsMVTGeom(ST_Centroid(ST_Collect(geom)), {mvt_geom_args}) AS geom,
+    count(*) AS point_count{aggregates}
+  FROM (
+    SELECT {cluster_id} AS cluster_id, geom{columns}
+    FROM (
+      SELECT {mvt_geometry} AS geom{columns}
+      FROM {source}
+    ) AS points
+  ) AS clustered
+  GROUP BY cluster_id{limit_clause}
+) AS tile
+"#
+    )
+    .trim()
+    .to_string())
+}
+
+/// Bands of a raster column rendered into the tiles
+fn raster_bands(id: &str, info: &TableInfo, cfg: &RasterInfo) -> PgResult<Vec<u32>> {
+    let invalid =
+        |msg: &str| InvalidRasterConfig(id.to_string(), info.format_id(), msg.to_string());
+    let bands = if let Some(bands) = &cfg.bands {
+        bands.clone()
+    } else if cfg.colormap.is_some() {
+        vec![1]
+    } else {
+        let num_bands = info.num_bands.and_then(|v| u32::try_from(v).ok());
+        (1..=num_bands.unwrap_or(1).clamp(1, 4)).collect()
+    };
+    if bands.is_empty() || bands.len() > 4 || bands.contains(&0) {
+        return Err(invalid(
+            "bands must list 1 to 4 band numbers, starting from 1",
+        ));
+    }
+    if cfg.colormap.is_some() && bands.len() > 1 {
+        return Err(invalid("colormap can only be applied to a single band"));
+    }
+    Ok(bands)
+}
+
+/// Get the value ranges of the rendered bands that must be rescaled to 8 bits for the image encoders.
+/// Bands with a color map are not rescaled, and the range of the values is only computed if not configured.
+async fn calc_raster_ranges(
+    pool: &PgPool,
+    id: &str,
+    info: &TableInfo,
+    schema: &str,
+    table: &str,
+    raster_column: &str,
+) -> PgResult<BTreeMap<u32, [f64; 2]>> {
+    let cfg = info.raster.clone().unwrap_or_default();
+    let mut ranges = BTreeMap::new();
+    if cfg.colormap.is_some() {
+        return Ok(ranges);
+    }
+    for band in raster_bands(id, info, &cfg)? {
+        let pixel_type = info
+            .pixel_types
+            .as_ref()
+            .and_then(|v| v.get(band as usize - 1));
+        // Without the raster constraints, the pixel types are only known to be wrong if a range is set
+        if !pixel_type.map_or(cfg.range.is_some(), |v| v != "8BUI") {
+            continue;
+        }
+        let range = if let Some(range) = cfg.range {
+            range
+        } else {
+            debug!(
+                "Computing the value range of band {band} of {}",
+                info.format_id()
+            );
+            let row = pool
+                .get()
+                .await?
+                .query_one(
+                    &format!(
+                        "SELECT (stats).min, (stats).max FROM (SELECT ST_SummaryStatsAgg({raster_column}, {band}, true) AS stats FROM {schema}.{table}) AS agg"
+                    ),
+                    &[],
+                )
+                .await
+                .map_err(|e| PostgresError(e, "querying raster band statistics"))?;
+            if let (Some(min), Some(max)) = (row.get(0), row.get(1)) {
+                [min, max]
+            } else {
+                warn!(
+                    "Unable to compute the value range of band {band} of {} for source {id}, set raster.range to rescale it",
+                    info.format_id()
+                );
+                [0.0, 255.0]
+            }
+        };
+        ranges.insert(band, range);
+    }
+    Ok(ranges)
+}
+
+/// Generate a query rendering a raster column as an image tile.
+/// The rasters are clipped to the tile envelope, reprojected and resampled to the tile grid,
+/// and extended with nodata to cover the whole tile. The bands with a value range are rescaled
+/// to 8 bits, using 0 as nodata.
+fn raster_to_query(
+    id: &str,
+    info: &TableInfo,
+    schema: &str,
+    table: &str,
+    raster_column: &str,
+    ranges: &BTreeMap<u32, [f64; 2]>,
+) -> PgResult<(String, Format)> {
+    let cfg = info.raster.clone().unwrap_or_default();
+    let invalid =
+        |msg: &str| InvalidRasterConfig(id.to_string(), info.format_id(), msg.to_string());
+
+    let tile_size = cfg.tile_size.unwrap_or(DEFAULT_RASTER_TILE_SIZE);
+    if !(1..=MAX_RASTER_TILE_SIZE).contains(&tile_size) {
+        return Err(invalid(&format!(
+            "tile_size must be between 1 and {MAX_RASTER_TILE_SIZE}"
+        )));
+    }
+    let bands = raster_bands(id, info, &cfg)?;
+    if cfg.nodata.is_some_and(|v| !v.is_finite()) {
+        return Err(invalid("nodata must be a finite number"));
+    }
+    if ranges
+        .values()
+        .any(|[min, max]| !min.is_finite() || !max.is_finite() || min > max)
+    {
+        return Err(invalid("range must be [min, max] finite numbers"));
+    }
+
+    let mut source = raster_column.to_string();
+    if let Some(nodata) = cfg.nodata {
+        for band in &bands {
+            source = format!("ST_SetBandNoDataValue({source}, {band}, {nodata})");
+        }
+    }
+
+    // Extend each band to the full tile by combining it with an empty reference raster
+    let extend = |band: u32| {
+        if let Some([min, max]) = ranges.get(&band) {
+            let scale = if max > min { 254.0 / (max - min) } else { 0.0 };
+            let expr = format!("GREATEST(1, LEAST(255, ROUND(1 + ([rast1] - ({min})) * {scale})))");
+            format!("ST_MapAlgebra(rast, {band}, grid, 1, '{expr}'::text, '8BUI'::text, 'SECOND'::text, NULL::text, NULL::text, 0)")
+        } else {
+            format!(
+                "ST_MapAlgebra(rast, {band}, grid, 1, '[rast1]'::text, NULL::text, 'SECOND'::text)"
+            )
+        }
+    };
+    let mut image = extend(bands[0]);
+    if bands.len() > 1 {
+        let rest = bands[1..].iter().map(|b| extend(*b)).join(", ");
+        image = format!("ST_AddBand({image}, ARRAY[{rest}])");
+    }
+    if let Some(colormap) = &cfg.colormap {
+        image = format!("ST_ColorMap({image}, 1, {})", escape_literal(colormap));
+    }
+    let (image, format) = match cfg.format.unwrap_or_default() {
+        RasterFormat::Png => (format!("ST_AsPNG({image})"), Format::Png),
+        RasterFormat::Webp => (format!("ST_AsGDALRaster({image}, 'WEBP')"), Format::Webp),
+    };
+
+    let srid = info.srid;
+    let resampling = cfg.resampling.unwrap_or_default().to_sql();
+    let query = format!(
+        r#"
+WITH
+  tile AS (
+    SELECT ST_TileEnvelope($1::integer, $2::integer, $3::integer) AS env
+  ),
+  reference AS (
+    SELECT ST_AddBand(
+        ST_MakeEmptyRaster(
+            {tile_size}, {tile_size}, ST_XMin(env), ST_YMax(env),
+            (ST_XMax(env) - ST_XMin(env)) / {tile_size}, (ST_YMin(env) - ST_YMax(env)) / {tile_size},
+            0, 0, 3857
+        ),
+        '8BUI'::text, 0, NULL
+    ) AS rast
+    FROM tile
+  ),
+  source AS (
+    SELECT ST_Union(ST_Clip({source}, ST_Transform(tile.env, {srid}))) AS rast
+    FROM {schema}.{table}, tile
+    WHERE ST_Intersects({raster_column}, ST_Transform(tile.env, {srid}))
+  ),
+  aligned AS (
+    SELECT ST_Transform(source.rast, reference.rast, '{resampling}') AS rast, reference.rast AS grid
+    FROM source, reference
+    WHERE source.rast IS NOT NULL
+  )
+SELECT {image} FROM aligned;
+"#
+    )
+    .trim()
+    .to_string();
+
+    Ok((query, format))
 }
 
 /// Compute the bounds of a table. This could be slow if the table is large or has no geo index.
@@ -245,6 +783,41 @@ FROM {schema}.{table};
         .and_then(|p| polygon_to_bbox(&p)))
 }
 
+/// Compute the time interval covered by the features, which is open-ended
+/// if the end of any feature's time interval is not set
+async fn calc_temporal_extent(
+    pool: &PgPool,
+    schema: &str,
+    table: &str,
+    start_column: &str,
+    end_column: Option<&str>,
+) -> PgResult<Option<[Option<String>; 2]>> {
+    let to_text = |v: String| {
+        format!(r#"to_char(({v})::timestamptz AT TIME ZONE 'UTC', 'YYYY-MM-DD"T"HH24:MI:SS"Z"')"#)
+    };
+    let start = to_text(format!("min({start_column})"));
+    let end = match end_column {
+        Some(end_column) => format!(
+            "CASE WHEN bool_or({start_column} IS NOT NULL AND {end_column} IS NULL) THEN NULL ELSE {} END",
+            to_text(format!("max({end_column})"))
+        ),
+        None => to_text(format!("max({start_column})")),
+    };
+    let row = pool
+        .get()
+        .await?
+        .query_one(
+            &format!("SELECT {start} AS start_time, {end} AS end_time FROM {schema}.{table}"),
+            &[],
+        )
+        .await
+        .map_err(|e| PostgresError(e, "querying table temporal extent"))?;
+    let start: Option<String> = row.get("start_time");
+    let end: Option<String> = row.get("end_time");
+    // An empty table has no extent
+    Ok(start.map(|start| [Some(start), end]))
+}
+
 pub async fn fetch_postgis_metadata(schema: &str, table_or_function: &str) -> Result<SourceMetadata, Error> {
     // Implement your logic here to query PostgreSQL and fetch the metadata
     // For now, let's assume you have a structure like this:
@@ -254,3 +827,193 @@ pub async fn fetch_postgis_metadata(schema: &str, table_or_function: &str) -> Re
         // Add other necessary metadata fields here
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pg::config_table::{ClusterInfo, RASTER_TYPE};
+
+    const TILE_ENVELOPE: &str = "ST_TileEnvelope($1::integer, $2::integer, $3::integer)";
+
+    fn geometry_sql(info: TableInfo) -> GeometrySql {
+        GeometrySql::new(&TableInfo {
+            geometry_column: "geom".to_string(),
+            srid: 4326,
+            geometry_type: Some("POINT".to_string()),
+            ..info
+        })
+    }
+
+    #[test]
+    fn tile_query_geometry() {
+        let sql = geometry_sql(TableInfo::default());
+        assert_eq!(sql.to_webmercator(), r#"ST_Transform("geom", 3857)"#);
+        assert_eq!(
+            sql.intersects(TILE_ENVELOPE, 3857),
+            format!(r#""geom" && ST_Transform({TILE_ENVELOPE}, 4326)"#)
+        );
+    }
+
+    #[test]
+    fn tile_query_geography() {
+        let sql = geometry_sql(TableInfo {
+            is_geography: Some(true),
+            ..Default::default()
+        });
+        assert_eq!(
+            sql.to_webmercator(),
+            r#"ST_Transform("geom"::geometry, 3857)"#
+        );
+        assert_eq!(
+            sql.intersects(TILE_ENVELOPE, 3857),
+            format!(
+                r#""geom" && ST_Segmentize(ST_Transform({TILE_ENVELOPE}, 4326), 1)::geography"#
+            )
+        );
+    }
+
+    #[test]
+    fn tile_query_webmercator_column() {
+        let sql = geometry_sql(TableInfo {
+            webmercator_column: Some("geom_3857".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(sql.to_webmercator(), r#""geom_3857""#);
+        assert_eq!(
+            sql.intersects(TILE_ENVELOPE, 3857),
+            format!(r#""geom_3857" && {TILE_ENVELOPE}"#)
+        );
+        // Envelopes in other SRIDs use the original column and its index
+        assert_eq!(
+            sql.intersects("envelope", 4326),
+            r#""geom" && ST_Transform(envelope, 4326)"#
+        );
+
+        let sql = geometry_sql(TableInfo {
+            webmercator_column: Some("geom_3857".to_string()),
+            curve_to_line: Some(true),
+            ..Default::default()
+        });
+        assert_eq!(sql.to_webmercator(), r#"ST_CurveToLine("geom_3857")"#);
+    }
+
+    #[test]
+    fn cluster_query_limit() {
+        let info = TableInfo {
+            cluster: Some(ClusterInfo::default()),
+            ..Default::default()
+        };
+        let query = |max_feature_count| {
+            cluster_to_query(
+                "points",
+                &info,
+                "geom",
+                "points",
+                4096,
+                "",
+                max_feature_count,
+            )
+            .unwrap()
+        };
+        assert!(!query(None).contains("LIMIT"));
+        assert!(query(Some(100))
+            .contains("GROUP BY cluster_id\n  ORDER BY point_count DESC\n  LIMIT 100\n) AS tile"));
+    }
+
+    #[test]
+    fn raster_query() {
+        let info = TableInfo {
+            schema: "public".to_string(),
+            table: "dem".to_string(),
+            geometry_column: "rast".to_string(),
+            srid: 4326,
+            geometry_type: Some(RASTER_TYPE.to_string()),
+            num_bands: Some(1),
+            pixel_types: Some(vec!["32BF".to_string()]),
+            ..Default::default()
+        };
+        let query = |info: &TableInfo, ranges: &BTreeMap<u32, [f64; 2]>| {
+            raster_to_query("dem", info, r#""public""#, r#""dem""#, r#""rast""#, ranges)
+        };
+
+        // Float bands are rescaled to 8 bits
+        let ranges = BTreeMap::from([(1, [-10.0, 244.0])]);
+        let (sql, format) = query(&info, &ranges).unwrap();
+        assert_eq!(format, Format::Png);
+        assert!(sql.contains(
+            "ST_AsPNG(ST_MapAlgebra(rast, 1, grid, 1, 'GREATEST(1, LEAST(255, ROUND(1 + ([rast1] - (-10)) * 1)))'::text, '8BUI'::text, 'SECOND'::text, NULL::text, NULL::text, 0))"
+        ));
+        assert!(sql.contains(r#"FROM "public"."dem", tile"#));
+
+        // Bands without a range are passed as is
+        let (sql, _) = query(&info, &BTreeMap::new()).unwrap();
+        assert!(sql.contains(
+            "ST_AsPNG(ST_MapAlgebra(rast, 1, grid, 1, '[rast1]'::text, NULL::text, 'SECOND'::text))"
+        ));
+
+        let ranges = BTreeMap::from([(1, [f64::NAN, 1.0])]);
+        assert!(query(&info, &ranges).is_err());
+
+        let info = TableInfo {
+            raster: Some(RasterInfo {
+                bands: Some(vec![1, 2]),
+                colormap: Some("pseudocolor".to_string()),
+                ..Default::default()
+            }),
+            ..info
+        };
+        assert!(query(&info, &BTreeMap::new()).is_err());
+    }
+
+    #[test]
+    fn lookup_query_filter() {
+        let sql = geometry_sql(TableInfo::default());
+        assert_eq!(
+            sql.intersects_exactly("envelope", 3857),
+            r#""geom" && ST_Transform(envelope, 4326) AND ST_Intersects(ST_Transform("geom", 3857), ST_Envelope(envelope))"#
+        );
+
+        let sql = geometry_sql(TableInfo {
+            webmercator_column: Some("geom_3857".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(
+            sql.intersects_exactly("envelope", 3857),
+            r#""geom_3857" && envelope AND ST_Intersects("geom_3857", ST_Envelope(envelope))"#
+        );
+        assert_eq!(
+            sql.intersects_exactly("envelope", 4326),
+            r#""geom" && ST_Transform(envelope, 4326) AND ST_Intersects(ST_Transform("geom", 4326), ST_Envelope(envelope))"#
+        );
+    }
+
+    #[test]
+    fn lookup_query_datetime() {
+        let columns = (r#""start""#.to_string(), Some(r#""end""#.to_string()));
+        assert_eq!(
+            temporal_condition(Some(&columns), 6),
+            r#" AND ($6::tstzrange IS NULL OR tstzrange("start"::timestamptz, "end"::timestamptz, '[]') && $6::tstzrange)"#
+        );
+        let columns = (r#""start""#.to_string(), None);
+        let filter = temporal_condition(Some(&columns), 6);
+        assert_eq!(
+            filter,
+            r#" AND ($6::tstzrange IS NULL OR $6::tstzrange @> "start"::timestamptz)"#
+        );
+        assert_eq!(temporal_condition(None, 6), "");
+
+        let info = TableInfo::default();
+        let sql = lookup_to_query(
+            &info,
+            r#""public""#,
+            r#""events""#,
+            &geometry_sql(TableInfo::default()),
+            "",
+            "",
+            &filter,
+        );
+        assert!(sql.contains(&format!(
+            "ST_Envelope(ST_MakeEnvelope($1, $2, $3, $4, 3857))){filter}\n  LIMIT $5"
+        )));
+    }
+}
@@ -0,0 +1,353 @@
+use std::fmt::Write as _;
+use std::str::FromStr as _;
+
+use deadpool_postgres::tokio_postgres::types::ToSql;
+use log::{debug, info, warn};
+use martin_tile_utils::TileInfo;
+use postgres_protocol::escape::escape_identifier;
+use serde_json::{json, Value};
+use tilejson::{tilejson, Bounds, Center, TileJSON};
+
+use crate::pg::config::{PgConfig, PgInfo};
+use crate::pg::config_tile_table::TileTableInfo;
+use crate::pg::pg_source::PgSqlInfo;
+use crate::pg::pool::PgPool;
+use crate::pg::PgError::PostgresError;
+use crate::pg::PgResult;
+
+/// Load the metadata of a tile table, detect its tile format, and create the tile query
+pub async fn tile_table_to_query(
+    pool: &PgPool,
+    mut info: TileTableInfo,
+) -> PgResult<(PgSqlInfo, TileTableInfo)> {
+    let schema = escape_identifier(&info.schema);
+    let table = escape_identifier(&info.table);
+    let zoom = escape_identifier(info.zoom_column());
+    let x = escape_identifier(info.x_column());
+    let y = escape_identifier(info.y_column());
+    let data = escape_identifier(info.data_column());
+    let conn = pool.get().await?;
+
+    if let Some(metadata_table) = &info.metadata_table {
+        let metadata_table = escape_identifier(metadata_table);
+        let rows = conn
+            .query(
+                &format!("SELECT name, value FROM {schema}.{metadata_table}"),
+                &[],
+            )
+            .await
+            .map_err(|e| PostgresError(e, "querying tile table metadata"))?;
+        let metadata = rows
+            .iter()
+            .filter_map(|row| Some((row.get(0), row.get::<_, Option<String>>(1)?)));
+        info.tilejson = Some(metadata_to_tilejson(metadata, &info.format_id()));
+    }
+
+    // Tiles in the table are assumed to share the same format and encoding
+    let sample = conn
+        .query_opt(
+            &format!("SELECT {data} FROM {schema}.{table} WHERE {data} IS NOT NULL LIMIT 1"),
+            &[],
+        )
+        .await
+        .map_err(|e| PostgresError(e, "querying a sample tile"))?
+        .map(|row| row.get::<_, Vec<u8>>(0));
+    let tile_info = sample
+        .filter(|v| !v.is_empty())
+        .map(|v| TileInfo::detect(&v).unwrap_or(martin_tile_utils::Format::Mvt.into()));
+
+    let y_value = if info.tms.unwrap_or_default() {
+        "(1::bigint << $1) - 1 - $3"
+    } else {
+        "$3"
+    };
+    let query = format!(
+        "SELECT {data} FROM {schema}.{table} WHERE {zoom} = $1 AND {x} = $2 AND {y} = {y_value}"
+    );
+    debug!("Tile table {} query: {query}", info.format_id());
+
+    let mut sql_info = PgSqlInfo::new(query, false, info.format_id());
+    sql_info.tile_info = tile_info;
+    Ok((sql_info, info))
+}
+
+/// Convert `MBTiles`-style metadata `name`/`value` pairs into a `TileJSON`
+pub fn metadata_to_tilejson(
+    metadata: impl Iterator<Item = (String, String)>,
+    id: &str,
+) -> TileJSON {
+    let mut tj = tilejson! { tiles: vec![] };
+    for (name, value) in metadata {
+        if value.is_empty() {
+            continue;
+        }
+        match name.as_str() {
+            "name" => tj.name = Some(value),
+            "version" => tj.version = Some(value),
+            "description" => tj.description = Some(value),
+            "attribution" => tj.attribution = Some(value),
+            "legend" => tj.legend = Some(value),
+            "template" => tj.template = Some(value),
+            "bounds" => match Bounds::from_str(&value) {
+                Ok(v) => tj.bounds = Some(v),
+                Err(e) => warn!("Unable to parse metadata bounds value in {id}: {e}"),
+            },
+            "center" => match Center::from_str(&value) {
+                Ok(v) => tj.center = Some(v),
+                Err(e) => warn!("Unable to parse metadata center value in {id}: {e}"),
+            },
+            "minzoom" => match value.parse() {
+                Ok(v) => tj.minzoom = Some(v),
+                Err(e) => warn!("Unable to parse metadata minzoom value in {id}: {e}"),
+            },
+            "maxzoom" => match value.parse() {
+                Ok(v) => tj.maxzoom = Some(v),
+                Err(e) => warn!("Unable to parse metadata maxzoom value in {id}: {e}"),
+            },
+            "json" => match serde_json::from_str::<Value>(&value) {
+                Ok(Value::Object(mut obj)) => {
+                    if let Some(layers) = obj.remove("vector_layers") {
+                        match serde_json::from_value(layers) {
+                            Ok(v) => tj.vector_layers = Some(v),
+                            Err(e) => {
+                                warn!("Unable to parse metadata vector_layers value in {id}: {e}");
+                            }
+                        }
+                    }
+                    tj.other.extend(obj);
+                }
+                Ok(_) => warn!("Metadata json value in {id} is not an object"),
+                Err(e) => warn!("Unable to parse metadata json value in {id}: {e}"),
+            },
+            _ => {
+                tj.other.insert(name, Value::String(value));
+            }
+        }
+    }
+    tj
+}
+
+/// Convert a `TileJSON` into `MBTiles`-style metadata `name`/`value` pairs
+#[must_use]
+pub fn tilejson_to_metadata(tj: &TileJSON) -> Vec<(String, String)> {
+    let mut metadata = Vec::new();
+    for (key, value) in [
+        ("name", tj.name.clone()),
+        ("version", tj.version.clone()),
+        ("description", tj.description.clone()),
+        ("attribution", tj.attribution.clone()),
+        ("legend", tj.legend.clone()),
+        ("template", tj.template.clone()),
+        ("bounds", tj.bounds.map(|v| v.to_string())),
+        ("center", tj.center.map(|v| v.to_string())),
+        ("minzoom", tj.minzoom.map(|v| v.to_string())),
+        ("maxzoom", tj.maxzoom.map(|v| v.to_string())),
+        (
+            "json",
+            tj.vector_layers
+                .as_ref()
+                .map(|v| json!({ "vector_layers": v }).to_string()),
+        ),
+    ] {
+        if let Some(value) = value {
+            metadata.push((key.to_string(), value));
+        }
+    }
+    for (key, value) in &tj.other {
+        let value = match value {
+            Value::String(v) => v.clone(),
+            v => v.to_string(),
+        };
+        metadata.push((key.clone(), value));
+    }
+    metadata
+}
+
+/// What to do when a tile with the same Z/X/Y already exists in the table
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TileTableConflict {
+    Override,
+    Ignore,
+    Abort,
+}
+
+/// Writes tiles into a table with the same layout as a tile table source
+#[derive(Clone, Debug)]
+pub struct PgTileTableWriter {
+    pool: PgPool,
+    info: TileTableInfo,
+}
+
+impl PgTileTableWriter {
+    /// Connect to the database. The `table` may be qualified with a schema, e.g. `cache.tiles`
+    pub async fn new(
+        connection_string: String,
+        table: &str,
+        metadata_table: Option<String>,
+    ) -> PgResult<Self> {
+        let (schema, table) = table.split_once('.').unwrap_or(("public", table));
+        let config = PgConfig {
+            connection_string: Some(connection_string),
+            ..Default::default()
+        };
+        let mut info = TileTableInfo::new(schema.to_string(), table.to_string());
+        info.metadata_table = metadata_table;
+        Ok(Self {
+            pool: PgPool::new(&config).await?,
+            info,
+        })
+    }
+
+    #[must_use]
+    pub fn get_id(&self) -> String {
+        self.info.format_id()
+    }
+
+    /// Create the tile table and the metadata table if they do not exist yet
+    pub async fn create_tables(&self) -> PgResult<()> {
+        let schema = escape_identifier(&self.info.schema);
+        let table = escape_identifier(&self.info.table);
+        let zoom = escape_identifier(self.info.zoom_column());
+        let x = escape_identifier(self.info.x_column());
+        let y = escape_identifier(self.info.y_column());
+        let data = escape_identifier(self.info.data_column());
+        let mut sql = format!(
+            "CREATE TABLE IF NOT EXISTS {schema}.{table} (
+                 {zoom} integer NOT NULL,
+                 {x} integer NOT NULL,
+                 {y} integer NOT NULL,
+                 {data} bytea,
+                 PRIMARY KEY ({zoom}, {x}, {y}));"
+        );
+        if let Some(metadata_table) = &self.info.metadata_table {
+            let metadata_table = escape_identifier(metadata_table);
+            write!(
+                sql,
+                "CREATE TABLE IF NOT EXISTS {schema}.{metadata_table} (name text PRIMARY KEY, value text);"
+            )
+            .unwrap();
+        }
+        self.pool
+            .get()
+            .await?
+            .batch_execute(&sql)
+            .await
+            .map_err(|e| PostgresError(e, "creating a tile table"))
+    }
+
+    /// Returns true if the tile table has no tiles
+    pub async fn is_empty(&self) -> PgResult<bool> {
+        let schema = escape_identifier(&self.info.schema);
+        let table = escape_identifier(&self.info.table);
+        let row = self
+            .pool
+            .get()
+            .await?
+            .query_opt(&format!("SELECT 1 FROM {schema}.{table} LIMIT 1"), &[])
+            .await
+            .map_err(|e| PostgresError(e, "checking if a tile table is empty"))?;
+        Ok(row.is_none())
+    }
+
+    /// Insert a batch of `(z, x, y, data)` tiles
+    #[allow(clippy::cast_possible_wrap)] // tile coordinates at zoom 30 still fit into i32
+    pub async fn insert_tiles(
+        &self,
+        tiles: &[(u8, u32, u32, Vec<u8>)],
+        on_conflict: TileTableConflict,
+    ) -> PgResult<()> {
+        let schema = escape_identifier(&self.info.schema);
+        let table = escape_identifier(&self.info.table);
+        let zoom = escape_identifier(self.info.zoom_column());
+        let x = escape_identifier(self.info.x_column());
+        let y = escape_identifier(self.info.y_column());
+        let data = escape_identifier(self.info.data_column());
+        let conflict = match on_conflict {
+            TileTableConflict::Override => {
+                format!("ON CONFLICT ({zoom}, {x}, {y}) DO UPDATE SET {data} = EXCLUDED.{data}")
+            }
+            TileTableConflict::Ignore => "ON CONFLICT DO NOTHING".to_string(),
+            TileTableConflict::Abort => String::new(),
+        };
+        let sql = format!(
+            "INSERT INTO {schema}.{table} ({zoom}, {x}, {y}, {data})
+             SELECT * FROM UNNEST($1::integer[], $2::integer[], $3::integer[], $4::bytea[])
+             {conflict}"
+        );
+
+        let zooms: Vec<i32> = tiles.iter().map(|t| i32::from(t.0)).collect();
+        let xs: Vec<i32> = tiles.iter().map(|t| t.1 as i32).collect();
+        let ys: Vec<i32> = tiles.iter().map(|t| t.2 as i32).collect();
+        let blobs: Vec<&[u8]> = tiles.iter().map(|t| t.3.as_slice()).collect();
+        let params: &[&(dyn ToSql + Sync)] = &[&zooms, &xs, &ys, &blobs];
+        self.pool
+            .get()
+            .await?
+            .execute(&sql, params)
+            .await
+            .map_err(|e| PostgresError(e, "inserting tiles"))?;
+        Ok(())
+    }
+
+    /// Insert or replace metadata values. Does nothing if there is no metadata table.
+    pub async fn set_metadata(&self, metadata: &[(String, String)]) -> PgResult<()> {
+        let Some(metadata_table) = &self.info.metadata_table else {
+            return Ok(());
+        };
+        let schema = escape_identifier(&self.info.schema);
+        let metadata_table = escape_identifier(metadata_table);
+        let sql = format!(
+            "INSERT INTO {schema}.{metadata_table} (name, value) VALUES ($1, $2)
+             ON CONFLICT (name) DO UPDATE SET value = EXCLUDED.value"
+        );
+        let conn = self.pool.get().await?;
+        for (name, value) in metadata {
+            info!("Setting metadata key={name} value={value}");
+            conn.execute(&sql, &[name, value])
+                .await
+                .map_err(|e| PostgresError(e, "setting a metadata value"))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metadata_roundtrip() {
+        let metadata = [
+            ("name", "tiles"),
+            ("bounds", "-10,-20,30,40"),
+            ("center", "1,2,3"),
+            ("minzoom", "0"),
+            ("maxzoom", "14"),
+            ("format", "pbf"),
+            ("json", r#"{"vector_layers":[{"fields":{},"id":"roads"}]}"#),
+            ("unused", ""),
+        ];
+        let tj = metadata_to_tilejson(
+            metadata
+                .iter()
+                .map(|(k, v)| ((*k).to_string(), (*v).to_string())),
+            "test",
+        );
+        assert_eq!(tj.name.as_deref(), Some("tiles"));
+        assert_eq!(tj.bounds, Some(Bounds::new(-10.0, -20.0, 30.0, 40.0)));
+        assert_eq!(tj.minzoom, Some(0));
+        assert_eq!(tj.maxzoom, Some(14));
+        assert_eq!(tj.vector_layers.as_ref().unwrap()[0].id, "roads");
+        assert_eq!(tj.other["format"], Value::String("pbf".to_string()));
+        assert!(!tj.other.contains_key("unused"));
+
+        let mut result = tilejson_to_metadata(&tj);
+        result.sort();
+        let mut expected: Vec<_> = metadata[..7]
+            .iter()
+            .map(|(k, v)| ((*k).to_string(), (*v).to_string()))
+            .collect();
+        expected.sort();
+        assert_eq!(result, expected);
+    }
+}
@@ -1,7 +1,8 @@
 -- Find SQL functions that match these criteria:
---     * The function must have 3 or 4 input parameters,
---       first 3 must be integers and named z (or zoom), x, y (in that order),
---       with the optional JSON parameter as the 4th parameter (any name).
+--     * The function must have at least 3 input parameters,
+--       first 3 must be integers and named z (or zoom), x, y (in that order).
+--       Any additional parameters are set from the URL query parameters with the same name,
+--       except for json/jsonb parameters (any name) which get all URL query parameters as a JSON object.
 --     * The function output must be either a single bytea value or a table,
 --       with the table row being either [bytea] or [bytea, text] (in that order).
 --     * If the output is a two-column row, the second column will be used as etag (usually the MD5 hash)
@@ -13,15 +14,23 @@
 --   output_record_types: an optional JSON array of parameter types ["bytea"] or ["bytea", "text"]
 --   output_record_names: an optional JSON array of output column names, e.g. ["mvt", "key"]
 --   input_names: a JSON array of input parameter names
---   input_types: a JSON array of input parameter types
+--   input_types: a JSON array of input parameter types, e.g. "integer" or "text[]"
+--   input_defaults: a JSON array of input parameter default values as SQL expressions, or nulls
 WITH
     --
     inputs AS (
         -- list of input parameters for each function, returned as a jsonb array [{name: type}, ...]
         SELECT specific_name,
                jsonb_agg(COALESCE(parameter_name::text, '_') ORDER BY ordinal_position) as input_names,
-               jsonb_agg(data_type::text ORDER BY ordinal_position)                     as input_types
+               jsonb_agg(CASE
+                             WHEN data_type = 'ARRAY'
+                                 THEN format_type(to_regtype(quote_ident(udt_schema) || '.' || quote_ident(udt_name)), NULL)
+                             ELSE data_type::text END ORDER BY ordinal_position)  as input_types,
+               -- parameter_default is only visible to the function owner, so read pg_proc.proargdefaults instead
+               jsonb_agg(pg_get_function_arg_default(pg_proc.oid, ordinal_position::integer)
+                         ORDER BY ordinal_position)                                 as input_defaults
         FROM information_schema.parameters
+                 JOIN pg_proc ON specific_name = pg_proc.proname || '_' || pg_proc.oid
         WHERE parameter_mode = 'IN'
           AND specific_schema NOT IN ('pg_catalog', 'information_schema')
         GROUP BY specific_name),
@@ -50,20 +59,19 @@ SELECT routines.specific_schema AS schema,
        out_names                AS output_record_names,
        inputs.input_types       AS input_types,
        inputs.input_names       AS input_names,
+       inputs.input_defaults    AS input_defaults,
        comments.description     AS description
 FROM information_schema.routines
          JOIN inputs ON routines.specific_name = inputs.specific_name
          LEFT JOIN outputs ON routines.specific_name = outputs.specific_name
          LEFT JOIN comments ON comments.schema = routines.specific_schema AND comments.name = routines.routine_name
-WHERE jsonb_array_length(input_names) IN (3, 4) -- 3 or 4 input parameters
+WHERE jsonb_array_length(input_names) >= 3      -- at least 3 input parameters
   AND lower(input_names ->> 0) IN ('z', 'zoom') -- the first int param is either z or zoom
   AND input_types ->> 0 = 'integer'
   AND lower(input_names ->> 1) = 'x'            -- the second int param is x
   AND input_types ->> 1 = 'integer'
   AND lower(input_names ->> 2) = 'y'            -- the third param is y
   AND input_types ->> 2 = 'integer'
-  -- the 4th optional parameter can be any name, and must be either json or jsonb
-  AND (input_types ->> 3 = 'json' OR input_types ->> 3 = 'jsonb' OR (input_types ->> 3) IS NULL)
   -- the output must be either a single bytea value or a table, with the table row being either [bytea] or [bytea, text]
   AND (
         (data_type = 'bytea' AND out_params IS NULL)
@@ -0,0 +1,19 @@
+-- Same columns as query_available_tables.sql, but for raster columns.
+-- Requires the postgis_raster extension, which provides the raster_columns view.
+-- The SRID is NULL if the raster column has no SRID constraint.
+SELECT rc.r_table_schema                      AS schema,
+       rc.r_table_name                        AS name,
+       rc.r_raster_column                     AS geom,
+       NULLIF(rc.srid, 0)                     AS srid,
+       'RASTER'                               AS type,
+       COALESCE(class.relkind = 'v', false)   AS is_view,
+       NULL::boolean                          AS geom_idx,
+       '{}'::jsonb                            AS properties,
+       CAST(obj_description(class.oid, 'pg_class') AS VARCHAR) AS description,
+       rc.num_bands                           AS num_bands,
+       rc.pixel_types                         AS pixel_types
+FROM raster_columns AS rc
+         JOIN pg_catalog.pg_namespace AS ns
+              ON ns.nspname = rc.r_table_schema
+         JOIN pg_catalog.pg_class AS class
+              ON class.relname = rc.r_table_name AND class.relnamespace = ns.oid;
@@ -28,7 +28,7 @@ WITH
                  JOIN pg_opclass op ON
                     op.oid = ix.indclass[0] AND
                     op.opcname IN ('gist_geometry_ops_2d', 'spgist_geometry_ops_2d',
-                                   'brin_geometry_inclusion_ops_2d')
+                                   'brin_geometry_inclusion_ops_2d', 'gist_geography_ops')
         GROUP BY 1, 2, 3),
     --
     annotated_geometry_columns AS (
@@ -39,7 +39,8 @@ WITH
                srid,
                type,
                COALESCE(class.relkind = 'v', false) AS is_view,
-               bool_or(sic.column_name is not null) as geom_idx
+               bool_or(sic.column_name is not null) as geom_idx,
+               false                                AS is_geography
         FROM geometry_columns
                  JOIN pg_catalog.pg_class AS class
                       ON class.relname = geometry_columns.f_table_name
@@ -50,6 +51,32 @@ WITH
                     geometry_columns.f_table_name = sic.table_name AND
                     geometry_columns.f_geometry_column = sic.column_name
         GROUP BY 1, 2, 3, 4, 5, 6),
+    --
+    annotated_geography_columns AS (
+        -- list of geography columns with additional metadata
+        SELECT f_table_schema                       AS schema,
+               f_table_name                         AS name,
+               f_geography_column                   AS geom,
+               srid,
+               upper(type)                          AS type,
+               COALESCE(class.relkind = 'v', false) AS is_view,
+               bool_or(sic.column_name is not null) as geom_idx,
+               true                                 AS is_geography
+        FROM geography_columns
+                 JOIN pg_catalog.pg_class AS class
+                      ON class.relname = geography_columns.f_table_name
+                 JOIN pg_catalog.pg_namespace AS ns
+                      ON ns.nspname = geography_columns.f_table_schema
+                 LEFT JOIN spatially_indexed_columns AS sic ON
+                    geography_columns.f_table_schema = sic.table_schema AND
+                    geography_columns.f_table_name = sic.table_name AND
+                    geography_columns.f_geography_column = sic.column_name
+        GROUP BY 1, 2, 3, 4, 5, 6),
+    --
+    annotated_columns AS (
+        SELECT * FROM annotated_geometry_columns
+        UNION ALL
+        SELECT * FROM annotated_geography_columns),
     descriptions AS (
         -- comments on table/views
         SELECT
@@ -67,13 +94,14 @@ SELECT schema,
        type,
        is_view,
        geom_idx,
+       is_geography,
        COALESCE(
                        jsonb_object_agg(columns.column_name, columns.type_name)
-                       FILTER (WHERE columns.column_name IS NOT NULL AND columns.type_name != 'geometry'),
+                       FILTER (WHERE columns.column_name IS NOT NULL AND columns.type_name NOT IN ('geometry', 'geography')),
                        '{}'::jsonb
            ) as properties,
       dc.description
-FROM annotated_geometry_columns AS gc
+FROM annotated_columns AS gc
          LEFT JOIN columns ON
             gc.schema = columns.table_schema AND
             gc.name = columns.table_name AND
@@ -81,4 +109,4 @@ FROM annotated_geometry_columns AS gc
          LEFT JOIN descriptions AS dc on
             gc.schema = dc.schema_name AND
             gc.name = dc.table_name
-GROUP BY gc.schema, gc.name, gc.geom, gc.srid, gc.type, gc.is_view, gc.geom_idx,dc.description;
+GROUP BY gc.schema, gc.name, gc.geom, gc.srid, gc.type, gc.is_view, gc.geom_idx, gc.is_geography, dc.description;
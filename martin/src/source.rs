@@ -1,8 +1,12 @@
 use std::collections::{BTreeMap, HashMap};
 use std::fmt::Debug;
+use std::sync::{Arc, PoisonError, RwLock};
 
 use actix_web::error::ErrorNotFound;
+use actix_web::web::Query;
+use actix_web::HttpRequest;
 use async_trait::async_trait;
+use futures::stream::BoxStream;
 use log::debug;
 use martin_tile_utils::{TileCoord, TileInfo};
 use serde::{Deserialize, Serialize};
@@ -17,6 +21,35 @@ pub type TileInfoSource = Box<dyn Source>;
 
 pub type TileInfoSources = Vec<TileInfoSource>;
 
+/// Database settings derived from the request, e.g. the user identity used by row-level security policies
+pub type RequestContext = BTreeMap<String, String>;
+
+/// Attributes of an HTTP request that a source may pass to its data store
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RequestAttributes {
+    /// Headers with lowercase names
+    pub headers: HashMap<String, String>,
+    pub query: UrlQuery,
+}
+
+impl RequestAttributes {
+    #[must_use]
+    pub fn from_request(req: &HttpRequest) -> Self {
+        let headers = req
+            .headers()
+            .iter()
+            .filter_map(|(k, v)| Some((k.as_str().to_lowercase(), v.to_str().ok()?.to_string())))
+            .collect();
+        let query = Query::<UrlQuery>::from_query(req.query_string())
+            .map(Query::into_inner)
+            .unwrap_or_default();
+        Self { headers, query }
+    }
+}
+
+/// A stream of `GeoJSON` features, each serialized as a JSON object
+pub type ItemStream = BoxStream<'static, MartinResult<String>>;
+
 #[derive(Default, Clone)]
 pub struct TileSources(HashMap<String, Box<dyn Source>>);
 pub type TileCatalog = BTreeMap<String, CatalogSourceEntry>;
@@ -41,6 +74,10 @@ impl TileSources {
             .collect()
     }
 
+    pub fn iter(&self) -> impl Iterator<Item = &dyn Source> {
+        self.0.values().map(AsRef::as_ref)
+    }
+
     pub fn get_source(&self, id: &str) -> actix_web::Result<&dyn Source> {
         Ok(self
             .0
@@ -99,8 +136,44 @@ impl TileSources {
     }
 }
 
+/// Tile sources shared by all server workers, which can be changed while the server is running
+#[derive(Default, Clone)]
+pub struct SharedTileSources(Arc<RwLock<Arc<TileSources>>>);
+
+impl SharedTileSources {
+    #[must_use]
+    pub fn new(sources: TileSources) -> Self {
+        Self(Arc::new(RwLock::new(Arc::new(sources))))
+    }
+
+    /// Get the current sources. The result is not affected by any later changes.
+    #[must_use]
+    pub fn load(&self) -> Arc<TileSources> {
+        self.0
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    #[must_use]
+    pub fn get_catalog(&self) -> TileCatalog {
+        self.load().get_catalog()
+    }
+
+    /// Remove the `removed` sources, and add the `added` ones, replacing any sources with the same ID
+    pub fn update<'a>(&self, removed: impl IntoIterator<Item = &'a str>, added: TileInfoSources) {
+        let mut current = self.0.write().unwrap_or_else(PoisonError::into_inner);
+        let mut sources = current.0.clone();
+        for id in removed {
+            sources.remove(id);
+        }
+        sources.extend(added.into_iter().map(|src| (src.get_id().to_string(), src)));
+        *current = Arc::new(TileSources(sources));
+    }
+}
+
 #[async_trait]
-pub trait Source: Send + Debug {
+pub trait Source: Send + Sync + Debug {
     fn get_id(&self) -> &str;
 
     fn get_tilejson(&self) -> &TileJSON;
@@ -119,6 +192,93 @@ pub trait Source: Send + Debug {
         url_query: Option<&UrlQuery>,
     ) -> MartinResult<TileData>;
 
+    /// Map the request attributes to the context passed to the data source.
+    /// Tiles are cached separately for each context.
+    fn get_request_context(
+        &self,
+        _attrs: &RequestAttributes,
+    ) -> MartinResult<Option<RequestContext>> {
+        Ok(None)
+    }
+
+    /// Lowercase names of the HTTP headers used by `get_request_context`.
+    /// Responses depending on them must not be shared between users.
+    fn get_request_headers(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Names of the URL query parameters used by `get_request_context`.
+    /// They are not used as the property filters of the `items` queries.
+    fn get_request_query_params(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Same as `get_tile`, but also returns a hash of the tile content if the source provides one,
+    /// and uses the request context if the source supports it.
+    async fn get_tile_with_etag(
+        &self,
+        xyz: TileCoord,
+        url_query: Option<&UrlQuery>,
+        _context: Option<&RequestContext>,
+    ) -> MartinResult<TileWithEtag> {
+        Ok(TileWithEtag {
+            data: self.get_tile(xyz, url_query).await?,
+            etag: None,
+        })
+    }
+
+    /// Get the features within a Web Mercator bounding box `[left, bottom, right, top]`
+    /// as a `GeoJSON` `FeatureCollection`, returning at most `limit` features.
+    /// The URL query may filter the features the same way as the tiles, e.g. by `datetime`.
+    /// Returns `None` if the source does not support feature lookups.
+    async fn get_features(
+        &self,
+        _bbox: [f64; 4],
+        _limit: Option<usize>,
+        _url_query: Option<&UrlQuery>,
+        _context: Option<&RequestContext>,
+    ) -> MartinResult<Option<serde_json::Value>> {
+        Ok(None)
+    }
+
+    /// SRIDs the features can be returned in, or `None` if the source
+    /// cannot be published as an OGC API Features collection.
+    fn get_items_srids(&self) -> Option<Vec<i32>> {
+        None
+    }
+
+    /// Check if `get_items` always returns the features in the same order,
+    /// so that requests with different offsets return pages that do not overlap.
+    fn has_stable_items_order(&self) -> bool {
+        false
+    }
+
+    /// Get the features matching an OGC API Features `items` query,
+    /// or `None` if the source does not support it.
+    async fn get_items(
+        &self,
+        _query: &ItemsQuery,
+        _context: Option<&RequestContext>,
+    ) -> MartinResult<Option<ItemStream>> {
+        Ok(None)
+    }
+
+    /// Describe the SQL query used to generate the tiles, or `None` if the source does not use one.
+    fn get_sql_info(&self) -> Option<serde_json::Value> {
+        None
+    }
+
+    /// Run the tile query with `EXPLAIN ANALYZE`, and return the query plan and timings.
+    /// Returns `None` if the source does not use an SQL query.
+    async fn explain_tile(
+        &self,
+        _xyz: TileCoord,
+        _url_query: Option<&UrlQuery>,
+        _context: Option<&RequestContext>,
+    ) -> MartinResult<Option<serde_json::Value>> {
+        Ok(None)
+    }
+
     fn is_valid_zoom(&self, zoom: u8) -> bool {
         let tj = self.get_tilejson();
         tj.minzoom.map_or(true, |minzoom| zoom >= minzoom)
@@ -145,6 +305,21 @@ impl Clone for Box<dyn Source> {
     }
 }
 
+/// Filters and paging of an OGC API Features `items` request
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ItemsQuery {
+    /// Bounding box `[left, bottom, right, top]` in WGS84 degrees
+    pub bbox: Option<[f64; 4]>,
+    pub limit: usize,
+    pub offset: usize,
+    /// Start and end of the `datetime` filter, the same value for an instant, or `None` for an open end
+    pub datetime: Option<(Option<String>, Option<String>)>,
+    /// Property values to match exactly
+    pub properties: Vec<(String, String)>,
+    /// SRID of the returned geometries
+    pub srid: i32,
+}
+
 #[serde_with::skip_serializing_none]
 #[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
 pub struct CatalogSourceEntry {
@@ -158,6 +333,7 @@ pub struct CatalogSourceEntry {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::srv::server::tests::TestSource;
 
     #[test]
     fn xyz_format() {
@@ -165,17 +341,52 @@ mod tests {
         assert_eq!(format!("{xyz}"), "1,2,3");
         assert_eq!(format!("{xyz:#}"), "1/2/3");
     }
+    #[test]
+    fn shared_sources_update() {
+        let src = |id| -> TileInfoSource {
+            Box::new(TestSource {
+                id,
+                tj: tilejson::tilejson! { tiles: vec![] },
+                data: Vec::default(),
+            })
+        };
+        let shared = SharedTileSources::new(TileSources::new(vec![vec![src("a"), src("b")]]));
+        let before = shared.load();
+
+        shared.update(["b"], vec![src("c")]);
+        let after = shared.load();
+        assert!(after.get_source("a").is_ok());
+        assert!(after.get_source("b").is_err());
+        assert!(after.get_source("c").is_ok());
+
+        // earlier snapshots are not affected
+        assert!(before.get_source("b").is_ok());
+        assert!(before.get_source("c").is_err());
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Tile {
     pub data: TileData,
     pub info: TileInfo,
+    /// Hash of the tile content as provided by the source, usable as a weak `ETag`
+    pub etag: Option<String>,
 }
 
 impl Tile {
     #[must_use]
     pub fn new(data: TileData, info: TileInfo) -> Self {
-        Self { data, info }
+        Self {
+            data,
+            info,
+            etag: None,
+        }
     }
 }
+
+/// Raw tile data as returned by a source, with an optional hash of its content
+#[derive(Debug, Clone, Default)]
+pub struct TileWithEtag {
+    pub data: TileData,
+    pub etag: Option<String>,
+}
@@ -0,0 +1,140 @@
+use actix_web::error::{ErrorBadRequest, ErrorUnauthorized};
+use actix_web::http::header::{AUTHORIZATION, CACHE_CONTROL};
+use actix_web::web::{Data, Path, Query};
+use actix_web::{route, HttpRequest, HttpResponse, Result as ActixResult};
+use martin_tile_utils::TileCoord;
+use serde::Deserialize;
+use subtle::ConstantTimeEq as _;
+
+use crate::source::{RequestAttributes, SharedTileSources, UrlQuery};
+use crate::srv::tiles::map_tile_error;
+use crate::srv::SrvConfig;
+
+#[derive(Deserialize)]
+struct AdminSourceRequest {
+    source_id: String,
+}
+
+#[derive(Deserialize)]
+struct AdminTileRequest {
+    source_id: String,
+    z: u8,
+    x: u32,
+    y: u32,
+}
+
+/// Only allow requests with the configured `admin_token` as the bearer token.
+/// The admin endpoints are not registered at all if the token is not configured.
+/// The token is compared in constant time to avoid leaking it through response timings.
+fn check_admin(req: &HttpRequest, srv_config: &SrvConfig) -> ActixResult<()> {
+    let token = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::trim);
+    match (&srv_config.admin_token, token) {
+        (Some(expected), Some(token)) if expected.as_bytes().ct_eq(token.as_bytes()).into() => {
+            Ok(())
+        }
+        _ => Err(ErrorUnauthorized("A valid admin token is required")),
+    }
+}
+
+#[route("/admin/sources/{source_id}/sql", method = "GET")]
+async fn get_source_sql(
+    req: HttpRequest,
+    srv_config: Data<SrvConfig>,
+    path: Path<AdminSourceRequest>,
+    sources: Data<SharedTileSources>,
+) -> ActixResult<HttpResponse> {
+    check_admin(&req, &srv_config)?;
+    let sources = sources.load();
+    let info = sources
+        .get_source(&path.source_id)?
+        .get_sql_info()
+        .ok_or_else(|| {
+            ErrorBadRequest(format!(
+                "Source {} is not generated by an SQL query",
+                path.source_id
+            ))
+        })?;
+    Ok(HttpResponse::Ok()
+        .insert_header((CACHE_CONTROL, "no-cache"))
+        .json(info))
+}
+
+#[route("/admin/sources/{source_id}/explain/{z}/{x}/{y}", method = "GET")]
+async fn get_source_explain(
+    req: HttpRequest,
+    srv_config: Data<SrvConfig>,
+    path: Path<AdminTileRequest>,
+    sources: Data<SharedTileSources>,
+) -> ActixResult<HttpResponse> {
+    check_admin(&req, &srv_config)?;
+    let sources = sources.load();
+    let src = sources.get_source(&path.source_id)?;
+    let xyz = TileCoord {
+        z: path.z,
+        x: path.x,
+        y: path.y,
+    };
+    if !src.is_valid_zoom(xyz.z) {
+        return Err(ErrorBadRequest(format!(
+            "Source {} does not support zoom {}",
+            path.source_id, xyz.z
+        )));
+    }
+    let url_query = if src.support_url_query() && !req.query_string().is_empty() {
+        Some(Query::<UrlQuery>::from_query(req.query_string())?.into_inner())
+    } else {
+        None
+    };
+    let context = src
+        .get_request_context(&RequestAttributes::from_request(&req))
+        .map_err(map_tile_error)?;
+    let report = src
+        .explain_tile(xyz, url_query.as_ref(), context.as_ref())
+        .await
+        .map_err(map_tile_error)?
+        .ok_or_else(|| {
+            ErrorBadRequest(format!(
+                "Source {} is not generated by an SQL query",
+                path.source_id
+            ))
+        })?;
+    Ok(HttpResponse::Ok()
+        .insert_header((CACHE_CONTROL, "no-cache"))
+        .json(report))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    #[test]
+    fn admin_token() {
+        let config = SrvConfig {
+            admin_token: Some("secret".to_string()),
+            ..Default::default()
+        };
+        let req = |token: Option<&str>| {
+            let req = TestRequest::default();
+            match token {
+                Some(token) => req.insert_header((AUTHORIZATION, token)),
+                None => req,
+            }
+            .to_http_request()
+        };
+
+        assert!(check_admin(&req(Some("Bearer secret")), &config).is_ok());
+        assert!(check_admin(&req(Some("Bearer other")), &config).is_err());
+        assert!(check_admin(&req(Some("Bearer secre")), &config).is_err());
+        assert!(check_admin(&req(Some("Bearer secrets")), &config).is_err());
+        assert!(check_admin(&req(Some("secret")), &config).is_err());
+        assert!(check_admin(&req(None), &config).is_err());
+        assert!(check_admin(&req(Some("Bearer secret")), &SrvConfig::default()).is_err());
+    }
+}
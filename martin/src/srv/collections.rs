@@ -0,0 +1,403 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
+use actix_web::error::{ErrorBadRequest, ErrorNotFound};
+use actix_web::http::header::CONTENT_TYPE;
+use actix_web::web::{Bytes, Data, Path, Query};
+use actix_web::{middleware, route, HttpRequest, HttpResponse, Result as ActixResult};
+use futures::{stream, StreamExt as _};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::source::{ItemsQuery, RequestAttributes, SharedTileSources, Source, UrlQuery};
+use crate::srv::server::map_internal_error;
+use crate::srv::tiles::{map_tile_error, set_request_headers_vary};
+use crate::srv::SrvConfig;
+
+/// Number of features returned if the request has no `limit`
+const DEFAULT_ITEMS_LIMIT: usize = 10;
+/// Larger `limit` values are reduced to this value
+const MAX_ITEMS_LIMIT: usize = 10_000;
+const CRS84: &str = "http://www.opengis.net/def/crs/OGC/1.3/CRS84";
+const EPSG_PREFIX: &str = "http://www.opengis.net/def/crs/EPSG/0/";
+/// Query parameters that are not property filters
+const ITEMS_PARAMS: &[&str] = &["bbox", "limit", "offset", "datetime", "crs", "f"];
+
+#[derive(Deserialize)]
+struct CollectionRequest {
+    collection_id: String,
+}
+
+#[route("/conformance", method = "GET", method = "HEAD")]
+#[allow(clippy::unused_async)]
+async fn get_conformance() -> HttpResponse {
+    HttpResponse::Ok().json(json!({
+        "conformsTo": [
+            "http://www.opengis.net/spec/ogcapi-features-1/1.0/conf/core",
+            "http://www.opengis.net/spec/ogcapi-features-1/1.0/conf/geojson",
+            "http://www.opengis.net/spec/ogcapi-features-2/1.0/conf/crs",
+        ]
+    }))
+}
+
+#[route(
+    "/collections",
+    method = "GET",
+    method = "HEAD",
+    wrap = "middleware::Compress::default()"
+)]
+#[allow(clippy::unused_async)]
+async fn get_collections(
+    req: HttpRequest,
+    srv_config: Data<SrvConfig>,
+    sources: Data<SharedTileSources>,
+) -> HttpResponse {
+    let base_url = get_base_url(&req, &srv_config);
+    let sources = sources.load();
+    let mut collections: Vec<_> = sources
+        .iter()
+        .filter_map(|src| collection_info(src, &base_url))
+        .collect();
+    collections.sort_by(|a, b| a["id"].as_str().cmp(&b["id"].as_str()));
+    HttpResponse::Ok().json(json!({
+        "links": [{
+            "href": format!("{base_url}/collections"),
+            "rel": "self",
+            "type": "application/json",
+        }],
+        "collections": collections,
+    }))
+}
+
+#[route(
+    "/collections/{collection_id}",
+    method = "GET",
+    method = "HEAD",
+    wrap = "middleware::Compress::default()"
+)]
+#[allow(clippy::unused_async)]
+async fn get_collection(
+    req: HttpRequest,
+    path: Path<CollectionRequest>,
+    srv_config: Data<SrvConfig>,
+    sources: Data<SharedTileSources>,
+) -> ActixResult<HttpResponse> {
+    let base_url = get_base_url(&req, &srv_config);
+    let sources = sources.load();
+    let src = sources.get_source(&path.collection_id)?;
+    let info = collection_info(src, &base_url).ok_or_else(|| not_a_collection(src))?;
+    Ok(HttpResponse::Ok().json(info))
+}
+
+#[route(
+    "/collections/{collection_id}/items",
+    method = "GET",
+    wrap = "middleware::Compress::default()"
+)]
+async fn get_items(
+    req: HttpRequest,
+    path: Path<CollectionRequest>,
+    srv_config: Data<SrvConfig>,
+    sources: Data<SharedTileSources>,
+) -> ActixResult<HttpResponse> {
+    let sources = sources.load();
+    let src = sources.get_source(&path.collection_id)?;
+    let srids = src.get_items_srids().ok_or_else(|| not_a_collection(src))?;
+
+    let url_query = Query::<UrlQuery>::from_query(req.query_string())?.into_inner();
+    let context_params = src.get_request_query_params();
+    let (query, crs) =
+        parse_items_query(&url_query, &srids, &context_params).map_err(ErrorBadRequest)?;
+    let context = src
+        .get_request_context(&RequestAttributes::from_request(&req))
+        .map_err(map_tile_error)?;
+    let items = src
+        .get_items(&query, context.as_ref())
+        .await
+        .map_err(map_tile_error)?
+        .ok_or_else(|| not_a_collection(src))?;
+
+    // Links to the other pages keep all query parameters except the offset
+    let items_url = format!(
+        "{}/collections/{}/items",
+        get_base_url(&req, &srv_config),
+        path.collection_id
+    );
+    let params: Vec<_> = req
+        .query_string()
+        .split('&')
+        .filter(|v| !v.is_empty() && !v.starts_with("offset="))
+        .map(ToString::to_string)
+        .collect();
+    // Without a stable order, other pages could repeat or skip features, so they are not linked
+    let paged = src.has_stable_items_order();
+    let page_url = move |offset: usize| {
+        let mut params = params.clone();
+        if offset > 0 {
+            params.push(format!("offset={offset}"));
+        }
+        if params.is_empty() {
+            items_url.clone()
+        } else {
+            format!("{items_url}?{}", params.join("&"))
+        }
+    };
+
+    let count = Rc::new(Cell::new(0_usize));
+    let header = stream::once(async {
+        Ok(Bytes::from_static(
+            br#"{"type":"FeatureCollection","features":["#,
+        ))
+    });
+    let features = items.map({
+        let count = count.clone();
+        move |item| {
+            let item = item.map_err(map_internal_error)?;
+            let separator = if count.get() == 0 { "" } else { "," };
+            count.set(count.get() + 1);
+            Ok::<_, actix_web::Error>(Bytes::from(format!("{separator}{item}")))
+        }
+    });
+    let footer = stream::once(async move {
+        let returned = count.get();
+        let mut links = vec![json!({
+            "href": page_url(query.offset),
+            "rel": "self",
+            "type": "application/geo+json",
+        })];
+        if paged && query.offset > 0 {
+            links.push(json!({
+                "href": page_url(query.offset.saturating_sub(query.limit)),
+                "rel": "prev",
+                "type": "application/geo+json",
+            }));
+        }
+        if paged && returned == query.limit {
+            links.push(json!({
+                "href": page_url(query.offset + query.limit),
+                "rel": "next",
+                "type": "application/geo+json",
+            }));
+        }
+        let links = Value::Array(links);
+        Ok(Bytes::from(format!(
+            r#"],"numberReturned":{returned},"links":{links}}}"#
+        )))
+    });
+
+    let mut response = HttpResponse::Ok();
+    set_request_headers_vary(&mut response, [src]);
+    Ok(response
+        .insert_header((CONTENT_TYPE, "application/geo+json"))
+        .insert_header(("Content-Crs", format!("<{crs}>")))
+        .streaming(header.chain(features).chain(footer)))
+}
+
+/// Parse the `items` query parameters, returning the query and the URI of the response CRS.
+/// The parameters read by the request context are not used as the property filters.
+fn parse_items_query(
+    query: &UrlQuery,
+    srids: &[i32],
+    context_params: &[String],
+) -> Result<(ItemsQuery, String), String> {
+    let parse_usize = |name: &str| {
+        query
+            .get(name)
+            .map(|v| v.parse::<usize>())
+            .transpose()
+            .map_err(|e| format!("Invalid {name} parameter: {e}"))
+    };
+    let limit = parse_usize("limit")?
+        .unwrap_or(DEFAULT_ITEMS_LIMIT)
+        .min(MAX_ITEMS_LIMIT);
+    let offset = parse_usize("offset")?.unwrap_or_default();
+
+    let bbox = if let Some(bbox) = query.get("bbox") {
+        let values = bbox
+            .split(',')
+            .map(|v| v.trim().parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Invalid bbox '{bbox}': {e}"))?;
+        let [left, bottom, right, top] = values[..] else {
+            return Err(format!(
+                "Invalid bbox '{bbox}', expected left,bottom,right,top"
+            ));
+        };
+        Some([left, bottom, right, top])
+    } else {
+        None
+    };
+
+    let datetime = query.get("datetime").map(|v| {
+        let value = |v: &str| (!v.is_empty() && v != "..").then(|| v.to_string());
+        match v.split_once('/') {
+            Some((start, end)) => (value(start), value(end)),
+            None => (value(v), value(v)),
+        }
+    });
+
+    let (srid, crs) = match query.get("crs").map(String::as_str) {
+        None | Some(CRS84) => (4326, CRS84.to_string()),
+        Some(crs) => {
+            let srid = crs
+                .strip_prefix(EPSG_PREFIX)
+                .and_then(|v| v.parse::<i32>().ok())
+                .filter(|v| *v != 4326 && srids.contains(v))
+                .ok_or_else(|| format!("Unsupported crs {crs}"))?;
+            (srid, crs.to_string())
+        }
+    };
+
+    let mut properties: Vec<_> = query
+        .iter()
+        .filter(|(k, _)| !ITEMS_PARAMS.contains(&k.as_str()) && !context_params.contains(k))
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+    properties.sort();
+
+    Ok((
+        ItemsQuery {
+            bbox,
+            limit,
+            offset,
+            datetime,
+            properties,
+            srid,
+        },
+        crs,
+    ))
+}
+
+fn collection_info(src: &dyn Source, base_url: &str) -> Option<Value> {
+    let srids = src.get_items_srids()?;
+    let id = src.get_id();
+    let tilejson = src.get_tilejson();
+    let crs: Vec<_> = srids
+        .iter()
+        .map(|srid| {
+            if *srid == 4326 {
+                CRS84.to_string()
+            } else {
+                format!("{EPSG_PREFIX}{srid}")
+            }
+        })
+        .collect();
+    let mut info = json!({
+        "id": id,
+        "title": tilejson.name.as_deref().unwrap_or(id),
+        "itemType": "feature",
+        "crs": crs,
+        "links": [
+            {
+                "href": format!("{base_url}/collections/{id}"),
+                "rel": "self",
+                "type": "application/json",
+            },
+            {
+                "href": format!("{base_url}/collections/{id}/items"),
+                "rel": "items",
+                "type": "application/geo+json",
+            },
+        ],
+    });
+    if let Some(description) = &tilejson.description {
+        info["description"] = json!(description);
+    }
+    if let Some(bounds) = tilejson.bounds {
+        info["extent"]["spatial"] = json!({
+            "bbox": [[bounds.left, bounds.bottom, bounds.right, bounds.top]],
+            "crs": CRS84,
+        });
+    }
+    if let Some(extent) = tilejson.other.get("temporal_extent") {
+        info["extent"]["temporal"] = json!({ "interval": [extent] });
+    }
+    Some(info)
+}
+
+fn get_base_url(req: &HttpRequest, srv_config: &SrvConfig) -> String {
+    let info = req.connection_info();
+    let base_path = srv_config.base_path.as_deref().unwrap_or_default();
+    format!("{}://{}{base_path}", info.scheme(), info.host())
+}
+
+fn not_a_collection(src: &dyn Source) -> actix_web::Error {
+    ErrorNotFound(format!(
+        "Source {} is not a feature collection",
+        src.get_id()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(query: &[(&str, &str)]) -> Result<(ItemsQuery, String), String> {
+        let query = query
+            .iter()
+            .map(|(k, v)| ((*k).to_string(), (*v).to_string()))
+            .collect();
+        parse_items_query(&query, &[4326, 3857], &[])
+    }
+
+    #[test]
+    fn items_query() {
+        let (query, crs) = parse(&[]).unwrap();
+        assert_eq!(crs, CRS84);
+        assert_eq!(
+            query,
+            ItemsQuery {
+                limit: DEFAULT_ITEMS_LIMIT,
+                srid: 4326,
+                ..Default::default()
+            }
+        );
+
+        let (query, crs) = parse(&[
+            ("bbox", "-10,-5,10,5"),
+            ("limit", "1000000"),
+            ("offset", "20"),
+            ("datetime", "2024-01-01T00:00:00Z/.."),
+            ("crs", "http://www.opengis.net/def/crs/EPSG/0/3857"),
+            ("kind", "fire"),
+        ])
+        .unwrap();
+        assert_eq!(crs, "http://www.opengis.net/def/crs/EPSG/0/3857");
+        assert_eq!(
+            query,
+            ItemsQuery {
+                bbox: Some([-10.0, -5.0, 10.0, 5.0]),
+                limit: MAX_ITEMS_LIMIT,
+                offset: 20,
+                datetime: Some((Some("2024-01-01T00:00:00Z".to_string()), None)),
+                properties: vec![("kind".to_string(), "fire".to_string())],
+                srid: 3857,
+            }
+        );
+
+        let (query, _) = parse(&[("datetime", "2024-01-01")]).unwrap();
+        let day = Some("2024-01-01".to_string());
+        assert_eq!(query.datetime, Some((day.clone(), day)));
+    }
+
+    #[test]
+    fn items_query_context_params() {
+        let query = [("region", "north"), ("kind", "fire")]
+            .iter()
+            .map(|(k, v)| ((*k).to_string(), (*v).to_string()))
+            .collect();
+        let (query, _) = parse_items_query(&query, &[4326], &["region".to_string()]).unwrap();
+        assert_eq!(
+            query.properties,
+            vec![("kind".to_string(), "fire".to_string())]
+        );
+    }
+
+    #[test]
+    fn items_query_errors() {
+        assert!(parse(&[("bbox", "1,2,3")]).is_err());
+        assert!(parse(&[("limit", "-1")]).is_err());
+        assert!(parse(&[("crs", "http://www.opengis.net/def/crs/EPSG/0/2193")]).is_err());
+        assert!(parse(&[("crs", "EPSG:3857")]).is_err());
+    }
+}
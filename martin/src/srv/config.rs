@@ -13,6 +13,8 @@ pub struct SrvConfig {
     pub base_path: Option<String>,
     pub worker_processes: Option<usize>,
     pub preferred_encoding: Option<PreferredEncoding>,
+    /// Bearer token required by the `/admin` endpoints, which are disabled if not set
+    pub admin_token: Option<String>,
     #[cfg(feature = "webui")]
     pub web_ui: Option<crate::args::WebUiMode>,
 }
@@ -22,7 +24,9 @@ mod tests {
     use indoc::indoc;
 
     use super::*;
+    use crate::config::tests::parse_cfg;
     use crate::test_utils::some;
+    use crate::MartinError::EmptyAdminToken;
 
     #[test]
     fn parse_config() {
@@ -73,4 +77,10 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn reject_empty_admin_token() {
+        let mut config = parse_cfg("admin_token: ' '");
+        assert!(matches!(config.finalize(), Err(EmptyAdminToken)));
+    }
 }
@@ -0,0 +1,164 @@
+use actix_web::error::ErrorBadRequest;
+use actix_web::web::{Data, Path, Query};
+use actix_web::{middleware, route, HttpRequest, HttpResponse, Result as ActixResult};
+use martin_tile_utils::{wgs84_to_webmercator, EARTH_CIRCUMFERENCE};
+use serde::Deserialize;
+
+use crate::source::{RequestAttributes, SharedTileSources, UrlQuery};
+use crate::srv::tiles::{map_tile_error, set_request_headers_vary};
+
+/// Lookup radius in pixels if not given in the request
+const DEFAULT_RADIUS_PX: f64 = 5.0;
+/// Tile size used by the map clients to compute the zoom level
+const CLIENT_TILE_SIZE: f64 = 512.0;
+/// Latitude limit of the Web Mercator projection
+const MAX_LATITUDE: f64 = 85.051_128_779_806_59;
+
+#[derive(Deserialize)]
+struct FeatureRequest {
+    source_id: String,
+}
+
+/// Either a point with a radius in pixels at the given zoom, or a `bbox=left,bottom,right,top`
+#[derive(Deserialize, Debug, Default)]
+struct FeatureQuery {
+    lon: Option<f64>,
+    lat: Option<f64>,
+    radius_px: Option<f64>,
+    z: Option<f64>,
+    bbox: Option<String>,
+    limit: Option<usize>,
+}
+
+impl FeatureQuery {
+    /// Compute the Web Mercator bounding box to search
+    fn to_bbox(&self) -> Result<[f64; 4], String> {
+        if let Some(bbox) = &self.bbox {
+            let values = bbox
+                .split(',')
+                .map(|v| v.trim().parse::<f64>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| format!("Invalid bbox '{bbox}': {e}"))?;
+            let [left, bottom, right, top] = values[..] else {
+                return Err(format!(
+                    "Invalid bbox '{bbox}', expected left,bottom,right,top"
+                ));
+            };
+            if left > right || bottom > top {
+                return Err(format!(
+                    "Invalid bbox '{bbox}', expected left,bottom,right,top"
+                ));
+            }
+            let (left, bottom) = to_mercator(left, bottom);
+            let (right, top) = to_mercator(right, top);
+            return Ok([left, bottom, right, top]);
+        }
+
+        let (Some(lon), Some(lat), Some(z)) = (self.lon, self.lat, self.z) else {
+            return Err("Either bbox, or lon, lat and z parameters are required".to_string());
+        };
+        let radius_px = self.radius_px.unwrap_or(DEFAULT_RADIUS_PX);
+        if !(0.0..=30.0).contains(&z) || radius_px < 0.0 {
+            return Err(format!("Invalid zoom {z} or radius {radius_px}"));
+        }
+        let radius = radius_px * EARTH_CIRCUMFERENCE / CLIENT_TILE_SIZE / 2_f64.powf(z);
+        let (x, y) = to_mercator(lon, lat);
+        Ok([x - radius, y - radius, x + radius, y + radius])
+    }
+}
+
+fn to_mercator(lon: f64, lat: f64) -> (f64, f64) {
+    wgs84_to_webmercator(
+        lon.clamp(-180.0, 180.0),
+        lat.clamp(-MAX_LATITUDE, MAX_LATITUDE),
+    )
+}
+
+#[route(
+    "/{source_id}/features",
+    method = "GET",
+    wrap = "middleware::Compress::default()"
+)]
+async fn get_features(
+    req: HttpRequest,
+    path: Path<FeatureRequest>,
+    query: Query<FeatureQuery>,
+    sources: Data<SharedTileSources>,
+) -> ActixResult<HttpResponse> {
+    let bbox = query.to_bbox().map_err(ErrorBadRequest)?;
+    // Other parameters, e.g. the datetime, filter the features the same way as the tiles
+    let url_query = Query::<UrlQuery>::from_query(req.query_string())?.into_inner();
+    let sources = sources.load();
+    let src = sources.get_source(&path.source_id)?;
+    let context = src
+        .get_request_context(&RequestAttributes::from_request(&req))
+        .map_err(map_tile_error)?;
+    let features = src
+        .get_features(bbox, query.limit, Some(&url_query), context.as_ref())
+        .await
+        .map_err(map_tile_error)?
+        .ok_or_else(|| {
+            ErrorBadRequest(format!(
+                "Source {} does not support feature lookups",
+                path.source_id
+            ))
+        })?;
+    let mut response = HttpResponse::Ok();
+    set_request_headers_vary(&mut response, [src]);
+    Ok(response.content_type("application/geo+json").json(features))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bbox_query() {
+        let query = FeatureQuery {
+            bbox: Some("-180,-90,180,90".to_string()),
+            ..Default::default()
+        };
+        let [left, bottom, right, top] = query.to_bbox().unwrap();
+        let half = EARTH_CIRCUMFERENCE / 2.0;
+        assert!((left + half).abs() < 1e-6);
+        assert!((bottom + half).abs() < 1.0);
+        assert!((right - half).abs() < 1e-6);
+        assert!((top - half).abs() < 1.0);
+
+        let query = FeatureQuery {
+            bbox: Some("1,2,3".to_string()),
+            ..Default::default()
+        };
+        assert!(query.to_bbox().is_err());
+
+        let query = FeatureQuery {
+            bbox: Some("3,2,1,4".to_string()),
+            ..Default::default()
+        };
+        assert!(query.to_bbox().is_err());
+    }
+
+    #[test]
+    fn point_query() {
+        let query = FeatureQuery {
+            lon: Some(0.0),
+            lat: Some(0.0),
+            radius_px: Some(128.0),
+            z: Some(0.0),
+            ..Default::default()
+        };
+        let [left, bottom, right, top] = query.to_bbox().unwrap();
+        let quarter = EARTH_CIRCUMFERENCE / 4.0;
+        assert!((left + quarter).abs() < 1e-6);
+        assert!((bottom + quarter).abs() < 1e-6);
+        assert!((right - quarter).abs() < 1e-6);
+        assert!((top - quarter).abs() < 1e-6);
+
+        let query = FeatureQuery {
+            lon: Some(0.0),
+            lat: Some(0.0),
+            ..Default::default()
+        };
+        assert!(query.to_bbox().is_err());
+    }
+}
@@ -1,6 +1,10 @@
 mod config;
 pub use config::{SrvConfig, KEEP_ALIVE_DEFAULT, LISTEN_ADDRESSES_DEFAULT};
 
+mod admin;
+mod collections;
+mod features;
+
 #[cfg(feature = "fonts")]
 mod fonts;
 
@@ -14,7 +14,7 @@ use serde::{Deserialize, Serialize};
 #[cfg(feature = "webui")]
 use crate::args::WebUiMode;
 use crate::config::ServerState;
-use crate::source::TileCatalog;
+use crate::source::{SharedTileSources, TileCatalog};
 use crate::srv::config::{SrvConfig, KEEP_ALIVE_DEFAULT, LISTEN_ADDRESSES_DEFAULT};
 use crate::srv::tiles::get_tile;
 use crate::srv::tiles_info::get_source_info;
@@ -95,7 +95,10 @@ async fn get_health() -> impl Responder {
     wrap = "middleware::Compress::default()"
 )]
 #[allow(clippy::unused_async)]
-async fn get_catalog(catalog: Data<Catalog>) -> impl Responder {
+async fn get_catalog(catalog: Data<Catalog>, sources: Data<SharedTileSources>) -> impl Responder {
+    // Tile sources may change while the server is running
+    let mut catalog = catalog.as_ref().clone();
+    catalog.tiles = sources.get_catalog();
     HttpResponse::Ok().json(catalog)
 }
 
@@ -116,9 +119,20 @@ async fn post_add_source(
 
 // Configure the web service routes
 pub fn router(cfg: &mut web::ServiceConfig, #[allow(unused_variables)] usr_cfg: &SrvConfig) {
+    // Must be registered before the tile route, which matches the same number of path segments
+    if usr_cfg.admin_token.is_some() {
+        cfg.service(crate::srv::admin::get_source_sql)
+            .service(crate::srv::admin::get_source_explain);
+    }
+
     cfg.service(get_health)
         .service(get_catalog)
+        .service(crate::srv::collections::get_conformance)
+        .service(crate::srv::collections::get_collections)
+        .service(crate::srv::collections::get_collection)
+        .service(crate::srv::collections::get_items)
         .service(get_source_info)
+        .service(crate::srv::features::get_features)
         .service(get_tile)
         .service(post_add_source); // Add the new POST route here
 
@@ -135,6 +149,12 @@ pub fn router(cfg: &mut web::ServiceConfig, #[allow(unused_variables)] usr_cfg:
     }
 }
 
+#[cfg(not(feature = "webui"))]
+#[allow(clippy::unused_async)]
+async fn get_index_no_ui() -> HttpResponse {
+    HttpResponse::Ok().finish()
+}
+
 // Create a new server with Actix-web
 pub fn new_server(config: SrvConfig, state: ServerState) -> MartinResult<(Server, String)> {
     let catalog = Catalog::new(&state)?;
@@ -212,7 +232,7 @@ pub mod tests {
         }
 
         fn clone_source(&self) -> Box<dyn Source> {
-            unimplemented!()
+            Box::new(self.clone())
         }
 
         async fn get_tile(
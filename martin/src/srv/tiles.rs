@@ -1,25 +1,32 @@
+use std::collections::BTreeSet;
+
 use actix_http::header::Quality;
 use actix_http::ContentEncoding;
 use actix_web::error::{ErrorBadRequest, ErrorNotAcceptable, ErrorNotFound};
 use actix_web::http::header::{
-    AcceptEncoding, Encoding as HeaderEnc, Preference, CONTENT_ENCODING,
+    AcceptEncoding, ETag, Encoding as HeaderEnc, EntityTag, Preference, CACHE_CONTROL,
+    CONTENT_ENCODING, VARY,
 };
 use actix_web::web::{Data, Path, Query};
-use actix_web::{route, HttpMessage, HttpRequest, HttpResponse, Result as ActixResult};
+use actix_web::{
+    route, HttpMessage, HttpRequest, HttpResponse, HttpResponseBuilder, Result as ActixResult,
+};
 use futures::future::try_join_all;
+use itertools::Itertools as _;
 use log::trace;
 use martin_tile_utils::{
-    decode_brotli, decode_gzip, encode_brotli, encode_gzip, Encoding, Format, TileCoord, TileInfo,
+    decode_brotli, decode_gzip, decode_zlib, decode_zstd, encode_brotli, encode_gzip, Encoding,
+    Format, TileCoord, TileInfo,
 };
 use serde::Deserialize;
 
 use crate::args::PreferredEncoding;
-use crate::source::{Source, TileSources, UrlQuery};
+use crate::source::{RequestAttributes, SharedTileSources, Source, TileSources, UrlQuery};
 use crate::srv::server::map_internal_error;
 use crate::srv::SrvConfig;
 use crate::utils::cache::get_or_insert_cached_value;
-use crate::utils::{CacheKey, CacheValue, MainCache, OptMainCache};
-use crate::{Tile, TileData};
+use crate::utils::{CacheKey, CacheValue, MainCache, MartinError, OptMainCache};
+use crate::Tile;
 
 static SUPPORTED_ENC: &[HeaderEnc] = &[
     HeaderEnc::gzip(),
@@ -40,18 +47,20 @@ async fn get_tile(
     req: HttpRequest,
     srv_config: Data<SrvConfig>,
     path: Path<TileRequest>,
-    sources: Data<TileSources>,
+    sources: Data<SharedTileSources>,
     cache: Data<OptMainCache>,
 ) -> ActixResult<HttpResponse> {
+    let sources = sources.load();
     let src = DynTileSource::new(
-        sources.as_ref(),
+        &sources,
         &path.source_ids,
         Some(path.z),
         req.query_string(),
         req.get_header::<AcceptEncoding>(),
         srv_config.preferred_encoding,
         cache.as_ref().as_ref(),
-    )?;
+    )?
+    .with_request(RequestAttributes::from_request(&req));
 
     src.get_http_response(TileCoord {
         z: path.z,
@@ -69,6 +78,8 @@ pub struct DynTileSource<'a> {
     pub accept_enc: Option<AcceptEncoding>,
     pub preferred_enc: Option<PreferredEncoding>,
     pub cache: Option<&'a MainCache>,
+    /// Request attributes the sources may pass to their data stores
+    pub request: RequestAttributes,
 }
 
 impl<'a> DynTileSource<'a> {
@@ -102,47 +113,75 @@ impl<'a> DynTileSource<'a> {
             accept_enc,
             preferred_enc,
             cache,
+            request: RequestAttributes::default(),
         })
     }
 
+    #[must_use]
+    pub fn with_request(self, request: RequestAttributes) -> Self {
+        Self { request, ..self }
+    }
+
     pub async fn get_http_response(&self, xyz: TileCoord) -> ActixResult<HttpResponse> {
         let tile = self.get_tile_content(xyz).await?;
 
         Ok(if tile.data.is_empty() {
-            HttpResponse::NoContent().finish()
+            let mut response = HttpResponse::NoContent();
+            set_request_headers_vary(&mut response, self.sources.iter().copied());
+            response.finish()
         } else {
             let mut response = HttpResponse::Ok();
+            set_request_headers_vary(&mut response, self.sources.iter().copied());
             response.content_type(tile.info.format.content_type());
             if let Some(val) = tile.info.encoding.content_encoding() {
                 response.insert_header((CONTENT_ENCODING, val));
             }
+            if let Some(etag) = tile.etag.filter(|v| is_valid_etag(v)) {
+                // the same content may be sent with different encodings, so the ETag is weak
+                response.insert_header(ETag(EntityTag::new_weak(etag)));
+            }
             response.body(tile.data)
         })
     }
 
     pub async fn get_tile_content(&self, xyz: TileCoord) -> ActixResult<Tile> {
         let mut tiles = try_join_all(self.sources.iter().map(|s| async {
+            let context = s.get_request_context(&self.request)?;
             get_or_insert_cached_value!(
                 self.cache,
                 CacheValue::Tile,
-                s.get_tile(xyz, self.query_obj.as_ref()),
+                s.get_tile_with_etag(xyz, self.query_obj.as_ref(), context.as_ref()),
                 {
                     let id = s.get_id().to_string();
-                    if let Some(query_str) = self.query_str {
-                        CacheKey::TileWithQuery(id, xyz, query_str.to_string())
-                    } else {
-                        CacheKey::Tile(id, xyz)
+                    let query_str = self.query_str.map(ToString::to_string);
+                    match (context.clone(), query_str) {
+                        (Some(context), query_str) => {
+                            CacheKey::TileWithContext(id, xyz, query_str, context)
+                        }
+                        (None, Some(query_str)) => CacheKey::TileWithQuery(id, xyz, query_str),
+                        (None, None) => CacheKey::Tile(id, xyz),
                     }
                 }
             )
         }))
         .await
-        .map_err(map_internal_error)?;
+        .map_err(map_tile_error)?;
+
+        // Some sources only know their tile format after the first tile has been retrieved
+        let info = match self.sources.as_slice() {
+            [src] => src.get_tile_info(),
+            _ => self.info,
+        };
+        // A content hash is only meaningful if the tile comes from a single source
+        let etag = match tiles.as_mut_slice() {
+            [tile] => tile.etag.take(),
+            _ => None,
+        };
 
         let mut layer_count = 0;
         let mut last_non_empty_layer = 0;
         for (idx, tile) in tiles.iter().enumerate() {
-            if !tile.is_empty() {
+            if !tile.data.is_empty() {
                 layer_count += 1;
                 last_non_empty_layer = idx;
             }
@@ -150,28 +189,29 @@ impl<'a> DynTileSource<'a> {
 
         // Minor optimization to prevent concatenation if there are less than 2 tiles
         let data = match layer_count {
-            1 => tiles.swap_remove(last_non_empty_layer),
-            0 => return Ok(Tile::new(Vec::new(), self.info)),
+            1 => tiles.swap_remove(last_non_empty_layer).data,
+            0 => return Ok(Tile::new(Vec::new(), info)),
             _ => {
                 // Make sure tiles can be concatenated, or if not, that there is only one non-empty tile for each zoom level
                 // TODO: can zlib, brotli, or zstd be concatenated?
                 // TODO: implement decompression step for other concatenate-able formats
-                let can_join = self.info.format == Format::Mvt
-                    && (self.info.encoding == Encoding::Uncompressed
-                        || self.info.encoding == Encoding::Gzip);
+                let can_join = info.format == Format::Mvt
+                    && (info.encoding == Encoding::Uncompressed || info.encoding == Encoding::Gzip);
                 if !can_join {
                     return Err(ErrorBadRequest(format!(
                         "Can't merge {} tiles. Make sure there is only one non-empty tile source at zoom level {}",
-                        self.info,
+                        info,
                         xyz.z
                     )))?;
                 }
-                tiles.concat()
+                tiles.into_iter().flat_map(|t| t.data).collect()
             }
         };
 
         // decide if (re-)encoding of the tile data is needed, and recompress if so
-        self.recompress(data)
+        let mut tile = self.recompress(Tile::new(data, info))?;
+        tile.etag = etag;
+        Ok(tile)
     }
 
     /// Decide which encoding to use for the uncompressed tile data, based on the client's Accept-Encoding header
@@ -217,10 +257,9 @@ impl<'a> DynTileSource<'a> {
         }
     }
 
-    fn recompress(&self, tile: TileData) -> ActixResult<Tile> {
-        let mut tile = Tile::new(tile, self.info);
+    fn recompress(&self, mut tile: Tile) -> ActixResult<Tile> {
         if let Some(accept_enc) = &self.accept_enc {
-            if self.info.encoding.is_encoded() {
+            if tile.info.encoding.is_encoded() {
                 // already compressed, see if we can send it as is, or need to re-compress
                 if !accept_enc.iter().any(|e| {
                     if let Preference::Specific(HeaderEnc::Known(enc)) = e.item {
@@ -274,6 +313,14 @@ fn decode(tile: Tile) -> ActixResult<Tile> {
                 decode_brotli(&tile.data)?,
                 info.encoding(Encoding::Uncompressed),
             ),
+            Encoding::Zlib => Tile::new(
+                decode_zlib(&tile.data)?,
+                info.encoding(Encoding::Uncompressed),
+            ),
+            Encoding::Zstd => Tile::new(
+                decode_zstd(&tile.data)?,
+                info.encoding(Encoding::Uncompressed),
+            ),
             _ => Err(ErrorBadRequest(format!(
                 "Tile is is stored as {info}, but the client does not accept this encoding"
             )))?,
@@ -293,13 +340,61 @@ pub fn to_encoding(val: ContentEncoding) -> Option<Encoding> {
     })
 }
 
+/// Check that the value can be used as an entity tag without escaping
+fn is_valid_etag(value: &str) -> bool {
+    value
+        .bytes()
+        .all(|c| c == b'!' || (b'#'..=b'~').contains(&c))
+}
+
+/// Responses that depend on request headers, e.g. the identity of the user,
+/// must only be cached by the client, and only for the same header values.
+pub fn set_request_headers_vary<'a>(
+    response: &mut HttpResponseBuilder,
+    sources: impl IntoIterator<Item = &'a dyn Source>,
+) {
+    let headers: BTreeSet<String> = sources
+        .into_iter()
+        .flat_map(Source::get_request_headers)
+        .collect();
+    if !headers.is_empty() {
+        response.insert_header((CACHE_CONTROL, "private"));
+        response.append_header((VARY, headers.iter().join(", ")));
+    }
+}
+
+pub fn map_tile_error(e: MartinError) -> actix_web::Error {
+    #[cfg(feature = "postgres")]
+    match e {
+        MartinError::PostgresError(crate::pg::PgError::GetTileTimeout(..)) => {
+            log::warn!("{e}");
+            return actix_web::error::ErrorGatewayTimeout(e.to_string());
+        }
+        MartinError::PostgresError(
+            crate::pg::PgError::InvalidUrlParam(..) | crate::pg::PgError::InvalidItemsQuery(..),
+        ) => {
+            return ErrorBadRequest(e.to_string());
+        }
+        MartinError::PostgresError(crate::pg::PgError::InvalidRequestContext(..)) => {
+            return actix_web::error::ErrorUnauthorized(e.to_string());
+        }
+        _ => {}
+    }
+    map_internal_error(e)
+}
+
 #[cfg(test)]
 mod tests {
     use rstest::rstest;
     use tilejson::tilejson;
 
+    use std::collections::HashSet;
+
     use super::*;
+    use crate::source::RequestContext;
     use crate::srv::server::tests::TestSource;
+    use crate::utils::invalidate_source_tiles;
+    use crate::TileWithEtag;
 
     #[actix_rt::test]
     async fn test_deleteme() {
@@ -378,4 +473,149 @@ mod tests {
             assert_eq!(expected, &src.get_tile_content(xyz).await.unwrap().data);
         }
     }
+
+    #[test]
+    fn test_recompress_uses_tile_encoding() {
+        let sources = TileSources::new(vec![vec![Box::new(TestSource {
+            id: "test_source",
+            tj: tilejson! { tiles: vec![] },
+            data: Vec::default(),
+        })]]);
+        let accept_enc = Some(AcceptEncoding(vec!["br".parse().unwrap()]));
+        let src =
+            DynTileSource::new(&sources, "test_source", None, "", accept_enc, None, None).unwrap();
+
+        // The source info is not yet aware that its tiles are gzip-compressed
+        let info = TileInfo::new(Format::Mvt, Encoding::Gzip);
+        let tile = Tile::new(encode_gzip(&[1_u8, 2, 3]).unwrap(), info);
+        let tile = src.recompress(tile).unwrap();
+        assert_eq!(tile.info.encoding, Encoding::Brotli);
+        assert_eq!(decode_brotli(&tile.data).unwrap(), vec![1_u8, 2, 3]);
+    }
+
+    /// A source reading the request context from the `authorization` header
+    #[derive(Debug, Clone)]
+    struct PrivateSource(TestSource);
+
+    #[async_trait::async_trait]
+    impl Source for PrivateSource {
+        fn get_id(&self) -> &str {
+            self.0.get_id()
+        }
+
+        fn get_tilejson(&self) -> &tilejson::TileJSON {
+            self.0.get_tilejson()
+        }
+
+        fn get_tile_info(&self) -> TileInfo {
+            self.0.get_tile_info()
+        }
+
+        fn clone_source(&self) -> Box<dyn Source> {
+            Box::new(self.clone())
+        }
+
+        async fn get_tile(
+            &self,
+            xyz: TileCoord,
+            url_query: Option<&UrlQuery>,
+        ) -> crate::MartinResult<crate::source::TileData> {
+            self.0.get_tile(xyz, url_query).await
+        }
+
+        fn get_request_headers(&self) -> Vec<String> {
+            vec!["authorization".to_string()]
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_private_response_headers() {
+        let source = |id| TestSource {
+            id,
+            tj: tilejson! { tiles: vec![] },
+            data: vec![1_u8, 2, 3],
+        };
+        let sources = TileSources::new(vec![vec![
+            Box::new(source("public")),
+            Box::new(PrivateSource(source("private"))),
+        ]]);
+        let xyz = TileCoord { z: 0, x: 0, y: 0 };
+
+        let src = DynTileSource::new(&sources, "public", None, "", None, None, None).unwrap();
+        let response = src.get_http_response(xyz).await.unwrap();
+        assert!(response.headers().get(CACHE_CONTROL).is_none());
+        assert!(response.headers().get(VARY).is_none());
+
+        let src =
+            DynTileSource::new(&sources, "public,private", None, "", None, None, None).unwrap();
+        let response = src.get_http_response(xyz).await.unwrap();
+        assert_eq!(response.headers().get(CACHE_CONTROL).unwrap(), "private");
+        assert_eq!(response.headers().get(VARY).unwrap(), "authorization");
+    }
+
+    async fn cached_tile(sources: &SharedTileSources, cache: &MainCache) -> Vec<u8> {
+        let sources = sources.load();
+        let src =
+            DynTileSource::new(&sources, "test_source", None, "", None, None, Some(cache)).unwrap();
+        let xyz = TileCoord { z: 0, x: 0, y: 0 };
+        src.get_tile_content(xyz).await.unwrap().data
+    }
+
+    #[actix_rt::test]
+    async fn test_replaced_source_cache() {
+        let source = |data: u8| -> Box<dyn Source> {
+            Box::new(TestSource {
+                id: "test_source",
+                tj: tilejson! { tiles: vec![] },
+                data: vec![data],
+            })
+        };
+        let sources = SharedTileSources::new(TileSources::new(vec![vec![source(1)]]));
+        let cache = MainCache::builder()
+            .max_capacity(1000)
+            .support_invalidation_closures()
+            .build();
+        assert_eq!(cached_tile(&sources, &cache).await, vec![1]);
+
+        // Tiles cached with a request context belong to the same source
+        let xyz = TileCoord { z: 1, x: 0, y: 0 };
+        let context = RequestContext::from([("user".to_string(), "42".to_string())]);
+        let context_key =
+            || CacheKey::TileWithContext("test_source".to_string(), xyz, None, context.clone());
+        let tile = TileWithEtag {
+            data: vec![1],
+            etag: None,
+        };
+        cache.insert(context_key(), CacheValue::Tile(tile)).await;
+
+        // The cached tile outlives the replaced source until it is invalidated
+        sources.update([], vec![source(2)]);
+        assert_eq!(cached_tile(&sources, &cache).await, vec![1]);
+        invalidate_source_tiles(&cache, HashSet::from(["test_source".to_string()]));
+        assert_eq!(cached_tile(&sources, &cache).await, vec![2]);
+        assert!(cache.get(&context_key()).await.is_none());
+    }
+
+    #[cfg(feature = "postgres")]
+    #[test]
+    fn test_map_pg_tile_errors() {
+        use actix_web::http::StatusCode;
+
+        use crate::pg::PgError;
+
+        let status = |e: PgError| {
+            map_tile_error(MartinError::PostgresError(e))
+                .as_response_error()
+                .status_code()
+        };
+        let err = PgError::InvalidUrlParam(
+            "src".to_string(),
+            "min_gid".to_string(),
+            "the parameter is required".to_string(),
+        );
+        assert_eq!(status(err), StatusCode::BAD_REQUEST);
+
+        let err = PgError::GetTileTimeout("src".to_string(), TileCoord { z: 0, x: 0, y: 0 });
+        assert_eq!(status(err), StatusCode::GATEWAY_TIMEOUT);
+    }
 }
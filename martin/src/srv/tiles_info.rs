@@ -8,7 +8,7 @@ use itertools::Itertools as _;
 use serde::Deserialize;
 use tilejson::{tilejson, TileJSON};
 
-use crate::source::{Source, TileSources};
+use crate::source::{SharedTileSources, Source};
 use crate::srv::SrvConfig;
 
 #[derive(Deserialize)]
@@ -26,9 +26,10 @@ pub struct SourceIDsRequest {
 async fn get_source_info(
     req: HttpRequest,
     path: Path<SourceIDsRequest>,
-    sources: Data<TileSources>,
+    sources: Data<SharedTileSources>,
     srv_config: Data<SrvConfig>,
 ) -> ActixResult<HttpResponse> {
+    let sources = sources.load();
     let sources = sources.get_sources(&path.source_ids, None)?.0;
 
     let tiles_path = if let Some(base_path) = &srv_config.base_path {
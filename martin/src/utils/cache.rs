@@ -1,7 +1,11 @@
+use std::collections::HashSet;
+
+use log::warn;
 use martin_tile_utils::TileCoord;
 use moka::future::Cache;
 
-use crate::TileData;
+use crate::source::RequestContext;
+use crate::TileWithEtag;
 
 pub type MainCache = Cache<CacheKey, CacheValue>;
 pub type OptMainCache = Option<MainCache>;
@@ -15,11 +19,40 @@ pub enum CacheKey {
     Tile(String, TileCoord),
     /// (`source_id`, `xyz`, `url_query`)
     TileWithQuery(String, TileCoord, String),
+    /// (`source_id`, `xyz`, `url_query`, `request_context`)
+    TileWithContext(String, TileCoord, Option<String>, RequestContext),
+}
+
+impl CacheKey {
+    /// ID of the tile source the value was cached for
+    #[must_use]
+    pub fn source_id(&self) -> Option<&str> {
+        match self {
+            Self::PmtDirectory(..) => None,
+            Self::Tile(id, _) | Self::TileWithQuery(id, ..) | Self::TileWithContext(id, ..) => {
+                Some(id)
+            }
+        }
+    }
+}
+
+/// Remove the cached tiles of the given sources, e.g. after the sources were replaced or removed.
+/// The cache must be built with invalidation closures support.
+pub fn invalidate_source_tiles(cache: &MainCache, source_ids: HashSet<String>) {
+    if source_ids.is_empty() {
+        return;
+    }
+    let res = cache.invalidate_entries_if(move |key, _| {
+        key.source_id().is_some_and(|id| source_ids.contains(id))
+    });
+    if let Err(e) = res {
+        warn!("Unable to remove the cached tiles of the changed sources: {e}");
+    }
 }
 
 #[derive(Debug, Clone)]
 pub enum CacheValue {
-    Tile(TileData),
+    Tile(TileWithEtag),
     #[cfg(feature = "pmtiles")]
     PmtDirectory(pmtiles::Directory),
 }
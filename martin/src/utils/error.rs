@@ -37,6 +37,9 @@ pub enum MartinError {
     #[error("Base path must be a valid URL path, and must begin with a '/' symbol, but is '{0}'")]
     BasePathError(String),
 
+    #[error("The admin_token must not be empty")]
+    EmptyAdminToken,
+
     #[error("Unable to load config file {}: {0}", .1.display())]
     ConfigLoadError(io::Error, PathBuf),
 
@@ -1,5 +1,7 @@
 pub(crate) mod cache;
-pub use cache::{CacheKey, CacheValue, MainCache, OptMainCache, NO_MAIN_CACHE};
+pub use cache::{
+    invalidate_source_tiles, CacheKey, CacheValue, MainCache, OptMainCache, NO_MAIN_CACHE,
+};
 
 mod cfg_containers;
 pub use cfg_containers::{OptBoolObj, OptOneMany};
@@ -16,7 +16,7 @@ fn init() {
 #[actix_rt::test]
 async fn function_source_tilejson() {
     let mock = mock_sources(mock_pgcfg("connection_string: $DATABASE_URL")).await;
-    let tj = source(&mock, "function_zxy_query").get_tilejson();
+    let tj = source(&mock, "function_zxy_query").get_tilejson().clone();
     assert_yaml_snapshot!(tj, @r###"
     ---
     tilejson: 3.0.0
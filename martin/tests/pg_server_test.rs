@@ -1,6 +1,7 @@
 #![cfg(feature = "postgres")]
 
 use actix_http::Request;
+use actix_web::http::header::ETAG;
 use actix_web::http::StatusCode;
 use actix_web::test::{call_and_read_body_json, call_service, read_body, TestRequest};
 use ctor::ctor;
@@ -93,6 +94,9 @@ postgres:
       function_zxy2:
         content_type: application/x-protobuf
         description: public.function_zxy2
+      function_zxy_params:
+        content_type: application/x-protobuf
+        description: public.function_zxy_params
       function_zxy_query:
         content_type: application/x-protobuf
       function_zxy_query_jsonb:
@@ -525,6 +529,12 @@ postgres:
 
     let req = test_get("/function_zxy_row_key/6/38/20");
     assert!(call_service(&app, req).await.status().is_success());
+
+    // the second output column is used as the ETag
+    let req = test_get("/function_zxy_row_key/0/0/0");
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers().contains_key(ETAG));
 }
 
 #[actix_rt::test]
@@ -960,6 +970,32 @@ postgres:
     assert_response(response).await;
 }
 
+#[actix_rt::test]
+async fn pg_get_function_source_typed_params() {
+    let app = create_app! { "
+postgres:
+  connection_string: $DATABASE_URL
+"};
+
+    // kinds uses its declared default
+    let req = test_get("/function_zxy_params/0/0/0?min_gid=1");
+    let response = call_service(&app, req).await;
+    assert_response(response).await;
+
+    let req = test_get("/function_zxy_params/0/0/0?min_gid=1&kinds=POINT,LINESTRING");
+    let response = call_service(&app, req).await;
+    assert_response(response).await;
+
+    // min_gid has no default
+    let req = test_get("/function_zxy_params/0/0/0");
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let req = test_get("/function_zxy_params/0/0/0?min_gid=1.5");
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
 #[actix_rt::test]
 async fn pg_get_health_returns_ok() {
     let app = create_app! { "
@@ -1023,7 +1059,7 @@ tables:
     let src = table(&mock, "no_id");
     assert_eq!(src.id_column, None);
     assert!(matches!(&src.properties, Some(v) if v.len() == 1));
-    let tj = source(&mock, "no_id").get_tilejson();
+    let tj = source(&mock, "no_id").get_tilejson().clone();
     assert_yaml_snapshot!(tj, @r###"
     ---
     tilejson: 3.0.0
@@ -3,7 +3,7 @@
 use ctor::ctor;
 use indoc::indoc;
 use insta::assert_yaml_snapshot;
-use martin_tile_utils::TileCoord;
+use martin_tile_utils::{wgs84_to_webmercator, TileCoord};
 pub mod utils;
 pub use utils::*;
 
@@ -53,6 +53,9 @@ async fn table_source() {
     function_zxy2:
       content_type: application/x-protobuf
       description: public.function_zxy2
+    function_zxy_params:
+      content_type: application/x-protobuf
+      description: public.function_zxy_params
     function_zxy_query:
       content_type: application/x-protobuf
     function_zxy_query_jsonb:
@@ -110,7 +113,7 @@ async fn table_source() {
 #[actix_rt::test]
 async fn tables_tilejson() {
     let mock = mock_sources(mock_pgcfg("connection_string: $DATABASE_URL")).await;
-    let tj = source(&mock, "table_source").get_tilejson();
+    let tj = source(&mock, "table_source").get_tilejson().clone();
     assert_yaml_snapshot!(tj, @r###"
     ---
     tilejson: 3.0.0
@@ -141,6 +144,24 @@ async fn tables_tile_ok() {
     assert!(!tile.is_empty());
 }
 
+#[actix_rt::test]
+async fn tables_features_lookup() {
+    let mock = mock_sources(mock_pgcfg("connection_string: $DATABASE_URL")).await;
+    let src = source(&mock, "table_source");
+    let lookup = |lon: f64, lat: f64| {
+        let (x, y) = wgs84_to_webmercator(lon, lat);
+        src.get_features([x, y, x, y], None, None, None)
+    };
+
+    // Inside the bounding box of several polygons and lines, but not touching any of them
+    let features = lookup(11.0, 36.0).await.unwrap().unwrap();
+    assert_eq!(features["features"].as_array().unwrap().len(), 0);
+
+    // Inside POLYGON((30 10, 40 40, 20 40, 10 20, 30 10))
+    let features = lookup(25.0, 30.0).await.unwrap().unwrap();
+    assert!(!features["features"].as_array().unwrap().is_empty());
+}
+
 #[actix_rt::test]
 async fn tables_srid_ok() {
     let mock = mock_sources(mock_pgcfg(indoc! {"
@@ -0,0 +1,490 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fmt::{Display, Formatter};
+
+use enum_display::EnumDisplay;
+use futures::TryStreamExt as _;
+use log::{info, warn};
+use martin_tile_utils::{decode, Encoding, Format};
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use size_format::SizeFormatterBinary;
+use sqlx::{query, Row as _, SqliteExecutor};
+use tilejson::VectorLayer;
+
+use crate::mvt::{decode_layers, LayerContent, MvtValue};
+use crate::{LayerSizeInfo, MbtError, MbtResult, Mbtiles};
+
+/// Number of sample values of each attribute, same as tippecanoe
+const MAX_SAMPLE_VALUES: usize = 100;
+/// Distinct values of each attribute are only counted up to this number
+const MAX_DISTINCT_VALUES: usize = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, EnumDisplay)]
+pub enum GeometryType {
+    Unknown,
+    Point,
+    LineString,
+    Polygon,
+}
+
+impl From<u64> for GeometryType {
+    fn from(value: u64) -> Self {
+        match value {
+            1 => Self::Point,
+            2 => Self::LineString,
+            3 => Self::Polygon,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, EnumDisplay)]
+pub enum AttributeType {
+    String,
+    Number,
+    Boolean,
+}
+
+/// Statistics of a single feature attribute
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct AttributeStats {
+    /// All value types seen for this attribute
+    pub types: BTreeSet<AttributeType>,
+    /// Number of distinct values, counted up to 1000
+    pub distinct_count: usize,
+    /// Up to 100 distinct values, in the order they were found
+    pub values: Vec<Value>,
+    /// Smallest numeric value
+    pub min: Option<f64>,
+    /// Largest numeric value
+    pub max: Option<f64>,
+    #[serde(skip)]
+    distinct: HashSet<String>,
+}
+
+impl AttributeStats {
+    fn add(&mut self, value: &MvtValue) {
+        let (attr_type, key) = match value {
+            MvtValue::String(v) => (AttributeType::String, format!("s{v}")),
+            MvtValue::Number(v) => {
+                self.min = Some(self.min.map_or(*v, |m| m.min(*v)));
+                self.max = Some(self.max.map_or(*v, |m| m.max(*v)));
+                (AttributeType::Number, format!("n{v}"))
+            }
+            MvtValue::Bool(v) => (AttributeType::Boolean, format!("b{v}")),
+        };
+        self.types.insert(attr_type);
+        if self.distinct.len() < MAX_DISTINCT_VALUES && self.distinct.insert(key) {
+            self.distinct_count = self.distinct.len();
+            if self.values.len() < MAX_SAMPLE_VALUES {
+                self.values.push(to_json(value));
+            }
+        }
+    }
+
+    /// Type name as used by the `vector_layers` fields
+    #[must_use]
+    pub fn field_type(&self) -> String {
+        if self.types.len() == 1 {
+            self.types
+                .first()
+                .map(ToString::to_string)
+                .unwrap_or_default()
+        } else {
+            "Mixed".to_string()
+        }
+    }
+
+    fn sorted_values(&self) -> Vec<Value> {
+        let mut values = self.values.clone();
+        values.sort_by(|a, b| match (a, b) {
+            (Value::Number(a), Value::Number(b)) => a
+                .as_f64()
+                .partial_cmp(&b.as_f64())
+                .unwrap_or(std::cmp::Ordering::Equal),
+            (Value::String(a), Value::String(b)) => a.cmp(b),
+            (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+            _ => value_rank(a).cmp(&value_rank(b)),
+        });
+        values
+    }
+}
+
+fn value_rank(value: &Value) -> u8 {
+    match value {
+        Value::Bool(_) => 0,
+        Value::Number(_) => 1,
+        _ => 2,
+    }
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn to_json(value: &MvtValue) -> Value {
+    match value {
+        MvtValue::String(v) => Value::String(v.clone()),
+        // Keep integers without a fractional part, e.g. 3 instead of 3.0
+        MvtValue::Number(v) if v.fract() == 0.0 && v.abs() < 9_007_199_254_740_992.0 => {
+            json!(*v as i64)
+        }
+        MvtValue::Number(v) => json!(v),
+        MvtValue::Bool(v) => Value::Bool(*v),
+    }
+}
+
+/// Statistics of a single vector tile layer
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct LayerStats {
+    pub min_zoom: u8,
+    pub max_zoom: u8,
+    /// Number of tiles containing this layer
+    pub tile_count: u64,
+    /// Number of features in all tiles, counting features present in several tiles each time
+    pub feature_count: u64,
+    /// Total size of the layer in all uncompressed tiles, in bytes
+    pub size: u64,
+    pub geometry_types: BTreeMap<GeometryType, u64>,
+    pub attributes: BTreeMap<String, AttributeStats>,
+}
+
+impl LayerStats {
+    fn new(zoom: u8) -> Self {
+        Self {
+            min_zoom: zoom,
+            max_zoom: zoom,
+            tile_count: 0,
+            feature_count: 0,
+            size: 0,
+            geometry_types: BTreeMap::new(),
+            attributes: BTreeMap::new(),
+        }
+    }
+
+    fn add(&mut self, zoom: u8, layer: &LayerContent) {
+        self.min_zoom = self.min_zoom.min(zoom);
+        self.max_zoom = self.max_zoom.max(zoom);
+        self.tile_count += 1;
+        self.feature_count += layer.features.len() as u64;
+        self.size += layer.size as u64;
+        for feature in &layer.features {
+            *self
+                .geometry_types
+                .entry(feature.geom_type.into())
+                .or_default() += 1;
+            for (key, value) in &feature.tags {
+                self.attributes
+                    .entry(layer.keys[*key].clone())
+                    .or_default()
+                    .add(&layer.values[*value]);
+            }
+        }
+    }
+
+    /// The most common geometry type of the layer
+    #[must_use]
+    pub fn geometry_type(&self) -> GeometryType {
+        self.geometry_types
+            .iter()
+            .max_by_key(|(_, count)| **count)
+            .map_or(GeometryType::Unknown, |(v, _)| *v)
+    }
+}
+
+/// Content statistics of all vector tiles in a file
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct TileStats {
+    /// Number of analyzed tiles
+    pub tile_count: u64,
+    /// Number of tiles that could not be decoded
+    pub invalid_tile_count: u64,
+    pub layers: BTreeMap<String, LayerStats>,
+}
+
+impl TileStats {
+    fn add_tile(&mut self, zoom: u8, data: &[u8], encoding: Encoding) {
+        self.tile_count += 1;
+        let layers = decode(data, encoding)
+            .map_err(MbtError::from)
+            .and_then(|v| decode_layers(&v));
+        match layers {
+            Ok(layers) => {
+                for layer in layers {
+                    self.layers
+                        .entry(layer.name.clone())
+                        .or_insert_with(|| LayerStats::new(zoom))
+                        .add(zoom, &layer);
+                }
+            }
+            Err(e) => {
+                warn!("Skipping a tile at zoom {zoom} that cannot be decoded: {e}");
+                self.invalid_tile_count += 1;
+            }
+        }
+    }
+
+    /// Layer descriptions for the `vector_layers` field of the `json` metadata value
+    #[must_use]
+    pub fn to_vector_layers(&self) -> Vec<VectorLayer> {
+        self.layers
+            .iter()
+            .map(|(id, layer)| VectorLayer {
+                id: id.clone(),
+                fields: layer
+                    .attributes
+                    .iter()
+                    .map(|(name, attr)| (name.clone(), attr.field_type()))
+                    .collect(),
+                description: None,
+                minzoom: Some(layer.min_zoom),
+                maxzoom: Some(layer.max_zoom),
+                other: BTreeMap::new(),
+            })
+            .collect()
+    }
+
+    /// Statistics in the [tippecanoe](https://github.com/felt/tippecanoe) `tilestats` format
+    #[must_use]
+    pub fn to_tilestats(&self) -> Value {
+        let layers: Vec<Value> = self
+            .layers
+            .iter()
+            .map(|(name, layer)| {
+                let attributes: Vec<Value> = layer
+                    .attributes
+                    .iter()
+                    .map(|(attr_name, attr)| {
+                        let mut value = json!({
+                            "attribute": attr_name,
+                            "count": attr.distinct_count,
+                            "type": attr.field_type().to_lowercase(),
+                            "values": attr.sorted_values(),
+                        });
+                        if let (Some(min), Some(max)) = (attr.min, attr.max) {
+                            value["min"] = json!(min);
+                            value["max"] = json!(max);
+                        }
+                        value
+                    })
+                    .collect();
+                json!({
+                    "layer": name,
+                    "count": layer.feature_count,
+                    "geometry": layer.geometry_type(),
+                    "attributeCount": attributes.len(),
+                    "attributes": attributes,
+                })
+            })
+            .collect();
+        json!({ "layerCount": layers.len(), "layers": layers })
+    }
+
+    /// Size of each layer, for the file summary
+    #[must_use]
+    pub fn layer_sizes(&self) -> Vec<LayerSizeInfo> {
+        self.layers
+            .iter()
+            .map(|(name, layer)| LayerSizeInfo {
+                layer: name.clone(),
+                tile_count: layer.tile_count,
+                feature_count: layer.feature_count,
+                size: layer.size,
+            })
+            .collect()
+    }
+}
+
+impl Display for TileStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Analyzed tiles: {}", self.tile_count)?;
+        if self.invalid_tile_count > 0 {
+            write!(f, " ({} could not be decoded)", self.invalid_tile_count)?;
+        }
+        writeln!(f)?;
+
+        for (name, layer) in &self.layers {
+            let size = SizeFormatterBinary::new(layer.size);
+            writeln!(f)?;
+            writeln!(
+                f,
+                "Layer {name}: zoom {}-{}, {} tiles, {} features, {size:.1}B uncompressed, {}",
+                layer.min_zoom,
+                layer.max_zoom,
+                layer.tile_count,
+                layer.feature_count,
+                layer.geometry_type(),
+            )?;
+            let width = layer.attributes.keys().map(String::len).max().unwrap_or(0);
+            for (attr_name, attr) in &layer.attributes {
+                let values = attr
+                    .values
+                    .iter()
+                    .take(5)
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                let more = if attr.distinct_count > 5 { ", ..." } else { "" };
+                writeln!(
+                    f,
+                    "  {attr_name:<width$} | {:<7} | {:>5} values | {values}{more}",
+                    attr.field_type(),
+                    attr.distinct_count,
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Mbtiles {
+    /// Decode vector tiles and collect statistics of their layers and attributes.
+    /// If `sample` is set, only analyze up to that many randomly chosen tiles of each zoom level.
+    pub async fn analyze<T>(&self, conn: &mut T, sample: Option<u64>) -> MbtResult<TileStats>
+    where
+        for<'e> &'e mut T: SqliteExecutor<'e>,
+    {
+        let tile_info = self.get_metadata(&mut *conn).await?.tile_info;
+        if tile_info.format != Format::Mvt {
+            return Err(MbtError::NotVectorTiles(
+                self.filepath().to_string(),
+                tile_info.format,
+            ));
+        }
+
+        let sql = if let Some(sample) = sample {
+            // Pick the sampled tiles by their coordinates first, and only read their data
+            format!(
+                "
+    SELECT tiles.zoom_level, tiles.tile_data
+    FROM (SELECT zoom_level, tile_column, tile_row
+          FROM (SELECT zoom_level,
+                       tile_column,
+                       tile_row,
+                       row_number() OVER (PARTITION BY zoom_level ORDER BY random()) AS num
+                FROM tiles
+                WHERE tile_data NOTNULL)
+          WHERE num <= {sample}) AS sample
+    JOIN tiles
+      ON tiles.zoom_level = sample.zoom_level
+        AND tiles.tile_column = sample.tile_column
+        AND tiles.tile_row = sample.tile_row"
+            )
+        } else {
+            "SELECT zoom_level, tile_data FROM tiles WHERE tile_data NOTNULL".to_string()
+        };
+
+        let mut stats = TileStats::default();
+        let mut rows = query(&sql).fetch(&mut *conn);
+        while let Some(row) = rows.try_next().await? {
+            let data: Vec<u8> = row.get(1);
+            stats.add_tile(row.get(0), &data, tile_info.encoding);
+        }
+
+        Ok(stats)
+    }
+
+    /// Replace the `vector_layers` and `tilestats` values of the `json` metadata with the analysis result.
+    /// Other values of the `json` metadata, and existing layer descriptions, are kept.
+    pub async fn update_vector_layers<T>(&self, conn: &mut T, stats: &TileStats) -> MbtResult<()>
+    where
+        for<'e> &'e mut T: SqliteExecutor<'e>,
+    {
+        let mut json = Map::new();
+        if let Some(value) = self.get_metadata_value(&mut *conn, "json").await? {
+            if let Ok(Value::Object(obj)) = serde_json::from_str(&value) {
+                json = obj;
+            } else {
+                warn!("Replacing invalid json metadata value in {self}");
+            }
+        }
+
+        let old_layers: Vec<VectorLayer> = json
+            .get("vector_layers")
+            .and_then(|v| serde_json::from_value(v.clone()).ok())
+            .unwrap_or_default();
+        let mut layers = stats.to_vector_layers();
+        for layer in &mut layers {
+            if let Some(old) = old_layers.iter().find(|v| v.id == layer.id) {
+                layer.description.clone_from(&old.description);
+            }
+        }
+
+        info!(
+            "Updating vector_layers metadata of {self} with {} layers",
+            layers.len()
+        );
+        json.insert("vector_layers".to_string(), serde_json::to_value(layers)?);
+        json.insert("tilestats".to_string(), stats.to_tilestats());
+        self.set_metadata_value(conn, "json", serde_json::to_string(&json)?)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::Executor as _;
+
+    use crate::{MbtilesCopier, Metadata};
+
+    use super::*;
+
+    #[actix_rt::test]
+    async fn analyze_world_cities() {
+        let mbt = Mbtiles::new("../tests/fixtures/mbtiles/world_cities.mbtiles").unwrap();
+        let mut conn = mbt.open_readonly().await.unwrap();
+        let stats = mbt.analyze(&mut conn, None).await.unwrap();
+        assert_eq!(stats.tile_count, 196);
+        assert_eq!(stats.invalid_tile_count, 0);
+
+        let cities = &stats.layers["cities"];
+        assert_eq!((cities.min_zoom, cities.max_zoom), (0, 6));
+        assert_eq!(cities.tile_count, 196);
+        assert_eq!(cities.geometry_type(), GeometryType::Point);
+        let name = &cities.attributes["name"];
+        assert_eq!(name.field_type(), "String");
+        assert_eq!(name.distinct_count, 68);
+        assert!(name.min.is_none());
+
+        let tilestats = stats.to_tilestats();
+        assert_eq!(tilestats["layerCount"], 1);
+        assert_eq!(tilestats["layers"][0]["geometry"], "Point");
+        assert_eq!(
+            tilestats["layers"][0]["attributes"][0]["values"][0],
+            "Addis Ababa"
+        );
+
+        let sampled = mbt.analyze(&mut conn, Some(2)).await.unwrap();
+        // At most 2 tiles of each of the 7 zoom levels
+        assert_eq!(sampled.tile_count, 13);
+    }
+
+    #[actix_rt::test]
+    async fn update_vector_layers() {
+        let file = "file:update_vector_layers_mem_db?mode=memory&cache=shared";
+        let mut conn = MbtilesCopier {
+            src_file: "../tests/fixtures/mbtiles/world_cities.mbtiles".into(),
+            dst_file: file.into(),
+            ..Default::default()
+        }
+        .run()
+        .await
+        .unwrap();
+        conn.execute(
+            r#"UPDATE metadata SET value = '{"vector_layers": [{"id": "cities", "description": "Big cities", "fields": {}}], "other": 1}' WHERE name = 'json'"#,
+        )
+        .await
+        .unwrap();
+
+        let mbt = Mbtiles::new(file).unwrap();
+        let stats = mbt.analyze(&mut conn, None).await.unwrap();
+        mbt.update_vector_layers(&mut conn, &stats).await.unwrap();
+
+        let Metadata { tilejson, json, .. } = mbt.get_metadata(&mut conn).await.unwrap();
+        let layers = tilejson.vector_layers.unwrap();
+        assert_eq!(layers.len(), 1);
+        assert_eq!(layers[0].description.as_deref(), Some("Big cities"));
+        assert_eq!(layers[0].fields["name"], "String");
+        assert_eq!((layers[0].minzoom, layers[0].maxzoom), (Some(0), Some(6)));
+        let json = json.unwrap();
+        assert_eq!(json["other"], 1);
+        assert_eq!(json["tilestats"]["layers"][0]["layer"], "cities");
+    }
+}
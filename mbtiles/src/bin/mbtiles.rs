@@ -1,10 +1,13 @@
 use std::path::{Path, PathBuf};
 
 use clap::{Parser, Subcommand};
-use log::error;
+use log::{error, info, warn};
+use mbtiles::sqlx::Connection as _;
 use mbtiles::{
-    apply_patch, AggHashType, CopyDuplicateMode, CopyType, IntegrityCheckType, MbtResult,
-    MbtTypeCli, Mbtiles, MbtilesCopier, PatchTypeCli, UpdateZoomType,
+    apply_patches, squash_patches, AggHashType, CopyDuplicateMode, CopyType, EncodingCli,
+    IntegrityCheckType, MbtResult, MbtTypeCli, Mbtiles, MbtilesConverter, MbtilesCopier,
+    MbtilesDirExporter, MbtilesDirImporter, MbtilesMerger, MbtilesPool, MergeStrategy,
+    PatchTypeCli, ReportFormat, Severity, TileScheme, UpdateZoomType,
 };
 use tilejson::Bounds;
 
@@ -28,7 +31,24 @@ pub struct Args {
 enum Commands {
     /// Show MBTiles file summary statistics
     #[command(name = "summary", alias = "info")]
-    Summary { file: PathBuf },
+    Summary {
+        file: PathBuf,
+        /// Decode all vector tiles to show the size of each layer
+        #[arg(long)]
+        layers: bool,
+    },
+    /// Decode vector tiles and show statistics of each layer: zoom range, feature counts, geometry types, and attributes
+    #[command(name = "analyze")]
+    Analyze {
+        /// MBTiles file to analyze
+        file: PathBuf,
+        /// Only analyze up to this many randomly chosen tiles of each zoom level
+        #[arg(long)]
+        sample: Option<u64>,
+        /// Print the `vector_layers` and `tilestats` values as JSON, in the format used by the `json` metadata value
+        #[arg(long)]
+        json: bool,
+    },
     /// Prints all values in the metadata table in a free-style, unstable YAML format
     #[command(name = "meta-all")]
     MetaAll {
@@ -59,17 +79,43 @@ enum Commands {
     /// Copy tiles from one mbtiles file to another.
     #[command(name = "copy", alias = "cp")]
     Copy(CopyArgs),
-    /// Apply diff file generated from 'copy' command
+    /// Decode all tiles and encode them with a different compression, e.g. to switch vector tiles from gzip to brotli or zstd
+    #[command(name = "recompress")]
+    Recompress(RecompressArgs),
+    /// Merge any number of MBTiles files into a new file, resolving tiles that exist in more than one file.
+    #[command(name = "merge")]
+    Merge(MergeArgs),
+    /// Convert an MBTiles file to a PMTiles archive, or a PMTiles archive to an MBTiles file.
+    /// The direction is determined by the .pmtiles file extension.
+    #[command(name = "convert")]
+    Convert(ConvertArgs),
+    /// Export tiles into a z/x/y directory tree, with the metadata in a metadata.json file
+    #[command(name = "export-dir")]
+    ExportDir(ExportDirArgs),
+    /// Import tiles from a z/x/y directory tree into a new MBTiles file
+    #[command(name = "import-dir")]
+    ImportDir(ImportDirArgs),
+    /// Apply diff files generated from 'copy' command
     #[command(name = "apply-patch", alias = "apply-diff")]
     ApplyPatch {
         /// MBTiles file to apply diff to
         base_file: PathBuf,
-        /// Diff file
-        patch_file: PathBuf,
+        /// Diff files, applied in order. Each one must be created from the result of the previous one.
+        #[arg(required = true)]
+        patch_files: Vec<PathBuf>,
         /// Force patching operation, ignoring some warnings that otherwise would prevent the operation. Use with caution.
         #[arg(short, long)]
         force: bool,
+        /// Record the hashes of each applied patch in the `patch_history` metadata value
+        #[arg(long)]
+        patch_history: bool,
+        /// Write a new diff file that undoes this operation, restoring all modified tiles and metadata
+        #[arg(long)]
+        reverse_patch: Option<PathBuf>,
     },
+    /// Combine a chain of consecutive diff files into a single diff file
+    #[command(name = "squash-patches")]
+    SquashPatches(SquashPatchesArgs),
     /// Update metadata to match the content of the file
     #[command(name = "meta-update", alias = "update-meta")]
     UpdateMetadata {
@@ -78,6 +124,21 @@ enum Commands {
         /// Update the min and max zoom levels in the metadata table to match the tiles table.
         #[arg(long, value_enum, default_value_t=UpdateZoomType::default())]
         update_zoom: UpdateZoomType,
+        /// Update the `bounds` metadata value to the area covered by the tiles of the highest zoom level.
+        #[arg(long, value_enum, num_args = 0..=1, require_equals = true, default_missing_value = "reset")]
+        update_bounds: Option<UpdateZoomType>,
+        /// Update the `center` metadata value to the middle of the area covered by the tiles.
+        #[arg(long, value_enum, num_args = 0..=1, require_equals = true, default_missing_value = "reset")]
+        update_center: Option<UpdateZoomType>,
+        /// Detect the tile format and compression from the tile content, and update the `format` and `compression` metadata values.
+        #[arg(long)]
+        update_format: bool,
+        /// Decode all vector tiles, and regenerate the `vector_layers` and `tilestats` values of the `json` metadata.
+        #[arg(long)]
+        vector_layers: bool,
+        /// Only analyze up to this many randomly chosen tiles of each zoom level when updating `vector_layers`
+        #[arg(long, requires("vector_layers"))]
+        sample: Option<u64>,
     },
     /// Validate tile data if hash of tile data exists in file
     #[command(name = "validate", alias = "check", alias = "verify")]
@@ -93,6 +154,21 @@ enum Commands {
         /// How should the aggregate tiles hash be checked or updated.
         #[arg(long, value_enum)]
         agg_hash: Option<AggHashType>,
+        /// Decompress and fully decode every tile to verify its content: image headers, JSON, or the vector tile layers and geometries.
+        #[arg(long)]
+        check_content: bool,
+        /// Stop checking the tile content after finding this many invalid tiles
+        #[arg(long, default_value_t = 10, requires("check_content"))]
+        max_errors: usize,
+        /// Check that the metadata conforms to the MBTiles 1.3 specification and matches the tile content.
+        #[arg(long)]
+        metadata: bool,
+        /// Apply safe corrections to the metadata issues found with `--metadata`
+        #[arg(long, requires("metadata"))]
+        fix: bool,
+        /// Print the metadata findings as JSON
+        #[arg(long, requires("metadata"))]
+        json: bool,
     },
 }
 
@@ -115,6 +191,10 @@ pub struct CopyArgs {
     /// Use `mbtiles apply-patch` to apply the patch file in-place, without making a copy of the original.
     #[arg(long, conflicts_with("diff_with_file"))]
     apply_patch: Option<PathBuf>,
+    /// When applying a patch, also write a new patch file that undoes it,
+    /// restoring the tiles and metadata of the source file.
+    #[arg(long, requires("apply_patch"))]
+    reverse_patch: Option<PathBuf>,
     /// Specify the type of patch file to generate.
     #[arg(long, requires("diff_with_file"), default_value_t=PatchTypeCli::default())]
     patch_type: PatchTypeCli,
@@ -127,16 +207,160 @@ pub struct DiffArgs {
     file1: PathBuf,
     /// Second MBTiles file to compare
     file2: PathBuf,
-    /// Output file to write the resulting difference to
-    diff: PathBuf,
+    /// Output file to write the resulting difference to. Not required if only a report is needed.
+    #[arg(required_unless_present("report"))]
+    diff: Option<PathBuf>,
     /// Specify the type of patch file to generate.
     #[arg(long, default_value_t=PatchTypeCli::default())]
     patch_type: PatchTypeCli,
+    /// Print the number of added, removed and modified tiles per zoom, the size change, and the bounding box of the changed tiles.
+    /// Use `--report=json` or `--report=csv` for machine-readable output.
+    #[arg(long, value_enum, num_args = 0..=1, require_equals = true, default_missing_value = "text")]
+    report: Option<ReportFormat>,
+    /// Write the footprints of all changed tiles to a GeoJSON file
+    #[arg(long, requires("report"))]
+    report_geojson: Option<PathBuf>,
+    /// Add the change of the feature count of each layer to the report. Only for vector tiles.
+    #[arg(long, requires("report"))]
+    report_layers: bool,
 
     #[command(flatten)]
     pub options: SharedCopyOpts,
 }
 
+#[allow(clippy::doc_markdown)]
+#[derive(Clone, PartialEq, Debug, clap::Args)]
+pub struct RecompressArgs {
+    /// MBTiles file to read from
+    src_file: PathBuf,
+    /// MBTiles file to write to. If not set, the source file is replaced once all tiles are recompressed.
+    dst_file: Option<PathBuf>,
+    /// Compression to use for the tile data
+    #[arg(long, value_enum)]
+    encoding: EncodingCli,
+    /// Compression level, e.g. 0-9 for gzip and zlib, 0-11 for brotli, 1-22 for zstd. Uses the default level of each compression if not set.
+    #[arg(long)]
+    level: Option<u32>,
+    /// Output format of the destination file. If not specified, defaults to the type of source
+    #[arg(long, alias = "dst-type", alias = "dst_type", value_name = "SCHEMA")]
+    mbtiles_type: Option<MbtTypeCli>,
+    /// Skip generating a global hash for mbtiles validation. By default, `mbtiles` will compute `agg_tiles_hash` metadata value.
+    #[arg(long)]
+    skip_agg_tiles_hash: bool,
+}
+
+#[allow(clippy::doc_markdown)]
+#[derive(Clone, Default, PartialEq, Debug, clap::Args)]
+pub struct MergeArgs {
+    /// MBTiles file to write to. It must not exist or be empty.
+    dst_file: PathBuf,
+    /// MBTiles files to merge, in order
+    #[arg(required = true)]
+    src_files: Vec<PathBuf>,
+    /// How to resolve tiles with the same Z/X/Y in more than one file
+    #[arg(long, value_enum, default_value_t=MergeStrategy::default())]
+    strategy: MergeStrategy,
+    /// Output format of the destination file. If not specified, defaults to the type of the first source
+    #[arg(long, alias = "dst-type", alias = "dst_type", value_name = "SCHEMA")]
+    mbtiles_type: Option<MbtTypeCli>,
+    /// Skip generating a global hash for mbtiles validation. By default, `mbtiles` will compute `agg_tiles_hash` metadata value.
+    #[arg(long)]
+    skip_agg_tiles_hash: bool,
+}
+
+#[derive(Clone, Default, PartialEq, Debug, clap::Args)]
+pub struct SquashPatchesArgs {
+    /// Diff file to write to. It must not exist or be empty.
+    dst_file: PathBuf,
+    /// Diff files to combine, in the order they would be applied
+    #[arg(required = true)]
+    patch_files: Vec<PathBuf>,
+    /// The file the first diff applies to. Required to squash bin-diff patches.
+    #[arg(long)]
+    base_file: Option<PathBuf>,
+    /// Ignore hash mismatches between consecutive diff files. Use with caution.
+    #[arg(short, long)]
+    force: bool,
+}
+
+#[allow(clippy::doc_markdown)]
+#[derive(Clone, Default, PartialEq, Debug, clap::Args)]
+pub struct ConvertArgs {
+    /// MBTiles or PMTiles file to read from
+    src_file: PathBuf,
+    /// PMTiles or MBTiles file to write to
+    dst_file: PathBuf,
+    /// Output format of the destination MBTiles file. Defaults to flat.
+    #[arg(long, alias = "dst-type", alias = "dst_type", value_name = "SCHEMA")]
+    mbtiles_type: Option<MbtTypeCli>,
+    /// Minimum zoom level to convert
+    #[arg(long, conflicts_with("zoom_levels"))]
+    min_zoom: Option<u8>,
+    /// Maximum zoom level to convert
+    #[arg(long, conflicts_with("zoom_levels"))]
+    max_zoom: Option<u8>,
+    /// List of zoom levels to convert
+    #[arg(long, value_delimiter = ',')]
+    zoom_levels: Vec<u8>,
+    /// Bounding box to convert, in the format `min_lon,min_lat,max_lon,max_lat`. Can be used multiple times.
+    #[arg(long)]
+    bbox: Vec<Bounds>,
+    /// Skip generating a global hash for the destination MBTiles file.
+    #[arg(long)]
+    skip_agg_tiles_hash: bool,
+}
+
+impl From<ConvertArgs> for MbtilesConverter {
+    fn from(args: ConvertArgs) -> Self {
+        Self {
+            src_file: args.src_file,
+            dst_file: args.dst_file,
+            dst_type_cli: args.mbtiles_type,
+            min_zoom: args.min_zoom,
+            max_zoom: args.max_zoom,
+            zoom_levels: args.zoom_levels,
+            bbox: args.bbox,
+            skip_agg_tiles_hash: args.skip_agg_tiles_hash,
+        }
+    }
+}
+
+#[allow(clippy::doc_markdown)]
+#[derive(Clone, Default, PartialEq, Debug, clap::Args)]
+pub struct ExportDirArgs {
+    /// MBTiles file to read from
+    file: PathBuf,
+    /// Directory to write to. It must not exist or be empty.
+    dir: PathBuf,
+    /// Order of the tile rows in the directory
+    #[arg(long, value_enum, default_value_t=TileScheme::default())]
+    scheme: TileScheme,
+    /// Do not add a file extension based on the tile format, e.g. `.png` or `.pbf`
+    #[arg(long)]
+    skip_extension: bool,
+    /// Do not write the metadata table to a metadata.json file
+    #[arg(long)]
+    skip_metadata: bool,
+}
+
+#[allow(clippy::doc_markdown)]
+#[derive(Clone, Default, PartialEq, Debug, clap::Args)]
+pub struct ImportDirArgs {
+    /// Directory to read from. Tile file extensions are ignored, and a metadata.json file is imported if present.
+    dir: PathBuf,
+    /// MBTiles file to write to. It must not exist or be empty.
+    file: PathBuf,
+    /// Order of the tile rows in the directory
+    #[arg(long, value_enum, default_value_t=TileScheme::default())]
+    scheme: TileScheme,
+    /// Output format of the destination file. Defaults to flat.
+    #[arg(long, alias = "dst-type", alias = "dst_type", value_name = "SCHEMA")]
+    mbtiles_type: Option<MbtTypeCli>,
+    /// Skip generating a global hash for mbtiles validation. By default, `mbtiles` will compute `agg_tiles_hash` metadata value.
+    #[arg(long)]
+    skip_agg_tiles_hash: bool,
+}
+
 #[allow(clippy::doc_markdown)]
 #[derive(Clone, Default, PartialEq, Debug, clap::Args)]
 pub struct SharedCopyOpts {
@@ -201,6 +425,9 @@ impl SharedCopyOpts {
             validate: self.validate,
             // Constants
             dst_type: None, // Taken from dst_type_cli
+            recompress: None,
+            compression_level: None,
+            reverse_patch: None,
         }
     }
 }
@@ -221,6 +448,7 @@ async fn main() {
     }
 }
 
+#[allow(clippy::too_many_lines)]
 async fn main_int() -> anyhow::Result<()> {
     let args = Args::parse();
     match args.command {
@@ -234,42 +462,103 @@ async fn main_int() -> anyhow::Result<()> {
             meta_set_value(file.as_path(), &key, value.as_deref()).await?;
         }
         Commands::Copy(args) => {
-            let copier = args.options.into_copier(
+            let mut copier = args.options.into_copier(
                 args.src_file,
                 args.dst_file,
                 args.diff_with_file,
                 args.apply_patch,
                 args.patch_type,
             );
+            copier.reverse_patch = args.reverse_patch;
             copier.run().await?;
         }
         Commands::Diff(args) => {
-            let copier = args.options.into_copier(
-                args.file1,
-                args.diff,
-                Some(args.file2),
-                None,
-                args.patch_type,
-            );
-            copier.run().await?;
+            diff(args).await?;
+        }
+        Commands::Merge(args) => {
+            let merger = MbtilesMerger {
+                src_files: args.src_files,
+                dst_file: args.dst_file,
+                strategy: args.strategy,
+                dst_type_cli: args.mbtiles_type,
+                skip_agg_tiles_hash: args.skip_agg_tiles_hash,
+            };
+            merger.run().await?;
+        }
+        Commands::Recompress(args) => {
+            recompress(args).await?;
+        }
+        Commands::Convert(args) => {
+            MbtilesConverter::from(args).run().await?;
+        }
+        Commands::ExportDir(args) => {
+            let exporter = MbtilesDirExporter {
+                mbtiles_file: args.file,
+                dir: args.dir,
+                scheme: args.scheme,
+                skip_extension: args.skip_extension,
+                skip_metadata: args.skip_metadata,
+            };
+            exporter.run().await?;
+        }
+        Commands::ImportDir(args) => {
+            let importer = MbtilesDirImporter {
+                dir: args.dir,
+                mbtiles_file: args.file,
+                scheme: args.scheme,
+                dst_type_cli: args.mbtiles_type,
+                skip_agg_tiles_hash: args.skip_agg_tiles_hash,
+            };
+            importer.run().await?;
         }
         Commands::ApplyPatch {
             base_file,
-            patch_file,
+            patch_files,
             force,
+            patch_history,
+            reverse_patch,
         } => {
-            apply_patch(base_file, patch_file, force).await?;
+            apply_patches(base_file, &patch_files, force, patch_history, reverse_patch).await?;
+        }
+        Commands::SquashPatches(args) => {
+            squash_patches(args.dst_file, &args.patch_files, args.base_file, args.force).await?;
         }
-        Commands::UpdateMetadata { file, update_zoom } => {
+        Commands::UpdateMetadata {
+            file,
+            update_zoom,
+            update_bounds,
+            update_center,
+            update_format,
+            vector_layers,
+            sample,
+        } => {
             let mbt = Mbtiles::new(file.as_path())?;
             let mut conn = mbt.open().await?;
             mbt.update_metadata(&mut conn, update_zoom).await?;
+            if let Some(update) = update_bounds {
+                mbt.update_bounds(&mut conn, update).await?;
+            }
+            if let Some(update) = update_center {
+                mbt.update_center(&mut conn, update).await?;
+            }
+            if update_format {
+                mbt.update_format(&mut conn).await?;
+            }
+            if vector_layers {
+                let stats = mbt.analyze(&mut conn, sample).await?;
+                mbt.update_vector_layers(&mut conn, &stats).await?;
+            }
         }
         Commands::Validate {
             file,
             integrity_check,
             update_agg_tiles_hash,
             agg_hash,
+            check_content,
+            max_errors,
+            metadata,
+            fix,
+            json,
         } => {
             if update_agg_tiles_hash && agg_hash.is_some() {
                 anyhow::bail!("Cannot use both --agg-hash and --update-agg-tiles-hash");
@@ -283,15 +572,147 @@ async fn main_int() -> anyhow::Result<()> {
             });
             let mbt = Mbtiles::new(file.as_path())?;
             mbt.open_and_validate(integrity_check, agg_hash).await?;
+            if check_content {
+                let pool = MbtilesPool::new(file.as_path()).await?;
+                let errors = pool.check_tiles_content(max_errors).await?;
+                for err in &errors {
+                    error!("Invalid tile {err}");
+                }
+                if !errors.is_empty() {
+                    anyhow::bail!("Found {} tiles with invalid content in {mbt}", errors.len());
+                }
+            }
+            if metadata {
+                validate_metadata(&mbt, fix, json).await?;
+            }
         }
-        Commands::Summary { file } => {
+        Commands::Summary { file, layers } => {
             let mbt = Mbtiles::new(file.as_path())?;
             let mut conn = mbt.open_readonly().await?;
+            let mut summary = mbt.summary(&mut conn).await?;
+            if layers {
+                summary.layers = Some(mbt.analyze(&mut conn, None).await?.layer_sizes());
+            }
             println!("MBTiles file summary for {mbt}");
-            println!("{}", mbt.summary(&mut conn).await?);
+            println!("{summary}");
+        }
+        Commands::Analyze { file, sample, json } => {
+            let mbt = Mbtiles::new(file.as_path())?;
+            let mut conn = mbt.open_readonly().await?;
+            let stats = mbt.analyze(&mut conn, sample).await?;
+            if json {
+                let value = serde_json::json!({
+                    "vector_layers": stats.to_vector_layers(),
+                    "tilestats": stats.to_tilestats(),
+                });
+                println!("{}", serde_json::to_string_pretty(&value)?);
+            } else {
+                println!("Vector tile statistics for {mbt}");
+                print!("{stats}");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn diff(args: DiffArgs) -> anyhow::Result<()> {
+    if let Some(format) = args.report {
+        let copier = args.options.clone().into_copier(
+            args.file1.clone(),
+            PathBuf::new(),
+            Some(args.file2.clone()),
+            None,
+            args.patch_type,
+        );
+        let report = copier
+            .diff_report(args.report_geojson.is_some(), args.report_layers)
+            .await?;
+        match format {
+            ReportFormat::Text => print!("{report}"),
+            ReportFormat::Json => println!("{}", serde_json::to_string_pretty(&report)?),
+            ReportFormat::Csv => print!("{}", report.to_csv()),
+        }
+        if let (Some(path), Some(geojson)) = (&args.report_geojson, report.to_geojson()) {
+            std::fs::write(path, serde_json::to_string(&geojson)?)?;
+        }
+    }
+    if let Some(diff) = args.diff {
+        let copier =
+            args.options
+                .into_copier(args.file1, diff, Some(args.file2), None, args.patch_type);
+        copier.run().await?;
+    }
+    Ok(())
+}
+
+async fn recompress(args: RecompressArgs) -> anyhow::Result<()> {
+    // Without a destination, write to a temporary file next to the source, and replace the source with it
+    let in_place = args.dst_file.is_none();
+    let dst_file = args.dst_file.clone().unwrap_or_else(|| {
+        let mut name = args.src_file.clone().into_os_string();
+        name.push(".recompress-tmp");
+        PathBuf::from(name)
+    });
+    if in_place && dst_file.exists() {
+        warn!(
+            "Removing {} left over from an earlier recompression",
+            dst_file.display()
+        );
+        std::fs::remove_file(&dst_file)?;
+    }
+    let copier = MbtilesCopier {
+        src_file: args.src_file.clone(),
+        dst_file: dst_file.clone(),
+        dst_type_cli: args.mbtiles_type,
+        skip_agg_tiles_hash: args.skip_agg_tiles_hash,
+        recompress: Some(args.encoding),
+        compression_level: args.level,
+        ..Default::default()
+    };
+    let result = async {
+        copier.run().await?.close().await?;
+        if in_place {
+            std::fs::rename(&dst_file, &args.src_file)?;
         }
+        anyhow::Ok(())
+    }
+    .await;
+    if result.is_err() && in_place {
+        let _ = std::fs::remove_file(&dst_file);
     }
+    result
+}
 
+async fn validate_metadata(mbt: &Mbtiles, fix: bool, json: bool) -> anyhow::Result<()> {
+    let mut conn = if fix {
+        mbt.open().await?
+    } else {
+        mbt.open_readonly().await?
+    };
+    let mut findings = mbt.check_metadata(&mut conn).await?;
+    if fix && findings.iter().any(|v| v.fix.is_some()) {
+        mbt.fix_metadata(&mut conn, &findings).await?;
+        findings = mbt.check_metadata(&mut conn).await?;
+    }
+    if json {
+        println!("{}", serde_json::to_string_pretty(&findings)?);
+    } else if findings.is_empty() {
+        info!("Metadata of {mbt} conforms to the MBTiles specification");
+        return Ok(());
+    } else {
+        println!("Metadata findings for {mbt}");
+        for finding in &findings {
+            println!("{finding}");
+        }
+    }
+    let errors = findings
+        .iter()
+        .filter(|v| v.severity == Severity::Error)
+        .count();
+    if errors > 0 {
+        anyhow::bail!("Found {errors} metadata errors in {mbt}");
+    }
     Ok(())
 }
 
@@ -331,7 +752,10 @@ mod tests {
     use mbtiles::CopyDuplicateMode;
 
     use super::*;
-    use crate::Commands::{ApplyPatch, Copy, Diff, MetaGetValue, MetaSetValue, Validate};
+    use crate::Commands::{
+        Analyze, ApplyPatch, Convert, Copy, Diff, ExportDir, ImportDir, Merge, MetaGetValue,
+        MetaSetValue, Recompress, SquashPatches, UpdateMetadata, Validate,
+    };
     use crate::{Args, IntegrityCheckType};
 
     #[test]
@@ -538,12 +962,64 @@ mod tests {
                 command: Diff(DiffArgs {
                     file1: PathBuf::from("file1.mbtiles"),
                     file2: PathBuf::from("file2.mbtiles"),
-                    diff: PathBuf::from("../delta.mbtiles"),
+                    diff: Some(PathBuf::from("../delta.mbtiles")),
                     patch_type: PatchTypeCli::Whole,
                     options: SharedCopyOpts {
                         on_duplicate: Some(CopyDuplicateMode::Override),
                         ..Default::default()
                     },
+                    ..Default::default()
+                })
+            }
+        );
+    }
+
+    #[test]
+    fn test_diff_report() {
+        assert_eq!(
+            Args::try_parse_from(["mbtiles", "diff", "file1.mbtiles", "file2.mbtiles"])
+                .unwrap_err()
+                .kind(),
+            ErrorKind::MissingRequiredArgument
+        );
+        assert_eq!(
+            Args::parse_from([
+                "mbtiles",
+                "diff",
+                "file1.mbtiles",
+                "file2.mbtiles",
+                "--report",
+            ]),
+            Args {
+                verbose: false,
+                command: Diff(DiffArgs {
+                    file1: PathBuf::from("file1.mbtiles"),
+                    file2: PathBuf::from("file2.mbtiles"),
+                    report: Some(ReportFormat::Text),
+                    ..Default::default()
+                })
+            }
+        );
+        assert_eq!(
+            Args::parse_from([
+                "mbtiles",
+                "diff",
+                "file1.mbtiles",
+                "file2.mbtiles",
+                "--report=csv",
+                "--report-geojson",
+                "changes.geojson",
+                "--report-layers",
+            ]),
+            Args {
+                verbose: false,
+                command: Diff(DiffArgs {
+                    file1: PathBuf::from("file1.mbtiles"),
+                    file2: PathBuf::from("file2.mbtiles"),
+                    report: Some(ReportFormat::Csv),
+                    report_geojson: Some(PathBuf::from("changes.geojson")),
+                    report_layers: true,
+                    ..Default::default()
                 })
             }
         );
@@ -621,11 +1097,271 @@ mod tests {
                 verbose: false,
                 command: ApplyPatch {
                     base_file: PathBuf::from("src_file"),
-                    patch_file: PathBuf::from("diff_file"),
+                    patch_files: vec![PathBuf::from("diff_file")],
+                    force: false,
+                    patch_history: false,
+                    reverse_patch: None,
+                }
+            }
+        );
+        assert_eq!(
+            Args::parse_from([
+                "mbtiles",
+                "apply-patch",
+                "src_file",
+                "diff1",
+                "diff2",
+                "--patch-history",
+            ]),
+            Args {
+                verbose: false,
+                command: ApplyPatch {
+                    base_file: PathBuf::from("src_file"),
+                    patch_files: vec![PathBuf::from("diff1"), PathBuf::from("diff2")],
                     force: false,
+                    patch_history: true,
+                    reverse_patch: None,
                 }
             }
         );
+        assert_eq!(
+            Args::try_parse_from(["mbtiles", "apply-patch", "src_file"])
+                .unwrap_err()
+                .kind(),
+            ErrorKind::MissingRequiredArgument
+        );
+    }
+
+    #[test]
+    fn test_copy_reverse_patch() {
+        assert_eq!(
+            Args::try_parse_from(["mbtiles", "copy", "src", "dst", "--reverse-patch", "undo"])
+                .unwrap_err()
+                .kind(),
+            ErrorKind::MissingRequiredArgument
+        );
+        assert_eq!(
+            Args::parse_from([
+                "mbtiles",
+                "copy",
+                "src",
+                "dst",
+                "--apply-patch",
+                "diff",
+                "--reverse-patch",
+                "undo",
+            ]),
+            Args {
+                verbose: false,
+                command: Copy(CopyArgs {
+                    src_file: PathBuf::from("src"),
+                    dst_file: PathBuf::from("dst"),
+                    apply_patch: Some(PathBuf::from("diff")),
+                    reverse_patch: Some(PathBuf::from("undo")),
+                    ..Default::default()
+                })
+            }
+        );
+    }
+
+    #[test]
+    fn test_analyze() {
+        assert_eq!(
+            Args::parse_from(["mbtiles", "analyze", "file", "--sample", "10"]),
+            Args {
+                verbose: false,
+                command: Analyze {
+                    file: PathBuf::from("file"),
+                    sample: Some(10),
+                    json: false,
+                }
+            }
+        );
+        assert_eq!(
+            Args::try_parse_from(["mbtiles", "meta-update", "file", "--sample", "10"])
+                .unwrap_err()
+                .kind(),
+            ErrorKind::MissingRequiredArgument
+        );
+    }
+
+    #[test]
+    fn test_meta_update() {
+        assert_eq!(
+            Args::parse_from([
+                "mbtiles",
+                "meta-update",
+                "file",
+                "--update-bounds",
+                "--update-center=grow-only",
+                "--update-format",
+            ]),
+            Args {
+                verbose: false,
+                command: UpdateMetadata {
+                    file: PathBuf::from("file"),
+                    update_zoom: UpdateZoomType::Reset,
+                    update_bounds: Some(UpdateZoomType::Reset),
+                    update_center: Some(UpdateZoomType::GrowOnly),
+                    update_format: true,
+                    vector_layers: false,
+                    sample: None,
+                }
+            }
+        );
+    }
+
+    #[test]
+    fn test_squash_patches() {
+        assert_eq!(
+            Args::parse_from([
+                "mbtiles",
+                "squash-patches",
+                "dst_file",
+                "diff1",
+                "diff2",
+                "--base-file",
+                "base",
+            ]),
+            Args {
+                verbose: false,
+                command: SquashPatches(SquashPatchesArgs {
+                    dst_file: PathBuf::from("dst_file"),
+                    patch_files: vec![PathBuf::from("diff1"), PathBuf::from("diff2")],
+                    base_file: Some(PathBuf::from("base")),
+                    force: false,
+                })
+            }
+        );
+    }
+
+    #[test]
+    fn test_recompress() {
+        assert_eq!(
+            Args::try_parse_from(["mbtiles", "recompress", "src_file"])
+                .unwrap_err()
+                .kind(),
+            ErrorKind::MissingRequiredArgument
+        );
+        assert_eq!(
+            Args::parse_from([
+                "mbtiles",
+                "recompress",
+                "src_file",
+                "--encoding",
+                "zstd",
+                "--level",
+                "19",
+            ]),
+            Args {
+                verbose: false,
+                command: Recompress(RecompressArgs {
+                    src_file: PathBuf::from("src_file"),
+                    dst_file: None,
+                    encoding: EncodingCli::Zstd,
+                    level: Some(19),
+                    mbtiles_type: None,
+                    skip_agg_tiles_hash: false,
+                })
+            }
+        );
+    }
+
+    #[test]
+    fn test_merge() {
+        assert_eq!(
+            Args::try_parse_from(["mbtiles", "merge", "dst_file"])
+                .unwrap_err()
+                .kind(),
+            ErrorKind::MissingRequiredArgument
+        );
+        assert_eq!(
+            Args::parse_from([
+                "mbtiles",
+                "merge",
+                "dst_file",
+                "a",
+                "b",
+                "--strategy",
+                "merge-layers",
+            ]),
+            Args {
+                verbose: false,
+                command: Merge(MergeArgs {
+                    dst_file: PathBuf::from("dst_file"),
+                    src_files: vec![PathBuf::from("a"), PathBuf::from("b")],
+                    strategy: MergeStrategy::MergeLayers,
+                    ..Default::default()
+                })
+            }
+        );
+    }
+
+    #[test]
+    fn test_convert() {
+        assert_eq!(
+            Args::parse_from([
+                "mbtiles",
+                "convert",
+                "src_file.pmtiles",
+                "dst_file",
+                "--mbtiles-type",
+                "normalized",
+                "--zoom-levels",
+                "3,7",
+            ]),
+            Args {
+                verbose: false,
+                command: Convert(ConvertArgs {
+                    src_file: PathBuf::from("src_file.pmtiles"),
+                    dst_file: PathBuf::from("dst_file"),
+                    mbtiles_type: Some(MbtTypeCli::Normalized),
+                    zoom_levels: vec![3, 7],
+                    ..Default::default()
+                })
+            }
+        );
+    }
+
+    #[test]
+    fn test_export_import_dir() {
+        assert_eq!(
+            Args::parse_from([
+                "mbtiles",
+                "export-dir",
+                "src_file",
+                "dir",
+                "--scheme",
+                "tms"
+            ]),
+            Args {
+                verbose: false,
+                command: ExportDir(ExportDirArgs {
+                    file: PathBuf::from("src_file"),
+                    dir: PathBuf::from("dir"),
+                    scheme: TileScheme::Tms,
+                    ..Default::default()
+                })
+            }
+        );
+        assert_eq!(
+            Args::parse_from([
+                "mbtiles",
+                "import-dir",
+                "dir",
+                "dst_file",
+                "--skip-agg-tiles-hash"
+            ]),
+            Args {
+                verbose: false,
+                command: ImportDir(ImportDirArgs {
+                    dir: PathBuf::from("dir"),
+                    file: PathBuf::from("dst_file"),
+                    skip_agg_tiles_hash: true,
+                    ..Default::default()
+                })
+            }
+        );
     }
 
     #[test]
@@ -639,6 +1375,57 @@ mod tests {
                     integrity_check: IntegrityCheckType::Quick,
                     update_agg_tiles_hash: false,
                     agg_hash: Some(AggHashType::Off),
+                    check_content: false,
+                    max_errors: 10,
+                    metadata: false,
+                    fix: false,
+                    json: false,
+                }
+            }
+        );
+        assert_eq!(
+            Args::parse_from(["mbtiles", "validate", "src_file", "--check-content"]),
+            Args {
+                verbose: false,
+                command: Validate {
+                    file: PathBuf::from("src_file"),
+                    integrity_check: IntegrityCheckType::Quick,
+                    update_agg_tiles_hash: false,
+                    agg_hash: None,
+                    check_content: true,
+                    max_errors: 10,
+                    metadata: false,
+                    fix: false,
+                    json: false,
+                }
+            }
+        );
+        assert_eq!(
+            Args::try_parse_from(["mbtiles", "validate", "src_file", "--max-errors", "5"])
+                .unwrap_err()
+                .kind(),
+            ErrorKind::MissingRequiredArgument
+        );
+        assert_eq!(
+            Args::try_parse_from(["mbtiles", "validate", "src_file", "--fix"])
+                .unwrap_err()
+                .kind(),
+            ErrorKind::MissingRequiredArgument
+        );
+        assert_eq!(
+            Args::parse_from(["mbtiles", "validate", "src_file", "--metadata", "--json"]),
+            Args {
+                verbose: false,
+                command: Validate {
+                    file: PathBuf::from("src_file"),
+                    integrity_check: IntegrityCheckType::Quick,
+                    update_agg_tiles_hash: false,
+                    agg_hash: None,
+                    check_content: false,
+                    max_errors: 10,
+                    metadata: true,
+                    fix: false,
+                    json: true,
                 }
             }
         );
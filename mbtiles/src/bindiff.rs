@@ -59,6 +59,11 @@ pub trait BinDiffer<S: Send + 'static, T: Send + 'static>: Sized + Send + Sync +
 
     fn process(&self, value: S) -> MbtResult<T>;
 
+    /// Error to return if any of the values could not be processed. The details are logged separately.
+    fn failure(&self) -> MbtError {
+        MbtError::BindiffError
+    }
+
     fn before_insert(
         &self,
         conn: &mut SqliteConnection,
@@ -81,17 +86,17 @@ pub trait BinDiffer<S: Send + 'static, T: Send + 'static>: Sized + Send + Sync +
             let patcher = patcher.clone();
             tokio::spawn(async move {
                 if let Err(e) = patcher.query(sql_where, tx_wrk).await {
-                    error!("Failed to query bindiff data: {e}");
+                    error!("Failed to query tile data: {e}");
                     has_errors.store(true, Relaxed);
                 }
             });
         }
 
         start_processor_threads(patcher.clone(), rx_wrk, tx_ins, has_errors.clone());
-        recv_and_insert(patcher, conn, rx_ins).await?;
+        recv_and_insert(patcher.clone(), conn, rx_ins).await?;
 
         if has_errors.load(Relaxed) {
-            Err(MbtError::BindiffError)
+            Err(patcher.failure())
         } else {
             Ok(())
         }
@@ -113,14 +118,14 @@ async fn recv_and_insert<S: Send + 'static, T: Send + 'static, P: BinDiffer<S, T
         if inserted % 100 == 0 {
             conn.execute("COMMIT").await?;
             if last_report_ts.elapsed().as_secs() >= 10 {
-                info!("Processed {inserted} bindiff tiles");
+                info!("Processed {inserted} tiles");
                 last_report_ts = Instant::now();
             }
             conn.execute("BEGIN").await?;
         }
     }
     conn.execute("COMMIT").await?;
-    info!("Finished processing {inserted} bindiff tiles");
+    info!("Finished processing {inserted} tiles");
 
     Ok(())
 }
@@ -134,7 +139,7 @@ fn start_processor_threads<S: Send + 'static, T: Send + 'static, P: BinDiffer<S,
     has_errors: Arc<AtomicBool>,
 ) {
     let cpus = num_cpus::get();
-    info!("Processing bindiff patches using {cpus} threads...");
+    info!("Processing tiles using {cpus} threads...");
     (0..cpus).for_each(|_| {
         let rx_wrk = rx_wrk.clone();
         let tx_ins = tx_ins.clone();
@@ -145,7 +150,7 @@ fn start_processor_threads<S: Send + 'static, T: Send + 'static, P: BinDiffer<S,
                 if match patcher.process(wrk) {
                     Ok(res) => tx_ins.send_async(res).await.is_err(),
                     Err(e) => {
-                        error!("Failed to process bindiff data: {e}");
+                        error!("Failed to process tile data: {e}");
                         true
                     }
                 } {
@@ -0,0 +1,430 @@
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::atomic::{AtomicBool, AtomicU64};
+use std::sync::Arc;
+
+use enum_display::EnumDisplay;
+use flate2::Crc;
+use flume::{bounded, unbounded, Receiver, Sender};
+use futures::TryStreamExt as _;
+use log::{debug, info};
+use martin_tile_utils::{decode, Format, TileCoord, TileInfo};
+use serde::Serialize;
+use sqlx::{query, Row as _, SqlitePool};
+
+use crate::errors::MbtResult;
+use crate::{invert_y_value, mvt, Mbtiles};
+
+/// Kind of problem found when decoding a tile
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, EnumDisplay)]
+#[enum_display(case = "Kebab")]
+#[serde(rename_all = "kebab-case")]
+pub enum TileErrorKind {
+    /// The tile data could not be decompressed with the detected encoding
+    Compression,
+    /// The image data is truncated or has invalid headers
+    Image,
+    /// The JSON tile data could not be parsed
+    Json,
+    /// The vector tile protobuf data could not be decoded
+    Protobuf,
+    /// A vector tile feature has invalid geometry commands
+    Geometry,
+    /// A vector tile layer has an invalid extent
+    Extent,
+    /// A vector tile has several layers with the same name
+    DuplicateLayer,
+    /// A vector tile layer has an unsupported version, or one that differs from the other layers
+    LayerVersion,
+}
+
+/// A tile with invalid content, with XYZ coordinates
+#[derive(Clone, Debug, PartialEq)]
+pub struct TileContentError {
+    pub coord: TileCoord,
+    pub kind: TileErrorKind,
+    pub message: String,
+}
+
+impl Display for TileContentError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:#} {}: {}", self.coord, self.kind, self.message)
+    }
+}
+
+/// Decompress and decode every tile. The tiles are read with a single query,
+/// and decoded by a worker per CPU on blocking threads.
+/// Stops after finding `max_errors` invalid tiles, and returns them sorted by their coordinates.
+pub(crate) async fn check_tiles_content(
+    mbt: &Mbtiles,
+    pool: &SqlitePool,
+    tile_info: TileInfo,
+    max_errors: usize,
+) -> MbtResult<Vec<TileContentError>> {
+    let workers = num_cpus::get();
+    info!("Checking the content of {tile_info} tiles in {mbt} using {workers} workers...");
+
+    let checked = Arc::new(AtomicU64::new(0));
+    let stop = Arc::new(AtomicBool::new(false));
+    let (tx_wrk, rx_wrk) = bounded(workers * 3);
+    let (tx, rx) = unbounded();
+    {
+        let pool = pool.clone();
+        let stop = stop.clone();
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            if let Err(e) = query_tiles(&pool, &stop, tx_wrk).await {
+                let _ = tx.send_async(Err(e)).await;
+            }
+        });
+    }
+    for _ in 0..workers {
+        let rx_wrk = rx_wrk.clone();
+        let checked = checked.clone();
+        let stop = stop.clone();
+        let tx = tx.clone();
+        tokio::task::spawn_blocking(move || {
+            check_worker(tile_info, &rx_wrk, &checked, &stop, &tx);
+        });
+    }
+    drop(rx_wrk);
+    drop(tx);
+
+    let mut errors = Vec::new();
+    while let Ok(res) = rx.recv_async().await {
+        errors.push(res?);
+        if errors.len() >= max_errors {
+            info!("Stopping after finding {max_errors} invalid tiles");
+            stop.store(true, Relaxed);
+            break;
+        }
+    }
+    if !stop.load(Relaxed) {
+        let count = checked.load(Relaxed);
+        info!("Checked the content of {count} tiles in {mbt}");
+    }
+
+    errors.sort_by_key(|v| (v.coord.z, v.coord.x, v.coord.y));
+    Ok(errors)
+}
+
+async fn query_tiles(
+    pool: &SqlitePool,
+    stop: &AtomicBool,
+    tx_wrk: Sender<(TileCoord, Vec<u8>)>,
+) -> MbtResult<()> {
+    let mut conn = pool.acquire().await?;
+    let sql = "
+        SELECT zoom_level, tile_column, tile_row, tile_data
+        FROM tiles
+        WHERE tile_data NOTNULL";
+    debug!("Querying tiles to check with {sql}");
+    let mut rows = query(sql).fetch(&mut *conn);
+    while let Some(row) = rows.try_next().await? {
+        if stop.load(Relaxed) {
+            break;
+        }
+        let z: u8 = row.get(0);
+        let coord = TileCoord {
+            z,
+            x: row.get(1),
+            y: invert_y_value(z, row.get(2)),
+        };
+        if tx_wrk.send_async((coord, row.get(3))).await.is_err() {
+            break; // all workers have stopped
+        }
+    }
+    Ok(())
+}
+
+fn check_worker(
+    tile_info: TileInfo,
+    rx_wrk: &Receiver<(TileCoord, Vec<u8>)>,
+    checked: &AtomicU64,
+    stop: &AtomicBool,
+    tx: &Sender<MbtResult<TileContentError>>,
+) {
+    while let Ok((coord, data)) = rx_wrk.recv() {
+        if stop.load(Relaxed) {
+            break;
+        }
+        if let Err((kind, message)) = check_tile(tile_info, &data) {
+            let error = TileContentError {
+                coord,
+                kind,
+                message,
+            };
+            if tx.send(Ok(error)).is_err() {
+                break; // the receiver has been dropped
+            }
+        }
+        let count = checked.fetch_add(1, Relaxed) + 1;
+        if count % 100_000 == 0 {
+            info!("Checked {count} tiles");
+        }
+    }
+}
+
+/// Decompress a single tile and decode its content according to the tile format
+pub(crate) fn check_tile(tile_info: TileInfo, data: &[u8]) -> Result<(), (TileErrorKind, String)> {
+    let data = decode(data, tile_info.encoding).map_err(|e| {
+        let message = format!("unable to decode {:?} data: {e}", tile_info.encoding);
+        (TileErrorKind::Compression, message)
+    })?;
+    let image = |e| (TileErrorKind::Image, e);
+    match tile_info.format {
+        Format::Mvt => mvt::check_tile(&data),
+        Format::Png => check_png(&data).map_err(image),
+        Format::Jpeg => check_jpeg(&data).map_err(image),
+        Format::Webp => check_webp(&data).map_err(image),
+        Format::Gif => check_gif(&data).map_err(image),
+        Format::Json => serde_json::from_slice::<serde_json::Value>(&data)
+            .map(|_| ())
+            .map_err(|e| (TileErrorKind::Json, e.to_string())),
+    }
+}
+
+fn read_u32_be(data: &[u8]) -> u32 {
+    u32::from_be_bytes([data[0], data[1], data[2], data[3]])
+}
+
+fn read_u32_le(data: &[u8]) -> u32 {
+    u32::from_le_bytes([data[0], data[1], data[2], data[3]])
+}
+
+/// Walk all PNG chunks, checking their CRCs, the image header, and that image data is present
+fn check_png(data: &[u8]) -> Result<(), String> {
+    let mut rest = data
+        .strip_prefix(b"\x89PNG\r\n\x1a\n")
+        .ok_or("missing PNG signature")?;
+    let mut has_data = false;
+    let mut first = true;
+    loop {
+        let len = rest
+            .get(0..4)
+            .map(|v| read_u32_be(v) as usize)
+            .ok_or("truncated PNG chunk")?;
+        let chunk = rest.get(4..8 + len).ok_or("truncated PNG chunk")?;
+        let crc = rest.get(8 + len..12 + len).ok_or("truncated PNG chunk")?;
+        let (kind, body) = chunk.split_at(4);
+        let name = String::from_utf8_lossy(kind);
+
+        let mut computed = Crc::new();
+        computed.update(chunk);
+        if computed.sum() != read_u32_be(crc) {
+            return Err(format!("invalid CRC of the {name} chunk"));
+        }
+        if first {
+            if kind != b"IHDR" || len != 13 {
+                return Err("PNG image does not start with a valid IHDR chunk".to_string());
+            }
+            if read_u32_be(&body[0..4]) == 0 || read_u32_be(&body[4..8]) == 0 {
+                return Err("PNG image has zero width or height".to_string());
+            }
+            first = false;
+        }
+        match kind {
+            b"IDAT" => has_data = true,
+            b"IEND" if has_data => return Ok(()),
+            b"IEND" => return Err("PNG image has no IDAT chunk".to_string()),
+            _ => {}
+        }
+        rest = &rest[12 + len..];
+    }
+}
+
+/// Walk all JPEG segments up to the end of image marker, checking the frame header
+fn check_jpeg(data: &[u8]) -> Result<(), String> {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return Err("missing JPEG start of image marker".to_string());
+    }
+    let mut pos = 2;
+    let mut has_frame = false;
+    loop {
+        if data.get(pos) != Some(&0xFF) {
+            return Err(format!("expected a JPEG marker at offset {pos}"));
+        }
+        while data.get(pos) == Some(&0xFF) {
+            pos += 1;
+        }
+        let marker = *data.get(pos).ok_or("truncated JPEG data")?;
+        pos += 1;
+        match marker {
+            0xD9 if has_frame => return Ok(()),
+            0xD9 => return Err("JPEG image has no frame header".to_string()),
+            0x01 | 0xD0..=0xD7 => continue,
+            _ => {}
+        }
+
+        let len = data
+            .get(pos..pos + 2)
+            .map(|v| usize::from(u16::from_be_bytes([v[0], v[1]])))
+            .ok_or("truncated JPEG segment")?;
+        let segment = data
+            .get(pos + 2..pos + len)
+            .ok_or("truncated JPEG segment")?;
+        // Start of frame markers, except for DHT, JPG, and DAC
+        if matches!(marker, 0xC0..=0xCF) && !matches!(marker, 0xC4 | 0xC8 | 0xCC) {
+            if segment.len() < 6 {
+                return Err("truncated JPEG frame header".to_string());
+            }
+            if u16::from_be_bytes([segment[3], segment[4]]) == 0 {
+                return Err("JPEG image has zero width".to_string());
+            }
+            has_frame = true;
+        }
+        pos += len;
+
+        if marker == 0xDA {
+            if !has_frame {
+                return Err("JPEG scan data before the frame header".to_string());
+            }
+            // Skip the entropy-coded data up to the next marker
+            loop {
+                match data.get(pos..pos + 2) {
+                    None => return Err("truncated JPEG scan data".to_string()),
+                    Some([0xFF, m]) if *m != 0 && !(0xD0..=0xD7).contains(m) => break,
+                    Some(_) => pos += 1,
+                }
+            }
+        }
+    }
+}
+
+/// Walk all RIFF chunks of a WebP image, checking the VP8 and VP8L bitstream headers
+fn check_webp(data: &[u8]) -> Result<(), String> {
+    if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WEBP" {
+        return Err("missing WebP RIFF header".to_string());
+    }
+    let size = read_u32_le(&data[4..8]) as usize;
+    let mut rest = data.get(12..size + 8).ok_or("truncated WebP data")?;
+    let mut has_image = false;
+    let mut first = true;
+    while !rest.is_empty() {
+        let len = rest
+            .get(4..8)
+            .map(|v| read_u32_le(v) as usize)
+            .ok_or("truncated WebP chunk")?;
+        let (kind, body) = (
+            &rest[0..4],
+            rest.get(8..8 + len).ok_or("truncated WebP chunk")?,
+        );
+        match kind {
+            b"VP8 " => {
+                if body.len() < 10 || body[3..6] != [0x9D, 0x01, 0x2A] {
+                    return Err("invalid VP8 frame header".to_string());
+                }
+                let width = u16::from_le_bytes([body[6], body[7]]) & 0x3FFF;
+                let height = u16::from_le_bytes([body[8], body[9]]) & 0x3FFF;
+                if width == 0 || height == 0 {
+                    return Err("WebP image has zero width or height".to_string());
+                }
+                has_image = true;
+            }
+            b"VP8L" => {
+                if body.len() < 5 || body[0] != 0x2F {
+                    return Err("invalid VP8L header".to_string());
+                }
+                has_image = true;
+            }
+            b"ANMF" => has_image = true,
+            b"VP8X" if first => {}
+            _ if first => return Err("WebP image does not start with an image chunk".to_string()),
+            _ => {}
+        }
+        first = false;
+        // Chunks are padded to an even size
+        rest = rest.get(8 + len + len % 2..).unwrap_or_default();
+    }
+    if has_image {
+        Ok(())
+    } else {
+        Err("WebP image has no image data".to_string())
+    }
+}
+
+/// Check the GIF header and trailer
+fn check_gif(data: &[u8]) -> Result<(), String> {
+    if !data.starts_with(b"GIF87a") && !data.starts_with(b"GIF89a") {
+        return Err("missing GIF signature".to_string());
+    }
+    if data.len() < 14 {
+        return Err("truncated GIF data".to_string());
+    }
+    if u16::from_le_bytes([data[6], data[7]]) == 0 || u16::from_le_bytes([data[8], data[9]]) == 0 {
+        return Err("GIF image has zero width or height".to_string());
+    }
+    if data.last() != Some(&0x3B) {
+        return Err("missing GIF trailer".to_string());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use martin_tile_utils::Encoding;
+    use rstest::rstest;
+    use sqlx::Executor as _;
+
+    use super::*;
+    use crate::{MbtilesCopier, MbtilesPool};
+
+    #[rstest]
+    #[case::png("geography-class-png.mbtiles")]
+    #[case::jpg("geography-class-jpg.mbtiles")]
+    #[case::webp("webp.mbtiles")]
+    #[case::json("json.mbtiles")]
+    #[case::mvt("uncompressed_mvt.mbtiles")]
+    #[case::gzip_mvt("world_cities.mbtiles")]
+    #[actix_rt::test]
+    async fn check_fixtures(#[case] file: &str) {
+        let pool = MbtilesPool::new(format!("../tests/fixtures/mbtiles/{file}"))
+            .await
+            .unwrap();
+        assert_eq!(pool.check_tiles_content(10).await.unwrap(), vec![]);
+    }
+
+    #[test]
+    fn check_invalid_tiles() {
+        let info = TileInfo::new(Format::Png, Encoding::Internal);
+        let err = check_tile(info, b"\x89PNG\r\n\x1a\n\0\0").unwrap_err();
+        assert_eq!(err.0, TileErrorKind::Image);
+        let info = TileInfo::new(Format::Jpeg, Encoding::Internal);
+        let err = check_tile(info, &[0xFF, 0xD8, 0xFF, 0xD9]).unwrap_err();
+        assert_eq!(err.1, "JPEG image has no frame header");
+        let info = TileInfo::new(Format::Mvt, Encoding::Gzip);
+        let err = check_tile(info, b"not gzip").unwrap_err();
+        assert_eq!(err.0, TileErrorKind::Compression);
+    }
+
+    #[actix_rt::test]
+    async fn check_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("check_content.mbtiles");
+        let mut conn = MbtilesCopier {
+            src_file: PathBuf::from("../tests/fixtures/mbtiles/world_cities.mbtiles"),
+            dst_file: file.clone(),
+            ..Default::default()
+        }
+        .run()
+        .await
+        .unwrap();
+
+        let pool = MbtilesPool::new(&file).await.unwrap();
+        assert!(pool.check_tiles_content(10).await.unwrap().is_empty());
+
+        conn.execute(
+            "UPDATE tiles SET tile_data = x'1f8b0800' WHERE zoom_level = 1 AND tile_column = 1 AND tile_row = 0",
+        )
+        .await
+        .unwrap();
+        let errors = pool.check_tiles_content(10).await.unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].to_string().split(':').next(),
+            Some("1/1/1 compression")
+        );
+    }
+}
@@ -0,0 +1,384 @@
+use std::path::{Path, PathBuf};
+
+use futures::TryStreamExt as _;
+use log::{debug, info};
+use martin_tile_utils::{bbox_to_xyz, MAX_ZOOM};
+use pmtiles::async_reader::AsyncPmTilesReader;
+use pmtiles::cache::HashMapCache;
+use serde_json::{Map, Value};
+use sqlx::{query, Row as _, SqliteConnection};
+use tilejson::Bounds;
+
+use crate::errors::MbtResult;
+use crate::pmtiles::{
+    mbtiles_format, tile_compression, tile_id_to_zxy, tile_type, zxy_to_tile_id, Header,
+    PmtilesEntries, PmtilesWriter,
+};
+use crate::queries::{compute_min_max_zoom, init_mbtiles_schema, is_empty_database};
+use crate::MbtType::{Flat, FlatWithHash, Normalized};
+use crate::{invert_y_value, CopyDuplicateMode, MbtError, MbtType, MbtTypeCli, Mbtiles};
+
+/// Number of tiles inserted into `MBTiles` in a single transaction
+const BATCH_SIZE: usize = 1000;
+
+/// Convert an `MBTiles` file into a `PMTiles` archive, or a `PMTiles` archive into an `MBTiles` file.
+/// The direction is determined by the `.pmtiles` extension of the source or destination file.
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct MbtilesConverter {
+    /// File to read from
+    pub src_file: PathBuf,
+    /// File to write to
+    pub dst_file: PathBuf,
+    /// Output format of the destination `MBTiles` file. If not specified, defaults to `flat`.
+    pub dst_type_cli: Option<MbtTypeCli>,
+    /// Minimum zoom level to convert
+    pub min_zoom: Option<u8>,
+    /// Maximum zoom level to convert
+    pub max_zoom: Option<u8>,
+    /// List of zoom levels to convert
+    pub zoom_levels: Vec<u8>,
+    /// Bounding box to convert, in the format `min_lon,min_lat,max_lon,max_lat`. Can be used multiple times.
+    pub bbox: Vec<Bounds>,
+    /// Skip generating a global hash for the destination `MBTiles` file.
+    pub skip_agg_tiles_hash: bool,
+}
+
+fn is_pmtiles(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|v| v.eq_ignore_ascii_case("pmtiles"))
+}
+
+impl MbtilesConverter {
+    pub async fn run(self) -> MbtResult<()> {
+        if self.src_file == self.dst_file {
+            return Err(MbtError::SameSourceAndDestination(self.src_file));
+        }
+        if self.dst_file.metadata().is_ok_and(|v| v.len() > 0) {
+            return Err(MbtError::NonEmptyTargetFile(self.dst_file));
+        }
+        match (is_pmtiles(&self.src_file), is_pmtiles(&self.dst_file)) {
+            (false, true) => self.mbtiles_to_pmtiles().await,
+            (true, false) => self.pmtiles_to_mbtiles().await,
+            _ => Err(MbtError::UnsupportedCopyOperation {
+                reason:
+                    "exactly one of the source and destination files must have a .pmtiles extension"
+                        .to_string(),
+            }),
+        }
+    }
+
+    fn dst_type(&self) -> MbtType {
+        match self.dst_type_cli {
+            None | Some(MbtTypeCli::Flat) => Flat,
+            Some(MbtTypeCli::FlatWithHash) => FlatWithHash,
+            Some(MbtTypeCli::Normalized) => Normalized { hash_view: true },
+        }
+    }
+
+    /// Check if the tile passes the zoom filters, and intersects with any of the bounding boxes
+    fn is_included(&self, z: u8, x: u32, y: u32) -> bool {
+        let zoom_ok = if self.zoom_levels.is_empty() {
+            self.min_zoom.map_or(true, |v| z >= v) && self.max_zoom.map_or(true, |v| z <= v)
+        } else {
+            self.zoom_levels.contains(&z)
+        };
+        zoom_ok
+            && z <= MAX_ZOOM
+            && (self.bbox.is_empty()
+                || self.bbox.iter().any(|bbox| {
+                    let (min_x, min_y, max_x, max_y) =
+                        bbox_to_xyz(bbox.left, bbox.bottom, bbox.right, bbox.top, z);
+                    (min_x..=max_x).contains(&x) && (min_y..=max_y).contains(&y)
+                }))
+    }
+
+    async fn mbtiles_to_pmtiles(&self) -> MbtResult<()> {
+        let mbt = Mbtiles::new(&self.src_file)?;
+        let mut conn = mbt.open_readonly().await?;
+        let metadata = mbt.get_metadata(&mut conn).await?;
+
+        info!("Converting tiles from {mbt} to {}", self.dst_file.display());
+        let mut writer = PmtilesWriter::new(&self.dst_file)?;
+        let mut rows = query(
+            "
+    SELECT zoom_level, tile_column, tile_row, tile_data
+    FROM tiles
+    WHERE tile_data NOTNULL
+    ORDER BY zoom_level, tile_column, tile_row",
+        )
+        .fetch(&mut conn);
+        let mut count = 0_u64;
+        while let Some(row) = rows.try_next().await? {
+            let (z, x, y): (u8, u32, u32) = (row.get(0), row.get(1), row.get(2));
+            let y = invert_y_value(z, y);
+            if self.is_included(z, x, y) {
+                let data: Vec<u8> = row.get(3);
+                writer.add_tile(z, zxy_to_tile_id(z, x, y), &data)?;
+                count += 1;
+            }
+        }
+        drop(rows);
+        info!("Writing {count} tiles to {}", self.dst_file.display());
+
+        let tj = &metadata.tilejson;
+        let bounds = tj.bounds.unwrap_or_default();
+        let center = tj.center.unwrap_or_else(|| tilejson::Center {
+            longitude: (bounds.left + bounds.right) / 2.0,
+            latitude: (bounds.bottom + bounds.top) / 2.0,
+            zoom: tj.minzoom.unwrap_or_default(),
+        });
+        let header = Header {
+            tile_compression: tile_compression(metadata.tile_info.encoding),
+            tile_type: tile_type(metadata.tile_info.format),
+            bounds: [bounds.left, bounds.bottom, bounds.right, bounds.top],
+            center_zoom: center.zoom,
+            center: [center.longitude, center.latitude],
+            ..Default::default()
+        };
+        writer.finish(header, &pmtiles_metadata(&metadata)?)?;
+        Ok(())
+    }
+
+    async fn pmtiles_to_mbtiles(&self) -> MbtResult<()> {
+        let reader =
+            AsyncPmTilesReader::new_with_cached_path(HashMapCache::default(), &self.src_file)
+                .await?;
+        let header = reader.get_header();
+        let metadata = reader.get_metadata().await?;
+        let metadata = if metadata.is_empty() {
+            Value::Object(Map::new())
+        } else {
+            serde_json::from_str(&metadata)?
+        };
+
+        let mbt = Mbtiles::new(&self.dst_file)?;
+        let mut conn = mbt.open_or_new().await?;
+        if !is_empty_database(&mut conn).await? {
+            return Err(MbtError::NonEmptyTargetFile(self.dst_file.clone()));
+        }
+        let dst_type = self.dst_type();
+        init_mbtiles_schema(&mut conn, dst_type).await?;
+        insert_pmtiles_metadata(&mbt, &mut conn, header, metadata).await?;
+
+        info!("Converting tiles from {} to {mbt}", self.src_file.display());
+        let mut entries = PmtilesEntries::open(&self.src_file)?;
+        let mut batch = Vec::with_capacity(BATCH_SIZE);
+        let mut count = 0_u64;
+        while let Some(entry) = entries.next_entry()? {
+            // All tiles of a run share the same data, which is only read once
+            let mut data = None;
+            for tile_id in entry.tile_id..entry.tile_id + u64::from(entry.run_length) {
+                let Some((z, x, y)) = tile_id_to_zxy(tile_id) else {
+                    continue;
+                };
+                if !self.is_included(z, x, y) {
+                    continue;
+                }
+                if data.is_none() {
+                    data = reader.get_tile(z, x.into(), y.into()).await?;
+                }
+                let Some(data) = &data else {
+                    break;
+                };
+                batch.push((z, x, y, data.to_vec()));
+                count += 1;
+                if batch.len() >= BATCH_SIZE {
+                    mbt.insert_tiles(&mut conn, dst_type, CopyDuplicateMode::Override, &batch)
+                        .await?;
+                    batch.clear();
+                }
+            }
+        }
+        mbt.insert_tiles(&mut conn, dst_type, CopyDuplicateMode::Override, &batch)
+            .await?;
+        info!("Converted {count} tiles into {mbt}");
+
+        if let Some((min_zoom, max_zoom)) = compute_min_max_zoom(&mut conn).await? {
+            mbt.set_metadata_value(&mut conn, "minzoom", min_zoom)
+                .await?;
+            mbt.set_metadata_value(&mut conn, "maxzoom", max_zoom)
+                .await?;
+        }
+        if !self.skip_agg_tiles_hash {
+            mbt.update_agg_tiles_hash(&mut conn).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Header coordinates are stored as 32-bit floats, round them to the precision of the `PMTiles` format
+fn header_coord(value: f32) -> f64 {
+    (f64::from(value) * 10_000_000.0).round() / 10_000_000.0
+}
+
+/// Map the `MBTiles` metadata to the `PMTiles` JSON metadata.
+/// Bounds, center and zoom levels are stored in the `PMTiles` header instead.
+fn pmtiles_metadata(metadata: &crate::Metadata) -> MbtResult<Value> {
+    let Value::Object(mut obj) = serde_json::to_value(&metadata.tilejson)? else {
+        return Ok(Value::Object(Map::new()));
+    };
+    for key in [
+        "tilejson", "tiles", "bounds", "center", "minzoom", "maxzoom",
+    ] {
+        obj.remove(key);
+    }
+    if let Some(layer_type) = &metadata.layer_type {
+        obj.insert("type".to_string(), Value::String(layer_type.clone()));
+    }
+    if let Some(Value::Object(json)) = &metadata.json {
+        for (key, value) in json {
+            obj.insert(key.clone(), value.clone());
+        }
+    }
+    Ok(Value::Object(obj))
+}
+
+/// Store the `PMTiles` JSON metadata and header values in the `MBTiles` metadata table.
+/// The `vector_layers` and `tilestats` values are stored in the `json` value, as in `MBTiles` 1.3.
+async fn insert_pmtiles_metadata(
+    mbt: &Mbtiles,
+    conn: &mut SqliteConnection,
+    header: &pmtiles::Header,
+    metadata: Value,
+) -> MbtResult<()> {
+    let mut json = Map::new();
+    if let Value::Object(obj) = metadata {
+        for (key, value) in obj {
+            match (key.as_str(), value) {
+                ("vector_layers" | "tilestats", value) => {
+                    json.insert(key, value);
+                }
+                ("bounds" | "center" | "minzoom" | "maxzoom" | "json", _) => {
+                    debug!("Ignoring PMTiles metadata value {key}");
+                }
+                (_, Value::String(value)) => mbt.set_metadata_value(conn, &key, value).await?,
+                (_, value) => mbt.set_metadata_value(conn, &key, value).await?,
+            }
+        }
+    }
+    if !json.is_empty() {
+        mbt.set_metadata_value(conn, "json", Value::Object(json))
+            .await?;
+    }
+    if mbt.get_metadata_value(conn, "format").await?.is_none() {
+        if let Some(format) = mbtiles_format(header.tile_type) {
+            mbt.set_metadata_value(conn, "format", format).await?;
+        }
+    }
+    let bounds = Bounds::new(
+        header_coord(header.min_longitude),
+        header_coord(header.min_latitude),
+        header_coord(header.max_longitude),
+        header_coord(header.max_latitude),
+    );
+    mbt.set_metadata_value(conn, "bounds", bounds).await?;
+    let lon = header_coord(header.center_longitude);
+    let lat = header_coord(header.center_latitude);
+    mbt.set_metadata_value(
+        conn,
+        "center",
+        format!("{lon},{lat},{}", header.center_zoom),
+    )
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mbtiles::tests::open;
+
+    #[actix_rt::test]
+    async fn roundtrip() -> MbtResult<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let dir = tmp_dir.path();
+        let src = PathBuf::from("../tests/fixtures/mbtiles/world_cities.mbtiles");
+        let pmtiles = dir.join("world_cities.pmtiles");
+        let dst = dir.join("world_cities.mbtiles");
+
+        MbtilesConverter {
+            src_file: src.clone(),
+            dst_file: pmtiles.clone(),
+            ..Default::default()
+        }
+        .run()
+        .await?;
+
+        // Only the converted file is left behind
+        assert_eq!(std::fs::read_dir(dir)?.count(), 1);
+        let reader = AsyncPmTilesReader::new_with_path(&pmtiles).await?;
+        assert_eq!(reader.get_header().tile_type, pmtiles::TileType::Mvt);
+        let metadata: Value = serde_json::from_str(&reader.get_metadata().await?)?;
+        assert!(metadata.get("vector_layers").is_some());
+
+        MbtilesConverter {
+            src_file: pmtiles.clone(),
+            dst_file: dst.clone(),
+            dst_type_cli: Some(MbtTypeCli::Normalized),
+            ..Default::default()
+        }
+        .run()
+        .await?;
+
+        let (mut conn, mbt) = open(dst.to_str().unwrap()).await?;
+        assert_eq!(
+            mbt.detect_type(&mut conn).await?,
+            Normalized { hash_view: true }
+        );
+        Mbtiles::new(&src)?.attach_to(&mut conn, "srcDb").await?;
+        let changed: i64 = query(
+            "SELECT count(*) FROM (
+                 SELECT * FROM srcDb.tiles EXCEPT SELECT * FROM tiles
+                 UNION ALL
+                 SELECT * FROM (SELECT * FROM tiles EXCEPT SELECT * FROM srcDb.tiles))",
+        )
+        .fetch_one(&mut conn)
+        .await?
+        .get(0);
+        assert_eq!(changed, 0);
+        let (mut src_conn, src_mbt) = open(src.to_str().unwrap()).await?;
+        assert_eq!(
+            mbt.get_metadata(&mut conn).await?.tilejson.vector_layers,
+            src_mbt
+                .get_metadata(&mut src_conn)
+                .await?
+                .tilejson
+                .vector_layers
+        );
+
+        // Converting into an existing file is not allowed
+        assert!(MbtilesConverter {
+            src_file: pmtiles.clone(),
+            dst_file: dst.clone(),
+            ..Default::default()
+        }
+        .run()
+        .await
+        .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn filters() {
+        let conv = MbtilesConverter {
+            min_zoom: Some(1),
+            max_zoom: Some(2),
+            ..Default::default()
+        };
+        assert!(!conv.is_included(0, 0, 0));
+        assert!(conv.is_included(1, 1, 1));
+        assert!(!conv.is_included(3, 0, 0));
+
+        let conv = MbtilesConverter {
+            zoom_levels: vec![3],
+            bbox: vec![Bounds::new(0.5, 0.5, 10.0, 10.0)],
+            ..Default::default()
+        };
+        // The tile north-east of the origin at zoom 3
+        assert!(conv.is_included(3, 4, 3));
+        assert!(!conv.is_included(3, 3, 3));
+        assert!(!conv.is_included(3, 4, 4));
+        assert!(!conv.is_included(2, 2, 1));
+    }
+}
@@ -2,9 +2,10 @@ use std::fmt::Write as _;
 use std::path::PathBuf;
 
 use enum_display::EnumDisplay;
+use futures::TryStreamExt as _;
 use itertools::Itertools as _;
 use log::{debug, info, trace, warn};
-use martin_tile_utils::{bbox_to_xyz, MAX_ZOOM};
+use martin_tile_utils::{bbox_to_xyz, decode, Encoding, Format, TileCoord, TileInfo, MAX_ZOOM};
 use serde::{Deserialize, Serialize};
 use sqlite_hashes::rusqlite::Connection;
 use sqlx::{query, Connection as _, Executor as _, Row, SqliteConnection};
@@ -12,19 +13,24 @@ use tilejson::Bounds;
 
 use crate::bindiff::PatchType::BinDiffGz;
 use crate::bindiff::{BinDiffDiffer, BinDiffPatcher, BinDiffer as _, PatchType};
+use crate::diff_report::DiffReport;
 use crate::errors::MbtResult;
 use crate::mbtiles::PatchFileInfo;
+use crate::mvt::layer_features;
+use crate::patcher::ReversePatch;
 use crate::queries::{
     create_tiles_with_hash_view, detach_db, init_mbtiles_schema, is_empty_database,
 };
+use crate::recompress::Recompressor;
+use crate::validation::calc_filtered_agg_tiles_hash;
 use crate::AggHashType::Verify;
 use crate::IntegrityCheckType::Quick;
 use crate::MbtType::{Flat, FlatWithHash, Normalized};
 use crate::PatchType::BinDiffRaw;
 use crate::{
     action_with_rusqlite, get_bsdiff_tbl_name, invert_y_value, reset_db_settings, AggHashType,
-    CopyType, MbtError, MbtType, MbtTypeCli, Mbtiles, AGG_TILES_HASH, AGG_TILES_HASH_AFTER_APPLY,
-    AGG_TILES_HASH_BEFORE_APPLY,
+    CopyType, EncodingCli, MbtError, MbtType, MbtTypeCli, Mbtiles, AGG_TILES_HASH,
+    AGG_TILES_HASH_AFTER_APPLY, AGG_TILES_HASH_BEFORE_APPLY, COMPRESSION,
 };
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, EnumDisplay)]
@@ -73,12 +79,18 @@ pub struct MbtilesCopier {
     pub diff_with_file: Option<(PathBuf, Option<PatchType>)>,
     /// Apply a patch file while copying src to dst.
     pub apply_patch: Option<PathBuf>,
+    /// When applying a patch, also write a new patch file that undoes it.
+    pub reverse_patch: Option<PathBuf>,
     /// Skip generating a global hash for mbtiles validation. By default, `mbtiles` will compute `agg_tiles_hash` metadata value.
     pub skip_agg_tiles_hash: bool,
     /// Ignore some warnings and continue with the copying operation
     pub force: bool,
     /// Perform `agg_hash` validation on the original and destination files.
     pub validate: bool,
+    /// Decode every tile and encode it with this compression, e.g. to switch vector tiles from gzip to brotli.
+    pub recompress: Option<EncodingCli>,
+    /// Compression level to use when recompressing. Uses the default level of each compression if not set.
+    pub compression_level: Option<u32>,
 }
 
 #[derive(Clone, Debug)]
@@ -93,6 +105,20 @@ impl MbtilesCopier {
         MbtileCopierInt::new(self)?.run().await
     }
 
+    /// Compare the source file with the `diff_with_file`, and report the differences without writing to the destination file.
+    /// Set `with_tiles` to include the list of all changed tiles, and `with_layers` to count the features of each vector tile layer.
+    pub async fn diff_report(self, with_tiles: bool, with_layers: bool) -> MbtResult<DiffReport> {
+        let Some((diff_file, _)) = &self.diff_with_file else {
+            return Err(MbtError::UnsupportedCopyOperation {
+                reason: "a diff report requires a file to compare with".to_string(),
+            });
+        };
+        let dif_mbt = Mbtiles::new(diff_file)?;
+        MbtileCopierInt::new(self)?
+            .run_diff_report(dif_mbt, with_tiles, with_layers)
+            .await
+    }
+
     pub(crate) fn dst_type(&self) -> Option<MbtType> {
         self.dst_type.or_else(|| {
             self.dst_type_cli.map(|t| match t {
@@ -123,6 +149,19 @@ impl MbtileCopierInt {
                 return Err(MbtError::SameDiffAndSourceOrDestination(options.src_file));
             }
         }
+        if options.reverse_patch.is_some() && options.apply_patch.is_none() {
+            return Err(MbtError::UnsupportedCopyOperation {
+                reason: "a reverse patch can only be created while applying a patch".to_string(),
+            });
+        }
+        if options.recompress.is_some()
+            && (options.diff_with_file.is_some() || options.apply_patch.is_some())
+        {
+            return Err(MbtError::UnsupportedCopyOperation {
+                reason: "tiles cannot be recompressed while creating or applying a patch"
+                    .to_string(),
+            });
+        }
 
         Ok(MbtileCopierInt {
             src_mbt: Mbtiles::new(&options.src_file)?,
@@ -148,6 +187,7 @@ impl MbtileCopierInt {
         let mut conn = self.src_mbt.open_readonly().await?;
         let src_type = self.src_mbt.detect_type(&mut conn).await?;
         conn.close().await?;
+        let recompression = self.get_recompression().await?;
 
         conn = self.dst_mbt.open_or_new().await?;
         let is_empty_db = is_empty_database(&mut conn).await?;
@@ -180,13 +220,18 @@ impl MbtileCopierInt {
             self.init_schema(&mut conn, src_type, dst_type).await?;
         }
 
-        self.copy_with_rusqlite(
-            &mut conn,
-            on_duplicate,
-            dst_type,
-            get_select_from(src_type, dst_type),
-        )
-        .await?;
+        if let Some((src_info, dst_enc)) = recompression {
+            self.recompress(&mut conn, on_duplicate, dst_type, src_info, dst_enc)
+                .await?;
+        } else {
+            self.copy_with_rusqlite(
+                &mut conn,
+                on_duplicate,
+                dst_type,
+                get_select_from(src_type, dst_type),
+            )
+            .await?;
+        }
 
         if self.options.copy.copy_tiles() && !self.options.skip_agg_tiles_hash {
             self.dst_mbt.update_agg_tiles_hash(&mut conn).await?;
@@ -276,6 +321,92 @@ impl MbtileCopierInt {
         Ok(conn)
     }
 
+    /// Summarize the differences between the source and the diff file, using the same tile comparison as [`Self::run_with_diff`]
+    async fn run_diff_report(
+        self,
+        dif_mbt: Mbtiles,
+        with_tiles: bool,
+        with_layers: bool,
+    ) -> MbtResult<DiffReport> {
+        let mut conn = self.src_mbt.open_readonly().await?;
+        let encoding = if with_layers {
+            let tile_info = self.src_mbt.get_metadata(&mut conn).await?.tile_info;
+            if tile_info.format != Format::Mvt {
+                return Err(MbtError::UnsupportedCopyOperation {
+                    reason: format!(
+                        "layers can only be compared for vector tiles, not {tile_info}"
+                    ),
+                });
+            }
+            Some(tile_info.encoding)
+        } else {
+            None
+        };
+        dif_mbt.attach_to(&mut conn, "diffDb").await?;
+
+        info!(
+            "Comparing {src_mbt} and {dif_path}",
+            src_mbt = self.src_mbt,
+            dif_path = dif_mbt.filepath(),
+        );
+
+        let tile_data = if with_layers {
+            ", srcTiles.tile_data, difTiles.tile_data"
+        } else {
+            ", NULL, NULL"
+        };
+        let diff_join = get_diff_join(
+            "tiles",
+            "diffDb.tiles",
+            "OR srcTiles.tile_data != difTiles.tile_data",
+        );
+        let sql = format!(
+            "
+        SELECT * FROM (
+            SELECT COALESCE(srcTiles.zoom_level, difTiles.zoom_level) as zoom_level
+                 , COALESCE(srcTiles.tile_column, difTiles.tile_column) as tile_column
+                 , COALESCE(srcTiles.tile_row, difTiles.tile_row) as tile_row
+                 , length(srcTiles.tile_data)
+                 , length(difTiles.tile_data)
+                 {tile_data}
+            {diff_join}
+        ) WHERE TRUE {where_clause}",
+            where_clause = self.get_where_clause(""),
+        );
+        debug!("Comparing tiles with {sql}");
+
+        let mut report = DiffReport {
+            tiles: with_tiles.then(Vec::new),
+            ..Default::default()
+        };
+        let mut rows = query(&sql).fetch(&mut conn);
+        while let Some(row) = rows.try_next().await? {
+            let z: u8 = row.get(0);
+            let coord = TileCoord {
+                z,
+                x: row.get(1),
+                y: invert_y_value(z, row.get(2)),
+            };
+            report.add(coord, row.get(3), row.get(4));
+            if let Some(encoding) = encoding {
+                let count = |data: Option<Vec<u8>>| -> MbtResult<Vec<(String, usize)>> {
+                    match data {
+                        Some(data) => layer_features(&decode(&data, encoding)?),
+                        None => Ok(Vec::new()),
+                    }
+                };
+                match (count(row.get(5)), count(row.get(6))) {
+                    (Ok(old), Ok(new)) => report.add_layers(old, new),
+                    (Err(e), _) | (_, Err(e)) => {
+                        warn!("Skipping layer feature counts of tile {coord:#}: {e}");
+                    }
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
     /// Apply a patch file to the source file and write the result to the destination file
     async fn run_with_patch(self, dif_mbt: Mbtiles) -> MbtResult<SqliteConnection> {
         let mut dif_conn = dif_mbt.open_readonly().await?;
@@ -284,7 +415,8 @@ impl MbtileCopierInt {
         dif_mbt.validate_diff_info(&dif_info, self.options.force)?;
         dif_conn.close().await?;
 
-        let src_type = self.validate_src_file().await?.mbt_type;
+        let src_info = self.validate_src_file().await?;
+        let src_type = src_info.mbt_type;
         let dst_type = self.options.dst_type().unwrap_or(src_type);
         if dif_info.patch_type.is_some() && matches!(dst_type, Normalized { .. }) {
             return Err(MbtError::BinDiffRequiresFlatWithHash(dst_type));
@@ -295,6 +427,17 @@ impl MbtileCopierInt {
             return Err(MbtError::NonEmptyTargetFile(self.options.dst_file));
         }
 
+        let reverse = if let Some(reverse_file) = &self.options.reverse_patch {
+            let patches = [(dif_mbt.clone(), dif_info.clone())];
+            let sql_where = self.get_where_clause("patch.");
+            Some(
+                ReversePatch::create(reverse_file.clone(), &self.src_mbt, &patches, &sql_where)
+                    .await?,
+            )
+        } else {
+            None
+        };
+
         self.src_mbt.attach_to(&mut conn, "sourceDb").await?;
         dif_mbt.attach_to(&mut conn, "diffDb").await?;
 
@@ -330,6 +473,8 @@ impl MbtileCopierInt {
             self.dst_mbt.update_agg_tiles_hash(&mut conn).await?;
             if matches!(dif_info.patch_type, Some(BinDiffGz)) {
                 info!("Skipping {AGG_TILES_HASH_AFTER_APPLY} validation because re-gzip-ing could produce different tile data. Each bindiff-ed tile was still verified with a hash value");
+            } else if !self.get_where_clause("").is_empty() {
+                info!("Skipping {AGG_TILES_HASH_AFTER_APPLY} validation because only some of the tiles were copied");
             } else {
                 let new_hash = self.dst_mbt.get_agg_tiles_hash(&mut conn).await?;
                 match (dif_info.agg_tiles_hash_after_apply, new_hash) {
@@ -357,6 +502,21 @@ impl MbtileCopierInt {
                 Verify
             };
 
+        if let Some(reverse) = reverse {
+            let new_hash = self.dst_mbt.get_agg_tiles_hash(&mut conn).await?;
+            // Applying the reverse patch restores only the copied part of the source
+            let sql_where = self.get_where_clause("");
+            let src_hash = if sql_where.is_empty() {
+                src_info.agg_tiles_hash
+            } else {
+                let mut src_conn = self.src_mbt.open_readonly().await?;
+                let hash = calc_filtered_agg_tiles_hash(&mut src_conn, &sql_where).await?;
+                src_conn.close().await?;
+                Some(hash)
+            };
+            reverse.finish(new_hash, src_hash).await?;
+        }
+
         if self.options.validate {
             self.dst_mbt.validate(&mut conn, Quick, hash_type).await?;
         }
@@ -364,6 +524,84 @@ impl MbtileCopierInt {
         Ok(conn)
     }
 
+    /// Get the source and destination encodings if the tiles need to be recompressed
+    async fn get_recompression(&self) -> MbtResult<Option<(TileInfo, Encoding)>> {
+        let Some(dst_enc) = self.options.recompress else {
+            return Ok(None);
+        };
+        if !self.options.copy.copy_tiles() {
+            return Ok(None);
+        }
+        let mut conn = self.src_mbt.open_readonly().await?;
+        let tile_info = self.src_mbt.get_metadata(&mut conn).await?.tile_info;
+        conn.close().await?;
+        let src_enc = tile_info.encoding;
+        if src_enc == Encoding::Internal {
+            return Err(MbtError::UnsupportedCopyOperation {
+                reason: format!("{tile_info} tiles cannot be recompressed"),
+            });
+        }
+        if src_enc == dst_enc.into() && self.options.compression_level.is_none() {
+            info!(
+                "Tiles in {} are already {dst_enc}-compressed, copying them as is",
+                self.src_mbt
+            );
+            return Ok(None);
+        }
+        Ok(Some((tile_info, dst_enc.into())))
+    }
+
+    /// Copy metadata, and decode and re-encode all tiles
+    async fn recompress(
+        &self,
+        conn: &mut SqliteConnection,
+        on_duplicate: CopyDuplicateMode,
+        dst_type: MbtType,
+        src_info: TileInfo,
+        dst_enc: Encoding,
+    ) -> MbtResult<()> {
+        info!(
+            "Recompressing tiles from {src_enc:?} to {dst_enc:?}{level}",
+            src_enc = src_info.encoding,
+            level = self
+                .options
+                .compression_level
+                .map(|v| format!(" with level {v}"))
+                .unwrap_or_default(),
+        );
+        if self.options.copy.copy_metadata() {
+            action_with_rusqlite(conn, |c| self.copy_metadata(c, on_duplicate)).await?;
+        }
+        Recompressor::new(
+            self.src_mbt.clone(),
+            src_info.encoding,
+            dst_enc,
+            self.options.compression_level,
+            dst_type,
+            on_duplicate,
+        )
+        .run(conn, self.get_where_clause(""))
+        .await?;
+
+        if let Some(dst_enc) = self.options.recompress {
+            self.dst_mbt
+                .set_metadata_value(conn, COMPRESSION, dst_enc.to_string())
+                .await?;
+        }
+        if self
+            .dst_mbt
+            .get_metadata_value(&mut *conn, "format")
+            .await?
+            .is_none()
+        {
+            let format = src_info.format.metadata_format_value();
+            self.dst_mbt
+                .set_metadata_value(conn, "format", format)
+                .await?;
+        }
+        Ok(())
+    }
+
     async fn validate(&self, mbt: &Mbtiles, conn: &mut SqliteConnection) -> MbtResult<()> {
         if self.options.validate {
             mbt.validate(conn, Quick, Verify).await?;
@@ -726,8 +964,10 @@ fn get_select_from_apply_patch(
 
     // Take dif tile_data if it is set, otherwise take the one from src
     // Skip tiles if src and dif both have a matching index, but the dif tile_data is NULL
+    // The outer query lets the zoom and bbox filters use the merged tile coordinates
     format!(
         "
+        SELECT * FROM (
         SELECT COALESCE(srcTiles.zoom_level, difTiles.zoom_level) as zoom_level
              , COALESCE(srcTiles.tile_column, difTiles.tile_column) as tile_column
              , COALESCE(srcTiles.tile_row, difTiles.tile_row) as tile_row
@@ -738,7 +978,8 @@ fn get_select_from_apply_patch(
                AND srcTiles.tile_column = difTiles.tile_column
                AND srcTiles.tile_row = difTiles.tile_row
              {bindiff_from}
-        WHERE (difTiles.zoom_level ISNULL OR difTiles.tile_data NOTNULL) {bindiff_cond}"
+        WHERE (difTiles.zoom_level ISNULL OR difTiles.tile_data NOTNULL) {bindiff_cond})
+        WHERE TRUE"
     )
 }
 
@@ -773,6 +1014,7 @@ fn get_select_from_with_diff(
     } else {
         "OR srcTiles.tile_data != difTiles.tile_data"
     };
+    let diff_join = get_diff_join("sourceDb.tiles", diff_tiles, sql_cond);
     format!(
         "
         SELECT COALESCE(srcTiles.zoom_level, difTiles.zoom_level) as zoom_level
@@ -780,7 +1022,16 @@ fn get_select_from_with_diff(
              , COALESCE(srcTiles.tile_row, difTiles.tile_row) as tile_row
              , difTiles.tile_data as tile_data
              {tile_hash_expr}
-        FROM sourceDb.tiles AS srcTiles FULL JOIN {diff_tiles} AS difTiles
+        {diff_join}"
+    )
+}
+
+/// Full join of the `srcTiles` and `difTiles` tables, keeping the tiles that exist in only one of them,
+/// plus the ones matching `sql_cond`
+fn get_diff_join(src_tiles: &str, diff_tiles: &str, sql_cond: &str) -> String {
+    format!(
+        "
+        FROM {src_tiles} AS srcTiles FULL JOIN {diff_tiles} AS difTiles
              ON srcTiles.zoom_level = difTiles.zoom_level
                AND srcTiles.tile_column = difTiles.tile_column
                AND srcTiles.tile_row = difTiles.tile_row
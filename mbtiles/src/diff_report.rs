@@ -0,0 +1,304 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter, Write as _};
+
+use enum_display::EnumDisplay;
+use martin_tile_utils::{get_zoom_precision, xyz_to_bbox, TileCoord};
+use serde::Serialize;
+use serde_json::{json, Value};
+use size_format::SizeFormatterBinary;
+use tilejson::Bounds;
+
+/// Output format of the diff report
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, EnumDisplay)]
+#[enum_display(case = "Kebab")]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum ReportFormat {
+    /// Human-readable table
+    #[default]
+    Text,
+    Json,
+    /// Comma-separated values, one row per zoom level
+    Csv,
+}
+
+/// How a tile differs between the two files
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, EnumDisplay)]
+#[enum_display(case = "Kebab")]
+#[serde(rename_all = "kebab-case")]
+pub enum TileChange {
+    Added,
+    Removed,
+    Modified,
+}
+
+/// A single changed tile, with XYZ coordinates
+#[derive(Clone, Debug, PartialEq)]
+pub struct TileDiff {
+    pub coord: TileCoord,
+    pub change: TileChange,
+    pub byte_delta: i64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ZoomDiff {
+    pub zoom: u8,
+    pub added: u64,
+    pub removed: u64,
+    pub modified: u64,
+    pub byte_delta: i64,
+    /// Bounding box of all changed tiles
+    pub bbox: Bounds,
+}
+
+/// Differences between two `MBTiles` files, computed without writing a patch file
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct DiffReport {
+    pub zoom_info: Vec<ZoomDiff>,
+    /// Change of the feature count of each vector tile layer, if requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub layers: Option<BTreeMap<String, i64>>,
+    /// All changed tiles, if requested
+    #[serde(skip)]
+    pub tiles: Option<Vec<TileDiff>>,
+}
+
+impl DiffReport {
+    /// Add a changed tile to the report. The `y` coordinate uses the XYZ scheme.
+    pub(crate) fn add(&mut self, coord: TileCoord, old_size: Option<i64>, new_size: Option<i64>) {
+        let change = match (old_size, new_size) {
+            (None, _) => TileChange::Added,
+            (_, None) => TileChange::Removed,
+            _ => TileChange::Modified,
+        };
+        let byte_delta = new_size.unwrap_or(0) - old_size.unwrap_or(0);
+
+        let idx = match self.zoom_info.binary_search_by_key(&coord.z, |v| v.zoom) {
+            Ok(idx) => idx,
+            Err(idx) => {
+                let bbox = tile_bbox(coord);
+                self.zoom_info.insert(
+                    idx,
+                    ZoomDiff {
+                        zoom: coord.z,
+                        added: 0,
+                        removed: 0,
+                        modified: 0,
+                        byte_delta: 0,
+                        bbox,
+                    },
+                );
+                idx
+            }
+        };
+        let info = &mut self.zoom_info[idx];
+        match change {
+            TileChange::Added => info.added += 1,
+            TileChange::Removed => info.removed += 1,
+            TileChange::Modified => info.modified += 1,
+        }
+        info.byte_delta += byte_delta;
+        info.bbox += tile_bbox(coord);
+
+        if let Some(tiles) = &mut self.tiles {
+            tiles.push(TileDiff {
+                coord,
+                change,
+                byte_delta,
+            });
+        }
+    }
+
+    /// Add the feature count changes of one tile, by layer name
+    pub(crate) fn add_layers(&mut self, old: Vec<(String, usize)>, new: Vec<(String, usize)>) {
+        let layers = self.layers.get_or_insert_with(BTreeMap::new);
+        #[allow(clippy::cast_possible_wrap)]
+        for (name, count) in old {
+            *layers.entry(name).or_default() -= count as i64;
+        }
+        #[allow(clippy::cast_possible_wrap)]
+        for (name, count) in new {
+            *layers.entry(name).or_default() += count as i64;
+        }
+    }
+
+    /// Format the report as CSV, with one row per zoom level.
+    /// Layer feature count changes, if any, follow as a second table after an empty line.
+    #[must_use]
+    pub fn to_csv(&self) -> String {
+        let mut csv =
+            "zoom,added,removed,modified,byte_delta,min_lon,min_lat,max_lon,max_lat\n".to_string();
+        for l in &self.zoom_info {
+            let b = l.bbox;
+            let _ = writeln!(
+                csv,
+                "{},{},{},{},{},{},{},{},{}",
+                l.zoom,
+                l.added,
+                l.removed,
+                l.modified,
+                l.byte_delta,
+                b.left,
+                b.bottom,
+                b.right,
+                b.top
+            );
+        }
+        if let Some(layers) = &self.layers {
+            csv.push_str("\nlayer,feature_delta\n");
+            for (name, delta) in layers {
+                let _ = writeln!(csv, "{},{delta}", csv_escape(name));
+            }
+        }
+        csv
+    }
+
+    /// A `GeoJSON` feature collection with the footprint of every changed tile.
+    /// Returns `None` unless the changed tiles were requested.
+    #[must_use]
+    pub fn to_geojson(&self) -> Option<Value> {
+        let features = self
+            .tiles
+            .as_ref()?
+            .iter()
+            .map(|t| {
+                let b = tile_bbox(t.coord);
+                json!({
+                    "type": "Feature",
+                    "geometry": {
+                        "type": "Polygon",
+                        "coordinates": [[
+                            [b.left, b.bottom],
+                            [b.right, b.bottom],
+                            [b.right, b.top],
+                            [b.left, b.top],
+                            [b.left, b.bottom],
+                        ]],
+                    },
+                    "properties": {
+                        "z": t.coord.z,
+                        "x": t.coord.x,
+                        "y": t.coord.y,
+                        "change": t.change,
+                        "byte_delta": t.byte_delta,
+                    },
+                })
+            })
+            .collect::<Vec<_>>();
+        Some(json!({ "type": "FeatureCollection", "features": features }))
+    }
+}
+
+impl Display for DiffReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.zoom_info.is_empty() {
+            writeln!(f, "No differences found")?;
+        } else {
+            writeln!(
+                f,
+                " {:^4} | {:^9} | {:^9} | {:^9} | {:^11} | Bounding Box",
+                "Zoom", "Added", "Removed", "Modified", "Size change"
+            )?;
+            for l in &self.zoom_info {
+                let prec = get_zoom_precision(l.zoom);
+                writeln!(
+                    f,
+                    " {:>4} | {:>9} | {:>9} | {:>9} | {:>11} | {:.prec$}",
+                    l.zoom,
+                    l.added,
+                    l.removed,
+                    l.modified,
+                    format_delta(l.byte_delta),
+                    l.bbox,
+                )?;
+            }
+            if self.zoom_info.len() > 1 {
+                let last = &self.zoom_info[self.zoom_info.len() - 1];
+                let prec = get_zoom_precision(last.zoom);
+                let bbox = self.zoom_info.iter().map(|l| l.bbox).reduce(|a, b| a + b);
+                writeln!(
+                    f,
+                    " {:>4} | {:>9} | {:>9} | {:>9} | {:>11} | {:.prec$}",
+                    "all",
+                    self.zoom_info.iter().map(|l| l.added).sum::<u64>(),
+                    self.zoom_info.iter().map(|l| l.removed).sum::<u64>(),
+                    self.zoom_info.iter().map(|l| l.modified).sum::<u64>(),
+                    format_delta(self.zoom_info.iter().map(|l| l.byte_delta).sum()),
+                    bbox.unwrap_or_default(),
+                )?;
+            }
+        }
+
+        if let Some(layers) = &self.layers {
+            writeln!(f)?;
+            let width = layers.keys().map(String::len).max().unwrap_or(0).max(5);
+            writeln!(f, " {:^width$} | Feature change", "Layer")?;
+            for (name, delta) in layers {
+                writeln!(f, " {name:<width$} | {delta:>+14}")?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn tile_bbox(coord: TileCoord) -> Bounds {
+    xyz_to_bbox(coord.z, coord.x, coord.y, coord.x, coord.y).into()
+}
+
+/// Format a size change in human-readable units, with a sign
+fn format_delta(delta: i64) -> String {
+    let size = SizeFormatterBinary::new(delta.unsigned_abs());
+    let sign = if delta < 0 { "-" } else { "+" };
+    format!("{sign}{size:.1}B")
+}
+
+fn csv_escape(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::{MbtilesCopier, TileChange};
+
+    #[actix_rt::test]
+    async fn diff_report() {
+        let report = MbtilesCopier {
+            src_file: PathBuf::from("../tests/fixtures/mbtiles/world_cities.mbtiles"),
+            diff_with_file: Some((
+                PathBuf::from("../tests/fixtures/mbtiles/world_cities_modified.mbtiles"),
+                None,
+            )),
+            ..Default::default()
+        }
+        .diff_report(true, true)
+        .await
+        .unwrap();
+
+        let counts: Vec<_> = report
+            .zoom_info
+            .iter()
+            .map(|v| (v.zoom, v.added, v.removed, v.modified, v.byte_delta))
+            .collect();
+        assert_eq!(
+            counts,
+            vec![(0, 0, 1, 0, -1107), (1, 0, 0, 1, -155), (4, 1, 0, 0, 24)]
+        );
+        assert_eq!(report.layers.as_ref().unwrap()["cities"], -79);
+
+        let tiles = report.tiles.as_ref().unwrap();
+        assert_eq!(tiles.len(), 3);
+        assert_eq!(tiles[0].change, TileChange::Removed);
+        let geojson = report.to_geojson().unwrap();
+        assert_eq!(geojson["features"].as_array().unwrap().len(), 3);
+
+        let csv = report.to_csv();
+        assert!(csv.starts_with("zoom,added,removed,modified,byte_delta,"));
+        assert!(csv.ends_with("layer,feature_delta\ncities,-79\n"));
+    }
+}
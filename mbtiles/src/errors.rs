@@ -1,6 +1,6 @@
 use std::path::PathBuf;
 
-use martin_tile_utils::{TileInfo, MAX_ZOOM};
+use martin_tile_utils::{Format, TileInfo, MAX_ZOOM};
 use sqlite_hashes::rusqlite;
 
 use crate::{MbtType, AGG_TILES_HASH, AGG_TILES_HASH_AFTER_APPLY, AGG_TILES_HASH_BEFORE_APPLY};
@@ -93,6 +93,9 @@ pub enum MbtError {
     #[error("The {AGG_TILES_HASH_BEFORE_APPLY}='{1}' in patch file {0} does not match {AGG_TILES_HASH}='{3}' in the file {2}")]
     AggHashMismatchWithDiff(String, String, String, String),
 
+    #[error("The {AGG_TILES_HASH_AFTER_APPLY}='{1}' in patch file {0} does not match {AGG_TILES_HASH_BEFORE_APPLY}='{3}' in the next patch file {2}")]
+    PatchChainMismatch(String, String, String, String),
+
     #[error("The {AGG_TILES_HASH_AFTER_APPLY}='{1}' in patch file {0} does not match {AGG_TILES_HASH}='{3}' in the file {2} after the patch was applied")]
     AggHashMismatchAfterApply(String, String, String, String),
 
@@ -105,9 +108,24 @@ pub enum MbtError {
     #[error("Unable to generate or apply bin-diff patch")]
     BindiffError,
 
+    #[error("Unable to recompress tiles")]
+    RecompressError,
+
     #[error("BinDiff patch files can be only applied with `mbtiles copy --apply-patch` command")]
     UnsupportedPatchType,
 
+    #[error("Invalid vector tile data: {0}")]
+    InvalidMvtData(String),
+
+    #[error("Only vector tiles can be analyzed, but {0} contains {1} tiles")]
+    NotVectorTiles(String, Format),
+
+    #[error("Invalid or unsupported PMTiles data: {0}")]
+    InvalidPmtiles(String),
+
+    #[error(transparent)]
+    PmtilesError(#[from] pmtiles::PmtError),
+
     #[error(transparent)]
     IoError(#[from] std::io::Error),
 }
@@ -7,17 +7,36 @@ pub use sqlx;
 mod copier;
 pub use copier::{CopyDuplicateMode, MbtilesCopier};
 
+mod content_check;
+pub use content_check::{TileContentError, TileErrorKind};
+
+mod converter;
+pub use converter::MbtilesConverter;
+
+mod diff_report;
+pub use diff_report::{DiffReport, ReportFormat, TileChange, TileDiff, ZoomDiff};
+
 mod errors;
 pub use errors::{MbtError, MbtResult};
 
 mod mbtiles;
 pub use mbtiles::{CopyType, MbtTypeCli, Mbtiles};
 
+mod merge;
+pub use merge::{MbtilesMerger, MergeStrategy};
+
 mod metadata;
 pub use metadata::Metadata;
 
+mod metadata_check;
+pub use metadata_check::{MetadataFinding, MetadataFix, Severity};
+
+mod mvt;
+
 mod patcher;
-pub use patcher::apply_patch;
+pub use patcher::{apply_patch, apply_patches, squash_patches, PatchHistoryEntry, PATCH_HISTORY};
+
+mod pmtiles;
 
 mod pool;
 pub use pool::MbtilesPool;
@@ -25,18 +44,28 @@ pub use pool::MbtilesPool;
 mod queries;
 pub use queries::*;
 
+mod recompress;
+pub use recompress::EncodingCli;
+
 mod summary;
+pub use summary::LayerSizeInfo;
+
+mod tile_dir;
+pub use tile_dir::{MbtilesDirExporter, MbtilesDirImporter, TileScheme};
 
 mod update;
 pub use update::UpdateZoomType;
 
+mod analyze;
+pub use analyze::{AttributeStats, AttributeType, GeometryType, LayerStats, TileStats};
+
 mod bindiff;
 
 mod validation;
 
 pub use validation::{
     calc_agg_tiles_hash, AggHashType, IntegrityCheckType, MbtType, AGG_TILES_HASH,
-    AGG_TILES_HASH_AFTER_APPLY, AGG_TILES_HASH_BEFORE_APPLY,
+    AGG_TILES_HASH_AFTER_APPLY, AGG_TILES_HASH_BEFORE_APPLY, COMPRESSION,
 };
 
 /// `MBTiles` uses a TMS (Tile Map Service) scheme for its tile coordinates (inverted along the Y axis).
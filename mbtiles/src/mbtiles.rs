@@ -44,6 +44,7 @@ impl CopyType {
     }
 }
 
+#[derive(Clone, Debug)]
 pub struct PatchFileInfo {
     pub mbt_type: MbtType,
     pub agg_tiles_hash: Option<String>,
@@ -186,7 +187,7 @@ impl Mbtiles {
         Ok(())
     }
 
-    fn get_insert_sql(
+    pub(crate) fn get_insert_sql(
         src_type: MbtType,
         on_duplicate: CopyDuplicateMode,
     ) -> (String, Option<String>) {
@@ -0,0 +1,315 @@
+use std::path::PathBuf;
+
+use enum_display::EnumDisplay;
+use futures::TryStreamExt as _;
+use log::{debug, info};
+use martin_tile_utils::{Encoding, Format};
+use serde::{Deserialize, Serialize};
+use sqlx::{query, Row as _, SqliteConnection};
+use tilejson::{TileJSON, VectorLayer};
+
+use crate::errors::MbtResult;
+use crate::mvt::merge_tiles;
+use crate::queries::{init_mbtiles_schema, is_empty_database};
+use crate::CopyDuplicateMode::Override;
+use crate::MbtType::{Flat, FlatWithHash, Normalized};
+use crate::{invert_y_value, MbtError, MbtType, MbtTypeCli, Mbtiles, Metadata};
+
+/// Number of tiles inserted into the destination in a single transaction
+const BATCH_SIZE: usize = 1000;
+
+/// How to resolve tiles with the same Z/X/Y in more than one source file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, EnumDisplay)]
+#[enum_display(case = "Kebab")]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum MergeStrategy {
+    /// Keep the tile from the first source that has it
+    #[default]
+    FirstWins,
+    /// Keep the tile from the last source that has it
+    LastWins,
+    /// Keep the largest tile, or the first one if they have the same size
+    Largest,
+    /// Combine the layers of vector tiles, merging the features of layers with the same name
+    MergeLayers,
+}
+
+/// Merge any number of `MBTiles` files into a new one
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct MbtilesMerger {
+    /// `MBTiles` files to read from, in order
+    pub src_files: Vec<PathBuf>,
+    /// `MBTiles` file to write to. It must not exist or be empty.
+    pub dst_file: PathBuf,
+    /// How to resolve tiles that exist in more than one source
+    pub strategy: MergeStrategy,
+    /// Output format of the destination file. If not specified, defaults to the type of the first source.
+    pub dst_type_cli: Option<MbtTypeCli>,
+    /// Skip generating a global hash for mbtiles validation. By default, `mbtiles` will compute `agg_tiles_hash` metadata value.
+    pub skip_agg_tiles_hash: bool,
+}
+
+impl MbtilesMerger {
+    pub async fn run(self) -> MbtResult<SqliteConnection> {
+        if self.src_files.is_empty() {
+            return Err(MbtError::UnsupportedCopyOperation {
+                reason: "at least one source file is required to merge".to_string(),
+            });
+        }
+        if self.src_files.contains(&self.dst_file) {
+            return Err(MbtError::SameSourceAndDestination(self.dst_file));
+        }
+
+        let mut sources = Vec::with_capacity(self.src_files.len());
+        for file in &self.src_files {
+            let mbt = Mbtiles::new(file)?;
+            let mut conn = mbt.open_readonly().await?;
+            let metadata = mbt.get_metadata(&mut conn).await?;
+            if let Some((_, first)) = sources.first() {
+                let first: &Metadata = first;
+                if first.tile_info != metadata.tile_info {
+                    return Err(MbtError::InconsistentMetadata(
+                        first.tile_info,
+                        metadata.tile_info,
+                    ));
+                }
+            }
+            sources.push((mbt, metadata));
+        }
+        let tile_info = sources[0].1.tile_info;
+        if self.strategy == MergeStrategy::MergeLayers && tile_info.format != Format::Mvt {
+            return Err(MbtError::UnsupportedCopyOperation {
+                reason: format!(
+                    "merging layers requires vector tiles, but the tiles are {tile_info}"
+                ),
+            });
+        }
+
+        let dst = Mbtiles::new(&self.dst_file)?;
+        let mut conn = dst.open_or_new().await?;
+        if !is_empty_database(&mut conn).await? {
+            return Err(MbtError::NonEmptyTargetFile(self.dst_file));
+        }
+        let dst_type = match self.dst_type_cli {
+            Some(MbtTypeCli::Flat) => Flat,
+            Some(MbtTypeCli::FlatWithHash) => FlatWithHash,
+            Some(MbtTypeCli::Normalized) => Normalized { hash_view: true },
+            None => {
+                let src = &sources[0].0;
+                src.detect_type(&mut src.open_readonly().await?).await?
+            }
+        };
+        init_mbtiles_schema(&mut conn, dst_type).await?;
+
+        for (src, _) in &sources {
+            info!("Merging {src} into {dst} using {} strategy", self.strategy);
+            self.merge_source(src, &dst, &mut conn, dst_type, tile_info.encoding)
+                .await?;
+        }
+        if let Normalized { .. } = dst_type {
+            // Replaced tiles may leave images that are no longer used
+            query("DELETE FROM images WHERE tile_id NOT IN (SELECT tile_id FROM map)")
+                .execute(&mut conn)
+                .await?;
+        }
+
+        let metadata: Vec<Metadata> = sources.into_iter().map(|(_, v)| v).collect();
+        dst.insert_metadata(&mut conn, &merge_tilejson(&metadata))
+            .await?;
+        if let Some(layer_type) = &metadata[0].layer_type {
+            dst.set_metadata_value(&mut conn, "type", layer_type)
+                .await?;
+        }
+        if dst.get_metadata_value(&mut conn, "format").await?.is_none() {
+            let format = tile_info.format.metadata_format_value();
+            dst.set_metadata_value(&mut conn, "format", format).await?;
+        }
+
+        if !self.skip_agg_tiles_hash {
+            dst.update_agg_tiles_hash(&mut conn).await?;
+        }
+        Ok(conn)
+    }
+
+    /// Insert all tiles of one source, resolving conflicts with the tiles already in the destination
+    async fn merge_source(
+        &self,
+        src: &Mbtiles,
+        dst: &Mbtiles,
+        conn: &mut SqliteConnection,
+        dst_type: MbtType,
+        encoding: Encoding,
+    ) -> MbtResult<()> {
+        let mut src_conn = src.open_readonly().await?;
+        let mut rows = query("SELECT zoom_level, tile_column, tile_row, tile_data FROM tiles")
+            .fetch(&mut src_conn);
+        let mut batch = Vec::with_capacity(BATCH_SIZE);
+        while let Some(row) = rows.try_next().await? {
+            let z: u8 = row.get(0);
+            let x: u32 = row.get(1);
+            let y = invert_y_value(z, row.get::<u32, _>(2));
+            let Some(data) = row.get::<Option<Vec<u8>>, _>(3) else {
+                continue;
+            };
+            let data = match self.strategy {
+                MergeStrategy::LastWins => Some(data),
+                MergeStrategy::FirstWins => match dst.get_tile(&mut *conn, z, x, y).await? {
+                    Some(_) => None,
+                    None => Some(data),
+                },
+                MergeStrategy::Largest => match dst.get_tile(&mut *conn, z, x, y).await? {
+                    Some(existing) if existing.len() >= data.len() => None,
+                    _ => Some(data),
+                },
+                MergeStrategy::MergeLayers => match dst.get_tile(&mut *conn, z, x, y).await? {
+                    Some(existing) => Some(merge_tiles(&existing, &data, encoding)?),
+                    None => Some(data),
+                },
+            };
+            if let Some(data) = data {
+                batch.push((z, x, y, data));
+            }
+            if batch.len() >= BATCH_SIZE {
+                dst.insert_tiles(&mut *conn, dst_type, Override, &batch)
+                    .await?;
+                batch.clear();
+            }
+        }
+        // All tiles of this source must be stored before the next one is compared with them
+        dst.insert_tiles(conn, dst_type, Override, &batch).await
+    }
+}
+
+/// Use the metadata of the first source, with the union of all bounds and zoom levels,
+/// and the vector layers of all sources. Layers with the same ID are combined into one.
+fn merge_tilejson(metadata: &[Metadata]) -> TileJSON {
+    let mut tj = metadata[0].tilejson.clone();
+    for tilejson in metadata[1..].iter().map(|v| &v.tilejson) {
+        tj.bounds = match (tj.bounds, tilejson.bounds) {
+            (Some(a), Some(b)) => Some(a + b),
+            (a, b) => a.or(b),
+        };
+        tj.minzoom = combine(tj.minzoom, tilejson.minzoom, u8::min);
+        tj.maxzoom = combine(tj.maxzoom, tilejson.maxzoom, u8::max);
+    }
+
+    let mut layers: Vec<VectorLayer> = Vec::new();
+    for layer in metadata
+        .iter()
+        .flat_map(|v| v.tilejson.vector_layers.iter().flatten())
+    {
+        if let Some(existing) = layers.iter_mut().find(|v| v.id == layer.id) {
+            for (key, value) in &layer.fields {
+                existing
+                    .fields
+                    .entry(key.clone())
+                    .or_insert_with(|| value.clone());
+            }
+            existing.minzoom = combine(existing.minzoom, layer.minzoom, u8::min);
+            existing.maxzoom = combine(existing.maxzoom, layer.maxzoom, u8::max);
+        } else {
+            layers.push(layer.clone());
+        }
+    }
+    if !layers.is_empty() {
+        tj.vector_layers = Some(layers);
+    }
+    debug!("Merged metadata: {tj:?}");
+    tj
+}
+
+/// Combine two optional values, or use the one that is present
+fn combine(a: Option<u8>, b: Option<u8>, f: fn(u8, u8) -> u8) -> Option<u8> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(f(a, b)),
+        (a, b) => a.or(b),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use martin_tile_utils::decode_gzip;
+
+    use super::*;
+    use crate::mvt::layer_features;
+
+    const ORIGINAL: &str = "../tests/fixtures/mbtiles/world_cities.mbtiles";
+    // Tile 1/1/1 is smaller, and each file has one tile that the other does not have
+    const MODIFIED: &str = "../tests/fixtures/mbtiles/world_cities_modified.mbtiles";
+
+    async fn merge(
+        files: &[&str],
+        strategy: MergeStrategy,
+        name: &str,
+    ) -> (Mbtiles, SqliteConnection) {
+        let dst = format!("file:merge_{name}_mem_db?mode=memory&cache=shared");
+        let conn = MbtilesMerger {
+            src_files: files.iter().map(PathBuf::from).collect(),
+            dst_file: PathBuf::from(&dst),
+            strategy,
+            ..Default::default()
+        }
+        .run()
+        .await
+        .unwrap();
+        (Mbtiles::new(dst).unwrap(), conn)
+    }
+
+    async fn get_tile(file: &str) -> Vec<u8> {
+        let mbt = Mbtiles::new(file).unwrap();
+        let mut conn = mbt.open_readonly().await.unwrap();
+        mbt.get_tile(&mut conn, 1, 1, 0).await.unwrap().unwrap()
+    }
+
+    async fn tile_len(mbt: &Mbtiles, conn: &mut SqliteConnection) -> usize {
+        // 1/1/1 in TMS
+        mbt.get_tile(conn, 1, 1, 0).await.unwrap().unwrap().len()
+    }
+
+    #[actix_rt::test]
+    async fn strategies() {
+        let (mbt, mut conn) = merge(&[ORIGINAL, MODIFIED], MergeStrategy::FirstWins, "first").await;
+        assert_eq!(tile_len(&mbt, &mut conn).await, 650);
+        let count: i64 = query("SELECT count(*) FROM tiles")
+            .fetch_one(&mut conn)
+            .await
+            .unwrap()
+            .get(0);
+        assert_eq!(count, 197);
+        assert!(mbt
+            .get_metadata_value(&mut conn, "agg_tiles_hash")
+            .await
+            .unwrap()
+            .is_some());
+        let metadata = mbt.get_metadata(&mut conn).await.unwrap();
+        assert_eq!(metadata.tilejson.vector_layers.unwrap().len(), 1);
+        assert_eq!(metadata.tilejson.maxzoom, Some(6));
+
+        let (mbt, mut conn) = merge(&[ORIGINAL, MODIFIED], MergeStrategy::LastWins, "last").await;
+        assert_eq!(tile_len(&mbt, &mut conn).await, 495);
+
+        let (mbt, mut conn) = merge(&[MODIFIED, ORIGINAL], MergeStrategy::Largest, "largest").await;
+        assert_eq!(tile_len(&mbt, &mut conn).await, 650);
+    }
+
+    #[actix_rt::test]
+    async fn merge_layers() {
+        let original = get_tile(ORIGINAL).await;
+        let modified = get_tile(MODIFIED).await;
+
+        let (mbt, mut conn) =
+            merge(&[ORIGINAL, MODIFIED], MergeStrategy::MergeLayers, "layers").await;
+        let merged = mbt.get_tile(&mut conn, 1, 1, 0).await.unwrap().unwrap();
+        // The test files contain gzip-compressed tiles
+        let layers = |data: &[u8]| layer_features(&decode_gzip(data).unwrap()).unwrap();
+        let count = |data: &[u8]| layers(data)[0].1;
+        assert!(count(&merged) > count(&original).max(count(&modified)));
+        assert!(count(&merged) <= count(&original) + count(&modified));
+
+        // Identical tiles are not changed
+        let (mbt, mut conn) =
+            merge(&[ORIGINAL, ORIGINAL], MergeStrategy::MergeLayers, "same").await;
+        let merged = mbt.get_tile(&mut conn, 1, 1, 0).await.unwrap().unwrap();
+        assert_eq!(layers(&merged), layers(&original));
+    }
+}
@@ -132,7 +132,7 @@ impl Mbtiles {
                     "legend" => tj.legend = Some(value),
                     "template" => tj.template = Some(value),
                     "json" => json = self.to_val(serde_json::from_str(&value), &name),
-                    "format" | "generator" => {
+                    "format" | "generator" | "compression" => {
                         tj.other.insert(name, Value::String(value));
                     }
                     "agg_tiles_hash" => agg_tiles_hash = Some(value),
@@ -0,0 +1,399 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr as _;
+
+use enum_display::EnumDisplay;
+use futures::TryStreamExt as _;
+use log::info;
+use martin_tile_utils::{Format, MAX_ZOOM};
+use serde::Serialize;
+use serde_json::Value;
+use sqlx::{query, Row as _, SqliteExecutor};
+use tilejson::{Bounds, Center};
+
+use crate::errors::{MbtError, MbtResult};
+use crate::Mbtiles;
+use crate::UpdateZoomType::Reset;
+
+/// Values of the `type` metadata key allowed by the `MBTiles` 1.3 specification
+const LAYER_TYPES: [&str; 2] = ["overlay", "baselayer"];
+
+/// Severity of a metadata finding
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, EnumDisplay)]
+#[enum_display(case = "Kebab")]
+#[serde(rename_all = "kebab-case")]
+pub enum Severity {
+    /// A recommended value is missing
+    Warning,
+    /// The metadata does not conform to the specification, or does not match the tiles
+    Error,
+}
+
+/// A safe correction of a metadata finding
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "kebab-case", tag = "action", content = "value")]
+pub enum MetadataFix {
+    /// Set the metadata value
+    Set(String),
+    /// Recompute `minzoom` and `maxzoom` from the tiles table
+    Zoom,
+    /// Detect `format` and `compression` from the tile content
+    Format,
+    /// Recompute `bounds` from the tiles table
+    Bounds,
+    /// Recompute `center` from the tiles table
+    Center,
+    /// Decode all vector tiles to regenerate the `vector_layers` of the `json` value
+    VectorLayers,
+}
+
+/// A problem with one metadata value
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MetadataFinding {
+    pub severity: Severity,
+    pub key: String,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fix: Option<MetadataFix>,
+}
+
+impl MetadataFinding {
+    fn new(severity: Severity, key: &str, message: String, fix: Option<MetadataFix>) -> Self {
+        Self {
+            severity,
+            key: key.to_string(),
+            message,
+            fix,
+        }
+    }
+}
+
+impl Display for MetadataFinding {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let severity = self.severity.to_string();
+        write!(f, "{severity:>7} {}: {}", self.key, self.message)?;
+        if self.fix.is_some() {
+            write!(f, " (fixable)")?;
+        }
+        Ok(())
+    }
+}
+
+impl Mbtiles {
+    /// Check the metadata values against the `MBTiles` 1.3 specification and the content of the tiles.
+    #[allow(clippy::too_many_lines)]
+    pub async fn check_metadata<T>(&self, conn: &mut T) -> MbtResult<Vec<MetadataFinding>>
+    where
+        for<'e> &'e mut T: SqliteExecutor<'e>,
+    {
+        use MetadataFix as Fix;
+        use Severity::{Error, Warning};
+
+        let mut values = HashMap::new();
+        let mut rows =
+            query("SELECT name, value FROM metadata WHERE value IS NOT ''").fetch(&mut *conn);
+        while let Some(row) = rows.try_next().await? {
+            let name: Option<String> = row.get(0);
+            let value: Option<String> = row.get(1);
+            if let (Some(name), Some(value)) = (name, value) {
+                values.insert(name, value);
+            }
+        }
+        drop(rows);
+
+        let mut findings = Vec::new();
+        let mut add = |severity, key, message: String, fix| {
+            findings.push(MetadataFinding::new(severity, key, message, fix));
+        };
+
+        if !values.contains_key("name") {
+            let name = self.filename().to_string();
+            add(
+                Error,
+                "name",
+                "required value is missing".to_string(),
+                Some(Fix::Set(name)),
+            );
+        }
+
+        let mut zoom = |key| match values.get(key).map(|v| v.parse::<u8>()) {
+            Some(Ok(v)) if v <= MAX_ZOOM => Some(v),
+            Some(_) => {
+                let message = format!("invalid zoom level {}", values[key]);
+                add(Error, key, message, Some(Fix::Zoom));
+                None
+            }
+            None => {
+                add(
+                    Warning,
+                    key,
+                    "value is missing".to_string(),
+                    Some(Fix::Zoom),
+                );
+                None
+            }
+        };
+        let (minzoom, maxzoom) = (zoom("minzoom"), zoom("maxzoom"));
+        if let (Some(min), Some(max)) = (minzoom, maxzoom) {
+            if min > max {
+                let message = format!("minzoom {min} is greater than maxzoom {max}");
+                add(Error, "minzoom", message, Some(Fix::Zoom));
+            }
+        }
+
+        let bounds = match values.get("bounds").map(|v| Bounds::from_str(v)) {
+            Some(Ok(b)) => {
+                let valid = (-180.0..=180.0).contains(&b.left)
+                    && (-180.0..=180.0).contains(&b.right)
+                    && (-90.0..=90.0).contains(&b.bottom)
+                    && (-90.0..=90.0).contains(&b.top)
+                    // left is greater than right when the bounds cross the antimeridian
+                    && b.left.total_cmp(&b.right).is_ne()
+                    && b.bottom < b.top;
+                if !valid {
+                    add(
+                        Error,
+                        "bounds",
+                        format!("invalid bounds {b}"),
+                        Some(Fix::Bounds),
+                    );
+                }
+                valid.then_some(b)
+            }
+            Some(Err(e)) => {
+                add(
+                    Error,
+                    "bounds",
+                    format!("unable to parse: {e}"),
+                    Some(Fix::Bounds),
+                );
+                None
+            }
+            None => {
+                add(
+                    Warning,
+                    "bounds",
+                    "value is missing".to_string(),
+                    Some(Fix::Bounds),
+                );
+                None
+            }
+        };
+
+        match values.get("center").map(|v| Center::from_str(v)) {
+            Some(Ok(c)) => {
+                if let Some(b) = bounds {
+                    let longitude = if b.left < b.right {
+                        (b.left..=b.right).contains(&c.longitude)
+                    } else {
+                        c.longitude >= b.left || c.longitude <= b.right
+                    };
+                    if !longitude || !(b.bottom..=b.top).contains(&c.latitude) {
+                        let message = format!("center {c} is outside of the bounds {b}");
+                        add(Error, "center", message, Some(Fix::Center));
+                    }
+                }
+                if let (Some(min), Some(max)) = (minzoom, maxzoom) {
+                    if !(min..=max).contains(&c.zoom) {
+                        let message = format!("center zoom {} is outside of {min}..{max}", c.zoom);
+                        add(Error, "center", message, Some(Fix::Center));
+                    }
+                }
+            }
+            Some(Err(e)) => {
+                add(
+                    Error,
+                    "center",
+                    format!("unable to parse: {e}"),
+                    Some(Fix::Center),
+                );
+            }
+            None => {
+                add(
+                    Warning,
+                    "center",
+                    "value is missing".to_string(),
+                    Some(Fix::Center),
+                );
+            }
+        }
+
+        // Updating the metadata cannot fix tiles with undetectable or mixed formats
+        let (detected, format_fix) = match self.detect_content_format(&mut *conn).await {
+            Ok(v) => (v, Some(Fix::Format)),
+            Err(e @ (MbtError::InconsistentMetadata(..) | MbtError::NoTilesFound)) => {
+                add(Error, "format", e.to_string(), None);
+                (None, None)
+            }
+            Err(e) => return Err(e),
+        };
+        let format = if let Some(value) = values.get("format") {
+            let format = Format::parse(value);
+            if format.is_none() {
+                let message = format!("unknown tile format {value}");
+                add(Error, "format", message, format_fix.clone());
+            }
+            format
+        } else {
+            let message = "required value is missing".to_string();
+            add(Error, "format", message, format_fix.clone());
+            None
+        };
+        if let (Some(format), Some(info)) = (format, detected) {
+            if format != info.format {
+                let message = format!(
+                    "format {} does not match the tile content {}",
+                    format.metadata_format_value(),
+                    info.format.metadata_format_value()
+                );
+                add(Error, "format", message, format_fix);
+            }
+        }
+
+        if detected.map_or(format, |v| Some(v.format)) == Some(Format::Mvt) {
+            match values.get("json").map(|v| serde_json::from_str::<Value>(v)) {
+                Some(Ok(json)) if json.get("vector_layers").is_some_and(Value::is_array) => {}
+                Some(Ok(_)) | None => {
+                    let message = "vector_layers value is required for vector tiles".to_string();
+                    add(Error, "json", message, Some(Fix::VectorLayers));
+                }
+                Some(Err(e)) => {
+                    let message = format!("unable to parse: {e}");
+                    add(Error, "json", message, Some(Fix::VectorLayers));
+                }
+            }
+        }
+
+        if let Some(value) = values.get("type") {
+            if !LAYER_TYPES.contains(&value.as_str()) {
+                let lower = value.to_ascii_lowercase();
+                let fix = LAYER_TYPES
+                    .contains(&lower.as_str())
+                    .then_some(Fix::Set(lower));
+                let message = format!("invalid value {value}, expecting overlay or baselayer");
+                add(Error, "type", message, fix);
+            }
+        }
+
+        Ok(findings)
+    }
+
+    /// Apply the safe corrections of the given findings, recomputing each value at most once
+    pub async fn fix_metadata<T>(&self, conn: &mut T, findings: &[MetadataFinding]) -> MbtResult<()>
+    where
+        for<'e> &'e mut T: SqliteExecutor<'e>,
+    {
+        let mut fixes: Vec<_> = findings
+            .iter()
+            .filter_map(|v| v.fix.clone().map(|fix| (fix, v.key.as_str())))
+            .collect();
+        // Zoom and bounds are fixed before the center that depends on them
+        fixes.sort();
+        fixes.dedup_by(|a, b| a.0 == b.0);
+
+        for (fix, key) in fixes {
+            match fix {
+                MetadataFix::Set(value) => {
+                    info!("Setting metadata value {key} to {value}");
+                    self.set_metadata_value(&mut *conn, key, value).await?;
+                }
+                MetadataFix::Zoom => self.update_metadata(&mut *conn, Reset).await?,
+                MetadataFix::Format => self.update_format(&mut *conn).await?,
+                MetadataFix::Bounds => self.update_bounds(&mut *conn, Reset).await?,
+                MetadataFix::Center => self.update_center(&mut *conn, Reset).await?,
+                MetadataFix::VectorLayers => {
+                    let stats = self.analyze(&mut *conn, None).await?;
+                    self.update_vector_layers(&mut *conn, &stats).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::Executor as _;
+
+    use super::*;
+    use crate::MbtilesCopier;
+
+    #[actix_rt::test]
+    async fn check_and_fix_metadata() {
+        let file = "file:check_and_fix_metadata_mem_db?mode=memory&cache=shared";
+        let mut conn = MbtilesCopier {
+            src_file: "../tests/fixtures/mbtiles/world_cities.mbtiles".into(),
+            dst_file: file.into(),
+            ..Default::default()
+        }
+        .run()
+        .await
+        .unwrap();
+        let mbt = Mbtiles::new(file).unwrap();
+
+        conn.execute(
+            "
+            DELETE FROM metadata WHERE name IN ('name', 'json');
+            UPDATE metadata SET value = 'png' WHERE name = 'format';
+            UPDATE metadata SET value = '-75,38,10' WHERE name = 'center';
+            INSERT OR REPLACE INTO metadata (name, value) VALUES ('type', 'Overlay');",
+        )
+        .await
+        .unwrap();
+
+        let findings = mbt.check_metadata(&mut conn).await.unwrap();
+        let keys: Vec<_> = findings
+            .iter()
+            .map(|v| (v.severity, v.key.as_str()))
+            .collect();
+        assert_eq!(
+            keys,
+            vec![
+                (Severity::Error, "name"),
+                (Severity::Error, "center"),
+                (Severity::Error, "format"),
+                (Severity::Error, "json"),
+                (Severity::Error, "type"),
+            ]
+        );
+
+        mbt.fix_metadata(&mut conn, &findings).await.unwrap();
+        assert_eq!(mbt.check_metadata(&mut conn).await.unwrap(), vec![]);
+        assert_eq!(
+            mbt.get_metadata_value(&mut conn, "type").await.unwrap(),
+            Some("overlay".to_string())
+        );
+    }
+
+    #[actix_rt::test]
+    async fn check_antimeridian_and_mixed_formats() {
+        let file = "file:check_antimeridian_mem_db?mode=memory&cache=shared";
+        let mut conn = MbtilesCopier {
+            src_file: "../tests/fixtures/mbtiles/world_cities.mbtiles".into(),
+            dst_file: file.into(),
+            ..Default::default()
+        }
+        .run()
+        .await
+        .unwrap();
+        let mbt = Mbtiles::new(file).unwrap();
+
+        conn.execute(
+            "
+            UPDATE metadata SET value = '170,-10,-170,10' WHERE name = 'bounds';
+            UPDATE metadata SET value = '-175,0,2' WHERE name = 'center';",
+        )
+        .await
+        .unwrap();
+        assert_eq!(mbt.check_metadata(&mut conn).await.unwrap(), vec![]);
+
+        conn.execute("UPDATE tiles SET tile_data = x'89504E470D0A1A0A' WHERE zoom_level = 0")
+            .await
+            .unwrap();
+        let findings = mbt.check_metadata(&mut conn).await.unwrap();
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].severity, Severity::Error);
+        assert_eq!(findings[0].key, "format");
+        assert_eq!(findings[0].fix, None);
+    }
+}
@@ -0,0 +1,762 @@
+//! Minimal [Mapbox Vector Tile](https://github.com/mapbox/vector-tile-spec/tree/master/2.1) protobuf handling,
+//! used to merge the layers of two tiles and to collect layer statistics without decoding their geometries.
+
+use std::collections::{HashMap, HashSet};
+
+use log::warn;
+use martin_tile_utils::{decode, encode, Encoding};
+
+use crate::errors::MbtResult;
+use crate::MbtError::InvalidMvtData;
+use crate::TileErrorKind;
+
+const WIRE_VARINT: u64 = 0;
+const WIRE_64BIT: u64 = 1;
+const WIRE_LEN: u64 = 2;
+const WIRE_32BIT: u64 = 5;
+
+const TILE_LAYERS: u64 = 3;
+const LAYER_NAME: u64 = 1;
+const LAYER_FEATURES: u64 = 2;
+const LAYER_KEYS: u64 = 3;
+const LAYER_VALUES: u64 = 4;
+const LAYER_EXTENT: u64 = 5;
+const LAYER_VERSION: u64 = 15;
+const FEATURE_TAGS: u64 = 2;
+const FEATURE_TYPE: u64 = 3;
+const FEATURE_GEOMETRY: u64 = 4;
+const VALUE_STRING: u64 = 1;
+const VALUE_FLOAT: u64 = 2;
+const VALUE_DOUBLE: u64 = 3;
+const VALUE_INT: u64 = 4;
+const VALUE_UINT: u64 = 5;
+const VALUE_SINT: u64 = 6;
+const VALUE_BOOL: u64 = 7;
+const DEFAULT_EXTENT: u64 = 4096;
+const DEFAULT_VERSION: u64 = 1;
+const GEOM_POINT: u64 = 1;
+const GEOM_LINESTRING: u64 = 2;
+const GEOM_POLYGON: u64 = 3;
+const CMD_MOVE_TO: u64 = 1;
+const CMD_LINE_TO: u64 = 2;
+const CMD_CLOSE_PATH: u64 = 7;
+
+fn invalid(reason: &str) -> crate::MbtError {
+    InvalidMvtData(reason.to_string())
+}
+
+fn read_varint(buf: &[u8], pos: &mut usize) -> MbtResult<u64> {
+    let mut value = 0_u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *buf.get(*pos).ok_or_else(|| invalid("truncated varint"))?;
+        *pos += 1;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(invalid("varint is too long"))
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn write_bytes(buf: &mut Vec<u8>, field: u64, data: &[u8]) {
+    write_varint(buf, field << 3 | WIRE_LEN);
+    write_varint(buf, data.len() as u64);
+    buf.extend_from_slice(data);
+}
+
+/// A single protobuf field, with the raw bytes of the whole field to copy it as is
+struct Field<'a> {
+    number: u64,
+    /// Value of a varint field
+    varint: u64,
+    /// Content of a length-delimited or a fixed-size field
+    bytes: &'a [u8],
+    raw: &'a [u8],
+}
+
+/// Split a protobuf message into its fields
+fn parse_fields(buf: &[u8]) -> MbtResult<Vec<Field<'_>>> {
+    let mut fields = Vec::new();
+    let mut pos = 0;
+    while pos < buf.len() {
+        let start = pos;
+        let key = read_varint(buf, &mut pos)?;
+        let (mut varint, mut bytes) = (0, &buf[0..0]);
+        match key & 7 {
+            WIRE_VARINT => varint = read_varint(buf, &mut pos)?,
+            WIRE_64BIT | WIRE_32BIT => {
+                let len = if key & 7 == WIRE_64BIT { 8 } else { 4 };
+                let end = pos
+                    .checked_add(len)
+                    .ok_or_else(|| invalid("truncated field"))?;
+                bytes = buf
+                    .get(pos..end)
+                    .ok_or_else(|| invalid("truncated field"))?;
+                pos = end;
+            }
+            WIRE_LEN => {
+                let len = usize::try_from(read_varint(buf, &mut pos)?)
+                    .map_err(|_| invalid("field is too long"))?;
+                let end = pos
+                    .checked_add(len)
+                    .ok_or_else(|| invalid("truncated field"))?;
+                bytes = buf
+                    .get(pos..end)
+                    .ok_or_else(|| invalid("truncated field"))?;
+                pos = end;
+            }
+            _ => return Err(invalid("unsupported wire type")),
+        }
+        if pos > buf.len() {
+            return Err(invalid("truncated field"));
+        }
+        fields.push(Field {
+            number: key >> 3,
+            varint,
+            bytes,
+            raw: &buf[start..pos],
+        });
+    }
+    Ok(fields)
+}
+
+#[derive(Default)]
+struct Layer {
+    name: Vec<u8>,
+    extent: u64,
+    features: Vec<Vec<u8>>,
+    keys: Vec<Vec<u8>>,
+    values: Vec<Vec<u8>>,
+    /// Raw bytes of all other fields, e.g. the version
+    other: Vec<u8>,
+}
+
+impl Layer {
+    fn parse(buf: &[u8]) -> MbtResult<Self> {
+        let mut layer = Self {
+            extent: DEFAULT_EXTENT,
+            ..Default::default()
+        };
+        for field in parse_fields(buf)? {
+            match field.number {
+                LAYER_NAME => layer.name = field.bytes.to_vec(),
+                LAYER_FEATURES => layer.features.push(field.bytes.to_vec()),
+                LAYER_KEYS => layer.keys.push(field.bytes.to_vec()),
+                LAYER_VALUES => layer.values.push(field.bytes.to_vec()),
+                LAYER_EXTENT => layer.extent = field.varint,
+                _ => layer.other.extend_from_slice(field.raw),
+            }
+        }
+        Ok(layer)
+    }
+
+    fn serialize(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.other);
+        write_bytes(buf, LAYER_NAME, &self.name);
+        for feature in &self.features {
+            write_bytes(buf, LAYER_FEATURES, feature);
+        }
+        for key in &self.keys {
+            write_bytes(buf, LAYER_KEYS, key);
+        }
+        for value in &self.values {
+            write_bytes(buf, LAYER_VALUES, value);
+        }
+        write_varint(buf, LAYER_EXTENT << 3 | WIRE_VARINT);
+        write_varint(buf, self.extent);
+    }
+
+    /// Append the features of another layer with the same extent,
+    /// re-indexing their tags into the keys and values of this layer.
+    /// Features identical to the existing ones are skipped.
+    fn append(&mut self, other: Layer) -> MbtResult<()> {
+        let key_map = merge_table(&mut self.keys, other.keys);
+        let value_map = merge_table(&mut self.values, other.values);
+        let mut existing: HashSet<Vec<u8>> = self.features.iter().cloned().collect();
+        for feature in other.features {
+            let mut buf = Vec::with_capacity(feature.len());
+            for field in parse_fields(&feature)? {
+                if field.number != FEATURE_TAGS {
+                    buf.extend_from_slice(field.raw);
+                    continue;
+                }
+                let mut tags = Vec::new();
+                let (mut pos, mut idx) = (0, 0);
+                while pos < field.bytes.len() {
+                    let tag = read_varint(field.bytes, &mut pos)?;
+                    let map = if idx % 2 == 0 { &key_map } else { &value_map };
+                    let tag = usize::try_from(tag)
+                        .ok()
+                        .and_then(|v| map.get(v))
+                        .ok_or_else(|| invalid("feature tag is out of range"))?;
+                    write_varint(&mut tags, *tag as u64);
+                    idx += 1;
+                }
+                write_bytes(&mut buf, FEATURE_TAGS, &tags);
+            }
+            if existing.insert(buf.clone()) {
+                self.features.push(buf);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Add the new entries to the table, reusing identical ones, and return the new index of each entry
+fn merge_table(table: &mut Vec<Vec<u8>>, entries: Vec<Vec<u8>>) -> Vec<usize> {
+    let mut index: HashMap<Vec<u8>, usize> = table
+        .iter()
+        .enumerate()
+        .map(|(idx, v)| (v.clone(), idx))
+        .collect();
+    entries
+        .into_iter()
+        .map(|entry| {
+            *index.entry(entry).or_insert_with_key(|entry| {
+                table.push(entry.clone());
+                table.len() - 1
+            })
+        })
+        .collect()
+}
+
+fn parse_tile(buf: &[u8]) -> MbtResult<Vec<Layer>> {
+    parse_fields(buf)?
+        .into_iter()
+        .filter(|f| f.number == TILE_LAYERS)
+        .map(|f| Layer::parse(f.bytes))
+        .collect()
+}
+
+/// Merge the layers of two vector tiles, both compressed with the given encoding.
+/// Features of layers with the same name are combined into one layer.
+/// The result is compressed with the same encoding.
+pub(crate) fn merge_tiles(first: &[u8], second: &[u8], encoding: Encoding) -> MbtResult<Vec<u8>> {
+    let first = decode(first, encoding)?;
+    let second = decode(second, encoding)?;
+    let mut layers = parse_tile(&first)?;
+    for layer in parse_tile(&second)? {
+        match layers.iter_mut().find(|v| v.name == layer.name) {
+            Some(existing) if existing.extent == layer.extent => existing.append(layer)?,
+            Some(existing) => warn!(
+                "Unable to merge layer {} with extents {} and {}, keeping the first one",
+                String::from_utf8_lossy(&layer.name),
+                existing.extent,
+                layer.extent
+            ),
+            None => layers.push(layer),
+        }
+    }
+
+    let mut tile = Vec::with_capacity(first.len() + second.len());
+    let mut buf = Vec::new();
+    for layer in &layers {
+        buf.clear();
+        layer.serialize(&mut buf);
+        write_bytes(&mut tile, TILE_LAYERS, &buf);
+    }
+    Ok(encode(&tile, encoding, None)?)
+}
+
+/// Names of the layers and their feature counts of an uncompressed vector tile
+pub(crate) fn layer_features(data: &[u8]) -> MbtResult<Vec<(String, usize)>> {
+    Ok(parse_tile(data)?
+        .into_iter()
+        .map(|v| {
+            (
+                String::from_utf8_lossy(&v.name).to_string(),
+                v.features.len(),
+            )
+        })
+        .collect())
+}
+
+/// Attribute value of a vector tile feature
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum MvtValue {
+    String(String),
+    Number(f64),
+    Bool(bool),
+}
+
+impl MvtValue {
+    #[allow(clippy::cast_possible_wrap, clippy::cast_precision_loss)]
+    fn parse(buf: &[u8]) -> MbtResult<Self> {
+        let mut value = None;
+        for field in parse_fields(buf)? {
+            value = Some(match field.number {
+                VALUE_STRING => Self::String(String::from_utf8_lossy(field.bytes).to_string()),
+                VALUE_FLOAT => Self::Number(f64::from(f32::from_le_bytes(
+                    field
+                        .bytes
+                        .try_into()
+                        .map_err(|_| invalid("invalid float value"))?,
+                ))),
+                VALUE_DOUBLE => Self::Number(f64::from_le_bytes(
+                    field
+                        .bytes
+                        .try_into()
+                        .map_err(|_| invalid("invalid double value"))?,
+                )),
+                VALUE_INT => Self::Number(field.varint as i64 as f64),
+                VALUE_UINT => Self::Number(field.varint as f64),
+                VALUE_SINT => {
+                    let v = field.varint;
+                    Self::Number(((v >> 1) as i64 ^ -((v & 1) as i64)) as f64)
+                }
+                VALUE_BOOL => Self::Bool(field.varint != 0),
+                _ => continue,
+            });
+        }
+        value.ok_or_else(|| invalid("feature value has no content"))
+    }
+}
+
+/// A feature of a decoded layer, without its geometry
+pub(crate) struct FeatureContent {
+    /// Geometry type: 0 for unknown, 1 for points, 2 for lines, and 3 for polygons
+    pub geom_type: u64,
+    /// Indexes of the keys and values of the layer
+    pub tags: Vec<(usize, usize)>,
+}
+
+/// A layer with decoded attribute keys and values
+pub(crate) struct LayerContent {
+    pub name: String,
+    /// Size of the encoded layer, in bytes
+    pub size: usize,
+    pub keys: Vec<String>,
+    pub values: Vec<MvtValue>,
+    pub features: Vec<FeatureContent>,
+}
+
+/// Decode the layers of an uncompressed vector tile, including feature attributes and geometry types
+pub(crate) fn decode_layers(data: &[u8]) -> MbtResult<Vec<LayerContent>> {
+    let mut result = Vec::new();
+    for field in parse_fields(data)? {
+        if field.number != TILE_LAYERS {
+            continue;
+        }
+        let layer = Layer::parse(field.bytes)?;
+        let keys: Vec<String> = layer
+            .keys
+            .iter()
+            .map(|v| String::from_utf8_lossy(v).to_string())
+            .collect();
+        let values = layer
+            .values
+            .iter()
+            .map(|v| MvtValue::parse(v))
+            .collect::<MbtResult<Vec<_>>>()?;
+
+        let mut features = Vec::with_capacity(layer.features.len());
+        for feature in &layer.features {
+            let mut content = FeatureContent {
+                geom_type: 0,
+                tags: Vec::new(),
+            };
+            for field in parse_fields(feature)? {
+                match field.number {
+                    FEATURE_TYPE => content.geom_type = field.varint,
+                    FEATURE_TAGS => {
+                        let mut pos = 0;
+                        while pos < field.bytes.len() {
+                            let key = read_varint(field.bytes, &mut pos)?;
+                            let value = read_varint(field.bytes, &mut pos)?;
+                            match (usize::try_from(key), usize::try_from(value)) {
+                                (Ok(k), Ok(v)) if k < keys.len() && v < values.len() => {
+                                    content.tags.push((k, v));
+                                }
+                                _ => return Err(invalid("feature tag is out of range")),
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            features.push(content);
+        }
+
+        result.push(LayerContent {
+            name: String::from_utf8_lossy(&layer.name).to_string(),
+            size: field.bytes.len(),
+            keys,
+            values,
+            features,
+        });
+    }
+    Ok(result)
+}
+
+/// Fully decode an uncompressed vector tile, checking the layer names, versions and extents,
+/// the feature attributes, and the geometry commands of every feature.
+pub(crate) fn check_tile(data: &[u8]) -> Result<(), (TileErrorKind, String)> {
+    let protobuf = |e: crate::MbtError| (TileErrorKind::Protobuf, e.to_string());
+    // Decoding the attribute values and tags validates them
+    decode_layers(data).map_err(protobuf)?;
+
+    let mut names = HashSet::new();
+    let mut tile_version = None;
+    for layer in parse_tile(data).map_err(protobuf)? {
+        let name = String::from_utf8_lossy(&layer.name).to_string();
+        if name.is_empty() {
+            return Err((TileErrorKind::Protobuf, "layer has no name".to_string()));
+        }
+        if !names.insert(layer.name.clone()) {
+            return Err((
+                TileErrorKind::DuplicateLayer,
+                format!("layer {name} appears more than once"),
+            ));
+        }
+
+        let version = parse_fields(&layer.other)
+            .map_err(protobuf)?
+            .iter()
+            .rev()
+            .find(|f| f.number == LAYER_VERSION)
+            .map_or(DEFAULT_VERSION, |f| f.varint);
+        if !(1..=2).contains(&version) {
+            return Err((
+                TileErrorKind::LayerVersion,
+                format!("layer {name} has unsupported version {version}"),
+            ));
+        }
+        match tile_version {
+            Some(v) if v != version => {
+                return Err((
+                    TileErrorKind::LayerVersion,
+                    format!(
+                        "layer {name} has version {version}, but other layers have version {v}"
+                    ),
+                ));
+            }
+            _ => tile_version = Some(version),
+        }
+
+        if layer.extent == 0 || layer.extent > u64::from(u32::MAX) {
+            return Err((
+                TileErrorKind::Extent,
+                format!("layer {name} has invalid extent {}", layer.extent),
+            ));
+        }
+
+        for (idx, feature) in layer.features.iter().enumerate() {
+            let mut geom_type = 0;
+            let mut geometry: &[u8] = &[];
+            for field in parse_fields(feature).map_err(protobuf)? {
+                match field.number {
+                    FEATURE_TYPE => geom_type = field.varint,
+                    FEATURE_GEOMETRY => geometry = field.bytes,
+                    _ => {}
+                }
+            }
+            check_geometry(geom_type, geometry).map_err(|e| {
+                (
+                    TileErrorKind::Geometry,
+                    format!("feature #{idx} of layer {name}: {e}"),
+                )
+            })?;
+        }
+    }
+    Ok(())
+}
+
+/// Validate the drawing commands of a feature geometry against its geometry type
+fn check_geometry(geom_type: u64, buf: &[u8]) -> Result<(), String> {
+    let mut commands = Vec::new();
+    let mut pos = 0;
+    while pos < buf.len() {
+        let cmd = read_varint(buf, &mut pos).map_err(|e| e.to_string())?;
+        let (id, count) = (cmd & 7, cmd >> 3);
+        let params = match id {
+            CMD_MOVE_TO | CMD_LINE_TO if count == 0 => {
+                return Err(format!("command {id} has a zero count"));
+            }
+            CMD_MOVE_TO | CMD_LINE_TO => count * 2,
+            CMD_CLOSE_PATH if count != 1 => {
+                return Err(format!("ClosePath command has count {count}"));
+            }
+            CMD_CLOSE_PATH => 0,
+            _ => return Err(format!("unknown command {id}")),
+        };
+        for _ in 0..params {
+            read_varint(buf, &mut pos).map_err(|_| "truncated command parameters".to_string())?;
+        }
+        commands.push((id, count));
+    }
+
+    let mut rest = commands.as_slice();
+    match geom_type {
+        0 => return Ok(()),
+        GEOM_POINT | GEOM_LINESTRING | GEOM_POLYGON if rest.is_empty() => {
+            return Err("geometry is empty".to_string());
+        }
+        GEOM_POINT => {
+            if !matches!(rest, [(CMD_MOVE_TO, _)]) {
+                return Err("point geometry must have a single MoveTo command".to_string());
+            }
+        }
+        GEOM_LINESTRING => {
+            while let [(CMD_MOVE_TO, 1), (CMD_LINE_TO, _), tail @ ..] = rest {
+                rest = tail;
+            }
+            if !rest.is_empty() {
+                return Err("line geometry must have MoveTo and LineTo command pairs".to_string());
+            }
+        }
+        GEOM_POLYGON => {
+            while let [(CMD_MOVE_TO, 1), (CMD_LINE_TO, 2..), (CMD_CLOSE_PATH, _), tail @ ..] = rest
+            {
+                rest = tail;
+            }
+            if !rest.is_empty() {
+                return Err(
+                    "polygon rings must have MoveTo, LineTo and ClosePath commands".to_string(),
+                );
+            }
+        }
+        _ => return Err(format!("unknown geometry type {geom_type}")),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feature(id: u64, tags: &[u64]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        write_varint(&mut buf, 1 << 3 | WIRE_VARINT);
+        write_varint(&mut buf, id);
+        let mut packed = Vec::new();
+        for tag in tags {
+            write_varint(&mut packed, *tag);
+        }
+        write_bytes(&mut buf, FEATURE_TAGS, &packed);
+        buf
+    }
+
+    /// Layer name, keys, values, and encoded features
+    type TestLayer<'a> = (&'a str, &'a [&'a str], &'a [&'a str], &'a [Vec<u8>]);
+
+    fn tile(layers: &[TestLayer]) -> Vec<u8> {
+        let mut tile = Vec::new();
+        for (name, keys, values, features) in layers {
+            let layer = Layer {
+                name: name.as_bytes().to_vec(),
+                extent: DEFAULT_EXTENT,
+                features: features.to_vec(),
+                keys: keys.iter().map(|v| v.as_bytes().to_vec()).collect(),
+                values: values.iter().map(|v| v.as_bytes().to_vec()).collect(),
+                other: vec![15 << 3, 2],
+            };
+            let mut buf = Vec::new();
+            layer.serialize(&mut buf);
+            write_bytes(&mut tile, TILE_LAYERS, &buf);
+        }
+        tile
+    }
+
+    #[test]
+    fn merge_layers() {
+        let first = tile(&[("roads", &["kind"], &["major"], &[feature(1, &[0, 0])])]);
+        let second = tile(&[
+            (
+                "roads",
+                &["name", "kind"],
+                &["minor", "Main St"],
+                &[feature(2, &[1, 0, 0, 1])],
+            ),
+            ("water", &[], &[], &[feature(3, &[])]),
+        ]);
+        let merged = merge_tiles(&first, &second, Encoding::Uncompressed).unwrap();
+        let merged = parse_tile(&merged).unwrap();
+        assert_eq!(merged.len(), 2);
+        let roads = &merged[0];
+        assert_eq!(roads.other, vec![15 << 3, 2]);
+        assert_eq!(roads.keys, vec![b"kind".to_vec(), b"name".to_vec()]);
+        assert_eq!(
+            roads.values,
+            vec![b"major".to_vec(), b"minor".to_vec(), b"Main St".to_vec()]
+        );
+        assert_eq!(
+            roads.features,
+            vec![feature(1, &[0, 0]), feature(2, &[0, 1, 1, 2])]
+        );
+        assert_eq!(merged[1].name, b"water");
+        assert_eq!(merged[1].features, vec![feature(3, &[])]);
+    }
+
+    #[test]
+    fn merge_compressed() {
+        let first = tile(&[("a", &[], &[], &[feature(1, &[])])]);
+        let second = tile(&[("a", &[], &[], &[feature(1, &[]), feature(2, &[])])]);
+        for encoding in [
+            Encoding::Gzip,
+            Encoding::Zlib,
+            Encoding::Brotli,
+            Encoding::Zstd,
+        ] {
+            let merged = merge_tiles(
+                &encode(&first, encoding, None).unwrap(),
+                &encode(&second, encoding, None).unwrap(),
+                encoding,
+            )
+            .unwrap();
+            let merged = decode(&merged, encoding).unwrap();
+            // The identical feature is not duplicated
+            assert_eq!(parse_tile(&merged).unwrap()[0].features.len(), 2);
+        }
+
+        assert!(merge_tiles(b"\x1a\x05abc", &first, Encoding::Uncompressed).is_err());
+        assert!(merge_tiles(&first, &second, Encoding::Gzip).is_err());
+    }
+
+    #[test]
+    fn field_length_overflow() {
+        // A length-delimited field claiming to be usize::MAX bytes long
+        let mut data = Vec::new();
+        write_varint(&mut data, TILE_LAYERS << 3 | WIRE_LEN);
+        write_varint(&mut data, u64::MAX);
+        assert!(parse_fields(&data).is_err());
+        assert_eq!(check_tile(&data).unwrap_err().0, TileErrorKind::Protobuf);
+    }
+
+    #[test]
+    fn decode_feature_values() {
+        let mut values = Vec::new();
+        let mut buf = Vec::new();
+        write_bytes(&mut buf, VALUE_STRING, b"Main St");
+        values.push(buf);
+        let mut buf = Vec::new();
+        write_varint(&mut buf, VALUE_DOUBLE << 3 | WIRE_64BIT);
+        buf.extend_from_slice(&1.5_f64.to_le_bytes());
+        values.push(buf);
+        let mut buf = Vec::new();
+        write_varint(&mut buf, VALUE_SINT << 3 | WIRE_VARINT);
+        write_varint(&mut buf, 5);
+        values.push(buf);
+        let mut buf = Vec::new();
+        write_varint(&mut buf, VALUE_BOOL << 3 | WIRE_VARINT);
+        write_varint(&mut buf, 1);
+        values.push(buf);
+
+        let mut line = feature(1, &[0, 0, 1, 1]);
+        write_varint(&mut line, FEATURE_TYPE << 3 | WIRE_VARINT);
+        write_varint(&mut line, 2);
+        let layer = Layer {
+            name: b"roads".to_vec(),
+            extent: DEFAULT_EXTENT,
+            features: vec![line, feature(2, &[1, 2, 2, 3])],
+            keys: vec![b"name".to_vec(), b"lanes".to_vec(), b"oneway".to_vec()],
+            values,
+            other: Vec::new(),
+        };
+        let mut buf = Vec::new();
+        layer.serialize(&mut buf);
+        let mut tile = Vec::new();
+        write_bytes(&mut tile, TILE_LAYERS, &buf);
+
+        let layers = decode_layers(&tile).unwrap();
+        assert_eq!(layers.len(), 1);
+        let roads = &layers[0];
+        assert_eq!(roads.name, "roads");
+        assert_eq!(roads.size, buf.len());
+        assert_eq!(
+            roads.values,
+            vec![
+                MvtValue::String("Main St".to_string()),
+                MvtValue::Number(1.5),
+                MvtValue::Number(-3.0),
+                MvtValue::Bool(true),
+            ]
+        );
+        assert_eq!(roads.features[0].geom_type, 2);
+        assert_eq!(roads.features[0].tags, vec![(0, 0), (1, 1)]);
+        assert_eq!(roads.features[1].geom_type, 0);
+        assert_eq!(roads.features[1].tags, vec![(1, 2), (2, 3)]);
+
+        // Tags must refer to existing keys and values
+        let layer = Layer {
+            features: vec![feature(1, &[0, 9])],
+            ..layer
+        };
+        let mut buf = Vec::new();
+        layer.serialize(&mut buf);
+        let mut tile = Vec::new();
+        write_bytes(&mut tile, TILE_LAYERS, &buf);
+        assert!(decode_layers(&tile).is_err());
+    }
+
+    fn geom_feature(geom_type: u64, commands: &[u64]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        write_varint(&mut buf, FEATURE_TYPE << 3 | WIRE_VARINT);
+        write_varint(&mut buf, geom_type);
+        let mut packed = Vec::new();
+        for cmd in commands {
+            write_varint(&mut packed, *cmd);
+        }
+        write_bytes(&mut buf, FEATURE_GEOMETRY, &packed);
+        buf
+    }
+
+    fn error_kind(layers: &[TestLayer]) -> TileErrorKind {
+        check_tile(&tile(layers)).unwrap_err().0
+    }
+
+    #[test]
+    fn check_tile_content() {
+        // MoveTo(1), LineTo(2), and ClosePath(1) commands are encoded as 9, 18, and 15
+        let point = geom_feature(GEOM_POINT, &[9, 20, 20]);
+        let polygon = geom_feature(GEOM_POLYGON, &[9, 0, 0, 18, 20, 0, 0, 20, 15]);
+        let valid = [point.clone(), polygon];
+        assert!(check_tile(&tile(&[("a", &[], &[], &valid)])).is_ok());
+
+        let truncated = [geom_feature(GEOM_POINT, &[9, 20])];
+        assert_eq!(
+            error_kind(&[("a", &[], &[], &truncated)]),
+            TileErrorKind::Geometry
+        );
+        let no_line_to = [geom_feature(GEOM_LINESTRING, &[9, 0, 0])];
+        assert_eq!(
+            error_kind(&[("a", &[], &[], &no_line_to)]),
+            TileErrorKind::Geometry
+        );
+        let points = [point];
+        assert_eq!(
+            error_kind(&[("a", &[], &[], &points), ("a", &[], &[], &points)]),
+            TileErrorKind::DuplicateLayer
+        );
+        let bad_tag = [feature(1, &[0, 0])];
+        assert_eq!(
+            error_kind(&[("a", &[], &[], &bad_tag)]),
+            TileErrorKind::Protobuf
+        );
+
+        let with_layer = |other: Vec<u8>| {
+            let mut buf = Vec::new();
+            Layer {
+                name: b"b".to_vec(),
+                extent: 0,
+                other,
+                ..Default::default()
+            }
+            .serialize(&mut buf);
+            let mut data = tile(&[("a", &[], &[], &points)]);
+            write_bytes(&mut data, TILE_LAYERS, &buf);
+            check_tile(&data).unwrap_err().0
+        };
+        // Test layers use version 2, the added one defaults to version 1
+        assert_eq!(with_layer(vec![]), TileErrorKind::LayerVersion);
+        assert_eq!(with_layer(vec![15 << 3, 2]), TileErrorKind::Extent);
+    }
+}
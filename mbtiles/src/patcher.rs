@@ -1,63 +1,343 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use log::{debug, info, warn};
-use sqlx::{query, Connection as _};
+use serde::{Deserialize, Serialize};
+use sqlx::{query, query_scalar, Connection as _, Executor as _, SqliteConnection};
+use tempfile::TempPath;
 
-use crate::queries::detach_db;
+use crate::mbtiles::PatchFileInfo;
+use crate::queries::{detach_db, get_bsdiff_tbl_name, init_mbtiles_schema, is_empty_database};
 use crate::MbtType::{Flat, FlatWithHash, Normalized};
 use crate::{
-    MbtError, MbtResult, MbtType, Mbtiles, AGG_TILES_HASH, AGG_TILES_HASH_AFTER_APPLY,
-    AGG_TILES_HASH_BEFORE_APPLY,
+    MbtError, MbtResult, MbtType, Mbtiles, MbtilesCopier, PatchType, AGG_TILES_HASH,
+    AGG_TILES_HASH_AFTER_APPLY, AGG_TILES_HASH_BEFORE_APPLY,
 };
 
+/// Metadata key with a JSON list of all patches applied to the file
+pub const PATCH_HISTORY: &str = "patch_history";
+
+/// `SQLite` can attach at most 10 databases to one connection
+const MAX_PATCH_CHAIN: usize = 10;
+
+/// An entry of the [`PATCH_HISTORY`] metadata value
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PatchHistoryEntry {
+    pub agg_tiles_hash_before_apply: Option<String>,
+    pub agg_tiles_hash_after_apply: Option<String>,
+    /// UTC time the patch was applied, in RFC 3339 format
+    pub applied_at: String,
+}
+
 pub async fn apply_patch(base_file: PathBuf, patch_file: PathBuf, force: bool) -> MbtResult<()> {
-    let base_mbt = Mbtiles::new(base_file)?;
-    let patch_mbt = Mbtiles::new(patch_file)?;
+    apply_patches(base_file, &[patch_file], force, false, None).await
+}
 
-    let mut conn = patch_mbt.open_readonly().await?;
-    let patch_info = patch_mbt.examine_diff(&mut conn).await?;
-    if patch_info.patch_type.is_some() {
+/// Apply a chain of patch files to the base file, in order.
+/// The whole chain is validated before the base file is modified:
+/// each patch must expect the hash the previous one produces.
+/// All patches are applied in a single transaction, so a failure leaves the base file unchanged.
+/// If `record_history` is set, each applied patch is appended to the [`PATCH_HISTORY`] metadata.
+/// If `reverse_patch` is set, a new patch file that undoes the whole chain is written there.
+pub async fn apply_patches(
+    base_file: PathBuf,
+    patch_files: &[PathBuf],
+    force: bool,
+    record_history: bool,
+    reverse_patch: Option<PathBuf>,
+) -> MbtResult<()> {
+    if patch_files.len() > MAX_PATCH_CHAIN {
+        return Err(MbtError::UnsupportedCopyOperation {
+            reason: format!("at most {MAX_PATCH_CHAIN} patches can be applied at once, use squash-patches to combine them first"),
+        });
+    }
+    let base_mbt = Mbtiles::new(base_file)?;
+    let patches = examine_patch_chain(patch_files, force).await?;
+    if patches.iter().any(|(_, info)| info.patch_type.is_some()) {
         return Err(MbtError::UnsupportedPatchType);
     }
-    patch_mbt.validate_diff_info(&patch_info, force)?;
-    let patch_type = patch_info.mbt_type;
-    conn.close().await?;
 
     let mut conn = base_mbt.open().await?;
     let base_info = base_mbt.examine_diff(&mut conn).await?;
-    let base_hash = base_mbt.get_agg_tiles_hash(&mut conn).await?;
     base_mbt.assert_hashes(&base_info, force)?;
+    // The rest of the chain was validated by examine_patch_chain
+    let (first_mbt, first_info) = &patches[0];
+    check_base_hash(
+        &base_mbt,
+        base_info.agg_tiles_hash.clone(),
+        first_mbt,
+        first_info,
+        force,
+    )?;
+
+    let reverse = if let Some(reverse_file) = reverse_patch {
+        Some(ReversePatch::create(reverse_file, &base_mbt, &patches, "").await?)
+    } else {
+        None
+    };
+
+    // Databases cannot be detached inside a transaction, so all patches are attached first
+    let aliases: Vec<_> = (0..patches.len()).map(|v| format!("patchDb{v}")).collect();
+    for ((patch_mbt, _), alias) in patches.iter().zip(&aliases) {
+        patch_mbt.attach_to(&mut conn, alias).await?;
+    }
+
+    conn.execute("BEGIN").await?;
+    let result = async {
+        for ((patch_mbt, patch_info), alias) in patches.iter().zip(&aliases) {
+            let base_hash = base_mbt.get_agg_tiles_hash(&mut conn).await?;
+            check_base_hash(&base_mbt, base_hash, patch_mbt, patch_info, force)?;
+            apply_one(
+                &base_mbt,
+                &mut conn,
+                base_info.mbt_type,
+                (patch_mbt, alias),
+                patch_info,
+            )
+            .await?;
+            if record_history {
+                append_patch_history(&base_mbt, &mut conn, patch_info).await?;
+            }
+        }
+        Ok(())
+    }
+    .await;
+    if let Err(e) = result {
+        conn.execute("ROLLBACK").await?;
+        return Err(e);
+    }
+    conn.execute("COMMIT").await?;
+
+    for alias in &aliases {
+        detach_db(&mut conn, alias).await?;
+    }
+
+    if let Some(reverse) = reverse {
+        let new_hash = base_mbt.get_agg_tiles_hash(&mut conn).await?;
+        reverse.finish(new_hash, base_info.agg_tiles_hash).await?;
+    }
+
+    Ok(())
+}
+
+/// A patch file that undoes applying other patches. It holds the original data
+/// of every tile and metadata value the patches overwrite or delete,
+/// and a `NULL` for each one they add.
+/// The patch is written to a temporary file, which is only renamed to `reverse_file`
+/// once the patches have been applied, and deleted otherwise.
+pub(crate) struct ReversePatch {
+    mbt: Mbtiles,
+    conn: SqliteConnection,
+    tmp_file: TempPath,
+    reverse_file: PathBuf,
+}
+
+impl ReversePatch {
+    /// Create a reverse patch from the current content of the base file.
+    /// Must be called before any of the patches are applied.
+    pub(crate) async fn create(
+        reverse_file: PathBuf,
+        base_mbt: &Mbtiles,
+        patches: &[(Mbtiles, PatchFileInfo)],
+        sql_where: &str,
+    ) -> MbtResult<Self> {
+        if reverse_file.exists() {
+            let mbt = Mbtiles::new(&reverse_file)?;
+            let mut conn = mbt.open_readonly().await?;
+            let is_empty = is_empty_database(&mut conn).await?;
+            conn.close().await?;
+            if !is_empty {
+                return Err(MbtError::NonEmptyTargetFile(reverse_file));
+            }
+        }
+
+        let tmp_file = temp_file_next_to(&reverse_file)?;
+        let mbt = Mbtiles::new(&tmp_file)?;
+        let mut conn = mbt.open_or_new().await?;
+        info!(
+            "Saving the tiles modified by the patch to a reverse patch file {}",
+            reverse_file.display()
+        );
+        init_mbtiles_schema(&mut conn, Flat).await?;
+
+        base_mbt.attach_to(&mut conn, "baseDb").await?;
+        for (patch_mbt, patch_info) in patches {
+            patch_mbt.attach_to(&mut conn, "patchDb").await?;
+
+            let coords = if let Some(patch_type) = patch_info.patch_type {
+                // Tiles modified with bin-diff are only listed in the bin-diff table
+                let tbl = get_bsdiff_tbl_name(patch_type);
+                format!(
+                    "
+            SELECT zoom_level, tile_column, tile_row FROM patchDb.tiles
+            UNION SELECT zoom_level, tile_column, tile_row FROM patchDb.{tbl}"
+                )
+            } else {
+                "SELECT zoom_level, tile_column, tile_row FROM patchDb.tiles".to_string()
+            };
+            let sql = format!(
+                "
+    INSERT OR IGNORE INTO tiles (zoom_level, tile_column, tile_row, tile_data)
+    SELECT patch.zoom_level, patch.tile_column, patch.tile_row, base.tile_data
+    FROM ({coords}) AS patch
+         LEFT JOIN baseDb.tiles AS base
+           ON base.zoom_level = patch.zoom_level
+          AND base.tile_column = patch.tile_column
+          AND base.tile_row = patch.tile_row
+    WHERE TRUE {sql_where}"
+            );
+            query(&sql).execute(&mut conn).await?;
+
+            let sql = format!(
+                "
+    INSERT OR IGNORE INTO metadata (name, value)
+    SELECT patch.name, base.value
+    FROM patchDb.metadata AS patch
+         LEFT JOIN baseDb.metadata AS base ON base.name = patch.name
+    WHERE patch.name NOTNULL
+      AND patch.name NOT IN ('{AGG_TILES_HASH}', '{AGG_TILES_HASH_BEFORE_APPLY}',
+                             '{AGG_TILES_HASH_AFTER_APPLY}', '{PATCH_HISTORY}');"
+            );
+            query(&sql).execute(&mut conn).await?;
+
+            detach_db(&mut conn, "patchDb").await?;
+        }
+        detach_db(&mut conn, "baseDb").await?;
 
-    match (force, base_hash, patch_info.agg_tiles_hash_before_apply) {
-        (false, Some(base_hash), Some(expected_hash)) if base_hash != expected_hash => {
+        Ok(Self {
+            mbt,
+            conn,
+            tmp_file,
+            reverse_file,
+        })
+    }
+
+    /// Set the hashes of the reverse patch, once the patches have been applied.
+    /// `before` is the hash of the patched file, and `after` is the hash of the original one.
+    pub(crate) async fn finish(
+        mut self,
+        before: Option<String>,
+        after: Option<String>,
+    ) -> MbtResult<()> {
+        let conn = &mut self.conn;
+        for (key, value) in [
+            (AGG_TILES_HASH_BEFORE_APPLY, before),
+            (AGG_TILES_HASH_AFTER_APPLY, after),
+        ] {
+            if let Some(value) = value {
+                self.mbt.set_metadata_value(&mut *conn, key, value).await?;
+            } else {
+                warn!("The reverse patch file {} has no {key} because the file it was created from has no {AGG_TILES_HASH}", self.reverse_file.display());
+            }
+        }
+        self.mbt.update_agg_tiles_hash(&mut *conn).await?;
+        self.conn.close().await?;
+        self.tmp_file
+            .persist(&self.reverse_file)
+            .map_err(|e| e.error)?;
+        Ok(())
+    }
+}
+
+/// Create a uniquely named empty file in the same directory as `path`, so that it can be
+/// renamed to `path` once it is complete. The file is deleted when the returned value is dropped.
+pub(crate) fn temp_file_next_to(path: &Path) -> MbtResult<TempPath> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let name = path
+        .file_name()
+        .map_or_else(String::new, |v| v.to_string_lossy().to_string());
+    let file = tempfile::Builder::new()
+        .prefix(&format!(".{name}."))
+        .suffix(".tmp")
+        .tempfile_in(dir)?;
+    Ok(file.into_temp_path())
+}
+
+/// Examine all patch files, and ensure the `after` hash of each patch
+/// matches the `before` hash of the next one.
+async fn examine_patch_chain(
+    patch_files: &[PathBuf],
+    force: bool,
+) -> MbtResult<Vec<(Mbtiles, PatchFileInfo)>> {
+    let mut patches: Vec<(Mbtiles, PatchFileInfo)> = Vec::with_capacity(patch_files.len());
+    for patch_file in patch_files {
+        let patch_mbt = Mbtiles::new(patch_file)?;
+        let mut conn = patch_mbt.open_readonly().await?;
+        let patch_info = patch_mbt.examine_diff(&mut conn).await?;
+        conn.close().await?;
+        patch_mbt.validate_diff_info(&patch_info, force)?;
+
+        if let Some((prev_mbt, prev_info)) = patches.last() {
+            match (
+                &prev_info.agg_tiles_hash_after_apply,
+                &patch_info.agg_tiles_hash_before_apply,
+            ) {
+                (Some(after), Some(before)) if after != before => {
+                    let err = MbtError::PatchChainMismatch(
+                        prev_mbt.filepath().to_string(),
+                        after.clone(),
+                        patch_mbt.filepath().to_string(),
+                        before.clone(),
+                    );
+                    if !force {
+                        return Err(err);
+                    }
+                    warn!("{err} (force mode)");
+                }
+                _ => {}
+            }
+        }
+        patches.push((patch_mbt, patch_info));
+    }
+    Ok(patches)
+}
+
+fn check_base_hash(
+    base_mbt: &Mbtiles,
+    base_hash: Option<String>,
+    patch_mbt: &Mbtiles,
+    patch_info: &PatchFileInfo,
+    force: bool,
+) -> MbtResult<()> {
+    match (force, base_hash, &patch_info.agg_tiles_hash_before_apply) {
+        (false, Some(base_hash), Some(expected_hash)) if &base_hash != expected_hash => {
             return Err(MbtError::AggHashMismatchWithDiff(
                 patch_mbt.filepath().to_string(),
-                expected_hash,
+                expected_hash.clone(),
                 base_mbt.filepath().to_string(),
                 base_hash,
             ));
         }
-        (true, Some(base_hash), Some(expected_hash)) if base_hash != expected_hash => {
+        (true, Some(base_hash), Some(expected_hash)) if &base_hash != expected_hash => {
             warn!("Aggregate tiles hash mismatch: Patch file expected {expected_hash} but found {base_hash} in {base_mbt} (force mode)");
         }
         _ => {}
     }
+    Ok(())
+}
 
-    info!(
-        "Applying patch file {patch_mbt} ({patch_type}) to {base_mbt} ({base_type})",
-        base_type = base_info.mbt_type
-    );
+/// Apply one patch file, already attached to the connection as `patch_db`
+async fn apply_one(
+    base_mbt: &Mbtiles,
+    conn: &mut SqliteConnection,
+    base_type: MbtType,
+    (patch_mbt, patch_db): (&Mbtiles, &str),
+    patch_info: &PatchFileInfo,
+) -> MbtResult<()> {
+    let patch_type = patch_info.mbt_type;
+    info!("Applying patch file {patch_mbt} ({patch_type}) to {base_mbt} ({base_type})");
 
-    patch_mbt.attach_to(&mut conn, "patchDb").await?;
-    let select_from = get_select_from(base_info.mbt_type, patch_type);
-    let (main_table, insert1, insert2) = get_insert_sql(base_info.mbt_type, select_from);
+    let select_from = get_select_from(base_type, patch_type, patch_db);
+    let (main_table, insert1, insert2) = get_insert_sql(base_type, &select_from);
 
     let sql = format!("{insert1} WHERE tile_data NOTNULL");
-    query(&sql).execute(&mut conn).await?;
+    query(&sql).execute(&mut *conn).await?;
 
     if let Some(insert2) = insert2 {
         let sql = format!("{insert2} WHERE tile_data NOTNULL");
-        query(&sql).execute(&mut conn).await?;
+        query(&sql).execute(&mut *conn).await?;
     }
 
     let sql = format!(
@@ -67,56 +347,245 @@ pub async fn apply_patch(base_file: PathBuf, patch_file: PathBuf, force: bool) -
         SELECT zoom_level, tile_column, tile_row FROM ({select_from} WHERE tile_data ISNULL)
     )"
     );
-    query(&sql).execute(&mut conn).await?;
+    query(&sql).execute(&mut *conn).await?;
 
-    if base_info.mbt_type.is_normalized() {
+    if base_type.is_normalized() {
         debug!("Removing unused tiles from the images table (normalized schema)");
         let sql = "DELETE FROM images WHERE tile_id NOT IN (SELECT tile_id FROM map)";
-        query(sql).execute(&mut conn).await?;
+        query(sql).execute(&mut *conn).await?;
     }
 
     // Copy metadata from patchDb to the destination file, replacing existing values
     // Convert 'agg_tiles_hash_in_patch' into 'agg_tiles_hash'
     // Delete metadata entries if the value is NULL in patchDb
+    // The patch history belongs to each file, and is never copied from the patch
     let sql = format!(
         "
     INSERT OR REPLACE INTO metadata (name, value)
     SELECT IIF(name = '{AGG_TILES_HASH_AFTER_APPLY}', '{AGG_TILES_HASH}', name) as name,
            value
-    FROM patchDb.metadata
-    WHERE name NOTNULL AND name NOT IN ('{AGG_TILES_HASH}', '{AGG_TILES_HASH_BEFORE_APPLY}');"
+    FROM {patch_db}.metadata
+    WHERE name NOTNULL
+      AND name NOT IN ('{AGG_TILES_HASH}', '{AGG_TILES_HASH_BEFORE_APPLY}', '{PATCH_HISTORY}');"
     );
-    query(&sql).execute(&mut conn).await?;
+    query(&sql).execute(&mut *conn).await?;
 
-    let sql = "
+    let sql = format!(
+        "
     DELETE FROM metadata
-    WHERE name IN (SELECT name FROM patchDb.metadata WHERE value ISNULL);";
-    query(sql).execute(&mut conn).await?;
+    WHERE name IN (SELECT name FROM {patch_db}.metadata WHERE value ISNULL)
+      AND name != '{PATCH_HISTORY}';"
+    );
+    query(&sql).execute(&mut *conn).await?;
+
+    Ok(())
+}
+
+async fn append_patch_history(
+    mbt: &Mbtiles,
+    conn: &mut SqliteConnection,
+    patch_info: &PatchFileInfo,
+) -> MbtResult<()> {
+    let mut history: Vec<PatchHistoryEntry> =
+        match mbt.get_metadata_value(&mut *conn, PATCH_HISTORY).await? {
+            Some(value) => serde_json::from_str(&value).unwrap_or_else(|e| {
+                warn!("Replacing invalid {PATCH_HISTORY} metadata in {mbt}: {e}");
+                Vec::new()
+            }),
+            None => Vec::new(),
+        };
+
+    let applied_at: String = query_scalar("SELECT strftime('%Y-%m-%dT%H:%M:%SZ', 'now')")
+        .fetch_one(&mut *conn)
+        .await?;
+    history.push(PatchHistoryEntry {
+        agg_tiles_hash_before_apply: patch_info.agg_tiles_hash_before_apply.clone(),
+        agg_tiles_hash_after_apply: patch_info.agg_tiles_hash_after_apply.clone(),
+        applied_at,
+    });
+
+    let value = serde_json::to_string(&history)?;
+    mbt.set_metadata_value(conn, PATCH_HISTORY, value).await
+}
+
+/// Combine a chain of consecutive patch files into a single patch file.
+/// Whole-tile patches are merged directly, with later patches overriding earlier ones.
+/// Bin-diff patches cannot be combined without the original tiles, so they require
+/// the `base_file` the first patch applies to: the chain is applied to temporary files,
+/// and the result is diffed with the base file.
+pub async fn squash_patches(
+    dst_file: PathBuf,
+    patch_files: &[PathBuf],
+    base_file: Option<PathBuf>,
+    force: bool,
+) -> MbtResult<()> {
+    let patches = examine_patch_chain(patch_files, force).await?;
+    let Some((first_mbt, _)) = patches.first() else {
+        return Err(MbtError::UnsupportedCopyOperation {
+            reason: "at least one patch file is required".to_string(),
+        });
+    };
+
+    let mut patch_types = patches.iter().filter_map(|(_, info)| info.patch_type);
+    let Some(patch_type) = patch_types.next() else {
+        return squash_whole_patches(dst_file, &patches).await;
+    };
+    if patch_types.any(|v| v != patch_type) {
+        return Err(MbtError::UnsupportedCopyOperation {
+            reason: "cannot squash patches that use different bin-diff types".to_string(),
+        });
+    }
+    let Some(base_file) = base_file else {
+        return Err(MbtError::UnsupportedCopyOperation {
+            reason: format!(
+                "squashing bin-diff patches like {first_mbt} requires the base file they apply to"
+            ),
+        });
+    };
+
+    squash_bindiff_patches(dst_file, &patches, base_file, patch_type, force).await
+}
+
+async fn squash_whole_patches(
+    dst_file: PathBuf,
+    patches: &[(Mbtiles, PatchFileInfo)],
+) -> MbtResult<()> {
+    let (first_mbt, _) = &patches[0];
+    let dst_mbt = Mbtiles::new(&dst_file)?;
+    let mut conn = MbtilesCopier {
+        src_file: PathBuf::from(first_mbt.filepath()),
+        dst_file,
+        ..Default::default()
+    }
+    .run()
+    .await?;
+    let dst_type = dst_mbt.detect_type(&mut conn).await?;
+
+    for (patch_mbt, patch_info) in &patches[1..] {
+        info!(
+            "Squashing patch file {patch_mbt} ({patch_type}) into {dst_mbt} ({dst_type})",
+            patch_type = patch_info.mbt_type
+        );
+        patch_mbt.attach_to(&mut conn, "patchDb").await?;
+
+        // Unlike when applying a patch, deleted tiles (NULLs) are copied as well,
+        // using an empty hash the same way a newly created patch file does
+        let select_from = get_select_from(dst_type, patch_info.mbt_type, "patchDb");
+        let select_from = if dst_type == Flat {
+            select_from
+        } else {
+            format!(
+                "
+        SELECT zoom_level, tile_column, tile_row, tile_data, COALESCE(hash, '') AS hash
+        FROM ({select_from})"
+            )
+        };
+        let (_, insert1, insert2) = get_insert_sql(dst_type, &select_from);
+        if let Some(insert2) = insert2 {
+            query(&insert2).execute(&mut conn).await?;
+        }
+        query(&insert1).execute(&mut conn).await?;
+
+        // Keep the 'before' hash of the first patch, and take all other values from the newer patch
+        let sql = format!(
+            "
+    INSERT OR REPLACE INTO metadata (name, value)
+    SELECT name, value
+    FROM patchDb.metadata
+    WHERE name NOTNULL AND name NOT IN ('{AGG_TILES_HASH}', '{AGG_TILES_HASH_BEFORE_APPLY}');"
+        );
+        query(&sql).execute(&mut conn).await?;
 
-    detach_db(&mut conn, "patchDb").await
+        detach_db(&mut conn, "patchDb").await?;
+    }
+
+    if dst_type.is_normalized() {
+        debug!("Removing unused tiles from the images table (normalized schema)");
+        let sql = "DELETE FROM images WHERE tile_id NOT IN (SELECT tile_id FROM map)";
+        query(sql).execute(&mut conn).await?;
+    }
+    dst_mbt.update_agg_tiles_hash(&mut conn).await?;
+
+    Ok(())
 }
 
-fn get_select_from(src_type: MbtType, patch_type: MbtType) -> &'static str {
+async fn squash_bindiff_patches(
+    dst_file: PathBuf,
+    patches: &[(Mbtiles, PatchFileInfo)],
+    base_file: PathBuf,
+    patch_type: PatchType,
+    force: bool,
+) -> MbtResult<()> {
+    let base_mbt = Mbtiles::new(&base_file)?;
+    let mut conn = base_mbt.open_readonly().await?;
+    let base_hash = base_mbt.get_agg_tiles_hash(&mut conn).await?;
+    conn.close().await?;
+    let (first_mbt, first_info) = &patches[0];
+    check_base_hash(&base_mbt, base_hash, first_mbt, first_info, force)?;
+
+    // Each temporary file is deleted when dropped, including on error
+    let mut tmp_files = Vec::with_capacity(patches.len());
+    let mut src_file = base_file.clone();
+    for (patch_mbt, _) in patches {
+        let tmp_file = temp_file_next_to(&dst_file)?;
+        info!(
+            "Applying patch file {patch_mbt} to a temporary file {}",
+            tmp_file.display()
+        );
+        MbtilesCopier {
+            src_file,
+            dst_file: tmp_file.to_path_buf(),
+            apply_patch: Some(PathBuf::from(patch_mbt.filepath())),
+            force,
+            ..Default::default()
+        }
+        .run()
+        .await?
+        .close()
+        .await?;
+        src_file = tmp_file.to_path_buf();
+        tmp_files.push(tmp_file);
+    }
+
+    info!(
+        "Creating a squashed {patch_type} patch from {base_mbt} to {}",
+        src_file.display()
+    );
+    MbtilesCopier {
+        src_file: base_file,
+        dst_file,
+        diff_with_file: Some((src_file, Some(patch_type))),
+        force,
+        ..Default::default()
+    }
+    .run()
+    .await?
+    .close()
+    .await?;
+    Ok(())
+}
+
+fn get_select_from(src_type: MbtType, patch_type: MbtType, patch_db: &str) -> String {
     if src_type == Flat {
-        "SELECT zoom_level, tile_column, tile_row, tile_data FROM patchDb.tiles"
+        format!("SELECT zoom_level, tile_column, tile_row, tile_data FROM {patch_db}.tiles")
     } else {
         match patch_type {
-            Flat => {
+            Flat => format!(
                 "
         SELECT zoom_level, tile_column, tile_row, tile_data, md5_hex(tile_data) as hash
-        FROM patchDb.tiles"
-            }
-            FlatWithHash => {
+        FROM {patch_db}.tiles"
+            ),
+            FlatWithHash => format!(
                 "
         SELECT zoom_level, tile_column, tile_row, tile_data, tile_hash AS hash
-        FROM patchDb.tiles_with_hash"
-            }
-            Normalized { .. } => {
+        FROM {patch_db}.tiles_with_hash"
+            ),
+            Normalized { .. } => format!(
                 "
         SELECT zoom_level, tile_column, tile_row, tile_data, map.tile_id AS hash
-        FROM patchDb.map LEFT JOIN patchDb.images
-          ON patchDb.map.tile_id = patchDb.images.tile_id"
-            }
+        FROM {patch_db}.map LEFT JOIN {patch_db}.images
+          ON {patch_db}.map.tile_id = {patch_db}.images.tile_id"
+            ),
         }
     }
 }
@@ -164,7 +633,8 @@ mod tests {
     use sqlx::Executor as _;
 
     use super::*;
-    use crate::MbtilesCopier;
+    use crate::calc_agg_tiles_hash;
+    use crate::validation::calc_filtered_agg_tiles_hash;
 
     #[actix_rt::test]
     async fn apply_flat_patch_file() -> MbtResult<()> {
@@ -228,4 +698,272 @@ mod tests {
 
         Ok(())
     }
+
+    async fn copy(src_file: &str, dst_file: &str, diff_with: Option<&str>) -> SqliteConnection {
+        copy_bindiff(src_file, dst_file, diff_with, None).await
+    }
+
+    async fn copy_bindiff(
+        src_file: &str,
+        dst_file: &str,
+        diff_with: Option<&str>,
+        patch_type: Option<PatchType>,
+    ) -> SqliteConnection {
+        MbtilesCopier {
+            src_file: PathBuf::from(src_file),
+            dst_file: PathBuf::from(dst_file),
+            diff_with_file: diff_with.map(|v| (PathBuf::from(v), patch_type)),
+            ..Default::default()
+        }
+        .run()
+        .await
+        .unwrap()
+    }
+
+    async fn assert_same_tiles(conn: &mut SqliteConnection, other: &str) {
+        Mbtiles::new(other)
+            .unwrap()
+            .attach_to(&mut *conn, "testOtherDb")
+            .await
+            .unwrap();
+        for sql in [
+            "SELECT * FROM tiles EXCEPT SELECT * FROM testOtherDb.tiles",
+            "SELECT * FROM testOtherDb.tiles EXCEPT SELECT * FROM tiles",
+        ] {
+            assert!(conn.fetch_optional(sql).await.unwrap().is_none());
+        }
+        detach_db(&mut *conn, "testOtherDb").await.unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn apply_and_squash_patch_chain() {
+        // A patch chain that modifies the file, and then reverts the change
+        let orig = "file:chain_orig_mem_db?mode=memory&cache=shared";
+        let modified = "file:chain_modified_mem_db?mode=memory&cache=shared";
+        let patch1 = "file:chain_patch1_mem_db?mode=memory&cache=shared";
+        let patch2 = "file:chain_patch2_mem_db?mode=memory&cache=shared";
+        let _c1 = copy("../tests/fixtures/mbtiles/world_cities.mbtiles", orig, None).await;
+        let _c2 = copy(
+            "../tests/fixtures/mbtiles/world_cities_modified.mbtiles",
+            modified,
+            None,
+        )
+        .await;
+        let _c3 = copy(orig, patch1, Some(modified)).await;
+        let _c4 = copy(modified, patch2, Some(orig)).await;
+        let patches = [PathBuf::from(patch1), PathBuf::from(patch2)];
+
+        // Broken chains are rejected before the base file is modified
+        let base = "file:chain_base_mem_db?mode=memory&cache=shared";
+        let mut conn = copy(orig, base, None).await;
+        let broken = [PathBuf::from(patch1), PathBuf::from(patch1)];
+        let err = apply_patches(PathBuf::from(base), &broken, false, false, None).await;
+        assert!(matches!(err, Err(MbtError::PatchChainMismatch(..))));
+        assert_same_tiles(&mut conn, orig).await;
+        let too_long = vec![PathBuf::from(patch1); MAX_PATCH_CHAIN + 1];
+        let err = apply_patches(PathBuf::from(base), &too_long, false, false, None).await;
+        assert!(matches!(
+            err,
+            Err(MbtError::UnsupportedCopyOperation { .. })
+        ));
+
+        apply_patches(PathBuf::from(base), &patches, false, true, None)
+            .await
+            .unwrap();
+        assert_same_tiles(&mut conn, orig).await;
+        let base_mbt = Mbtiles::new(base).unwrap();
+        let history = base_mbt
+            .get_metadata_value(&mut conn, PATCH_HISTORY)
+            .await
+            .unwrap()
+            .unwrap();
+        let history: Vec<PatchHistoryEntry> = serde_json::from_str(&history).unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(
+            history[0].agg_tiles_hash_after_apply,
+            history[1].agg_tiles_hash_before_apply
+        );
+
+        // The squashed patch goes directly from the first 'before' to the last 'after' hash
+        let squashed = "file:chain_squashed_mem_db?mode=memory&cache=shared";
+        // Keep the in-memory DB alive after squashing
+        let squashed_mbt = Mbtiles::new(squashed).unwrap();
+        let mut squashed_conn = squashed_mbt.open_or_new().await.unwrap();
+        squash_patches(PathBuf::from(squashed), &patches, None, false)
+            .await
+            .unwrap();
+        let info = squashed_mbt.examine_diff(&mut squashed_conn).await.unwrap();
+        assert_eq!(
+            info.agg_tiles_hash_before_apply,
+            history[0].agg_tiles_hash_before_apply
+        );
+        assert_eq!(
+            info.agg_tiles_hash_after_apply,
+            history[1].agg_tiles_hash_after_apply
+        );
+
+        let base2 = "file:chain_base2_mem_db?mode=memory&cache=shared";
+        let mut conn = copy(orig, base2, None).await;
+        apply_patch(PathBuf::from(base2), PathBuf::from(squashed), false)
+            .await
+            .unwrap();
+        assert_same_tiles(&mut conn, orig).await;
+    }
+
+    #[actix_rt::test]
+    async fn squash_bindiff_patch_chain() {
+        let orig = "file:bin_chain_orig_mem_db?mode=memory&cache=shared";
+        let modified = "file:bin_chain_modified_mem_db?mode=memory&cache=shared";
+        let patch1 = "file:bin_chain_patch1_mem_db?mode=memory&cache=shared";
+        let patch2 = "file:bin_chain_patch2_mem_db?mode=memory&cache=shared";
+        let _c1 = copy("../tests/fixtures/mbtiles/world_cities.mbtiles", orig, None).await;
+        let _c2 = copy(
+            "../tests/fixtures/mbtiles/world_cities_modified.mbtiles",
+            modified,
+            None,
+        )
+        .await;
+        let bin_diff = Some(PatchType::BinDiffRaw);
+        let _c3 = copy_bindiff(orig, patch1, Some(modified), bin_diff).await;
+        let _c4 = copy_bindiff(modified, patch2, Some(orig), bin_diff).await;
+        let patches = [PathBuf::from(patch1), PathBuf::from(patch2)];
+
+        let dir = tempfile::tempdir().unwrap();
+        let squashed = dir.path().join("squashed.mbtiles");
+        let err = squash_patches(squashed.clone(), &patches, None, false).await;
+        assert!(matches!(
+            err,
+            Err(MbtError::UnsupportedCopyOperation { .. })
+        ));
+
+        squash_patches(squashed.clone(), &patches, Some(PathBuf::from(orig)), false)
+            .await
+            .unwrap();
+        // The intermediate files are removed
+        let files: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|v| v.unwrap().file_name())
+            .collect();
+        assert_eq!(files, vec!["squashed.mbtiles"]);
+        let squashed_mbt = Mbtiles::new(&squashed).unwrap();
+        let mut squashed_conn = squashed_mbt.open_readonly().await.unwrap();
+        let info = squashed_mbt.examine_diff(&mut squashed_conn).await.unwrap();
+        assert_eq!(info.patch_type, bin_diff);
+
+        let result = "file:bin_chain_result_mem_db?mode=memory&cache=shared";
+        let mut conn = MbtilesCopier {
+            src_file: PathBuf::from(orig),
+            dst_file: PathBuf::from(result),
+            apply_patch: Some(squashed),
+            ..Default::default()
+        }
+        .run()
+        .await
+        .unwrap();
+        assert_same_tiles(&mut conn, orig).await;
+    }
+
+    #[actix_rt::test]
+    async fn reverse_patch() {
+        let orig = "file:reverse_orig_mem_db?mode=memory&cache=shared";
+        let modified = "file:reverse_modified_mem_db?mode=memory&cache=shared";
+        let patch = "file:reverse_patch_mem_db?mode=memory&cache=shared";
+        let bin_patch = "file:reverse_bin_patch_mem_db?mode=memory&cache=shared";
+        let _c1 = copy("../tests/fixtures/mbtiles/world_cities.mbtiles", orig, None).await;
+        let _c2 = copy(
+            "../tests/fixtures/mbtiles/world_cities_modified.mbtiles",
+            modified,
+            None,
+        )
+        .await;
+        let _c3 = copy(orig, patch, Some(modified)).await;
+        let _c4 = copy_bindiff(orig, bin_patch, Some(modified), Some(PatchType::BinDiffRaw)).await;
+
+        // Patch in-place, and undo it with the reverse patch
+        let dir = tempfile::tempdir().unwrap();
+        let base = "file:reverse_base_mem_db?mode=memory&cache=shared";
+        let reverse = dir.path().join("reverse.mbtiles");
+        let mut conn = copy(orig, base, None).await;
+
+        // A patch that does not match the base file does not leave a reverse patch behind
+        let err = apply_patches(
+            PathBuf::from(modified),
+            &[patch.into()],
+            false,
+            false,
+            Some(reverse.clone()),
+        )
+        .await;
+        assert!(err.is_err());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+
+        apply_patches(
+            PathBuf::from(base),
+            &[patch.into()],
+            false,
+            false,
+            Some(reverse.clone()),
+        )
+        .await
+        .unwrap();
+        assert_same_tiles(&mut conn, modified).await;
+        apply_patch(PathBuf::from(base), reverse, false)
+            .await
+            .unwrap();
+        assert_same_tiles(&mut conn, orig).await;
+        Mbtiles::new(orig)
+            .unwrap()
+            .attach_to(&mut conn, "origDb")
+            .await
+            .unwrap();
+        for sql in [
+            "SELECT * FROM metadata EXCEPT SELECT * FROM origDb.metadata",
+            "SELECT * FROM origDb.metadata EXCEPT SELECT * FROM metadata",
+        ] {
+            assert!(conn.fetch_optional(sql).await.unwrap().is_none());
+        }
+
+        // Apply a bin-diff patch while copying, and undo it with the reverse patch
+        let dst = "file:reverse_dst_mem_db?mode=memory&cache=shared";
+        let reverse = dir.path().join("bin_reverse.mbtiles");
+        let mut conn = MbtilesCopier {
+            src_file: PathBuf::from(orig),
+            dst_file: PathBuf::from(dst),
+            apply_patch: Some(PathBuf::from(bin_patch)),
+            reverse_patch: Some(reverse.clone()),
+            ..Default::default()
+        }
+        .run()
+        .await
+        .unwrap();
+        assert_same_tiles(&mut conn, modified).await;
+        apply_patch(PathBuf::from(dst), reverse, false)
+            .await
+            .unwrap();
+        assert_same_tiles(&mut conn, orig).await;
+
+        // The reverse patch of a filtered copy restores the filtered source
+        let dst = "file:reverse_filtered_dst_mem_db?mode=memory&cache=shared";
+        let reverse = dir.path().join("filtered_reverse.mbtiles");
+        let mut conn = MbtilesCopier {
+            src_file: PathBuf::from(orig),
+            dst_file: PathBuf::from(dst),
+            apply_patch: Some(PathBuf::from(patch)),
+            reverse_patch: Some(reverse.clone()),
+            max_zoom: Some(4),
+            ..Default::default()
+        }
+        .run()
+        .await
+        .unwrap();
+        let expected = Mbtiles::new(orig).unwrap();
+        let mut orig_conn = expected.open_readonly().await.unwrap();
+        let expected = calc_filtered_agg_tiles_hash(&mut orig_conn, " AND zoom_level <= 4")
+            .await
+            .unwrap();
+        apply_patch(PathBuf::from(dst), reverse, false)
+            .await
+            .unwrap();
+        assert_eq!(calc_agg_tiles_hash(&mut conn).await.unwrap(), expected);
+    }
 }
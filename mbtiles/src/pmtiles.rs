@@ -0,0 +1,657 @@
+//! A minimal writer of the [PMTiles v3](https://github.com/protomaps/PMTiles/blob/main/spec/v3/spec.md)
+//! archive format, used to convert `MBTiles` files to `PMTiles`. Tiles are read with the `pmtiles` crate,
+//! which can only look up single tiles, so the directories are walked here to list the existing ones.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Read as _, Seek as _, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use martin_tile_utils::{Encoding, Format};
+use pmtiles::TileType;
+use serde_json::Value;
+use xxhash_rust::xxh3::xxh3_128;
+
+use crate::errors::MbtResult;
+use crate::MbtError::InvalidPmtiles;
+
+const MAGIC: &[u8; 7] = b"PMTiles";
+const VERSION: u8 = 3;
+const HEADER_SIZE: usize = 127;
+/// The header and the root directory must fit into the first 16 KiB of the file
+const MAX_ROOT_DIR_SIZE: usize = 16_384 - HEADER_SIZE;
+/// Initial number of entries in each leaf directory, doubled until the root directory fits
+const LEAF_DIR_ENTRIES: usize = 4096;
+
+const COMPRESSION_UNKNOWN: u8 = 0;
+const COMPRESSION_NONE: u8 = 1;
+const COMPRESSION_GZIP: u8 = 2;
+const COMPRESSION_BROTLI: u8 = 3;
+const COMPRESSION_ZSTD: u8 = 4;
+
+const TILE_TYPE_UNKNOWN: u8 = 0;
+const TILE_TYPE_MVT: u8 = 1;
+const TILE_TYPE_PNG: u8 = 2;
+const TILE_TYPE_JPEG: u8 = 3;
+const TILE_TYPE_WEBP: u8 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub(crate) struct Header {
+    pub root_offset: u64,
+    pub root_length: u64,
+    pub metadata_offset: u64,
+    pub metadata_length: u64,
+    pub leaf_offset: u64,
+    pub leaf_length: u64,
+    pub data_offset: u64,
+    pub data_length: u64,
+    pub addressed_tiles: u64,
+    pub tile_entries: u64,
+    pub tile_contents: u64,
+    pub clustered: bool,
+    pub internal_compression: u8,
+    pub tile_compression: u8,
+    pub tile_type: u8,
+    pub min_zoom: u8,
+    pub max_zoom: u8,
+    /// `[left, bottom, right, top]` in WGS84 degrees
+    pub bounds: [f64; 4],
+    pub center_zoom: u8,
+    /// `[longitude, latitude]` in WGS84 degrees
+    pub center: [f64; 2],
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn to_e7(value: f64) -> [u8; 4] {
+    ((value * 10_000_000.0).round() as i32).to_le_bytes()
+}
+
+fn read_u64(buf: &[u8], pos: usize) -> u64 {
+    u64::from_le_bytes(buf[pos..pos + 8].try_into().unwrap())
+}
+
+impl Header {
+    fn to_bytes(self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_SIZE);
+        buf.extend_from_slice(MAGIC);
+        buf.push(VERSION);
+        for value in [
+            self.root_offset,
+            self.root_length,
+            self.metadata_offset,
+            self.metadata_length,
+            self.leaf_offset,
+            self.leaf_length,
+            self.data_offset,
+            self.data_length,
+            self.addressed_tiles,
+            self.tile_entries,
+            self.tile_contents,
+        ] {
+            buf.extend_from_slice(&value.to_le_bytes());
+        }
+        buf.push(u8::from(self.clustered));
+        buf.push(self.internal_compression);
+        buf.push(self.tile_compression);
+        buf.push(self.tile_type);
+        buf.push(self.min_zoom);
+        buf.push(self.max_zoom);
+        for value in self.bounds {
+            buf.extend_from_slice(&to_e7(value));
+        }
+        buf.push(self.center_zoom);
+        for value in self.center {
+            buf.extend_from_slice(&to_e7(value));
+        }
+        debug_assert_eq!(buf.len(), HEADER_SIZE);
+        buf
+    }
+}
+
+/// `PMTiles` compression type of the tile data
+pub(crate) fn tile_compression(encoding: Encoding) -> u8 {
+    match encoding {
+        Encoding::Uncompressed | Encoding::Internal => COMPRESSION_NONE,
+        Encoding::Gzip => COMPRESSION_GZIP,
+        Encoding::Brotli => COMPRESSION_BROTLI,
+        Encoding::Zstd => COMPRESSION_ZSTD,
+        Encoding::Zlib => COMPRESSION_UNKNOWN,
+    }
+}
+
+/// `PMTiles` tile type of the tile data
+pub(crate) fn tile_type(format: Format) -> u8 {
+    match format {
+        Format::Mvt => TILE_TYPE_MVT,
+        Format::Png => TILE_TYPE_PNG,
+        Format::Jpeg => TILE_TYPE_JPEG,
+        Format::Webp => TILE_TYPE_WEBP,
+        Format::Gif | Format::Json => TILE_TYPE_UNKNOWN,
+    }
+}
+
+/// `MBTiles` `format` metadata value of a `PMTiles` tile type
+pub(crate) fn mbtiles_format(tile_type: TileType) -> Option<&'static str> {
+    match tile_type {
+        TileType::Mvt => Some("pbf"),
+        TileType::Png => Some("png"),
+        TileType::Jpeg => Some("jpg"),
+        TileType::Webp => Some("webp"),
+        TileType::Unknown => None,
+    }
+}
+
+/// Compute the Hilbert curve tile ID, with tiles of all lower zooms numbered first
+#[must_use]
+#[allow(clippy::many_single_char_names)]
+pub(crate) fn zxy_to_tile_id(z: u8, x: u32, y: u32) -> u64 {
+    let base = ((1_u64 << (2 * u32::from(z))) - 1) / 3;
+    let n = 1_u64 << z;
+    let (mut x, mut y) = (u64::from(x), u64::from(y));
+    let mut d = 0;
+    let mut s = n >> 1;
+    while s > 0 {
+        let rx = u64::from(x & s > 0);
+        let ry = u64::from(y & s > 0);
+        d += s * s * ((3 * rx) ^ ry);
+        rotate(n, &mut x, &mut y, rx, ry);
+        s >>= 1;
+    }
+    base + d
+}
+
+/// Inverse of [`zxy_to_tile_id`]
+#[must_use]
+#[allow(clippy::cast_possible_truncation, clippy::many_single_char_names)]
+pub(crate) fn tile_id_to_zxy(tile_id: u64) -> Option<(u8, u32, u32)> {
+    let mut base = 0_u64;
+    for z in 0..=martin_tile_utils::MAX_ZOOM {
+        let count = 1_u64 << (2 * u32::from(z));
+        if tile_id < base + count {
+            let n = 1_u64 << z;
+            let mut t = tile_id - base;
+            let (mut x, mut y) = (0, 0);
+            let mut s = 1;
+            while s < n {
+                let rx = 1 & (t / 2);
+                let ry = 1 & (t ^ rx);
+                rotate(s, &mut x, &mut y, rx, ry);
+                x += s * rx;
+                y += s * ry;
+                t /= 4;
+                s *= 2;
+            }
+            return Some((z, x as u32, y as u32));
+        }
+        base += count;
+    }
+    None
+}
+
+fn rotate(n: u64, x: &mut u64, y: &mut u64, rx: u64, ry: u64) {
+    if ry == 0 {
+        if rx == 1 {
+            *x = n - 1 - *x;
+            *y = n - 1 - *y;
+        }
+        std::mem::swap(x, y);
+    }
+}
+
+/// A directory entry. A zero `run_length` points to a leaf directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Entry {
+    pub tile_id: u64,
+    pub offset: u64,
+    pub length: u32,
+    pub run_length: u32,
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn read_varint(buf: &[u8], pos: &mut usize) -> MbtResult<u64> {
+    let mut value = 0_u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *buf
+            .get(*pos)
+            .ok_or_else(|| InvalidPmtiles("truncated directory".to_string()))?;
+        *pos += 1;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(InvalidPmtiles("invalid varint in directory".to_string()))
+}
+
+/// Serialize and gzip-compress a directory
+fn serialize_directory(entries: &[Entry]) -> MbtResult<Vec<u8>> {
+    let mut buf = Vec::new();
+    write_varint(&mut buf, entries.len() as u64);
+    let mut last_id = 0;
+    for entry in entries {
+        write_varint(&mut buf, entry.tile_id - last_id);
+        last_id = entry.tile_id;
+    }
+    for entry in entries {
+        write_varint(&mut buf, u64::from(entry.run_length));
+    }
+    for entry in entries {
+        write_varint(&mut buf, u64::from(entry.length));
+    }
+    for (idx, entry) in entries.iter().enumerate() {
+        // Zero means the data immediately follows the previous entry
+        if idx > 0 && entry.offset == entries[idx - 1].offset + u64::from(entries[idx - 1].length) {
+            write_varint(&mut buf, 0);
+        } else {
+            write_varint(&mut buf, entry.offset + 1);
+        }
+    }
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&buf)?;
+    Ok(encoder.finish()?)
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn deserialize_directory(data: &[u8], compression: u8) -> MbtResult<Vec<Entry>> {
+    let buf = decompress(data, compression)?;
+    let pos = &mut 0;
+    let count = read_varint(&buf, pos)? as usize;
+    let mut entries = Vec::with_capacity(count.min(buf.len()));
+    let mut last_id = 0;
+    for _ in 0..count {
+        last_id += read_varint(&buf, pos)?;
+        entries.push(Entry {
+            tile_id: last_id,
+            offset: 0,
+            length: 0,
+            run_length: 0,
+        });
+    }
+    for entry in &mut entries {
+        entry.run_length = read_varint(&buf, pos)? as u32;
+    }
+    for entry in &mut entries {
+        entry.length = read_varint(&buf, pos)? as u32;
+    }
+    for idx in 0..count {
+        let value = read_varint(&buf, pos)?;
+        entries[idx].offset = if value == 0 && idx > 0 {
+            entries[idx - 1].offset + u64::from(entries[idx - 1].length)
+        } else if value == 0 {
+            return Err(InvalidPmtiles("invalid first directory offset".to_string()));
+        } else {
+            value - 1
+        };
+    }
+    Ok(entries)
+}
+
+fn decompress(data: &[u8], compression: u8) -> MbtResult<Vec<u8>> {
+    match compression {
+        COMPRESSION_NONE => Ok(data.to_vec()),
+        COMPRESSION_GZIP => {
+            let mut buf = Vec::new();
+            GzDecoder::new(data).read_to_end(&mut buf)?;
+            Ok(buf)
+        }
+        v => Err(InvalidPmtiles(format!(
+            "internal compression type {v} is not supported"
+        ))),
+    }
+}
+
+/// Split the entries into leaf directories if they do not fit into the root directory.
+/// Returns the serialized root and leaf directories.
+fn build_directories(entries: &[Entry]) -> MbtResult<(Vec<u8>, Vec<u8>)> {
+    let root = serialize_directory(entries)?;
+    if root.len() <= MAX_ROOT_DIR_SIZE {
+        return Ok((root, Vec::new()));
+    }
+    let mut leaf_size = LEAF_DIR_ENTRIES;
+    loop {
+        let mut leaves = Vec::new();
+        let mut root_entries = Vec::new();
+        for chunk in entries.chunks(leaf_size) {
+            let leaf = serialize_directory(chunk)?;
+            root_entries.push(Entry {
+                tile_id: chunk[0].tile_id,
+                offset: leaves.len() as u64,
+                length: u32::try_from(leaf.len())
+                    .map_err(|_| InvalidPmtiles("leaf directory is too large".to_string()))?,
+                run_length: 0,
+            });
+            leaves.extend(leaf);
+        }
+        let root = serialize_directory(&root_entries)?;
+        if root.len() <= MAX_ROOT_DIR_SIZE {
+            return Ok((root, leaves));
+        }
+        leaf_size *= 2;
+    }
+}
+
+/// Writes tiles in any order, storing identical tiles only once.
+/// The tile data is buffered in a temporary file next to the destination until all directories are known,
+/// and is then copied to the destination in the order of increasing tile IDs.
+/// The temporary file is removed when the writer is dropped.
+pub(crate) struct PmtilesWriter {
+    path: PathBuf,
+    tmp_path: PathBuf,
+    tmp: Option<BufWriter<File>>,
+    /// Tile ID, offset and length of the data in the temporary file
+    tiles: Vec<(u64, u64, u32)>,
+    contents: HashMap<u128, (u64, u32)>,
+    tmp_length: u64,
+    zooms: Option<(u8, u8)>,
+}
+
+impl PmtilesWriter {
+    pub fn new(path: &Path) -> MbtResult<Self> {
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        Ok(Self {
+            path: path.to_path_buf(),
+            tmp: Some(BufWriter::new(File::create(&tmp_path)?)),
+            tmp_path,
+            tiles: Vec::new(),
+            contents: HashMap::new(),
+            tmp_length: 0,
+            zooms: None,
+        })
+    }
+
+    /// Add a tile. Each tile ID must only be added once.
+    pub fn add_tile(&mut self, z: u8, tile_id: u64, data: &[u8]) -> MbtResult<()> {
+        let hash = xxh3_128(data);
+        let (offset, length) = if let Some(v) = self.contents.get(&hash) {
+            *v
+        } else {
+            let length = u32::try_from(data.len())
+                .map_err(|_| InvalidPmtiles(format!("tile {tile_id} is too large")))?;
+            let offset = self.tmp_length;
+            if let Some(tmp) = &mut self.tmp {
+                tmp.write_all(data)?;
+            }
+            self.tmp_length += u64::from(length);
+            self.contents.insert(hash, (offset, length));
+            (offset, length)
+        };
+        self.tiles.push((tile_id, offset, length));
+        self.zooms = Some(
+            self.zooms
+                .map_or((z, z), |(min, max)| (min.min(z), max.max(z))),
+        );
+        Ok(())
+    }
+
+    /// Write the archive, using the `header` for the tile type, compression, bounds and center.
+    /// Returns the header as written. The destination file is removed if it could not be written.
+    pub fn finish(mut self, header: Header, metadata: &Value) -> MbtResult<Header> {
+        let result = self.write(header, metadata);
+        if result.is_err() {
+            let _ = fs::remove_file(&self.path);
+        }
+        result
+    }
+
+    fn write(&mut self, mut header: Header, metadata: &Value) -> MbtResult<Header> {
+        if let Some(mut tmp) = self.tmp.take() {
+            tmp.flush()?;
+        }
+
+        // Assign the data offsets in the order of the tile IDs, so that the archive is clustered
+        self.tiles.sort_unstable_by_key(|v| v.0);
+        let mut offsets = HashMap::with_capacity(self.contents.len());
+        let mut copies = Vec::with_capacity(self.contents.len());
+        let mut data_length = 0;
+        let mut entries: Vec<Entry> = Vec::new();
+        for &(tile_id, tmp_offset, length) in &self.tiles {
+            let offset = *offsets.entry(tmp_offset).or_insert_with(|| {
+                copies.push((tmp_offset, length));
+                data_length += u64::from(length);
+                data_length - u64::from(length)
+            });
+            if let Some(last) = entries.last_mut() {
+                if last.offset == offset && last.tile_id + u64::from(last.run_length) == tile_id {
+                    last.run_length += 1;
+                    continue;
+                }
+            }
+            entries.push(Entry {
+                tile_id,
+                offset,
+                length,
+                run_length: 1,
+            });
+        }
+
+        let (root, leaves) = build_directories(&entries)?;
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&serde_json::to_vec(metadata)?)?;
+        let metadata = encoder.finish()?;
+
+        let (min_zoom, max_zoom) = self.zooms.unwrap_or_default();
+        header.root_offset = HEADER_SIZE as u64;
+        header.root_length = root.len() as u64;
+        header.metadata_offset = header.root_offset + header.root_length;
+        header.metadata_length = metadata.len() as u64;
+        header.leaf_offset = header.metadata_offset + header.metadata_length;
+        header.leaf_length = leaves.len() as u64;
+        header.data_offset = header.leaf_offset + header.leaf_length;
+        header.data_length = data_length;
+        header.addressed_tiles = self.tiles.len() as u64;
+        header.tile_entries = entries.len() as u64;
+        header.tile_contents = self.contents.len() as u64;
+        header.clustered = true;
+        header.internal_compression = COMPRESSION_GZIP;
+        header.min_zoom = min_zoom;
+        header.max_zoom = max_zoom;
+        header.center_zoom = header.center_zoom.clamp(min_zoom, max_zoom);
+
+        let mut file = BufWriter::new(File::create(&self.path)?);
+        file.write_all(&header.to_bytes())?;
+        file.write_all(&root)?;
+        file.write_all(&metadata)?;
+        file.write_all(&leaves)?;
+        let mut tmp = File::open(&self.tmp_path)?;
+        let mut buf = Vec::new();
+        for (tmp_offset, length) in copies {
+            buf.resize(length as usize, 0);
+            tmp.seek(SeekFrom::Start(tmp_offset))?;
+            tmp.read_exact(&mut buf)?;
+            file.write_all(&buf)?;
+        }
+        file.flush()?;
+        Ok(header)
+    }
+}
+
+impl Drop for PmtilesWriter {
+    fn drop(&mut self) {
+        drop(self.tmp.take());
+        let _ = fs::remove_file(&self.tmp_path);
+    }
+}
+
+/// Tile entries of an archive in tile ID order, read from the root and leaf directories.
+/// Leaf directories are only read when they are reached, so the entries are never all in memory.
+pub(crate) struct PmtilesEntries {
+    file: File,
+    leaf_offset: u64,
+    internal_compression: u8,
+    /// Entries yet to be returned, in reverse order
+    pending: Vec<Entry>,
+}
+
+impl PmtilesEntries {
+    pub fn open(path: &Path) -> MbtResult<Self> {
+        let mut file = File::open(path)?;
+        let mut buf = vec![0; HEADER_SIZE];
+        file.read_exact(&mut buf)
+            .map_err(|_| InvalidPmtiles("not a PMTiles file".to_string()))?;
+        if &buf[0..7] != MAGIC || buf[7] != VERSION {
+            return Err(InvalidPmtiles(format!(
+                "only version {VERSION} archives are supported"
+            )));
+        }
+        let mut entries = Self {
+            file,
+            leaf_offset: read_u64(&buf, 40),
+            internal_compression: buf[97],
+            pending: Vec::new(),
+        };
+        entries.push_directory(read_u64(&buf, 8), read_u64(&buf, 16))?;
+        Ok(entries)
+    }
+
+    fn push_directory(&mut self, offset: u64, length: u64) -> MbtResult<()> {
+        let length = usize::try_from(length)
+            .map_err(|_| InvalidPmtiles("directory is too large".to_string()))?;
+        let mut buf = vec![0; length];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut buf)?;
+        let entries = deserialize_directory(&buf, self.internal_compression)?;
+        self.pending.extend(entries.into_iter().rev());
+        Ok(())
+    }
+
+    /// The next tile entry, covering `run_length` consecutive tile IDs with the same data
+    pub fn next_entry(&mut self) -> MbtResult<Option<Entry>> {
+        while let Some(entry) = self.pending.pop() {
+            if entry.run_length > 0 {
+                return Ok(Some(entry));
+            }
+            let offset = self.leaf_offset + entry.offset;
+            self.push_directory(offset, u64::from(entry.length))?;
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+    use pmtiles::async_reader::AsyncPmTilesReader;
+    use pmtiles::MmapBackend;
+
+    use super::*;
+
+    async fn open(path: &Path) -> AsyncPmTilesReader<MmapBackend> {
+        AsyncPmTilesReader::new_with_path(path).await.unwrap()
+    }
+
+    #[test]
+    fn tile_ids() {
+        assert_eq!(zxy_to_tile_id(0, 0, 0), 0);
+        assert_eq!(zxy_to_tile_id(1, 0, 0), 1);
+        assert_eq!(zxy_to_tile_id(1, 0, 1), 2);
+        assert_eq!(zxy_to_tile_id(1, 1, 1), 3);
+        assert_eq!(zxy_to_tile_id(1, 1, 0), 4);
+        assert_eq!(zxy_to_tile_id(2, 0, 0), 5);
+        assert_eq!(zxy_to_tile_id(20, 0, 0), 366_503_875_925);
+        for (z, x, y) in [(0, 0, 0), (3, 5, 2), (12, 3000, 1234), (30, 1 << 29, 7)] {
+            assert_eq!(tile_id_to_zxy(zxy_to_tile_id(z, x, y)), Some((z, x, y)));
+        }
+    }
+
+    #[actix_rt::test]
+    async fn writer_clusters_tiles() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.pmtiles");
+        let mut writer = PmtilesWriter::new(&path).unwrap();
+        for (x, y, data) in [(1, 0, b"b"), (0, 0, b"a"), (1, 1, b"b"), (0, 1, b"b")] {
+            writer.add_tile(1, zxy_to_tile_id(1, x, y), data).unwrap();
+        }
+        let header = Header {
+            tile_compression: COMPRESSION_GZIP,
+            tile_type: TILE_TYPE_MVT,
+            bounds: [-180.0, -85.0, 180.0, 85.0],
+            center: [12.5, -3.25],
+            ..Default::default()
+        };
+        let header = writer.finish(header, &Value::Null).unwrap();
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+        assert!(header.clustered);
+        assert_eq!(header.addressed_tiles, 4);
+        assert_eq!(header.tile_entries, 2);
+        assert_eq!(header.tile_contents, 2);
+
+        let reader = open(&path).await;
+        let read = reader.get_header();
+        assert_eq!(read.tile_type, TileType::Mvt);
+        assert_eq!(read.tile_compression, pmtiles::Compression::Gzip);
+        assert_eq!((read.min_zoom, read.max_zoom, read.center_zoom), (1, 1, 1));
+        assert_relative_eq!(read.center_longitude, 12.5);
+        assert_relative_eq!(read.max_latitude, 85.0);
+        assert_eq!(reader.get_tile(1, 0, 0).await.unwrap().unwrap(), &b"a"[..]);
+        for (x, y) in [(0, 1), (1, 1), (1, 0)] {
+            assert_eq!(reader.get_tile(1, x, y).await.unwrap().unwrap(), &b"b"[..]);
+        }
+        assert!(reader.get_tile(2, 0, 0).await.unwrap().is_none());
+
+        let mut entries = PmtilesEntries::open(&path).unwrap();
+        let mut runs = Vec::new();
+        while let Some(entry) = entries.next_entry().unwrap() {
+            runs.push((entry.tile_id, entry.run_length));
+        }
+        assert_eq!(runs, vec![(1, 1), (2, 3)]);
+
+        // The temporary file is removed if the archive is never finished
+        let path = dir.path().join("unfinished.pmtiles");
+        let mut writer = PmtilesWriter::new(&path).unwrap();
+        writer.add_tile(0, 0, b"a").unwrap();
+        drop(writer);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    /// Distinct tiles with irregular lengths, so that the directory does not compress too well
+    fn leaf_tile(x: u32, y: u32) -> Vec<u8> {
+        let data = (x * 256 + y).wrapping_mul(2_654_435_761).to_le_bytes();
+        data[..=(x + y) as usize % 4].to_vec()
+    }
+
+    #[actix_rt::test]
+    async fn writer_uses_leaf_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.pmtiles");
+        let mut writer = PmtilesWriter::new(&path).unwrap();
+        for x in 0..256 {
+            for y in 0..256 {
+                writer
+                    .add_tile(8, zxy_to_tile_id(8, x, y), &leaf_tile(x, y))
+                    .unwrap();
+            }
+        }
+        let header = writer.finish(Header::default(), &Value::Null).unwrap();
+        assert!(header.leaf_length > 0);
+        assert!(header.root_length <= MAX_ROOT_DIR_SIZE as u64);
+
+        let reader = open(&path).await;
+        for (x, y) in [(0, 0), (17, 200), (255, 255), (128, 3)] {
+            let tile = reader.get_tile(8, x.into(), y.into()).await.unwrap();
+            assert_eq!(tile.unwrap(), leaf_tile(x, y));
+        }
+
+        // Walking the leaf directories lists every tile once, in tile ID order
+        let mut entries = PmtilesEntries::open(&path).unwrap();
+        let (mut next_id, mut tiles) = (zxy_to_tile_id(8, 0, 0), 0);
+        while let Some(entry) = entries.next_entry().unwrap() {
+            assert!(entry.tile_id >= next_id);
+            next_id = entry.tile_id + u64::from(entry.run_length);
+            tiles += entry.run_length;
+        }
+        assert_eq!(tiles, 256 * 256);
+    }
+}
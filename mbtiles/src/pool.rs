@@ -2,8 +2,9 @@ use std::path::Path;
 
 use sqlx::{Pool, Sqlite, SqlitePool};
 
+use crate::content_check::check_tiles_content;
 use crate::errors::MbtResult;
-use crate::{Mbtiles, Metadata};
+use crate::{Mbtiles, Metadata, TileContentError};
 
 #[derive(Clone, Debug)]
 pub struct MbtilesPool {
@@ -27,4 +28,11 @@ impl MbtilesPool {
         let mut conn = self.pool.acquire().await?;
         self.mbtiles.get_tile(&mut *conn, z, x, y).await
     }
+
+    /// Decompress and decode every tile in parallel, using the connections of this pool.
+    /// Stops after finding `max_errors` invalid tiles, and returns them sorted by their coordinates.
+    pub async fn check_tiles_content(&self, max_errors: usize) -> MbtResult<Vec<TileContentError>> {
+        let tile_info = self.get_metadata().await?.tile_info;
+        check_tiles_content(&self.mbtiles, &self.pool, tile_info, max_errors).await
+    }
 }
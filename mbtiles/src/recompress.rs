@@ -0,0 +1,223 @@
+use enum_display::EnumDisplay;
+use flume::Sender;
+use futures::TryStreamExt as _;
+use log::{debug, error};
+use martin_tile_utils::{decode, encode, Encoding, TileCoord};
+use serde::{Deserialize, Serialize};
+use sqlx::{query, Row as _, SqliteConnection};
+
+use crate::bindiff::BinDiffer;
+use crate::{CopyDuplicateMode, MbtError, MbtResult, MbtType, Mbtiles};
+
+/// Compression of the tile data
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, EnumDisplay)]
+#[enum_display(case = "Kebab")]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum EncodingCli {
+    /// Store tiles without compression
+    None,
+    Gzip,
+    Zlib,
+    Brotli,
+    Zstd,
+}
+
+impl From<EncodingCli> for Encoding {
+    fn from(value: EncodingCli) -> Self {
+        match value {
+            EncodingCli::None => Encoding::Uncompressed,
+            EncodingCli::Gzip => Encoding::Gzip,
+            EncodingCli::Zlib => Encoding::Zlib,
+            EncodingCli::Brotli => Encoding::Brotli,
+            EncodingCli::Zstd => Encoding::Zstd,
+        }
+    }
+}
+
+pub struct RecompressorBefore {
+    coord: TileCoord,
+    data: Vec<u8>,
+}
+
+pub struct RecompressorAfter {
+    coord: TileCoord,
+    data: Vec<u8>,
+}
+
+/// Decode every source tile and encode it with a different compression, in parallel.
+/// The tile hashes of the destination are computed from the new tile data,
+/// so normalized files stay deduplicated.
+pub struct Recompressor {
+    src_mbt: Mbtiles,
+    src_encoding: Encoding,
+    dst_encoding: Encoding,
+    level: Option<u32>,
+    insert_sql: (String, Option<String>),
+}
+
+impl Recompressor {
+    pub fn new(
+        src_mbt: Mbtiles,
+        src_encoding: Encoding,
+        dst_encoding: Encoding,
+        level: Option<u32>,
+        dst_type: MbtType,
+        on_duplicate: CopyDuplicateMode,
+    ) -> Self {
+        Self {
+            src_mbt,
+            src_encoding,
+            dst_encoding,
+            level,
+            insert_sql: Mbtiles::get_insert_sql(dst_type, on_duplicate),
+        }
+    }
+}
+
+impl BinDiffer<RecompressorBefore, RecompressorAfter> for Recompressor {
+    async fn query(&self, sql_where: String, tx_wrk: Sender<RecompressorBefore>) -> MbtResult<()> {
+        let sql = format!(
+            "
+        SELECT zoom_level, tile_column, tile_row, tile_data
+        FROM tiles
+        WHERE tile_data NOTNULL {sql_where}"
+        );
+
+        let mut conn = self.src_mbt.open_readonly().await?;
+        debug!("Querying tiles to recompress with {sql}");
+        let mut rows = query(&sql).fetch(&mut conn);
+
+        while let Some(row) = rows.try_next().await? {
+            let work = RecompressorBefore {
+                coord: TileCoord {
+                    z: row.get(0),
+                    x: row.get(1),
+                    y: row.get(2),
+                },
+                data: row.get(3),
+            };
+            if tx_wrk.send_async(work).await.is_err() {
+                break; // the receiver has been dropped
+            }
+        }
+
+        Ok(())
+    }
+
+    fn process(&self, value: RecompressorBefore) -> MbtResult<RecompressorAfter> {
+        let data = decode(&value.data, self.src_encoding).inspect_err(|e| {
+            error!(
+                "Unable to decode {:?} tile {:?}: {e}",
+                self.src_encoding, value.coord
+            );
+        })?;
+        let data = encode(&data, self.dst_encoding, self.level)?;
+        Ok(RecompressorAfter {
+            coord: value.coord,
+            data,
+        })
+    }
+
+    fn failure(&self) -> MbtError {
+        MbtError::RecompressError
+    }
+
+    async fn before_insert(&self, _conn: &mut SqliteConnection) -> MbtResult<()> {
+        Ok(())
+    }
+
+    async fn insert(&self, value: RecompressorAfter, conn: &mut SqliteConnection) -> MbtResult<()> {
+        let (sql1, sql2) = &self.insert_sql;
+        if let Some(sql2) = sql2 {
+            query(sql2).bind(&value.data).execute(&mut *conn).await?;
+        }
+        query(sql1)
+            .bind(value.coord.z)
+            .bind(value.coord.x)
+            .bind(value.coord.y)
+            .bind(value.data)
+            .execute(&mut *conn)
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use martin_tile_utils::decode_gzip;
+
+    use super::*;
+    use crate::{MbtTypeCli, MbtilesCopier, COMPRESSION};
+
+    const SRC: &str = "../tests/fixtures/mbtiles/world_cities.mbtiles";
+
+    async fn recompress(
+        src: &str,
+        dst: &str,
+        encoding: EncodingCli,
+        dst_type_cli: Option<MbtTypeCli>,
+    ) -> (Mbtiles, SqliteConnection) {
+        let conn = MbtilesCopier {
+            src_file: PathBuf::from(src),
+            dst_file: PathBuf::from(dst),
+            dst_type_cli,
+            recompress: Some(encoding),
+            ..Default::default()
+        }
+        .run()
+        .await
+        .unwrap();
+        (Mbtiles::new(dst).unwrap(), conn)
+    }
+
+    #[actix_rt::test]
+    async fn recompress_roundtrip() {
+        let brotli = "file:recompress_brotli_mem_db?mode=memory&cache=shared";
+        let (mbt, mut conn) = recompress(SRC, brotli, EncodingCli::Brotli, None).await;
+        let metadata = mbt.get_metadata(&mut conn).await.unwrap();
+        assert_eq!(metadata.tile_info.encoding, Encoding::Brotli);
+        assert_eq!(
+            mbt.get_metadata_value(&mut conn, COMPRESSION)
+                .await
+                .unwrap(),
+            Some("brotli".to_string())
+        );
+
+        let zstd = "file:recompress_zstd_mem_db?mode=memory&cache=shared";
+        let (mbt, mut conn) = recompress(
+            brotli,
+            zstd,
+            EncodingCli::Zstd,
+            Some(MbtTypeCli::Normalized),
+        )
+        .await;
+        let metadata = mbt.get_metadata(&mut conn).await.unwrap();
+        assert_eq!(metadata.tile_info.encoding, Encoding::Zstd);
+        // Tile IDs are the hashes of the new tile data
+        assert!(
+            query("SELECT 1 FROM images WHERE tile_id != md5_hex(tile_data)")
+                .fetch_optional(&mut conn)
+                .await
+                .unwrap()
+                .is_none()
+        );
+
+        let gzip = "file:recompress_gzip_mem_db?mode=memory&cache=shared";
+        let (mbt, mut conn) = recompress(zstd, gzip, EncodingCli::Gzip, None).await;
+        let src = Mbtiles::new(SRC).unwrap();
+        let mut src_conn = src.open_readonly().await.unwrap();
+        let expected = src.get_tile(&mut src_conn, 1, 1, 0).await.unwrap().unwrap();
+        let actual = mbt.get_tile(&mut conn, 1, 1, 0).await.unwrap().unwrap();
+        assert_eq!(
+            decode_gzip(&actual).unwrap(),
+            decode_gzip(&expected).unwrap()
+        );
+        assert!(mbt
+            .get_metadata_value(&mut conn, "agg_tiles_hash")
+            .await
+            .unwrap()
+            .is_some());
+    }
+}
@@ -26,6 +26,16 @@ pub struct ZoomInfo {
     pub bbox: Bounds,
 }
 
+/// Size of a vector tile layer in all tiles
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct LayerSizeInfo {
+    pub layer: String,
+    pub tile_count: u64,
+    pub feature_count: u64,
+    /// Total size of the layer in all uncompressed tiles, in bytes
+    pub size: u64,
+}
+
 #[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct Summary {
     pub file_size: Option<u64>,
@@ -40,6 +50,9 @@ pub struct Summary {
     pub min_zoom: Option<u8>,
     pub max_zoom: Option<u8>,
     pub zoom_info: Vec<ZoomInfo>,
+    /// Per-layer size breakdown of vector tiles, if requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub layers: Option<Vec<LayerSizeInfo>>,
 }
 
 impl Display for Summary {
@@ -103,6 +116,34 @@ impl Display for Summary {
             }
         }
 
+        if let Some(layers) = &self.layers {
+            let total = layers.iter().map(|l| l.size).sum::<u64>().max(1);
+            let width = layers
+                .iter()
+                .map(|l| l.layer.len())
+                .max()
+                .unwrap_or(0)
+                .max(5);
+            writeln!(f)?;
+            writeln!(
+                f,
+                " {:^width$} | {:^9} | {:^9} | {:^9} | Share",
+                "Layer", "Tiles", "Features", "Size"
+            )?;
+            for l in layers {
+                let size = SizeFormatterBinary::new(l.size);
+                writeln!(
+                    f,
+                    " {:<width$} | {:>9} | {:>9} | {:>9} | {:>5.1}%",
+                    l.layer,
+                    l.tile_count,
+                    l.feature_count,
+                    format!("{size:.1}B"),
+                    l.size as f64 * 100.0 / total as f64,
+                )?;
+            }
+        }
+
         Ok(())
     }
 }
@@ -183,6 +224,7 @@ impl Mbtiles {
             min_zoom: zoom_info.iter().map(|l| l.zoom).reduce(u8::min),
             max_zoom: zoom_info.iter().map(|l| l.zoom).reduce(u8::max),
             zoom_info,
+            layers: None,
         })
     }
 }
@@ -0,0 +1,447 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use enum_display::EnumDisplay;
+use futures::TryStreamExt as _;
+use log::{debug, info, warn};
+use martin_tile_utils::{TileInfo, MAX_ZOOM};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sqlx::{query, Row as _};
+
+use crate::errors::MbtResult;
+use crate::queries::{compute_min_max_zoom, init_mbtiles_schema, is_empty_database};
+use crate::MbtType::{Flat, FlatWithHash, Normalized};
+use crate::{
+    invert_y_value, CopyDuplicateMode, MbtError, MbtType, MbtTypeCli, Mbtiles, AGG_TILES_HASH,
+};
+
+/// Name of the optional metadata file in the root of the tile directory
+const METADATA_FILE: &str = "metadata.json";
+
+/// Number of tiles inserted into `MBTiles` in a single transaction
+const BATCH_SIZE: usize = 1000;
+
+/// Order of the tile rows in a `z/x/y` directory tree
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, EnumDisplay)]
+#[enum_display(case = "Kebab")]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum TileScheme {
+    /// Row 0 is at the top, as used by most web maps
+    #[default]
+    Xyz,
+    /// Row 0 is at the bottom, as stored in `MBTiles`
+    Tms,
+}
+
+impl TileScheme {
+    /// Convert between the `MBTiles` TMS row and the row of this scheme. The conversion is symmetrical.
+    fn row(self, z: u8, y: u32) -> u32 {
+        match self {
+            Self::Xyz => invert_y_value(z, y),
+            Self::Tms => y,
+        }
+    }
+}
+
+/// Export the tiles of an `MBTiles` file into a `z/x/y` directory tree
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct MbtilesDirExporter {
+    /// `MBTiles` file to read from
+    pub mbtiles_file: PathBuf,
+    /// Directory to write to. It must not exist or be empty.
+    pub dir: PathBuf,
+    /// Order of the tile rows in the directory
+    pub scheme: TileScheme,
+    /// Do not add a file extension based on the tile format
+    pub skip_extension: bool,
+    /// Do not write the metadata table to a `metadata.json` file
+    pub skip_metadata: bool,
+}
+
+/// Import the tiles of a `z/x/y` directory tree into a new `MBTiles` file
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct MbtilesDirImporter {
+    /// Directory to read from. Any file extensions are ignored.
+    pub dir: PathBuf,
+    /// `MBTiles` file to write to. It must not exist or be empty.
+    pub mbtiles_file: PathBuf,
+    /// Order of the tile rows in the directory
+    pub scheme: TileScheme,
+    /// Output format of the destination file. If not specified, defaults to `flat`.
+    pub dst_type_cli: Option<MbtTypeCli>,
+    /// Skip generating a global hash for mbtiles validation. By default, `mbtiles` will compute `agg_tiles_hash` metadata value.
+    pub skip_agg_tiles_hash: bool,
+}
+
+fn is_empty_dir(dir: &Path) -> MbtResult<bool> {
+    Ok(!dir.exists() || fs::read_dir(dir)?.next().is_none())
+}
+
+impl MbtilesDirExporter {
+    pub async fn run(self) -> MbtResult<()> {
+        if !is_empty_dir(&self.dir)? {
+            return Err(MbtError::NonEmptyTargetFile(self.dir));
+        }
+        let mbt = Mbtiles::new(&self.mbtiles_file)?;
+        let mut conn = mbt.open_readonly().await?;
+        let mbt_type = mbt.detect_type(&mut conn).await?;
+        let ext = if self.skip_extension {
+            String::new()
+        } else {
+            let info = mbt.get_metadata(&mut conn).await?.tile_info;
+            format!(".{}", info.format.metadata_format_value())
+        };
+        fs::create_dir_all(&self.dir)?;
+        info!("Exporting {mbt} to {}", self.dir.display());
+
+        let tile_path = |z: u8, x: u32, y: u32| {
+            let y = self.scheme.row(z, y);
+            self.dir.join(format!("{z}/{x}/{y}{ext}"))
+        };
+        let write_tile = |path: &Path, data: &[u8]| -> MbtResult<()> {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            Ok(fs::write(path, data)?)
+        };
+
+        let mut count = 0_u64;
+        if let Normalized { .. } = mbt_type {
+            // Each distinct image is written once, and all other tiles with the same image are hard links to it
+            let sql = "
+SELECT map.zoom_level, map.tile_column, map.tile_row, map.tile_id,
+       CASE WHEN row_number() OVER (PARTITION BY map.tile_id) = 1 THEN images.tile_data END
+FROM map JOIN images ON images.tile_id = map.tile_id";
+            let mut written = HashMap::<String, PathBuf>::new();
+            let mut links = Vec::new();
+            let mut rows = query(sql).fetch(&mut conn);
+            while let Some(row) = rows.try_next().await? {
+                let path = tile_path(row.get(0), row.get(1), row.get(2));
+                let tile_id: String = row.get(3);
+                if let Some(data) = row.get::<Option<Vec<u8>>, _>(4) {
+                    write_tile(&path, &data)?;
+                    written.insert(tile_id, path);
+                } else {
+                    links.push((tile_id, path));
+                }
+                count += 1;
+            }
+            for (tile_id, path) in links {
+                let Some(original) = written.get(&tile_id) else {
+                    warn!("Tile {} has no image data, skipping", path.display());
+                    continue;
+                };
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::hard_link(original, &path)?;
+            }
+        } else {
+            let sql = "SELECT zoom_level, tile_column, tile_row, tile_data FROM tiles";
+            let mut rows = query(sql).fetch(&mut conn);
+            while let Some(row) = rows.try_next().await? {
+                if let Some(data) = row.get::<Option<Vec<u8>>, _>(3) {
+                    write_tile(&tile_path(row.get(0), row.get(1), row.get(2)), &data)?;
+                    count += 1;
+                }
+            }
+        }
+
+        if !self.skip_metadata {
+            let mut metadata = Map::new();
+            let mut rows = query("SELECT name, value FROM metadata").fetch(&mut conn);
+            while let Some(row) = rows.try_next().await? {
+                if let (Some(name), Some(value)) = (row.get(0), row.get::<Option<String>, _>(1)) {
+                    metadata.insert(name, Value::String(value));
+                }
+            }
+            fs::write(
+                self.dir.join(METADATA_FILE),
+                serde_json::to_string_pretty(&metadata)?,
+            )?;
+        }
+        info!("Exported {count} tiles to {}", self.dir.display());
+        Ok(())
+    }
+}
+
+/// Parse a directory or file name as a tile index, ignoring the file extension
+fn parse_index<T: std::str::FromStr>(path: &Path, is_file: bool) -> Option<T> {
+    let name = if is_file {
+        path.file_stem()
+    } else {
+        path.file_name()
+    };
+    name?.to_str()?.parse().ok()
+}
+
+/// List the entries of a directory that are tile indexes, ignoring everything else
+fn list_dir<T: std::str::FromStr>(dir: &Path, is_file: bool) -> MbtResult<Vec<(T, PathBuf)>> {
+    let mut result = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() != is_file {
+            continue;
+        }
+        if let Some(idx) = parse_index(&path, is_file) {
+            result.push((idx, path));
+        } else {
+            debug!("Ignoring {}", path.display());
+        }
+    }
+    Ok(result)
+}
+
+impl MbtilesDirImporter {
+    fn dst_type(&self) -> MbtType {
+        match self.dst_type_cli {
+            None | Some(MbtTypeCli::Flat) => Flat,
+            Some(MbtTypeCli::FlatWithHash) => FlatWithHash,
+            Some(MbtTypeCli::Normalized) => Normalized { hash_view: true },
+        }
+    }
+
+    pub async fn run(self) -> MbtResult<()> {
+        let mbt = Mbtiles::new(&self.mbtiles_file)?;
+        let mut conn = mbt.open_or_new().await?;
+        if !is_empty_database(&mut conn).await? {
+            return Err(MbtError::NonEmptyTargetFile(self.mbtiles_file));
+        }
+        let dst_type = self.dst_type();
+        init_mbtiles_schema(&mut conn, dst_type).await?;
+        info!("Importing {} into {mbt}", self.dir.display());
+
+        let mut tile_info: Option<TileInfo> = None;
+        let mut batch = Vec::with_capacity(BATCH_SIZE);
+        let mut count = 0_u64;
+        for (z, z_dir) in list_dir::<u8>(&self.dir, false)? {
+            if z > MAX_ZOOM {
+                warn!("Skipping {}: zoom must be <= {MAX_ZOOM}", z_dir.display());
+                continue;
+            }
+            let tiles = 1_u32 << z;
+            for (x, x_dir) in list_dir::<u32>(&z_dir, false)? {
+                if x >= tiles {
+                    warn!("Skipping {}: column must be < {tiles}", x_dir.display());
+                    continue;
+                }
+                for (y, file) in list_dir::<u32>(&x_dir, true)? {
+                    if y >= tiles {
+                        warn!("Skipping {}: row must be < {tiles}", file.display());
+                        continue;
+                    }
+                    let data = fs::read(&file)?;
+                    match (tile_info, TileInfo::detect(&data)) {
+                        (Some(info), Some(detected)) if info != detected => {
+                            return Err(MbtError::InconsistentMetadata(info, detected));
+                        }
+                        (None, detected) => tile_info = detected,
+                        _ => {}
+                    }
+                    // insert_tiles expects XYZ rows
+                    let y = invert_y_value(z, self.scheme.row(z, y));
+                    batch.push((z, x, y, data));
+                    count += 1;
+                    if batch.len() >= BATCH_SIZE {
+                        mbt.insert_tiles(&mut conn, dst_type, CopyDuplicateMode::Override, &batch)
+                            .await?;
+                        batch.clear();
+                    }
+                }
+            }
+        }
+        mbt.insert_tiles(&mut conn, dst_type, CopyDuplicateMode::Override, &batch)
+            .await?;
+
+        let metadata_file = self.dir.join(METADATA_FILE);
+        if metadata_file.is_file() {
+            let metadata: Map<String, Value> = serde_json::from_slice(&fs::read(metadata_file)?)?;
+            for (key, value) in metadata {
+                // The hash of the exported file would not match the imported tiles
+                if key == AGG_TILES_HASH {
+                    continue;
+                }
+                match value {
+                    Value::String(value) => mbt.set_metadata_value(&mut conn, &key, value).await?,
+                    value => mbt.set_metadata_value(&mut conn, &key, value).await?,
+                }
+            }
+        }
+        if mbt.get_metadata_value(&mut conn, "format").await?.is_none() {
+            if let Some(info) = tile_info {
+                mbt.set_metadata_value(&mut conn, "format", info.format.metadata_format_value())
+                    .await?;
+            }
+        }
+        if let Some((min_zoom, max_zoom)) = compute_min_max_zoom(&mut conn).await? {
+            mbt.set_metadata_value(&mut conn, "minzoom", min_zoom)
+                .await?;
+            mbt.set_metadata_value(&mut conn, "maxzoom", max_zoom)
+                .await?;
+        }
+        if !self.skip_agg_tiles_hash {
+            mbt.update_agg_tiles_hash(&mut conn).await?;
+        }
+        info!("Imported {count} tiles into {mbt}");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mbtiles::tests::open;
+
+    #[actix_rt::test]
+    async fn roundtrip() -> MbtResult<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let tmp = tmp_dir.path();
+        // This file has two identical tiles
+        let src = PathBuf::from("../tests/fixtures/mbtiles/world_cities_modified.mbtiles");
+        let dir = tmp.join("tiles");
+        let normalized = tmp.join("normalized.mbtiles");
+
+        MbtilesDirExporter {
+            mbtiles_file: src.clone(),
+            dir: dir.clone(),
+            scheme: TileScheme::Tms,
+            ..Default::default()
+        }
+        .run()
+        .await?;
+        assert!(dir.join("1/0/1.pbf").is_file());
+        assert!(dir.join(METADATA_FILE).is_file());
+
+        MbtilesDirImporter {
+            dir: dir.clone(),
+            mbtiles_file: normalized.clone(),
+            scheme: TileScheme::Tms,
+            dst_type_cli: Some(MbtTypeCli::Normalized),
+            ..Default::default()
+        }
+        .run()
+        .await?;
+
+        let (mut conn, mbt) = open(normalized.to_str().unwrap()).await?;
+        Mbtiles::new(&src)?.attach_to(&mut conn, "srcDb").await?;
+        let changed: i64 = query(
+            "SELECT count(*) FROM (
+                 SELECT * FROM srcDb.tiles EXCEPT SELECT * FROM tiles
+                 UNION ALL
+                 SELECT * FROM (SELECT * FROM tiles EXCEPT SELECT * FROM srcDb.tiles))",
+        )
+        .fetch_one(&mut conn)
+        .await?
+        .get(0);
+        assert_eq!(changed, 0);
+        assert_eq!(
+            mbt.get_metadata_value(&mut conn, "name").await?.as_deref(),
+            Some("Major cities from Natural Earth data")
+        );
+        // The zoom range is computed from the imported tiles, not copied from metadata.json
+        assert_eq!(
+            mbt.get_metadata_value(&mut conn, "minzoom")
+                .await?
+                .as_deref(),
+            Some("1")
+        );
+
+        // Without a hash of its own, a partial import does not keep the exported hash
+        fs::remove_dir_all(dir.join("6"))?;
+        let partial = tmp.join("partial.mbtiles");
+        MbtilesDirImporter {
+            dir: dir.clone(),
+            mbtiles_file: partial.clone(),
+            scheme: TileScheme::Tms,
+            skip_agg_tiles_hash: true,
+            ..Default::default()
+        }
+        .run()
+        .await?;
+        let (mut partial_conn, partial_mbt) = open(partial.to_str().unwrap()).await?;
+        let metadata = partial_mbt.get_metadata(&mut partial_conn).await?;
+        assert_eq!(metadata.tilejson.maxzoom, Some(5));
+        assert_eq!(metadata.agg_tiles_hash, None);
+
+        // Exporting from a normalized file links identical tiles, using XYZ rows without extensions
+        let xyz_dir = tmp.join("xyz");
+        MbtilesDirExporter {
+            mbtiles_file: normalized.clone(),
+            dir: xyz_dir.clone(),
+            skip_extension: true,
+            skip_metadata: true,
+            ..Default::default()
+        }
+        .run()
+        .await?;
+        assert!(xyz_dir.join("1/1/0").is_file());
+        assert!(!xyz_dir.join(METADATA_FILE).exists());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt as _;
+            let distinct: i64 = query("SELECT count(*) FROM images")
+                .fetch_one(&mut conn)
+                .await?
+                .get(0);
+            let total: i64 = query("SELECT count(*) FROM map")
+                .fetch_one(&mut conn)
+                .await?
+                .get(0);
+            assert_eq!(distinct + 1, total);
+            let mut inodes = std::collections::HashSet::new();
+            for (_, z_dir) in list_dir::<u8>(&xyz_dir, false)? {
+                for (_, x_dir) in list_dir::<u32>(&z_dir, false)? {
+                    for (_, file) in list_dir::<u32>(&x_dir, true)? {
+                        inodes.insert(fs::metadata(file)?.ino());
+                    }
+                }
+            }
+            assert_eq!(inodes.len(), usize::try_from(distinct).unwrap());
+        }
+
+        // The destination must be empty
+        assert!(MbtilesDirExporter {
+            mbtiles_file: src.clone(),
+            dir: xyz_dir,
+            ..Default::default()
+        }
+        .run()
+        .await
+        .is_err());
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn import_skips_out_of_range_tiles() -> MbtResult<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let dir = tmp_dir.path().join("tiles");
+        for path in ["1/0/0.png", "1/0/7.png", "1/5/0.png", "32/0/0.png"] {
+            let file = dir.join(path);
+            fs::create_dir_all(file.parent().unwrap())?;
+            fs::write(file, b"\x89PNG\r\n\x1a\n")?;
+        }
+
+        let dst = tmp_dir.path().join("out.mbtiles");
+        MbtilesDirImporter {
+            dir,
+            mbtiles_file: dst.clone(),
+            ..Default::default()
+        }
+        .run()
+        .await?;
+
+        let (mut conn, _) = open(dst.to_str().unwrap()).await?;
+        let tiles: Vec<(u8, u32, u32)> =
+            query("SELECT zoom_level, tile_column, tile_row FROM tiles")
+                .fetch_all(&mut conn)
+                .await?
+                .iter()
+                .map(|row| (row.get(0), row.get(1), row.get(2)))
+                .collect();
+        assert_eq!(tiles, vec![(1, 0, 1)]);
+        Ok(())
+    }
+}
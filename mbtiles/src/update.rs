@@ -1,25 +1,29 @@
 // See https://github.com/SeedyROM/enum-display/issues/1
 #![allow(unused_qualifications)]
 
+use std::str::FromStr as _;
+
 use enum_display::EnumDisplay;
 use log::{info, warn};
+use martin_tile_utils::{get_zoom_precision, Encoding, TileInfo};
 use sqlx::SqliteExecutor;
+use tilejson::{tilejson, Bounds, Center};
 
 use self::UpdateZoomType::{GrowOnly, Reset, Skip};
 use crate::errors::MbtResult;
 use crate::MbtError::InvalidZoomValue;
-use crate::{compute_min_max_zoom, Mbtiles};
+use crate::{compute_min_max_zoom, Mbtiles, COMPRESSION};
 
 #[derive(Default, Debug, Clone, Copy, PartialEq, Eq, EnumDisplay)]
 #[enum_display(case = "Kebab")]
 #[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
 pub enum UpdateZoomType {
-    /// Reset the minzoom and maxzoom metadata values to match the content of the tiles table
+    /// Reset the metadata values to match the content of the tiles table
     #[default]
     Reset,
-    /// Only update minzoom and maxzoom if the zooms in the tiles table are outside the range set in the metadata
+    /// Only update the metadata values if the tiles table extends outside the zoom range or the area set in the metadata
     GrowOnly,
-    /// Perform a dry run and print result, without updating the minzoom and maxzoom metadata values
+    /// Perform a dry run and print result, without updating the metadata values
     Skip,
 }
 
@@ -99,4 +103,276 @@ impl Mbtiles {
 
         Ok(())
     }
+
+    /// Compute the bounds from the tiles of the highest zoom level, the most precise ones.
+    /// Returns the bounds and the precision to store them with.
+    async fn compute_bounds<T>(&self, conn: &mut T) -> MbtResult<Option<(Bounds, usize)>>
+    where
+        for<'e> &'e mut T: SqliteExecutor<'e>,
+    {
+        let summary = self.summary(&mut *conn).await?;
+        Ok(summary.zoom_info.last().map(|info| {
+            let b = info.bbox;
+            let bounds = Bounds::new(
+                b.left.max(-180.0),
+                b.bottom.max(-90.0),
+                b.right.min(180.0),
+                b.top.min(90.0),
+            );
+            (bounds, get_zoom_precision(info.zoom))
+        }))
+    }
+
+    /// Update the `bounds` metadata value to match the area covered by the tiles table
+    pub async fn update_bounds<T>(&self, conn: &mut T, update: UpdateZoomType) -> MbtResult<()>
+    where
+        for<'e> &'e mut T: SqliteExecutor<'e>,
+    {
+        let Some((calc, prec)) = self.compute_bounds(&mut *conn).await? else {
+            info!("No tiles found in the tiles table, skipping metadata bounds update");
+            return Ok(());
+        };
+        let calc_value = format!("{calc:.prec$}");
+        let meta_value = self.get_metadata_value(&mut *conn, "bounds").await?;
+        let meta = meta_value.as_deref().map(Bounds::from_str);
+
+        let new_value = match meta {
+            Some(Ok(meta)) => {
+                let meta_str = format!("{meta:.prec$}");
+                let union = meta + calc;
+                if meta_str == calc_value {
+                    info!("Metadata value bounds is already set to correct value {meta_str}");
+                    None
+                } else if update == Skip {
+                    info!("Metadata value bounds is set to {meta_str}, but should be set to {calc_value}. Skipping update");
+                    None
+                } else if update == GrowOnly && union == meta {
+                    info!("Metadata value bounds={meta_str} already contains the computed bounds {calc_value}, not updating");
+                    None
+                } else if update == GrowOnly {
+                    let union = format!("{union:.prec$}");
+                    info!("Growing metadata bounds from {meta_str} to {union}");
+                    Some(union)
+                } else {
+                    info!("Updating metadata bounds from {meta_str} to {calc_value}");
+                    Some(calc_value)
+                }
+            }
+            _ if update == Skip => {
+                info!("Metadata value bounds should be set to {calc_value}. Skipping update");
+                None
+            }
+            Some(Err(_)) => {
+                let val = meta_value.unwrap_or_default();
+                warn!("Overriding invalid metadata value bounds='{val}' to {calc_value}");
+                Some(calc_value)
+            }
+            None => {
+                info!("Setting metadata value bounds to {calc_value}");
+                Some(calc_value)
+            }
+        };
+
+        if let Some(value) = new_value {
+            self.set_metadata_value(conn, "bounds", value).await?;
+        }
+        Ok(())
+    }
+
+    /// Update the `center` metadata value to the middle of the area covered by the tiles table,
+    /// using the highest zoom level at which the whole area is still visible.
+    /// In grow-only mode, an existing center is kept if it is inside that area and its zoom range.
+    pub async fn update_center<T>(&self, conn: &mut T, update: UpdateZoomType) -> MbtResult<()>
+    where
+        for<'e> &'e mut T: SqliteExecutor<'e>,
+    {
+        let (Some((bounds, prec)), Some((min_zoom, max_zoom))) = (
+            self.compute_bounds(&mut *conn).await?,
+            compute_min_max_zoom(&mut *conn).await?,
+        ) else {
+            info!("No tiles found in the tiles table, skipping metadata center update");
+            return Ok(());
+        };
+
+        let width = (bounds.right - bounds.left).max(f64::EPSILON);
+        let height = (bounds.top - bounds.bottom).max(f64::EPSILON);
+        let fit_zoom = (360.0 / width).log2().min((170.0 / height).log2()).floor();
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let zoom = (fit_zoom.max(0.0) as u8).clamp(min_zoom, max_zoom);
+        let calc = Center::new(
+            (bounds.left + bounds.right) / 2.0,
+            (bounds.bottom + bounds.top) / 2.0,
+            zoom,
+        );
+        let calc_value = format!("{calc:.prec$}");
+
+        let meta_value = self.get_metadata_value(&mut *conn, "center").await?;
+        let new_value = match meta_value.as_deref().map(Center::from_str) {
+            Some(Ok(meta)) => {
+                let meta_str = format!("{meta:.prec$}");
+                let is_inside = (bounds.left..=bounds.right).contains(&meta.longitude)
+                    && (bounds.bottom..=bounds.top).contains(&meta.latitude)
+                    && (min_zoom..=max_zoom).contains(&meta.zoom);
+                if meta_str == calc_value {
+                    info!("Metadata value center is already set to correct value {meta_str}");
+                    None
+                } else if update == Skip {
+                    info!("Metadata value center is set to {meta_str}, but should be set to {calc_value}. Skipping update");
+                    None
+                } else if update == GrowOnly && is_inside {
+                    info!(
+                        "Metadata value center={meta_str} is inside the tiles area, not updating"
+                    );
+                    None
+                } else {
+                    info!("Updating metadata center from {meta_str} to {calc_value}");
+                    Some(calc_value)
+                }
+            }
+            _ if update == Skip => {
+                info!("Metadata value center should be set to {calc_value}. Skipping update");
+                None
+            }
+            Some(Err(_)) => {
+                let val = meta_value.unwrap_or_default();
+                warn!("Overriding invalid metadata value center='{val}' to {calc_value}");
+                Some(calc_value)
+            }
+            None => {
+                info!("Setting metadata value center to {calc_value}");
+                Some(calc_value)
+            }
+        };
+
+        if let Some(value) = new_value {
+            self.set_metadata_value(conn, "center", value).await?;
+        }
+        Ok(())
+    }
+
+    /// Detect the format and compression of the tiles, ignoring the current metadata values
+    pub(crate) async fn detect_content_format<T>(&self, conn: &mut T) -> MbtResult<Option<TileInfo>>
+    where
+        for<'e> &'e mut T: SqliteExecutor<'e>,
+    {
+        let Some((minzoom, maxzoom)) = compute_min_max_zoom(&mut *conn).await? else {
+            return Ok(None);
+        };
+        let tj = tilejson! { tiles: vec![], minzoom: minzoom, maxzoom: maxzoom };
+        Ok(Some(self.detect_format(&tj, conn).await?))
+    }
+
+    /// Set the `format` and `compression` metadata values to match the content of the tiles.
+    /// Brotli-compressed tiles cannot be told apart from uncompressed ones,
+    /// so an existing `brotli` compression value is kept for them.
+    pub async fn update_format<T>(&self, conn: &mut T) -> MbtResult<()>
+    where
+        for<'e> &'e mut T: SqliteExecutor<'e>,
+    {
+        let Some(info) = self.detect_content_format(&mut *conn).await? else {
+            info!("No tiles found in the tiles table, skipping metadata format update");
+            return Ok(());
+        };
+
+        let format = info.format.metadata_format_value();
+        if self
+            .get_metadata_value(&mut *conn, "format")
+            .await?
+            .as_deref()
+            == Some(format)
+        {
+            info!("Metadata value format is already set to correct value {format}");
+        } else {
+            info!("Setting metadata value format to {format}");
+            self.set_metadata_value(&mut *conn, "format", format)
+                .await?;
+        }
+
+        let meta = self.get_metadata_value(&mut *conn, COMPRESSION).await?;
+        let compression = match info.encoding {
+            Encoding::Gzip => Some("gzip"),
+            Encoding::Zlib => Some("zlib"),
+            Encoding::Zstd => Some("zstd"),
+            Encoding::Brotli => Some("brotli"),
+            Encoding::Uncompressed | Encoding::Internal => None,
+        };
+        match (compression, meta.as_deref()) {
+            (Some(enc), Some(meta)) if enc == meta => {
+                info!("Metadata value {COMPRESSION} is already set to correct value {enc}");
+            }
+            (Some(enc), _) => {
+                info!("Setting metadata value {COMPRESSION} to {enc}");
+                self.set_metadata_value(&mut *conn, COMPRESSION, enc)
+                    .await?;
+            }
+            (None, Some("brotli")) => {
+                info!("Keeping metadata value {COMPRESSION}=brotli because brotli compression cannot be detected");
+            }
+            (None, Some(meta)) => {
+                info!("Deleting metadata value {COMPRESSION}={meta} because the tiles are not compressed");
+                self.delete_metadata_value(&mut *conn, COMPRESSION).await?;
+            }
+            (None, None) => {}
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::{Executor as _, SqliteConnection};
+
+    use super::*;
+    use crate::MbtilesCopier;
+
+    async fn get(mbt: &Mbtiles, conn: &mut SqliteConnection, name: &str) -> String {
+        mbt.get_metadata_value(conn, name).await.unwrap().unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn update_bounds_center_format() {
+        let file = "file:update_bounds_center_format_mem_db?mode=memory&cache=shared";
+        let mut conn = MbtilesCopier {
+            src_file: "../tests/fixtures/mbtiles/world_cities.mbtiles".into(),
+            dst_file: file.into(),
+            ..Default::default()
+        }
+        .run()
+        .await
+        .unwrap();
+        let mbt = Mbtiles::new(file).unwrap();
+
+        mbt.set_metadata_value(&mut conn, "bounds", "0,0,1,1")
+            .await
+            .unwrap();
+        mbt.update_bounds(&mut conn, Skip).await.unwrap();
+        assert_eq!(get(&mbt, &mut conn, "bounds").await, "0,0,1,1");
+        mbt.update_bounds(&mut conn, GrowOnly).await.unwrap();
+        assert_eq!(get(&mbt, &mut conn, "bounds").await, "-124,-41,180,62");
+
+        mbt.set_metadata_value(&mut conn, "bounds", "-180,-85,180,85")
+            .await
+            .unwrap();
+        mbt.update_bounds(&mut conn, GrowOnly).await.unwrap();
+        assert_eq!(get(&mbt, &mut conn, "bounds").await, "-180,-85,180,85");
+        mbt.update_bounds(&mut conn, Reset).await.unwrap();
+        assert_eq!(get(&mbt, &mut conn, "bounds").await, "-124,-41,180,62");
+
+        mbt.delete_metadata_value(&mut conn, "center")
+            .await
+            .unwrap();
+        mbt.update_center(&mut conn, GrowOnly).await.unwrap();
+        assert_eq!(get(&mbt, &mut conn, "center").await, "28,10,0");
+
+        conn.execute("UPDATE metadata SET value = 'png' WHERE name = 'format'")
+            .await
+            .unwrap();
+        mbt.set_metadata_value(&mut conn, COMPRESSION, "zstd")
+            .await
+            .unwrap();
+        mbt.update_format(&mut conn).await.unwrap();
+        assert_eq!(get(&mbt, &mut conn, "format").await, "pbf");
+        assert_eq!(get(&mbt, &mut conn, COMPRESSION).await, "gzip");
+    }
 }
@@ -3,7 +3,7 @@ use std::str::from_utf8;
 
 use enum_display::EnumDisplay;
 use log::{debug, info, warn};
-use martin_tile_utils::{Format, TileInfo, MAX_ZOOM};
+use martin_tile_utils::{Encoding, Format, TileInfo, MAX_ZOOM};
 use serde::Serialize;
 use serde_json::Value;
 use sqlx::sqlite::SqliteRow;
@@ -25,6 +25,10 @@ use crate::{get_patch_type, invert_y_value, Mbtiles};
 /// Metadata key for the aggregate tiles hash value
 pub const AGG_TILES_HASH: &str = "agg_tiles_hash";
 
+/// Metadata key for the compression of the tile data, e.g. `gzip` or `brotli`.
+/// Only needed for compressions that cannot be detected from the tile data.
+pub const COMPRESSION: &str = "compression";
+
 /// Metadata key for a diff file, describing the eventual [`AGG_TILES_HASH`] value of the resulting tileset once the diff is applied
 pub const AGG_TILES_HASH_AFTER_APPLY: &str = "agg_tiles_hash_after_apply";
 
@@ -176,6 +180,26 @@ impl Mbtiles {
             }
         }
 
+        if let Some(Value::String(compression)) = tilejson.other.get(COMPRESSION) {
+            let file = self.filename();
+            match (&mut tile_info, Encoding::parse(compression)) {
+                (_, None) => {
+                    warn!("Unknown compression value in metadata: {compression}");
+                }
+                (Some(info), Some(enc)) if info.encoding == Encoding::Uncompressed => {
+                    // Brotli and uncompressed tiles cannot be detected, so trust the metadata
+                    debug!(
+                        "Using '{compression}' tile compression from metadata table in file {file}"
+                    );
+                    info.encoding = enc;
+                }
+                (Some(info), Some(enc)) if info.encoding.is_encoded() && info.encoding != enc => {
+                    warn!("Found inconsistency: metadata.compression='{compression}', but tiles were detected as {info} in file {file}. Tiles will be returned as {info}.");
+                }
+                _ => {}
+            }
+        }
+
         if let Some(info) = tile_info {
             if info.format != Format::Mvt && tilejson.vector_layers.is_some() {
                 warn!(
@@ -538,11 +562,23 @@ LIMIT 1;"
 /// Compute the hash of the combined tiles in the mbtiles file tiles table/view.
 /// This should work on all mbtiles files perf `MBTiles` specification.
 pub async fn calc_agg_tiles_hash<T>(conn: &mut T) -> MbtResult<String>
+where
+    for<'e> &'e mut T: SqliteExecutor<'e>,
+{
+    calc_filtered_agg_tiles_hash(conn, "").await
+}
+
+/// Compute the hash of the tiles matching `sql_where`, a list of `AND ...` conditions
+/// on the tiles table/view columns.
+pub(crate) async fn calc_filtered_agg_tiles_hash<T>(
+    conn: &mut T,
+    sql_where: &str,
+) -> MbtResult<String>
 where
     for<'e> &'e mut T: SqliteExecutor<'e>,
 {
     debug!("Calculating agg_tiles_hash");
-    let query = query(
+    let sql = format!(
         // The md5_concat func will return NULL if there are no rows in the tiles table.
         // For our use case, we will treat it as an empty string, and hash that.
         // `tile_data` values must be stored as a blob per MBTiles spec
@@ -560,10 +596,11 @@ SELECT coalesce(
                tile_data
                ORDER BY zoom_level, tile_column, tile_row),
            md5_hex(''))
-FROM tiles;
-",
+FROM tiles
+WHERE 1 = 1 {sql_where};
+"
     );
-    Ok(query.fetch_one(conn).await?.get::<String, _>(0))
+    Ok(query(&sql).fetch_one(conn).await?.get::<String, _>(0))
 }
 
 #[cfg(test)]
@@ -0,0 +1,18 @@
+DROP FUNCTION IF EXISTS public.function_zxy_params;
+
+CREATE OR REPLACE FUNCTION public.function_zxy_params(z integer, x integer, y integer, min_gid integer, kinds text[] DEFAULT '{POINT}') RETURNS bytea AS $$
+DECLARE
+  mvt bytea;
+BEGIN
+  SELECT INTO mvt ST_AsMVT(tile, 'public.function_zxy_params', 4096, 'geom') FROM (
+    SELECT
+      ST_AsMVTGeom(ST_Transform(ST_CurveToLine(geom), 3857), ST_TileEnvelope(z, x, y), 4096, 64, true) AS geom
+    FROM public.table_source
+    WHERE geom && ST_Transform(ST_TileEnvelope(z, x, y), 4326)
+      AND gid >= min_gid
+      AND GeometryType(geom) = ANY(kinds)
+  ) as tile WHERE geom IS NOT NULL;
+
+  RETURN mvt;
+END
+$$ LANGUAGE plpgsql IMMUTABLE STRICT PARALLEL SAFE;